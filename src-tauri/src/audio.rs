@@ -0,0 +1,291 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::Path;
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+use tauri::AppHandle;
+use uuid::Uuid;
+
+use crate::olscore::{
+    cache_dir_for_score, extract_entry_if_needed, modify_archive, modify_manifest,
+    read_manifest_at, score_path, ArchiveChanges, CropRect,
+};
+use crate::timestamp::Timestamp;
+
+pub const AUDIO_EXTENSIONS: &[&str] = &["mp3", "m4a", "aac", "ogg", "oga", "opus", "flac", "wav"];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AudioKind {
+    /// A recording to play or practice along with.
+    #[default]
+    Reference,
+    /// The musician's own practice recording.
+    Practice,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "storage", rename_all = "snake_case")]
+pub enum AudioLocation {
+    /// Stored in the archive under `audio/`.
+    Embedded { filename: String },
+    /// Left where it is, to keep the archive small.
+    External { path: String },
+}
+
+/// Links a moment of the recording to a page, and optionally a measure.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AudioSyncPoint {
+    /// Seconds from the start of the recording.
+    pub time: f64,
+    pub pdf: String,
+    pub page: u32,
+    /// The part of the page being played, e.g. a detected system.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region: Option<CropRect>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub measure: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AudioEntry {
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub kind: AudioKind,
+    /// File extension, e.g. "mp3".
+    pub format: String,
+    /// Seconds; `None` if the file couldn't be probed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration: Option<f64>,
+    #[serde(flatten)]
+    pub location: AudioLocation,
+    pub sha256: String,
+    pub added_at: Timestamp,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sync_points: Vec<AudioSyncPoint>,
+}
+
+/// Reads the duration from the container, counting packets if it doesn't
+/// say.
+fn probe_duration(path: &Path, format: &str) -> Option<f64> {
+    let file = fs::File::open(path).ok()?;
+    let stream = MediaSourceStream::new(Box::new(file), Default::default());
+    let mut hint = Hint::new();
+    hint.with_extension(format);
+    let mut reader = symphonia::default::get_probe()
+        .format(
+            &hint,
+            stream,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )
+        .ok()?
+        .format;
+
+    let track = reader.default_track()?;
+    let params = track.codec_params.clone();
+    let track_id = track.id;
+    let frames = match params.n_frames {
+        Some(frames) => frames,
+        None => {
+            let mut frames = 0;
+            while let Ok(packet) = reader.next_packet() {
+                if packet.track_id() == track_id {
+                    frames += packet.dur;
+                }
+            }
+            frames
+        }
+    };
+    match (params.time_base, params.sample_rate) {
+        (Some(base), _) => {
+            let time = base.calc_time(frames);
+            Some(time.seconds as f64 + time.frac)
+        }
+        (None, Some(rate)) => Some(frames as f64 / rate as f64),
+        _ => None,
+    }
+}
+
+fn archive_name(filename: &str) -> String {
+    format!("audio/{filename}")
+}
+
+pub fn find_entry<'a>(audio: &'a mut [AudioEntry], id: &str) -> Result<&'a mut AudioEntry, String> {
+    audio
+        .iter_mut()
+        .find(|a| a.id == id)
+        .ok_or_else(|| format!("Audio not found: {id}"))
+}
+
+/// Adds a recording to the score. With `embed` it is copied into the
+/// archive, otherwise only its path is kept.
+#[tauri::command]
+pub fn add_audio(
+    app: AppHandle,
+    score_id: String,
+    source_path: String,
+    embed: bool,
+    kind: Option<AudioKind>,
+    name: Option<String>,
+) -> Result<AudioEntry, String> {
+    let path = Path::new(&source_path);
+    let format = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_lowercase)
+        .filter(|e| AUDIO_EXTENSIONS.contains(&e.as_str()))
+        .ok_or_else(|| format!("Unsupported audio file: {source_path}"))?;
+    let bytes = fs::read(path).map_err(|e| format!("Failed to read audio: {e}"))?;
+    let mut hasher = Sha256::new();
+    hasher.update(&bytes);
+
+    let id = Uuid::new_v4().to_string();
+    let location = if embed {
+        AudioLocation::Embedded {
+            filename: format!("{id}.{format}"),
+        }
+    } else {
+        let absolute =
+            fs::canonicalize(path).map_err(|e| format!("Failed to resolve audio path: {e}"))?;
+        AudioLocation::External {
+            path: absolute.to_string_lossy().into_owned(),
+        }
+    };
+    let entry = AudioEntry {
+        name: name.unwrap_or_else(|| {
+            path.file_stem()
+                .and_then(|s| s.to_str())
+                .unwrap_or("Recording")
+                .to_string()
+        }),
+        kind: kind.unwrap_or_default(),
+        duration: probe_duration(path, &format),
+        format,
+        location,
+        sha256: format!("{:x}", hasher.finalize()),
+        added_at: Timestamp::now(),
+        sync_points: Vec::new(),
+        id,
+    };
+
+    let olscore_path = score_path(&app, &score_id)?;
+    modify_archive(&olscore_path, |manifest| {
+        manifest.audio.push(entry.clone());
+        let mut changes = ArchiveChanges::default();
+        if let AudioLocation::Embedded { filename } = &entry.location {
+            changes.entries.push((archive_name(filename), bytes));
+        }
+        Ok(changes)
+    })?;
+    Ok(entry)
+}
+
+#[tauri::command]
+pub fn remove_audio(app: AppHandle, score_id: String, audio_id: String) -> Result<(), String> {
+    let mut removed = None;
+    modify_archive(&score_path(&app, &score_id)?, |manifest| {
+        let entry = find_entry(&mut manifest.audio, &audio_id)?.clone();
+        manifest.audio.retain(|a| a.id != audio_id);
+        let mut changes = ArchiveChanges::default();
+        if let AudioLocation::Embedded { filename } = entry.location {
+            changes.remove.push(archive_name(&filename));
+        }
+        removed = changes.remove.first().cloned();
+        Ok(changes)
+    })?;
+
+    if let Some(name) = removed {
+        let cached = cache_dir_for_score(&app, &score_id)?.join(&name);
+        if cached.exists() {
+            fs::remove_file(&cached).map_err(|e| format!("Failed to remove cached audio: {e}"))?;
+        }
+    }
+    Ok(())
+}
+
+#[tauri::command]
+pub fn rename_audio(
+    app: AppHandle,
+    score_id: String,
+    audio_id: String,
+    name: String,
+) -> Result<AudioEntry, String> {
+    let mut manifest = modify_manifest(&score_path(&app, &score_id)?, |manifest| {
+        find_entry(&mut manifest.audio, &audio_id)?.name = name;
+        Ok(())
+    })?;
+    Ok(find_entry(&mut manifest.audio, &audio_id)?.clone())
+}
+
+/// A file path for the frontend to stream through the asset protocol.
+/// Embedded audio is extracted to the cache first.
+#[tauri::command]
+pub fn get_audio_path(
+    app: AppHandle,
+    score_id: String,
+    audio_id: String,
+) -> Result<String, String> {
+    let mut manifest = read_manifest_at(&score_path(&app, &score_id)?)?;
+    let path = match &find_entry(&mut manifest.audio, &audio_id)?.location {
+        AudioLocation::Embedded { filename } => {
+            let name = archive_name(filename);
+            extract_entry_if_needed(&app, &score_id, &name, &name)?
+        }
+        AudioLocation::External { path } => {
+            let path = Path::new(path).to_path_buf();
+            if !path.exists() {
+                return Err(format!("Audio file is missing: {}", path.display()));
+            }
+            path
+        }
+    };
+    path.to_str()
+        .map(String::from)
+        .ok_or_else(|| "Invalid path".into())
+}
+
+/// Replaces the sync points of a recording, sorted by time.
+pub fn save_sync_points(
+    app: &AppHandle,
+    score_id: &str,
+    audio_id: &str,
+    mut points: Vec<AudioSyncPoint>,
+) -> Result<AudioEntry, String> {
+    if points.iter().any(|p| p.time.is_nan() || p.time < 0.0) {
+        return Err("Sync points need a non-negative time".into());
+    }
+    if points
+        .iter()
+        .any(|p| p.region.is_some_and(|r| !r.is_valid()))
+    {
+        return Err("Sync point region must lie within the page".into());
+    }
+    points.sort_by(|a, b| a.time.total_cmp(&b.time));
+
+    let mut manifest = modify_manifest(&score_path(app, score_id)?, |manifest| {
+        if let Some(point) = points
+            .iter()
+            .find(|p| !manifest.pdfs.iter().any(|pdf| pdf.filename == p.pdf))
+        {
+            return Err(format!("PDF not in score: {}", point.pdf));
+        }
+        find_entry(&mut manifest.audio, audio_id)?.sync_points = points;
+        Ok(())
+    })?;
+    Ok(find_entry(&mut manifest.audio, audio_id)?.clone())
+}
+
+#[tauri::command]
+pub fn set_audio_sync_points(
+    app: AppHandle,
+    score_id: String,
+    audio_id: String,
+    points: Vec<AudioSyncPoint>,
+) -> Result<AudioEntry, String> {
+    save_sync_points(&app, &score_id, &audio_id, points)
+}
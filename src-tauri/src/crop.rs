@@ -0,0 +1,362 @@
+use serde::{Deserialize, Serialize};
+use tauri::AppHandle;
+
+use crate::olscore::{modify_manifest, read_manifest_at, score_path, CropRect, ScoreManifest};
+use crate::render::{render_pages, PageImage};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AutoCropOptions {
+    /// Pages are rendered at this width in pixels.
+    pub render_width: i32,
+    /// Pixels darker than this count as ink.
+    pub threshold: u8,
+    /// Fraction of a row or column that must be ink for it to count.
+    pub min_ink: f64,
+    /// Shortest band of ink rows or columns, as a fraction of the page, that
+    /// counts as content. Shorter bands are treated as scan noise.
+    pub min_run: f64,
+    /// Border, as a fraction of the page, ignored for scanner edges.
+    pub edge: f64,
+    /// Space kept around the detected content.
+    pub padding: f64,
+}
+
+impl Default for AutoCropOptions {
+    fn default() -> Self {
+        Self {
+            render_width: 600,
+            threshold: 160,
+            min_ink: 0.002,
+            min_run: 0.004,
+            edge: 0.01,
+            padding: 0.015,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PageCropSuggestion {
+    pub pdf: String,
+    pub page: u32,
+    /// `None` for blank pages.
+    pub crop: Option<CropRect>,
+}
+
+/// Per-page crops plus one crop covering all odd and one covering all even
+/// pages, suitable for `DisplaySettings::crop` and `even_crop`.
+#[derive(Debug, Clone, Serialize)]
+pub struct AutoCropResult {
+    pub pages: Vec<PageCropSuggestion>,
+    pub odd: Option<CropRect>,
+    pub even: Option<CropRect>,
+}
+
+/// First and last index of the outermost bands of at least `min_run`
+/// consecutive entries reaching `min_count`.
+fn content_range(profile: &[u32], min_count: u32, min_run: usize) -> Option<(usize, usize)> {
+    let mut bands = Vec::new();
+    let mut start = None;
+    for (i, &count) in profile.iter().chain(std::iter::once(&0)).enumerate() {
+        match (count >= min_count, start) {
+            (true, None) => start = Some(i),
+            (false, Some(s)) => {
+                if i - s >= min_run {
+                    bands.push((s, i - 1));
+                }
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    Some((bands.first()?.0, bands.last()?.1))
+}
+
+/// Finds the bounding box of the content of a grayscale page image.
+///
+/// Isolated dark pixels are dropped first, then rows and columns with too
+/// little ink or too short a run of inked neighbours are ignored.
+pub fn content_box(image: &PageImage, options: &AutoCropOptions) -> Option<CropRect> {
+    let (width, height) = (image.width, image.height);
+    if width < 3 || height < 3 {
+        return None;
+    }
+    let dark = |x: usize, y: usize| image.is_dark(x, y, options.threshold);
+    let edge_x = (width as f64 * options.edge) as usize;
+    let edge_y = (height as f64 * options.edge) as usize;
+
+    let mut ink = vec![false; width * height];
+    for y in edge_y.max(1)..(height - edge_y).min(height - 1) {
+        for x in edge_x.max(1)..(width - edge_x).min(width - 1) {
+            if !dark(x, y) {
+                continue;
+            }
+            let neighbours = [
+                (x - 1, y - 1),
+                (x, y - 1),
+                (x + 1, y - 1),
+                (x - 1, y),
+                (x + 1, y),
+                (x - 1, y + 1),
+                (x, y + 1),
+                (x + 1, y + 1),
+            ]
+            .iter()
+            .filter(|&&(nx, ny)| dark(nx, ny))
+            .count();
+            ink[y * width + x] = neighbours >= 2;
+        }
+    }
+    let rows: Vec<u32> = ink
+        .chunks(width)
+        .map(|row| row.iter().filter(|&&i| i).count() as u32)
+        .collect();
+    let columns: Vec<u32> = (0..width)
+        .map(|x| ink.iter().skip(x).step_by(width).filter(|&&i| i).count() as u32)
+        .collect();
+
+    let min_count = |len: usize| ((len as f64 * options.min_ink) as u32).max(1);
+    let min_run = |len: usize| ((len as f64 * options.min_run).ceil() as usize).max(1);
+    let (top, bottom) = content_range(&rows, min_count(width), min_run(height))?;
+    let (left, right) = content_range(&columns, min_count(height), min_run(width))?;
+
+    let x0 = (left as f64 / width as f64 - options.padding).max(0.0);
+    let y0 = (top as f64 / height as f64 - options.padding).max(0.0);
+    let x1 = ((right + 1) as f64 / width as f64 + options.padding).min(1.0);
+    let y1 = ((bottom + 1) as f64 / height as f64 + options.padding).min(1.0);
+    Some(CropRect {
+        x: x0,
+        y: y0,
+        width: x1 - x0,
+        height: y1 - y0,
+    })
+}
+
+fn union(a: Option<CropRect>, b: CropRect) -> CropRect {
+    let Some(a) = a else { return b };
+    let x = a.x.min(b.x);
+    let y = a.y.min(b.y);
+    CropRect {
+        x,
+        y,
+        width: (a.x + a.width).max(b.x + b.width) - x,
+        height: (a.y + a.height).max(b.y + b.height) - y,
+    }
+}
+
+impl AutoCropResult {
+    fn push(&mut self, pdf: &str, page: u32, crop: Option<CropRect>) {
+        if let Some(crop) = crop {
+            // Page 0 is the first, odd page
+            let side = if page % 2 == 1 {
+                &mut self.even
+            } else {
+                &mut self.odd
+            };
+            *side = Some(union(*side, crop));
+        }
+        self.pages.push(PageCropSuggestion {
+            pdf: pdf.to_string(),
+            page,
+            crop,
+        });
+    }
+}
+
+fn detect(
+    app: &AppHandle,
+    score_id: &str,
+    manifest: &ScoreManifest,
+    options: &AutoCropOptions,
+) -> Result<AutoCropResult, String> {
+    let mut result = AutoCropResult {
+        pages: Vec::new(),
+        odd: None,
+        even: None,
+    };
+    for pdf in &manifest.pdfs {
+        render_pages(
+            app,
+            score_id,
+            &pdf.filename,
+            options.render_width,
+            |page, image| {
+                result.push(&pdf.filename, page, content_box(&image, options));
+                Ok(())
+            },
+        )?;
+    }
+    Ok(result)
+}
+
+/// Renders every page and suggests crops that trim the white margins.
+/// Nothing is stored; apply the result with `set_score_crop` or
+/// `set_page_view`.
+#[tauri::command]
+pub async fn detect_crop(
+    app: AppHandle,
+    score_id: String,
+    options: Option<AutoCropOptions>,
+) -> Result<AutoCropResult, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let manifest = read_manifest_at(&score_path(&app, &score_id)?)?;
+        detect(&app, &score_id, &manifest, &options.unwrap_or_default())
+    })
+    .await
+    .map_err(|e| format!("Failed to detect crop: {e}"))?
+}
+
+/// Sets the score-wide crop; `even_crop` is only needed when even pages
+/// differ from odd ones.
+#[tauri::command]
+pub fn set_score_crop(
+    app: AppHandle,
+    score_id: String,
+    crop: Option<CropRect>,
+    even_crop: Option<CropRect>,
+) -> Result<ScoreManifest, String> {
+    if [crop, even_crop].iter().flatten().any(|c| !c.is_valid()) {
+        return Err("Crop must lie within the page".into());
+    }
+    modify_manifest(&score_path(&app, &score_id)?, |manifest| {
+        manifest.display.crop = crop;
+        manifest.display.even_crop = even_crop;
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WIDTH: usize = 600;
+    const HEIGHT: usize = 800;
+    const PAPER: u8 = 240;
+    const INK: u8 = 30;
+
+    fn blank() -> PageImage {
+        PageImage {
+            luma: vec![PAPER; WIDTH * HEIGHT],
+            width: WIDTH,
+            height: HEIGHT,
+        }
+    }
+
+    fn fill(image: &mut PageImage, xs: std::ops::Range<usize>, ys: std::ops::Range<usize>) {
+        for y in ys {
+            for x in xs.clone() {
+                image.luma[y * WIDTH + x] = INK;
+            }
+        }
+    }
+
+    /// Lines of "text" 4 pixels high every 12, spanning 100..500 across and
+    /// 150..646 down.
+    fn page() -> PageImage {
+        let mut image = blank();
+        for line in 0..42 {
+            let top = 150 + line * 12;
+            fill(&mut image, 100..500, top..top + 4);
+        }
+        image
+    }
+
+    fn options() -> AutoCropOptions {
+        AutoCropOptions {
+            padding: 0.0,
+            ..AutoCropOptions::default()
+        }
+    }
+
+    fn assert_box(crop: CropRect, left: usize, top: usize, right: usize, bottom: usize) {
+        let near = |actual: f64, px: usize, size: usize| {
+            assert!(
+                (actual * size as f64 - px as f64).abs() <= 1.0,
+                "{actual} is not within 1px of {px}"
+            );
+        };
+        near(crop.x, left, WIDTH);
+        near(crop.y, top, HEIGHT);
+        near(crop.x + crop.width, right, WIDTH);
+        near(crop.y + crop.height, bottom, HEIGHT);
+    }
+
+    #[test]
+    fn content_range_spans_the_outermost_long_bands() {
+        let profile = [0, 5, 0, 0, 3, 3, 3, 0, 9, 9, 0, 4, 4, 4, 4, 0, 7];
+        assert_eq!(content_range(&profile, 3, 3), Some((4, 14)));
+        assert_eq!(content_range(&profile, 3, 1), Some((1, 16)));
+        assert_eq!(content_range(&profile, 5, 2), Some((8, 9)));
+        assert_eq!(content_range(&profile, 10, 1), None);
+    }
+
+    #[test]
+    fn margins_are_trimmed_to_the_content() {
+        let crop = content_box(&page(), &options()).unwrap();
+        assert_box(crop, 100, 150, 500, 646);
+
+        let padded = content_box(&page(), &AutoCropOptions::default()).unwrap();
+        assert!((crop.x - padded.x - 0.015).abs() < 1e-9);
+        assert!(padded.is_valid());
+    }
+
+    #[test]
+    fn noise_in_the_margins_is_ignored() {
+        let mut image = page();
+        let mut state = 0x2545_f491_u32;
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                if state >> 24 < 3 {
+                    image.luma[y * WIDTH + x] = 0;
+                }
+            }
+        }
+        assert_box(content_box(&image, &options()).unwrap(), 100, 150, 500, 646);
+    }
+
+    #[test]
+    fn specks_and_scanner_edges_are_ignored() {
+        let mut image = page();
+        // A dust speck close to the corner, and a dark scanner border
+        fill(&mut image, 20..22, 20..22);
+        fill(&mut image, 0..4, 0..HEIGHT);
+        fill(&mut image, 0..WIDTH, HEIGHT - 3..HEIGHT);
+        assert_box(content_box(&image, &options()).unwrap(), 100, 150, 500, 646);
+    }
+
+    #[test]
+    fn blank_page_has_no_content() {
+        assert!(content_box(&blank(), &options()).is_none());
+
+        let mut specks = blank();
+        fill(&mut specks, 300..302, 400..402);
+        assert!(content_box(&specks, &options()).is_none());
+    }
+
+    #[test]
+    fn odd_and_even_pages_are_combined_separately() {
+        let rect = |x, width| CropRect {
+            x,
+            y: 0.1,
+            width,
+            height: 0.8,
+        };
+        let mut result = AutoCropResult {
+            pages: Vec::new(),
+            odd: None,
+            even: None,
+        };
+        result.push("score.pdf", 0, Some(rect(0.2, 0.6)));
+        result.push("score.pdf", 1, Some(rect(0.05, 0.6)));
+        result.push("score.pdf", 2, Some(rect(0.15, 0.6)));
+        result.push("score.pdf", 3, None);
+
+        let odd = result.odd.unwrap();
+        assert!((odd.x - 0.15).abs() < 1e-9);
+        assert!((odd.x + odd.width - 0.8).abs() < 1e-9);
+        assert_eq!(result.even, Some(rect(0.05, 0.6)));
+        assert_eq!(result.pages.len(), 4);
+        assert_eq!(result.pages[3].crop, None);
+    }
+}
@@ -0,0 +1,523 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tauri::AppHandle;
+
+use crate::olscore::{modify_manifest, read_manifest_at, score_path, DisplayMode, ScoreManifest};
+use crate::render::page_sizes;
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct Viewport {
+    pub width: f64,
+    pub height: f64,
+}
+
+/// A horizontal band of one page and where it goes on screen. `top` and
+/// `bottom` are fractions of the displayed (cropped, rotated) page, the
+/// placement is in viewport pixels.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ScreenRegion {
+    /// Position in the page sequence.
+    pub index: usize,
+    pub pdf: String,
+    pub page: u32,
+    pub top: f64,
+    pub bottom: f64,
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+/// A sequence entry as the layout sees it.
+#[derive(Debug, Clone)]
+pub struct PageView {
+    pub index: usize,
+    pub pdf: String,
+    pub page: u32,
+    /// Width over height as displayed.
+    pub aspect: f64,
+    /// Split points as fractions of the displayed page, sorted.
+    pub splits: Vec<f64>,
+}
+
+impl PageView {
+    fn region(
+        &self,
+        top: f64,
+        bottom: f64,
+        x: f64,
+        y: f64,
+        width: f64,
+        height: f64,
+    ) -> ScreenRegion {
+        ScreenRegion {
+            index: self.index,
+            pdf: self.pdf.clone(),
+            page: self.page,
+            top,
+            bottom,
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    /// Largest size with the page's aspect that fits the box.
+    fn fit(&self, width: f64, height: f64) -> (f64, f64) {
+        if width / height > self.aspect {
+            (height * self.aspect, height)
+        } else {
+            (width, width / self.aspect)
+        }
+    }
+
+    /// The split point closest to the middle, or the middle itself.
+    fn half_split(&self) -> f64 {
+        self.splits
+            .iter()
+            .copied()
+            .min_by(|a, b| (a - 0.5).abs().total_cmp(&(b - 0.5).abs()))
+            .unwrap_or(0.5)
+    }
+}
+
+fn single(pages: &[PageView], viewport: Viewport) -> Vec<Vec<ScreenRegion>> {
+    pages
+        .iter()
+        .map(|page| {
+            let (w, h) = page.fit(viewport.width, viewport.height);
+            let x = (viewport.width - w) / 2.0;
+            let y = (viewport.height - h) / 2.0;
+            vec![page.region(0.0, 1.0, x, y, w, h)]
+        })
+        .collect()
+}
+
+fn dual(pages: &[PageView], viewport: Viewport, starts_on_left: bool) -> Vec<Vec<ScreenRegion>> {
+    let half = viewport.width / 2.0;
+    let place = |page: &PageView, left: bool| {
+        let (w, h) = page.fit(half, viewport.height);
+        let x = if left { half - w } else { half };
+        page.region(0.0, 1.0, x, (viewport.height - h) / 2.0, w, h)
+    };
+
+    let mut screens = Vec::new();
+    let mut rest = pages;
+    if !starts_on_left {
+        if let Some((first, tail)) = rest.split_first() {
+            screens.push(vec![place(first, false)]);
+            rest = tail;
+        }
+    }
+    for pair in rest.chunks(2) {
+        let mut screen = vec![place(&pair[0], true)];
+        if let Some(right) = pair.get(1) {
+            screen.push(place(right, false));
+        }
+        screens.push(screen);
+    }
+    screens
+}
+
+/// Full page N, then the top of N+1 above the rest of N, then full N+1.
+fn half_page(pages: &[PageView], viewport: Viewport) -> Vec<Vec<ScreenRegion>> {
+    let full = single(pages, viewport);
+    let mut screens = Vec::new();
+    for (i, screen) in full.iter().enumerate() {
+        screens.push(screen.clone());
+        let (Some(current), Some(next)) = (screen.first(), full.get(i + 1).and_then(|s| s.first()))
+        else {
+            continue;
+        };
+        let split = pages[i + 1].half_split();
+        let line = next.y + next.height * split;
+        let top = ((line - current.y) / current.height).clamp(0.0, 1.0);
+        screens.push(vec![
+            pages[i + 1].region(0.0, split, next.x, next.y, next.width, line - next.y),
+            pages[i].region(
+                top,
+                1.0,
+                current.x,
+                line.max(current.y),
+                current.width,
+                current.height * (1.0 - top),
+            ),
+        ]);
+    }
+    screens
+}
+
+/// Viewport-high windows over all pages stacked at full width. Each window
+/// starts at the last split point or page top in the lower half of the
+/// previous one, so a system cut at the bottom is shown whole on the next.
+fn vertical_scroll(pages: &[PageView], viewport: Viewport) -> Vec<Vec<ScreenRegion>> {
+    let mut offsets = Vec::with_capacity(pages.len());
+    let mut boundaries = Vec::new();
+    let mut total = 0.0;
+    for page in pages {
+        let height = viewport.width / page.aspect;
+        offsets.push((total, height));
+        boundaries.push(total);
+        boundaries.extend(page.splits.iter().map(|s| total + s * height));
+        total += height;
+    }
+
+    let mut screens = Vec::new();
+    let mut start = 0.0;
+    while start < total {
+        let end = start + viewport.height;
+        let screen = pages
+            .iter()
+            .zip(&offsets)
+            .filter(|(_, &(offset, height))| offset < end && offset + height > start)
+            .map(|(page, &(offset, height))| {
+                let top = ((start - offset) / height).max(0.0);
+                let bottom = ((end - offset) / height).min(1.0);
+                page.region(
+                    top,
+                    bottom,
+                    0.0,
+                    offset + top * height - start,
+                    viewport.width,
+                    (bottom - top) * height,
+                )
+            })
+            .collect();
+        screens.push(screen);
+        if end >= total {
+            break;
+        }
+        // Only boundaries in the lower half, so each screen moves on by at
+        // least half a viewport
+        start = boundaries
+            .iter()
+            .copied()
+            .filter(|&b| b > start + viewport.height / 2.0 && b < end)
+            .max_by(f64::total_cmp)
+            .unwrap_or(end);
+    }
+    screens
+}
+
+/// Lays the pages out screen by screen for the given mode.
+pub fn screens(
+    pages: &[PageView],
+    viewport: Viewport,
+    mode: DisplayMode,
+    starts_on_left: bool,
+) -> Result<Vec<Vec<ScreenRegion>>, String> {
+    if !(viewport.width > 0.0 && viewport.height > 0.0) {
+        return Err("Viewport must not be empty".into());
+    }
+    Ok(match mode {
+        DisplayMode::Auto if viewport.width > viewport.height => {
+            dual(pages, viewport, starts_on_left)
+        }
+        DisplayMode::Auto | DisplayMode::Single => single(pages, viewport),
+        DisplayMode::Dual => dual(pages, viewport, starts_on_left),
+        DisplayMode::HalfPage => half_page(pages, viewport),
+        DisplayMode::VerticalScroll => vertical_scroll(pages, viewport),
+    })
+}
+
+/// Builds the page views of the sequence from each PDF's page sizes.
+fn page_views(
+    manifest: &ScoreManifest,
+    sizes: &HashMap<&str, Vec<(f64, f64)>>,
+) -> Result<Vec<PageView>, String> {
+    let mut views = Vec::new();
+    for (index, entry) in manifest.page_sequence().into_iter().enumerate() {
+        let (width, height) = sizes
+            .get(entry.pdf.as_str())
+            .and_then(|pages| pages.get(entry.page as usize))
+            .copied()
+            .ok_or_else(|| format!("Page {} not found in {}", entry.page, entry.pdf))?;
+        let crop = manifest.crop_for(&entry);
+        let (crop_y, crop_height) = crop.map_or((0.0, 1.0), |c| (c.y, c.height));
+        let width = width * crop.map_or(1.0, |c| c.width);
+        let height = height * crop_height;
+
+        // Split points are horizontal lines on the unrotated page
+        let splits: Vec<f64> = manifest
+            .split_points
+            .get(&entry.pdf)
+            .and_then(|pages| pages.get(&entry.page))
+            .map(|points| {
+                points
+                    .iter()
+                    .map(|p| (p - crop_y) / crop_height)
+                    .filter(|p| *p > 0.0 && *p < 1.0)
+                    .collect()
+            })
+            .unwrap_or_default();
+        let (aspect, splits) = match entry.rotation {
+            90 | 270 => (height / width, Vec::new()),
+            180 => (
+                width / height,
+                splits.iter().rev().map(|p| 1.0 - p).collect(),
+            ),
+            _ => (width / height, splits),
+        };
+        views.push(PageView {
+            index,
+            pdf: entry.pdf,
+            page: entry.page,
+            aspect,
+            splits,
+        });
+    }
+    Ok(views)
+}
+
+/// The screens to show for a viewport, in the score's mode unless `mode`
+/// overrides it.
+#[tauri::command]
+pub async fn get_screens(
+    app: AppHandle,
+    score_id: String,
+    viewport: Viewport,
+    mode: Option<DisplayMode>,
+) -> Result<Vec<Vec<ScreenRegion>>, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let manifest = read_manifest_at(&score_path(&app, &score_id)?)?;
+        let mut sizes = HashMap::new();
+        for pdf in &manifest.pdfs {
+            sizes.insert(
+                pdf.filename.as_str(),
+                page_sizes(&app, &score_id, &pdf.filename)?,
+            );
+        }
+        let pages = page_views(&manifest, &sizes)?;
+        screens(
+            &pages,
+            viewport,
+            mode.unwrap_or(manifest.display.default_spread),
+            manifest.display.starts_on_left,
+        )
+    })
+    .await
+    .map_err(|e| format!("Failed to lay out pages: {e}"))?
+}
+
+/// Replaces the split points of one page; an empty list removes them.
+#[tauri::command]
+pub fn set_split_points(
+    app: AppHandle,
+    score_id: String,
+    pdf: String,
+    page: u32,
+    mut points: Vec<f64>,
+) -> Result<(), String> {
+    if points.iter().any(|p| !(*p > 0.0 && *p < 1.0)) {
+        return Err("Split points must lie within the page".into());
+    }
+    points.sort_by(f64::total_cmp);
+    points.dedup();
+
+    modify_manifest(&score_path(&app, &score_id)?, |manifest| {
+        if !manifest.pdfs.iter().any(|p| p.filename == pdf) {
+            return Err(format!("PDF not in score: {pdf}"));
+        }
+        let pages = manifest.split_points.entry(pdf.clone()).or_default();
+        if points.is_empty() {
+            pages.remove(&page);
+            if pages.is_empty() {
+                manifest.split_points.remove(&pdf);
+            }
+        } else {
+            pages.insert(page, points);
+        }
+        Ok(())
+    })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::olscore::{new_score_manifest, CropRect, PageRef};
+
+    fn view(index: usize, aspect: f64, splits: &[f64]) -> PageView {
+        PageView {
+            index,
+            pdf: "score.pdf".into(),
+            page: index as u32,
+            aspect,
+            splits: splits.to_vec(),
+        }
+    }
+
+    fn viewport(width: f64, height: f64) -> Viewport {
+        Viewport { width, height }
+    }
+
+    /// Index, band and placement of each region of a screen.
+    fn layout(screen: &[ScreenRegion]) -> Vec<(usize, f64, f64, f64, f64, f64, f64)> {
+        screen
+            .iter()
+            .map(|r| (r.index, r.top, r.bottom, r.x, r.y, r.width, r.height))
+            .collect()
+    }
+
+    fn assert_layout(screen: &[ScreenRegion], expected: &[(usize, f64, f64, f64, f64, f64, f64)]) {
+        let actual = layout(screen);
+        assert_eq!(actual.len(), expected.len(), "{actual:?}");
+        for (a, e) in actual.iter().zip(expected) {
+            let close = [
+                (a.1, e.1),
+                (a.2, e.2),
+                (a.3, e.3),
+                (a.4, e.4),
+                (a.5, e.5),
+                (a.6, e.6),
+            ]
+            .iter()
+            .all(|(a, e)| (a - e).abs() < 1e-9);
+            assert!(a.0 == e.0 && close, "{actual:?} != {expected:?}");
+        }
+    }
+
+    #[test]
+    fn single_pages_are_centred() {
+        let pages = [view(0, 0.75, &[]), view(1, 1.5, &[])];
+        let screens = single(&pages, viewport(1000.0, 800.0));
+        assert_eq!(screens.len(), 2);
+        assert_layout(&screens[0], &[(0, 0.0, 1.0, 200.0, 0.0, 600.0, 800.0)]);
+        // Too wide for the viewport, so it fits the width instead
+        assert_layout(
+            &screens[1],
+            &[(1, 0.0, 1.0, 0.0, 400.0 - 1000.0 / 3.0, 1000.0, 2000.0 / 3.0)],
+        );
+    }
+
+    #[test]
+    fn dual_pages_meet_in_the_middle() {
+        let pages: Vec<_> = (0..3).map(|i| view(i, 0.75, &[])).collect();
+        let left = |index| (index, 0.0, 1.0, 200.0, 0.0, 600.0, 800.0);
+        let right = |index| (index, 0.0, 1.0, 800.0, 0.0, 600.0, 800.0);
+
+        let screens = dual(&pages, viewport(1600.0, 800.0), true);
+        assert_eq!(screens.len(), 2);
+        assert_layout(&screens[0], &[left(0), right(1)]);
+        assert_layout(&screens[1], &[left(2)]);
+
+        // The first page alone on the right, then pairs
+        let screens = dual(&pages, viewport(1600.0, 800.0), false);
+        assert_eq!(screens.len(), 2);
+        assert_layout(&screens[0], &[right(0)]);
+        assert_layout(&screens[1], &[left(1), right(2)]);
+    }
+
+    #[test]
+    fn half_page_turns_at_the_split_nearest_the_middle() {
+        let pages = [view(0, 0.75, &[]), view(1, 0.75, &[0.2, 0.4, 0.9])];
+        let screens = half_page(&pages, viewport(600.0, 800.0));
+        assert_eq!(screens.len(), 3);
+        assert_layout(&screens[0], &[(0, 0.0, 1.0, 0.0, 0.0, 600.0, 800.0)]);
+        // The top of page 1 down to its split, above the rest of page 0
+        assert_layout(
+            &screens[1],
+            &[
+                (1, 0.0, 0.4, 0.0, 0.0, 600.0, 320.0),
+                (0, 0.4, 1.0, 0.0, 320.0, 600.0, 480.0),
+            ],
+        );
+        assert_layout(&screens[2], &[(1, 0.0, 1.0, 0.0, 0.0, 600.0, 800.0)]);
+
+        // Without split points the page is cut in the middle
+        let pages = [view(0, 0.75, &[]), view(1, 0.75, &[])];
+        let screens = half_page(&pages, viewport(600.0, 800.0));
+        assert!((screens[1][0].bottom - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn vertical_scroll_advances_to_the_last_split_in_the_lower_half() {
+        let pages = [view(0, 0.75, &[0.3, 0.7]), view(1, 0.75, &[0.25])];
+        let screens = vertical_scroll(&pages, viewport(600.0, 800.0));
+        assert_eq!(screens.len(), 3);
+        assert_layout(&screens[0], &[(0, 0.0, 1.0, 0.0, 0.0, 600.0, 800.0)]);
+        // 0.3 lies in the upper half, so the window moves on to 0.7
+        assert_layout(
+            &screens[1],
+            &[
+                (0, 0.7, 1.0, 0.0, 0.0, 600.0, 240.0),
+                (1, 0.0, 0.7, 0.0, 240.0, 600.0, 560.0),
+            ],
+        );
+        assert_layout(&screens[2], &[(1, 0.25, 1.0, 0.0, 0.0, 600.0, 600.0)]);
+
+        // Without splits each window follows on from the last
+        let pages = [view(0, 0.5, &[])];
+        let screens = vertical_scroll(&pages, viewport(600.0, 500.0));
+        assert_eq!(screens.len(), 3);
+        assert_layout(
+            &screens[1],
+            &[(0, 500.0 / 1200.0, 1000.0 / 1200.0, 0.0, 0.0, 600.0, 500.0)],
+        );
+    }
+
+    #[test]
+    fn auto_mode_follows_the_viewport_orientation() {
+        let pages: Vec<_> = (0..2).map(|i| view(i, 0.75, &[])).collect();
+        let landscape = screens(&pages, viewport(1600.0, 800.0), DisplayMode::Auto, true).unwrap();
+        assert_eq!(landscape.len(), 1);
+        let portrait = screens(&pages, viewport(800.0, 1600.0), DisplayMode::Auto, true).unwrap();
+        assert_eq!(portrait.len(), 2);
+        assert!(screens(&pages, viewport(0.0, 800.0), DisplayMode::Single, true).is_err());
+    }
+
+    fn assert_splits(actual: &[f64], expected: &[f64]) {
+        assert_eq!(actual.len(), expected.len(), "{actual:?}");
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 1e-9, "{actual:?} != {expected:?}");
+        }
+    }
+
+    #[test]
+    fn page_views_apply_crops_rotation_and_splits() {
+        let mut manifest = new_score_manifest("score.pdf", "abc", 3);
+        let page = |page, rotation| PageRef {
+            rotation,
+            ..PageRef::new("score.pdf".into(), page)
+        };
+        manifest.page_order = Some(vec![page(0, 0), page(1, 90), page(2, 180)]);
+        manifest.display.crop = Some(CropRect {
+            x: 0.0,
+            y: 0.1,
+            width: 1.0,
+            height: 0.8,
+        });
+        for page in 0..3 {
+            manifest
+                .split_points
+                .entry("score.pdf".into())
+                .or_default()
+                .insert(page, vec![0.05, 0.3, 0.5]);
+        }
+        let sizes = HashMap::from([("score.pdf", vec![(600.0, 1000.0); 3])]);
+
+        let views = page_views(&manifest, &sizes).unwrap();
+        assert_eq!(views.len(), 3);
+        // 0.05 lies in the cropped-off strip; the others move with the crop
+        assert!((views[0].aspect - 0.75).abs() < 1e-9);
+        assert_splits(&views[0].splits, &[0.25, 0.5]);
+        // Turned sideways, horizontal splits no longer cut across the page
+        assert!((views[1].aspect - 800.0 / 600.0).abs() < 1e-9);
+        assert!(views[1].splits.is_empty());
+        // Upside down, the splits are mirrored
+        assert_splits(&views[2].splits, &[0.5, 0.75]);
+    }
+
+    #[test]
+    fn imported_scores_have_a_view_per_page() {
+        let manifest = new_score_manifest("score.pdf", "abc", 2);
+        let sizes = HashMap::from([("score.pdf", vec![(600.0, 800.0); 2])]);
+        let views = page_views(&manifest, &sizes).unwrap();
+        let screens = screens(&views, viewport(600.0, 800.0), DisplayMode::Single, false).unwrap();
+        assert_eq!(screens.len(), 2);
+
+        let missing = HashMap::from([("score.pdf", vec![(600.0, 800.0)])]);
+        assert!(page_views(&manifest, &missing).is_err());
+    }
+}
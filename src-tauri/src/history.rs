@@ -0,0 +1,689 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::Path;
+use tauri::{AppHandle, State};
+
+use crate::notes::{NotesSnapshot, NotesStore};
+use crate::olscore::{
+    notes_json, read_archive_entry, read_notes_at, replace_archive_entries, score_path, NotesData,
+};
+use crate::timestamp::Timestamp;
+
+const HISTORY_FORMAT_VERSION: &str = "0.1";
+const HISTORY_ENTRY: &str = "history.json";
+
+/// Oldest entries are folded into the base state beyond this.
+const MAX_HISTORY_ENTRIES: usize = 500;
+
+/// Addresses one annotation (or one page's bookmark list) in the notes.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum NoteKey {
+    Layer { id: String },
+    RepeatButton { id: String },
+    NavMark { id: String },
+    Stroke { pdf: String, page: u32, id: String },
+    TextNote { pdf: String, page: u32, id: String },
+    Bookmarks { pdf: String, page: u32 },
+}
+
+/// `index` is the position among all annotations, used to restore order.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum NoteOp {
+    Add {
+        key: NoteKey,
+        index: usize,
+        value: Value,
+    },
+    Modify {
+        key: NoteKey,
+        before: Value,
+        after: Value,
+    },
+    Delete {
+        key: NoteKey,
+        index: usize,
+        value: Value,
+    },
+}
+
+impl NoteOp {
+    fn key(&self) -> &NoteKey {
+        match self {
+            NoteOp::Add { key, .. } | NoteOp::Modify { key, .. } | NoteOp::Delete { key, .. } => {
+                key
+            }
+        }
+    }
+
+    fn inverse(&self) -> NoteOp {
+        match self.clone() {
+            NoteOp::Add { key, index, value } => NoteOp::Delete { key, index, value },
+            NoteOp::Delete { key, index, value } => NoteOp::Add { key, index, value },
+            NoteOp::Modify { key, before, after } => NoteOp::Modify {
+                key,
+                before: after,
+                after: before,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EntryKind {
+    Edit,
+    Undo { of: u64 },
+    Redo { of: u64 },
+    Restore,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub id: u64,
+    pub timestamp: Timestamp,
+    pub kind: EntryKind,
+    pub ops: Vec<NoteOp>,
+}
+
+/// Append-only timeline of changes to notes.json, stored as `history.json`
+/// in the archive. Undo and redo are recorded as entries too, so replaying
+/// the timeline up to any time gives the notes as they were then.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NotesHistory {
+    pub format_version: String,
+    /// Notes before the oldest entry.
+    pub base: NotesData,
+    pub base_at: Timestamp,
+    pub next_id: u64,
+    pub entries: Vec<HistoryEntry>,
+    /// Entry ids that can be undone, most recent last.
+    #[serde(default)]
+    pub undo_stack: Vec<u64>,
+    /// Undone entry ids that can be redone, most recent last.
+    #[serde(default)]
+    pub redo_stack: Vec<u64>,
+}
+
+impl NotesHistory {
+    fn new(base: NotesData) -> Self {
+        Self {
+            format_version: HISTORY_FORMAT_VERSION.into(),
+            base,
+            base_at: Timestamp::now(),
+            next_id: 1,
+            entries: Vec::new(),
+            undo_stack: Vec::new(),
+            redo_stack: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, kind: EntryKind, ops: Vec<NoteOp>) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.entries.push(HistoryEntry {
+            id,
+            timestamp: Timestamp::now(),
+            kind,
+            ops,
+        });
+        id
+    }
+
+    fn entry(&self, id: u64) -> Option<&HistoryEntry> {
+        self.entries.iter().find(|e| e.id == id)
+    }
+
+    /// Folds the oldest entries into `base` until the cap is met. Folded
+    /// entries can no longer be undone or redone.
+    fn compact(&mut self) -> Result<(), String> {
+        let excess = self.entries.len().saturating_sub(MAX_HISTORY_ENTRIES);
+        if excess == 0 {
+            return Ok(());
+        }
+        let folded: Vec<HistoryEntry> = self.entries.drain(..excess).collect();
+        let mut state = flatten(&self.base)?;
+        for entry in &folded {
+            apply(&mut state, &entry.ops);
+        }
+        self.base = unflatten(&self.base.format_version, state)?;
+        if let Some(last) = folded.last() {
+            self.base_at = last.timestamp;
+        }
+        let live = |id: &u64| folded.iter().all(|e| e.id != *id);
+        self.undo_stack.retain(live);
+        self.redo_stack.retain(live);
+        Ok(())
+    }
+
+    /// Notes as they were at `timestamp`.
+    pub fn state_at(&self, timestamp: Timestamp) -> Result<NotesData, String> {
+        if timestamp < self.base_at {
+            return Err(format!("History before {} was compacted", self.base_at));
+        }
+        let mut state = flatten(&self.base)?;
+        for entry in self.entries.iter().take_while(|e| e.timestamp <= timestamp) {
+            apply(&mut state, &entry.ops);
+        }
+        unflatten(&self.base.format_version, state)
+    }
+}
+
+type Flat = Vec<(NoteKey, Value)>;
+
+fn to_value<T: Serialize>(value: &T) -> Result<Value, String> {
+    serde_json::to_value(value).map_err(|e| format!("Failed to serialize annotation: {e}"))
+}
+
+fn from_value<T: serde::de::DeserializeOwned>(value: Value) -> Result<T, String> {
+    serde_json::from_value(value).map_err(|e| format!("Failed to parse annotation: {e}"))
+}
+
+fn flatten(notes: &NotesData) -> Result<Flat, String> {
+    let mut flat = Vec::new();
+    for layer in &notes.layers {
+        let key = NoteKey::Layer {
+            id: layer.id.clone(),
+        };
+        flat.push((key, to_value(layer)?));
+    }
+    for button in &notes.repeat_buttons {
+        let key = NoteKey::RepeatButton {
+            id: button.id.clone(),
+        };
+        flat.push((key, to_value(button)?));
+    }
+    for mark in &notes.navigation {
+        let key = NoteKey::NavMark {
+            id: mark.id.clone(),
+        };
+        flat.push((key, to_value(mark)?));
+    }
+    for (pdf, pages) in &notes.pages {
+        for (&page, page_notes) in pages {
+            for stroke in &page_notes.strokes {
+                let key = NoteKey::Stroke {
+                    pdf: pdf.clone(),
+                    page,
+                    id: stroke.id.clone(),
+                };
+                flat.push((key, to_value(stroke)?));
+            }
+            for note in &page_notes.text_notes {
+                let key = NoteKey::TextNote {
+                    pdf: pdf.clone(),
+                    page,
+                    id: note.id.clone(),
+                };
+                flat.push((key, to_value(note)?));
+            }
+            if !page_notes.bookmarks.is_empty() {
+                let key = NoteKey::Bookmarks {
+                    pdf: pdf.clone(),
+                    page,
+                };
+                flat.push((key, to_value(&page_notes.bookmarks)?));
+            }
+        }
+    }
+    Ok(flat)
+}
+
+fn unflatten(format_version: &str, flat: Flat) -> Result<NotesData, String> {
+    let mut notes = NotesData {
+        format_version: format_version.into(),
+        layers: Vec::new(),
+        ..NotesData::default()
+    };
+    for (key, value) in flat {
+        match key {
+            NoteKey::Layer { .. } => notes.layers.push(from_value(value)?),
+            NoteKey::RepeatButton { .. } => notes.repeat_buttons.push(from_value(value)?),
+            NoteKey::NavMark { .. } => notes.navigation.push(from_value(value)?),
+            NoteKey::Stroke { pdf, page, .. } => notes
+                .pages
+                .entry(pdf)
+                .or_default()
+                .entry(page)
+                .or_default()
+                .strokes
+                .push(from_value(value)?),
+            NoteKey::TextNote { pdf, page, .. } => notes
+                .pages
+                .entry(pdf)
+                .or_default()
+                .entry(page)
+                .or_default()
+                .text_notes
+                .push(from_value(value)?),
+            NoteKey::Bookmarks { pdf, page } => {
+                notes
+                    .pages
+                    .entry(pdf)
+                    .or_default()
+                    .entry(page)
+                    .or_default()
+                    .bookmarks = from_value(value)?
+            }
+        }
+    }
+    Ok(notes)
+}
+
+/// Operations turning `before` into `after`.
+fn diff(before: &Flat, after: &Flat) -> Vec<NoteOp> {
+    let old_index: HashMap<&NoteKey, usize> = before
+        .iter()
+        .enumerate()
+        .map(|(i, (k, _))| (k, i))
+        .collect();
+    let new_index: HashMap<&NoteKey, usize> =
+        after.iter().enumerate().map(|(i, (k, _))| (k, i)).collect();
+
+    // Annotations present in both that changed order are recorded as a
+    // delete plus an add. Greedy rather than minimal, reorders are rare.
+    let mut moved = vec![false; before.len()];
+    let mut last_kept = None;
+    for (i, (key, _)) in before.iter().enumerate() {
+        if let Some(&j) = new_index.get(key) {
+            if last_kept.is_some_and(|last| j < last) {
+                moved[i] = true;
+            } else {
+                last_kept = Some(j);
+            }
+        }
+    }
+
+    let mut ops = Vec::new();
+    for (i, (key, value)) in before.iter().enumerate() {
+        if moved[i] || !new_index.contains_key(key) {
+            ops.push(NoteOp::Delete {
+                key: key.clone(),
+                index: i,
+                value: value.clone(),
+            });
+        }
+    }
+    for (j, (key, value)) in after.iter().enumerate() {
+        match old_index.get(key) {
+            Some(&i) if !moved[i] => {
+                if before[i].1 != *value {
+                    ops.push(NoteOp::Modify {
+                        key: key.clone(),
+                        before: before[i].1.clone(),
+                        after: value.clone(),
+                    });
+                }
+            }
+            _ => ops.push(NoteOp::Add {
+                key: key.clone(),
+                index: j,
+                value: value.clone(),
+            }),
+        }
+    }
+    ops
+}
+
+fn apply(state: &mut Flat, ops: &[NoteOp]) {
+    for op in ops {
+        if let NoteOp::Delete { key, .. } = op {
+            state.retain(|(k, _)| k != key);
+        }
+    }
+    let mut adds: Vec<(usize, &NoteKey, &Value)> = ops
+        .iter()
+        .filter_map(|op| match op {
+            NoteOp::Add { key, index, value } => Some((*index, key, value)),
+            _ => None,
+        })
+        .collect();
+    adds.sort_by_key(|(index, _, _)| *index);
+    for (index, key, value) in adds {
+        state.insert(index.min(state.len()), (key.clone(), value.clone()));
+    }
+    for op in ops {
+        if let NoteOp::Modify { key, after, .. } = op {
+            if let Some((_, value)) = state.iter_mut().find(|(k, _)| k == key) {
+                *value = after.clone();
+            }
+        }
+    }
+}
+
+fn read_history(olscore_path: &Path, current: &NotesData) -> Result<NotesHistory, String> {
+    match read_archive_entry(olscore_path, HISTORY_ENTRY)? {
+        Some(data) => {
+            serde_json::from_str(&data).map_err(|e| format!("Failed to parse {HISTORY_ENTRY}: {e}"))
+        }
+        // Scores from before the history existed start it at their current notes
+        None => Ok(NotesHistory::new(current.clone())),
+    }
+}
+
+fn write_with_history(
+    olscore_path: &Path,
+    notes: &NotesData,
+    history: &NotesHistory,
+) -> Result<(), String> {
+    let history_json = serde_json::to_vec(history)
+        .map_err(|e| format!("Failed to serialize {HISTORY_ENTRY}: {e}"))?;
+    replace_archive_entries(
+        olscore_path,
+        &[
+            ("notes.json", notes_json(notes)?),
+            (HISTORY_ENTRY, history_json),
+        ],
+    )
+}
+
+/// Adds the change from `before` to `after` as an undoable entry. Returns
+/// false if there was no change.
+fn record_change(
+    history: &mut NotesHistory,
+    before: &NotesData,
+    after: &NotesData,
+    kind: EntryKind,
+) -> Result<bool, String> {
+    let ops = diff(&flatten(before)?, &flatten(after)?);
+    if ops.is_empty() {
+        return Ok(false);
+    }
+    let id = history.push(kind, ops);
+    history.undo_stack.push(id);
+    history.redo_stack.clear();
+    Ok(true)
+}
+
+/// Writes notes.json and records the difference to the previous notes as
+/// an undoable history entry.
+pub fn commit_notes(olscore_path: &Path, notes: &NotesData) -> Result<(), String> {
+    let current = read_notes_at(olscore_path)?;
+    let mut history = read_history(olscore_path, &current)?;
+    if !record_change(&mut history, &current, notes, EntryKind::Edit)? {
+        return Ok(());
+    }
+    history.compact()?;
+    write_with_history(olscore_path, notes, &history)
+}
+
+/// Reads the history and records `notes`, the cached and possibly not yet
+/// flushed notes, as an edit on top of it. Returns the history for the
+/// caller to extend and write out together with the next notes, so the
+/// pending edit and the operation share one archive rewrite.
+fn history_with_pending(olscore_path: &Path, notes: &NotesData) -> Result<NotesHistory, String> {
+    let stored = read_notes_at(olscore_path)?;
+    let mut history = read_history(olscore_path, &stored)?;
+    record_change(&mut history, &stored, notes, EntryKind::Edit)?;
+    Ok(history)
+}
+
+/// Layers an annotation op touches, from the annotations' `layer` field.
+fn op_layers(op: &NoteOp) -> Vec<&str> {
+    // Layer records themselves stay editable, so a lock can be undone
+    if matches!(op.key(), NoteKey::Layer { .. }) {
+        return Vec::new();
+    }
+    let values = match op {
+        NoteOp::Add { value, .. } | NoteOp::Delete { value, .. } => vec![value],
+        NoteOp::Modify { before, after, .. } => vec![before, after],
+    };
+    values
+        .into_iter()
+        .filter_map(|value| value.get("layer").and_then(Value::as_str))
+        .collect()
+}
+
+/// Fails if `ops` would change annotations on a locked layer.
+fn check_unlocked(notes: &NotesData, ops: &[NoteOp]) -> Result<(), String> {
+    for layer_id in ops.iter().flat_map(op_layers) {
+        if let Some(layer) = notes.layer(layer_id).filter(|l| l.locked) {
+            return Err(format!("Layer is locked: {}", layer.name));
+        }
+    }
+    Ok(())
+}
+
+/// Pops an entry from one stack, applies its ops (inverted for undo) to
+/// `current` and pushes it onto the other. Unflushed changes in `current`
+/// are recorded first, in the same write.
+fn step(olscore_path: &Path, current: &NotesData, undo: bool) -> Result<NotesData, String> {
+    let mut history = history_with_pending(olscore_path, current)?;
+    let stack = if undo {
+        &mut history.undo_stack
+    } else {
+        &mut history.redo_stack
+    };
+    let id = stack.pop().ok_or_else(|| {
+        if undo {
+            "Nothing to undo"
+        } else {
+            "Nothing to redo"
+        }
+        .to_string()
+    })?;
+    let entry = history
+        .entry(id)
+        .ok_or_else(|| format!("History entry {id} not found"))?;
+    let ops: Vec<NoteOp> = if undo {
+        entry.ops.iter().map(NoteOp::inverse).collect()
+    } else {
+        entry.ops.clone()
+    };
+    check_unlocked(current, &ops)?;
+
+    let mut state = flatten(current)?;
+    apply(&mut state, &ops);
+    let notes = unflatten(&current.format_version, state)?;
+
+    if undo {
+        history.push(EntryKind::Undo { of: id }, ops);
+        history.redo_stack.push(id);
+    } else {
+        history.push(EntryKind::Redo { of: id }, ops);
+        history.undo_stack.push(id);
+    }
+    history.compact()?;
+    write_with_history(olscore_path, &notes, &history)?;
+    Ok(notes)
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HistorySummary {
+    pub id: u64,
+    pub timestamp: Timestamp,
+    pub kind: EntryKind,
+    pub changes: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HistoryInfo {
+    pub entries: Vec<HistorySummary>,
+    pub can_undo: bool,
+    pub can_redo: bool,
+    /// Earliest time `restore_notes` can go back to.
+    pub oldest: Timestamp,
+}
+
+#[tauri::command]
+pub fn get_notes_history(
+    app: AppHandle,
+    store: State<'_, NotesStore>,
+    score_id: String,
+) -> Result<HistoryInfo, String> {
+    store.flush(&app, &score_id)?;
+    let path = score_path(&app, &score_id)?;
+    let history = read_history(&path, &read_notes_at(&path)?)?;
+    Ok(HistoryInfo {
+        entries: history
+            .entries
+            .iter()
+            .map(|e| HistorySummary {
+                id: e.id,
+                timestamp: e.timestamp,
+                kind: e.kind,
+                changes: e.ops.len(),
+            })
+            .collect(),
+        can_undo: !history.undo_stack.is_empty(),
+        can_redo: !history.redo_stack.is_empty(),
+        oldest: history.base_at,
+    })
+}
+
+/// Undoes the last change; pending edits are recorded first and undone
+/// as one step.
+#[tauri::command]
+pub fn undo_notes(
+    app: AppHandle,
+    store: State<'_, NotesStore>,
+    score_id: String,
+) -> Result<NotesSnapshot, String> {
+    store.replace_from_archive(&app, &score_id, |path, notes| step(path, notes, true))
+}
+
+#[tauri::command]
+pub fn redo_notes(
+    app: AppHandle,
+    store: State<'_, NotesStore>,
+    score_id: String,
+) -> Result<NotesSnapshot, String> {
+    store.replace_from_archive(&app, &score_id, |path, notes| step(path, notes, false))
+}
+
+/// Brings back the notes as they were at `timestamp`. The restore is itself
+/// recorded, so it can be undone.
+#[tauri::command]
+pub fn restore_notes(
+    app: AppHandle,
+    store: State<'_, NotesStore>,
+    score_id: String,
+    timestamp: Timestamp,
+) -> Result<NotesSnapshot, String> {
+    store.replace_from_archive(&app, &score_id, |path, current| {
+        let mut history = history_with_pending(path, current)?;
+        let notes = history.state_at(timestamp)?;
+        record_change(&mut history, current, &notes, EntryKind::Restore)?;
+        history.compact()?;
+        write_with_history(path, &notes, &history)?;
+        Ok(notes)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::olscore::Stroke;
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    fn new_archive(dir: &Path, notes: &NotesData) -> PathBuf {
+        let path = dir.join("score.olscore");
+        let mut writer = zip::ZipWriter::new(std::fs::File::create(&path).unwrap());
+        writer
+            .start_file("notes.json", zip::write::SimpleFileOptions::default())
+            .unwrap();
+        writer.write_all(&notes_json(notes).unwrap()).unwrap();
+        writer.finish().unwrap();
+        path
+    }
+
+    fn with_stroke(mut notes: NotesData) -> NotesData {
+        let layer = notes.layers[0].id.clone();
+        notes
+            .pages
+            .entry("score.pdf".into())
+            .or_default()
+            .entry(1)
+            .or_default()
+            .strokes
+            .push(Stroke {
+                id: "s1".into(),
+                layer,
+                points: Vec::new(),
+                color: "#000000".into(),
+                width: 2.0,
+                tool: Default::default(),
+            });
+        notes
+    }
+
+    fn locked(mut notes: NotesData) -> NotesData {
+        notes.layers[0].locked = true;
+        notes
+    }
+
+    fn stroke_count(notes: &NotesData) -> usize {
+        notes
+            .pages
+            .values()
+            .flat_map(|p| p.values())
+            .map(|p| p.strokes.len())
+            .sum()
+    }
+
+    fn stored_history(path: &Path) -> NotesHistory {
+        read_history(path, &read_notes_at(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn undo_and_redo() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = new_archive(dir.path(), &NotesData::default());
+        commit_notes(&path, &with_stroke(NotesData::default())).unwrap();
+
+        let undone = step(&path, &read_notes_at(&path).unwrap(), true).unwrap();
+        assert_eq!(stroke_count(&undone), 0);
+        assert_eq!(stroke_count(&read_notes_at(&path).unwrap()), 0);
+
+        let redone = step(&path, &undone, false).unwrap();
+        assert_eq!(stroke_count(&redone), 1);
+        assert!(step(&path, &redone, false).is_err());
+    }
+
+    #[test]
+    fn pending_edit_is_recorded_with_the_undo() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = new_archive(dir.path(), &NotesData::default());
+
+        // Cached but never flushed
+        let pending = with_stroke(NotesData::default());
+        let undone = step(&path, &pending, true).unwrap();
+        assert_eq!(stroke_count(&undone), 0);
+
+        let history = stored_history(&path);
+        let kinds: Vec<_> = history.entries.iter().map(|e| e.kind).collect();
+        assert_eq!(kinds, [EntryKind::Edit, EntryKind::Undo { of: 1 }]);
+        assert_eq!(history.redo_stack, [1]);
+    }
+
+    #[test]
+    fn undo_refuses_locked_layer() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = new_archive(dir.path(), &NotesData::default());
+        commit_notes(&path, &with_stroke(NotesData::default())).unwrap();
+        // Locked without going through the history, e.g. by an older version
+        let notes = locked(with_stroke(NotesData::default()));
+        replace_archive_entries(&path, &[("notes.json", notes_json(&notes).unwrap())]).unwrap();
+
+        let err = step(&path, &notes, true).unwrap_err();
+        assert!(err.contains("locked"), "{err}");
+        assert_eq!(stored_history(&path).undo_stack, [1]);
+        assert_eq!(stroke_count(&read_notes_at(&path).unwrap()), 1);
+    }
+
+    #[test]
+    fn undoing_a_lock_is_allowed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = new_archive(dir.path(), &NotesData::default());
+        commit_notes(&path, &with_stroke(NotesData::default())).unwrap();
+        commit_notes(&path, &locked(with_stroke(NotesData::default()))).unwrap();
+
+        let unlocked = step(&path, &read_notes_at(&path).unwrap(), true).unwrap();
+        assert!(!unlocked.layers[0].locked);
+        let without_stroke = step(&path, &unlocked, true).unwrap();
+        assert_eq!(stroke_count(&without_stroke), 0);
+    }
+}
@@ -0,0 +1,288 @@
+use image::imageops::FilterType;
+use image::{DynamicImage, GrayImage, ImageBuffer, ImageDecoder, ImageReader, Luma};
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+use tauri::AppHandle;
+use tiff::decoder::{Decoder as TiffDecoder, DecodingResult};
+use tiff::ColorType;
+
+use crate::library::LibraryEntry;
+use crate::olscore::import_pdf_bytes;
+use crate::render::images_to_pdf;
+
+pub const IMAGE_EXTENSIONS: &[&str] = &["jpg", "jpeg", "png", "tif", "tiff"];
+
+/// Largest skew corrected, in degrees.
+const MAX_SKEW: f64 = 5.0;
+const SKEW_STEP: f64 = 0.1;
+/// Skew is estimated on a copy scaled down to this width.
+const SKEW_SAMPLE_WIDTH: u32 = 800;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ImageImportOptions {
+    /// Straighten pages photographed or scanned at a slight angle.
+    pub deskew: bool,
+    /// Turn pages into black and white, dropping paper tint and shadows.
+    pub threshold: bool,
+    pub title: Option<String>,
+}
+
+fn is_tiff(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| e.eq_ignore_ascii_case("tif") || e.eq_ignore_ascii_case("tiff"))
+}
+
+/// Converts the current TIFF page to an image.
+fn tiff_page(decoder: &mut TiffDecoder<fs::File>) -> Result<DynamicImage, String> {
+    let (width, height) = decoder
+        .dimensions()
+        .map_err(|e| format!("Failed to read TIFF: {e}"))?;
+    let color = decoder
+        .colortype()
+        .map_err(|e| format!("Failed to read TIFF: {e}"))?;
+    let data = decoder
+        .read_image()
+        .map_err(|e| format!("Failed to decode TIFF: {e}"))?;
+    let image = match (color, data) {
+        (ColorType::Gray(1), DecodingResult::U8(packed)) => {
+            // Rows are padded to whole bytes
+            let stride = width.div_ceil(8) as usize;
+            (packed.len() >= stride * height as usize).then(|| {
+                DynamicImage::ImageLuma8(GrayImage::from_fn(width, height, |x, y| {
+                    let byte = packed[y as usize * stride + x as usize / 8];
+                    Luma([if byte & (0x80 >> (x % 8)) != 0 {
+                        255
+                    } else {
+                        0
+                    }])
+                }))
+            })
+        }
+        (ColorType::Gray(8), DecodingResult::U8(data)) => {
+            GrayImage::from_raw(width, height, data).map(DynamicImage::ImageLuma8)
+        }
+        (ColorType::Gray(16), DecodingResult::U16(data)) => {
+            ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageLuma16)
+        }
+        (ColorType::GrayA(8), DecodingResult::U8(data)) => {
+            ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageLumaA8)
+        }
+        (ColorType::RGB(8), DecodingResult::U8(data)) => {
+            ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgb8)
+        }
+        (ColorType::RGB(16), DecodingResult::U16(data)) => {
+            ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgb16)
+        }
+        (ColorType::RGBA(8), DecodingResult::U8(data)) => {
+            ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgba8)
+        }
+        (ColorType::RGBA(16), DecodingResult::U16(data)) => {
+            ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgba16)
+        }
+        (color, _) => return Err(format!("Unsupported TIFF color type: {color:?}")),
+    };
+    image.ok_or_else(|| "TIFF data doesn't match its size".into())
+}
+
+/// Reads every page of a (multi-page) TIFF.
+fn read_tiff(path: &Path) -> Result<Vec<DynamicImage>, String> {
+    let file = fs::File::open(path).map_err(|e| format!("Failed to open image: {e}"))?;
+    let mut decoder = TiffDecoder::new(file).map_err(|e| format!("Failed to read TIFF: {e}"))?;
+    let mut pages = vec![tiff_page(&mut decoder)?];
+    while decoder.more_images() {
+        decoder
+            .next_image()
+            .map_err(|e| format!("Failed to read TIFF: {e}"))?;
+        pages.push(tiff_page(&mut decoder)?);
+    }
+    Ok(pages)
+}
+
+/// Reads a JPEG or PNG, turned upright according to its EXIF orientation.
+fn read_image(path: &Path) -> Result<DynamicImage, String> {
+    let mut decoder = ImageReader::open(path)
+        .and_then(|r| r.with_guessed_format())
+        .map_err(|e| format!("Failed to open image: {e}"))?
+        .into_decoder()
+        .map_err(|e| format!("Failed to read image: {e}"))?;
+    let orientation = decoder
+        .orientation()
+        .map_err(|e| format!("Failed to read image: {e}"))?;
+    let mut image =
+        DynamicImage::from_decoder(decoder).map_err(|e| format!("Failed to decode image: {e}"))?;
+    image.apply_orientation(orientation);
+    Ok(image)
+}
+
+/// Otsu's threshold: the gray level best separating ink from paper.
+pub fn otsu_threshold(image: &GrayImage) -> u8 {
+    let mut histogram = [0u64; 256];
+    for pixel in image.pixels() {
+        histogram[pixel[0] as usize] += 1;
+    }
+    let total = image.pixels().len() as f64;
+    let sum: f64 = histogram
+        .iter()
+        .enumerate()
+        .map(|(i, &n)| i as f64 * n as f64)
+        .sum();
+
+    let (mut best, mut best_variance) = (128, 0.0);
+    let (mut weight, mut weighted_sum) = (0.0, 0.0);
+    for (level, &count) in histogram.iter().enumerate() {
+        weight += count as f64;
+        weighted_sum += level as f64 * count as f64;
+        if weight == 0.0 || weight == total {
+            continue;
+        }
+        let mean_below = weighted_sum / weight;
+        let mean_above = (sum - weighted_sum) / (total - weight);
+        let variance = weight * (total - weight) * (mean_below - mean_above).powi(2);
+        if variance > best_variance {
+            best = level as u8;
+            best_variance = variance;
+        }
+    }
+    best
+}
+
+fn binarize(image: &GrayImage) -> GrayImage {
+    let threshold = otsu_threshold(image);
+    GrayImage::from_fn(image.width(), image.height(), |x, y| {
+        Luma([if image.get_pixel(x, y)[0] <= threshold {
+            0
+        } else {
+            255
+        }])
+    })
+}
+
+/// Estimates the skew in degrees by finding the angle at which ink lines
+/// up best in rows; positive when lines fall to the right.
+pub fn skew_angle(image: &GrayImage) -> f64 {
+    let scale = (SKEW_SAMPLE_WIDTH as f64 / image.width() as f64).min(1.0);
+    let width = (image.width() as f64 * scale).round().max(1.0) as u32;
+    let height = (image.height() as f64 * scale).round().max(1.0) as u32;
+    let sample = binarize(&image::imageops::resize(
+        image,
+        width,
+        height,
+        FilterType::Triangle,
+    ));
+    let ink: Vec<(f64, f64)> = sample
+        .enumerate_pixels()
+        .filter(|(_, _, p)| p[0] == 0)
+        .map(|(x, y, _)| (x as f64, y as f64))
+        .collect();
+
+    let max_shift = width as f64 * MAX_SKEW.to_radians().tan();
+    let bins = (height as f64 + 2.0 * max_shift) as usize + 1;
+    let steps = (MAX_SKEW / SKEW_STEP).round() as i32;
+    let score = |angle: f64| {
+        let slope = angle.to_radians().tan();
+        let mut rows = vec![0u64; bins];
+        for &(x, y) in &ink {
+            let row = y - x * slope + max_shift;
+            rows[(row.max(0.0) as usize).min(bins - 1)] += 1;
+        }
+        rows.iter().map(|n| n * n).sum::<u64>()
+    };
+    (-steps..=steps)
+        .map(|step| step as f64 * SKEW_STEP)
+        .max_by_key(|&angle| score(angle))
+        .unwrap_or(0.0)
+}
+
+/// Rotates by `angle` degrees about the centre, filling with white.
+pub fn rotate(image: &GrayImage, angle: f64) -> GrayImage {
+    let (sin, cos) = angle.to_radians().sin_cos();
+    let cx = image.width() as f64 / 2.0;
+    let cy = image.height() as f64 / 2.0;
+    let sample = |x: i64, y: i64| {
+        if x < 0 || y < 0 || x >= image.width() as i64 || y >= image.height() as i64 {
+            255.0
+        } else {
+            image.get_pixel(x as u32, y as u32)[0] as f64
+        }
+    };
+    GrayImage::from_fn(image.width(), image.height(), |x, y| {
+        let (dx, dy) = (x as f64 - cx, y as f64 - cy);
+        let sx = cx + dx * cos - dy * sin;
+        let sy = cy + dx * sin + dy * cos;
+        let (x0, y0) = (sx.floor(), sy.floor());
+        let (fx, fy) = (sx - x0, sy - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+        let top = sample(x0, y0) * (1.0 - fx) + sample(x0 + 1, y0) * fx;
+        let bottom = sample(x0, y0 + 1) * (1.0 - fx) + sample(x0 + 1, y0 + 1) * fx;
+        Luma([(top * (1.0 - fy) + bottom * fy).round() as u8])
+    })
+}
+
+/// Applies the cleanup options. Processed pages come out grayscale.
+fn prepare(image: DynamicImage, options: &ImageImportOptions) -> DynamicImage {
+    if !options.deskew && !options.threshold {
+        return image;
+    }
+    let mut gray = image.into_luma8();
+    if options.deskew {
+        let angle = skew_angle(&gray);
+        if angle != 0.0 {
+            gray = rotate(&gray, angle);
+        }
+    }
+    if options.threshold {
+        gray = binarize(&gray);
+    }
+    DynamicImage::ImageLuma8(gray)
+}
+
+/// Imports images, in order, as one score. The images are turned into a
+/// PDF which becomes the score's unmodified original.
+#[tauri::command]
+pub async fn import_images(
+    app: AppHandle,
+    paths: Vec<String>,
+    options: Option<ImageImportOptions>,
+    library_id: Option<String>,
+) -> Result<LibraryEntry, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        import_images_blocking(&app, paths, options.unwrap_or_default(), library_id)
+    })
+    .await
+    .map_err(|e| format!("Failed to import images: {e}"))?
+}
+
+fn import_images_blocking(
+    app: &AppHandle,
+    paths: Vec<String>,
+    options: ImageImportOptions,
+    library_id: Option<String>,
+) -> Result<LibraryEntry, String> {
+    let first = paths.first().ok_or("No images to import")?;
+
+    let mut pages = Vec::new();
+    for path in &paths {
+        let path = Path::new(path);
+        let images = if is_tiff(path) {
+            read_tiff(path)?
+        } else {
+            vec![read_image(path)?]
+        };
+        pages.extend(images.into_iter().map(|image| prepare(image, &options)));
+    }
+    let pdf = images_to_pdf(app, &pages)?;
+
+    let title = match options.title {
+        Some(title) => title,
+        None => Path::new(first)
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .unwrap_or("score")
+            .to_string(),
+    };
+    let filename = format!("{}.pdf", title.replace(['/', '\\'], "-"));
+    import_pdf_bytes(app, pdf, filename, pages.len() as u32, library_id)
+}
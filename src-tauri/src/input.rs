@@ -0,0 +1,319 @@
+use evdev::{Device, InputEvent};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::os::fd::AsRawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use tauri::ipc::Channel;
+use tauri::{AppHandle, Emitter, State};
+
+use crate::mapping::CoordinateMapper;
+use crate::palm::{PalmState, PenProximity};
+use crate::stylus::{event_timestamp_us, StylusEvent, StylusEventKind, StylusParser, ToolType};
+
+/// How long a reader blocks in `poll` before rechecking its stop flag.
+const POLL_TIMEOUT_MS: i32 = 250;
+/// Delay between attempts to reopen an unplugged device.
+const RECONNECT_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StreamStatus {
+    pub device_path: String,
+    pub connected: bool,
+    /// Node the device was found at, which may change after a replug.
+    pub current_path: Option<String>,
+}
+
+/// Name and physical location used to find a device again after it is
+/// replugged and shows up under a different `/dev/input/eventN` node.
+#[derive(Debug, Clone, PartialEq)]
+struct DeviceIdentity {
+    name: Option<String>,
+    phys: Option<String>,
+}
+
+impl DeviceIdentity {
+    fn of(device: &Device) -> Self {
+        Self {
+            name: device.name().map(String::from),
+            phys: device.physical_path().map(String::from),
+        }
+    }
+}
+
+struct Reader {
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+impl Reader {
+    /// Spawns a thread that feeds every event from `path` to `handler`
+    /// until stopped or until `handler` returns `false`. With `grab` set the
+    /// device is opened exclusively, so its key presses don't also reach
+    /// the focused window.
+    fn spawn<F>(app: AppHandle, path: String, grab: bool, mut handler: F) -> Result<Self, String>
+    where
+        F: FnMut(&InputEvent) -> bool + Send + 'static,
+    {
+        let mut device = Device::open(&path).map_err(|e| format!("Failed to open device: {e}"))?;
+        if grab {
+            device
+                .grab()
+                .map_err(|e| format!("Failed to grab device: {e}"))?;
+        }
+        let identity = DeviceIdentity::of(&device);
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_flag = stop.clone();
+
+        let thread = std::thread::Builder::new()
+            .name(format!("evdev {path}"))
+            .spawn(move || {
+                let mut device = Some(device);
+                let mut current_path = path.clone();
+                while !stop_flag.load(Ordering::Relaxed) {
+                    let Some(dev) = device.as_mut() else {
+                        std::thread::sleep(RECONNECT_INTERVAL);
+                        if let Some((mut found, found_path)) = reopen(&current_path, &identity) {
+                            if grab {
+                                let _ = found.grab();
+                            }
+                            device = Some(found);
+                            current_path = found_path;
+                            emit_status(&app, &path, Some(&current_path));
+                        }
+                        continue;
+                    };
+
+                    match read_available(dev, &mut handler) {
+                        Ok(true) => {}
+                        Ok(false) => break,
+                        Err(_) => {
+                            // ENODEV after unplug, wait for the device to return
+                            device = None;
+                            emit_status(&app, &path, None);
+                        }
+                    }
+                }
+            })
+            .map_err(|e| format!("Failed to spawn reader thread: {e}"))?;
+
+        Ok(Self { stop, thread })
+    }
+
+    fn stop(self) {
+        self.stop.store(true, Ordering::Relaxed);
+        let _ = self.thread.join();
+    }
+}
+
+/// Blocks until the device is readable (or the poll times out) and hands
+/// all pending events to `handler`. Returns `Ok(false)` once the handler
+/// asks to stop.
+fn read_available<F>(device: &mut Device, handler: &mut F) -> std::io::Result<bool>
+where
+    F: FnMut(&InputEvent) -> bool,
+{
+    let mut pfd = libc::pollfd {
+        fd: device.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let ready = unsafe { libc::poll(&mut pfd, 1, POLL_TIMEOUT_MS) };
+    if ready < 0 {
+        let err = std::io::Error::last_os_error();
+        return if err.kind() == std::io::ErrorKind::Interrupted {
+            Ok(true)
+        } else {
+            Err(err)
+        };
+    }
+    if ready == 0 {
+        return Ok(true);
+    }
+    if pfd.revents & (libc::POLLERR | libc::POLLHUP | libc::POLLNVAL) != 0 {
+        return Err(std::io::Error::from_raw_os_error(libc::ENODEV));
+    }
+
+    for ev in device.fetch_events()? {
+        if !handler(&ev) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Reopens the device at its last known node, or scans `/dev/input` for a
+/// device with the same identity.
+fn reopen(last_path: &str, identity: &DeviceIdentity) -> Option<(Device, String)> {
+    if let Ok(device) = Device::open(last_path) {
+        if DeviceIdentity::of(&device) == *identity {
+            return Some((device, last_path.to_string()));
+        }
+    }
+
+    let entries = std::fs::read_dir("/dev/input").ok()?;
+    entries
+        .flatten()
+        .map(|e| e.path())
+        .filter(|p| {
+            p.file_name()
+                .is_some_and(|n| n.to_string_lossy().starts_with("event"))
+        })
+        .find_map(|p| {
+            let device = Device::open(&p).ok()?;
+            (DeviceIdentity::of(&device) == *identity)
+                .then(|| (device, p.to_string_lossy().to_string()))
+        })
+}
+
+fn emit_status(app: &AppHandle, device_path: &str, current_path: Option<&str>) {
+    let _ = app.emit(
+        "input-stream-status",
+        StreamStatus {
+            device_path: device_path.to_string(),
+            connected: current_path.is_some(),
+            current_path: current_path.map(String::from),
+        },
+    );
+}
+
+/// Long-lived evdev readers keyed by stream name, e.g. `stylus:<path>`.
+#[derive(Default)]
+pub struct InputService {
+    readers: Mutex<HashMap<String, Reader>>,
+}
+
+impl InputService {
+    pub fn start<F>(
+        &self,
+        app: AppHandle,
+        key: String,
+        device_path: String,
+        grab: bool,
+        handler: F,
+    ) -> Result<(), String>
+    where
+        F: FnMut(&InputEvent) -> bool + Send + 'static,
+    {
+        let mut readers = self.readers.lock().map_err(|e| e.to_string())?;
+        if let Some(previous) = readers.remove(&key) {
+            previous.stop();
+        }
+        let reader = Reader::spawn(app, device_path, grab, handler)?;
+        readers.insert(key, reader);
+        Ok(())
+    }
+
+    pub fn stop(&self, key: &str) -> Result<bool, String> {
+        let reader = self.readers.lock().map_err(|e| e.to_string())?.remove(key);
+        Ok(reader.map(Reader::stop).is_some())
+    }
+
+    fn active(&self) -> Result<Vec<String>, String> {
+        let readers = self.readers.lock().map_err(|e| e.to_string())?;
+        Ok(readers.keys().cloned().collect())
+    }
+}
+
+/// Every stage a stylus stream runs raw events through: parsing, pen
+/// proximity for palm rejection, and mapping. Trace replay uses it too,
+/// without an app handle and so without window geometry.
+pub struct StylusPipeline {
+    parser: StylusParser,
+    mapper: CoordinateMapper,
+    pen: Arc<Mutex<PenProximity>>,
+    app: Option<AppHandle>,
+}
+
+impl StylusPipeline {
+    pub fn new(
+        mapper: CoordinateMapper,
+        pen: Arc<Mutex<PenProximity>>,
+        app: Option<AppHandle>,
+    ) -> Self {
+        Self {
+            parser: StylusParser::new(),
+            mapper,
+            pen,
+            app,
+        }
+    }
+
+    pub fn feed(&mut self, ev: &InputEvent) -> Vec<StylusEvent> {
+        self.feed_raw(
+            ev.event_type().0,
+            ev.code(),
+            ev.value(),
+            event_timestamp_us(ev),
+        )
+    }
+
+    pub fn feed_raw(
+        &mut self,
+        ev_type: u16,
+        code: u16,
+        value: i32,
+        timestamp_us: u64,
+    ) -> Vec<StylusEvent> {
+        let mut events = self.parser.feed_raw(ev_type, code, value, timestamp_us);
+        for event in &mut events {
+            // The window may have moved between strokes
+            if let Some(app) = &self.app {
+                if event.event_type == StylusEventKind::ProximityIn
+                    || event.event_type == StylusEventKind::Down
+                {
+                    self.mapper.refresh_geometry(app);
+                }
+            }
+            // Lets touch streams reject the palm resting next to the pen
+            if event.tool != ToolType::Finger {
+                if let Ok(mut pen) = self.pen.lock() {
+                    pen.update(event);
+                }
+            }
+            event.mapped = Some(self.mapper.map(event));
+        }
+        events
+    }
+}
+
+/// Starts streaming `StylusEvent`s from `device_path` to `on_event` until
+/// `stop_stylus_stream` is called or the channel is closed.
+#[tauri::command]
+pub fn start_stylus_stream(
+    app: AppHandle,
+    service: State<'_, InputService>,
+    palm: State<'_, PalmState>,
+    device_path: String,
+    on_event: Channel<StylusEvent>,
+) -> Result<(), String> {
+    let mapper = CoordinateMapper::for_device(&app, &device_path)?;
+    let mut pipeline = StylusPipeline::new(mapper, palm.pen(), Some(app.clone()));
+    let key = format!("stylus:{device_path}");
+    service.start(app, key, device_path, false, move |ev| {
+        pipeline
+            .feed(ev)
+            .into_iter()
+            .all(|event| on_event.send(event).is_ok())
+    })
+}
+
+#[tauri::command]
+pub fn stop_stylus_stream(
+    service: State<'_, InputService>,
+    palm: State<'_, PalmState>,
+    device_path: String,
+) -> Result<bool, String> {
+    let stopped = service.stop(&format!("stylus:{device_path}"))?;
+    if let Ok(mut pen) = palm.pen().lock() {
+        pen.reset();
+    }
+    Ok(stopped)
+}
+
+#[tauri::command]
+pub fn list_active_streams(service: State<'_, InputService>) -> Result<Vec<String>, String> {
+    service.active()
+}
@@ -0,0 +1,571 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+use tauri::{AppHandle, State};
+use uuid::Uuid;
+
+use crate::navigation::NavMarkKind;
+use crate::notes::NotesStore;
+use crate::olscore::{
+    read_manifest_at, read_notes_at, read_verified_manifest_at, score_path, Layer, NotesData,
+    ScoreManifest,
+};
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct LayerUpdate {
+    pub name: Option<String>,
+    pub opacity: Option<f64>,
+    pub color: Option<String>,
+    pub width: Option<f64>,
+}
+
+fn find_layer<'a>(notes: &'a mut NotesData, layer_id: &str) -> Result<&'a mut Layer, String> {
+    notes
+        .layer_mut(layer_id)
+        .ok_or_else(|| format!("Layer not found: {layer_id}"))
+}
+
+fn unlocked<'a>(notes: &'a mut NotesData, layer_id: &str) -> Result<&'a mut Layer, String> {
+    let layer = find_layer(notes, layer_id)?;
+    if layer.locked {
+        return Err(format!("Layer is locked: {}", layer.name));
+    }
+    Ok(layer)
+}
+
+/// Applies `f` to the score's cached notes.
+fn modify_notes<T>(
+    app: &AppHandle,
+    store: &NotesStore,
+    score_id: &str,
+    f: impl FnOnce(&mut NotesData) -> Result<T, String>,
+) -> Result<T, String> {
+    store.modify(app, score_id, None, f).map(|(value, _)| value)
+}
+
+fn toggle_visibility(notes: &mut NotesData, layer_id: &str) -> Result<bool, String> {
+    let layer = find_layer(notes, layer_id)?;
+    layer.visible = !layer.visible;
+    Ok(layer.visible)
+}
+
+fn toggle_lock(notes: &mut NotesData, layer_id: &str) -> Result<bool, String> {
+    let layer = find_layer(notes, layer_id)?;
+    layer.locked = !layer.locked;
+    Ok(layer.locked)
+}
+
+fn merge(notes: &mut NotesData, source_id: &str, target_id: &str) -> Result<(), String> {
+    if source_id == target_id {
+        return Err("Cannot merge a layer into itself".into());
+    }
+    unlocked(notes, source_id)?;
+    unlocked(notes, target_id)?;
+    notes.retain_annotations(|layer| {
+        if *layer == source_id {
+            *layer = target_id.to_string();
+        }
+        true
+    });
+    notes.layers.retain(|l| l.id != source_id);
+    Ok(())
+}
+
+fn delete(notes: &mut NotesData, layer_id: &str) -> Result<(), String> {
+    unlocked(notes, layer_id)?;
+    if notes.layers.len() == 1 {
+        return Err("Cannot delete the last layer".into());
+    }
+    notes.retain_annotations(|layer| *layer != layer_id);
+    notes.layers.retain(|l| l.id != layer_id);
+    Ok(())
+}
+
+#[tauri::command]
+pub fn list_layers(
+    app: AppHandle,
+    store: State<'_, NotesStore>,
+    score_id: String,
+) -> Result<Vec<Layer>, String> {
+    Ok(store.snapshot(&app, &score_id)?.notes.layers)
+}
+
+#[tauri::command]
+pub fn create_layer(
+    app: AppHandle,
+    store: State<'_, NotesStore>,
+    score_id: String,
+    name: String,
+    color: Option<String>,
+) -> Result<Layer, String> {
+    modify_notes(&app, &store, &score_id, |notes| {
+        let mut layer = Layer::new(Uuid::new_v4().to_string(), name);
+        if let Some(color) = color {
+            layer.color = color;
+        }
+        notes.layers.push(layer.clone());
+        Ok(layer)
+    })
+}
+
+#[tauri::command]
+pub fn update_layer(
+    app: AppHandle,
+    store: State<'_, NotesStore>,
+    score_id: String,
+    layer_id: String,
+    update: LayerUpdate,
+) -> Result<Layer, String> {
+    modify_notes(&app, &store, &score_id, |notes| {
+        let layer = find_layer(notes, &layer_id)?;
+        if let Some(name) = update.name {
+            layer.name = name;
+        }
+        if let Some(opacity) = update.opacity {
+            layer.opacity = opacity.clamp(0.0, 1.0);
+        }
+        if let Some(color) = update.color {
+            layer.color = color;
+        }
+        if let Some(width) = update.width {
+            layer.width = width;
+        }
+        Ok(layer.clone())
+    })
+}
+
+/// Returns the new visibility.
+#[tauri::command]
+pub fn toggle_layer_visibility(
+    app: AppHandle,
+    store: State<'_, NotesStore>,
+    score_id: String,
+    layer_id: String,
+) -> Result<bool, String> {
+    modify_notes(&app, &store, &score_id, |notes| {
+        toggle_visibility(notes, &layer_id)
+    })
+}
+
+/// Returns the new lock state.
+#[tauri::command]
+pub fn toggle_layer_lock(
+    app: AppHandle,
+    store: State<'_, NotesStore>,
+    score_id: String,
+    layer_id: String,
+) -> Result<bool, String> {
+    modify_notes(&app, &store, &score_id, |notes| {
+        toggle_lock(notes, &layer_id)
+    })
+}
+
+/// Moves every annotation of `source_id` into `target_id` and removes the
+/// source layer.
+#[tauri::command]
+pub fn merge_layers(
+    app: AppHandle,
+    store: State<'_, NotesStore>,
+    score_id: String,
+    source_id: String,
+    target_id: String,
+) -> Result<(), String> {
+    modify_notes(&app, &store, &score_id, |notes| {
+        merge(notes, &source_id, &target_id)
+    })
+}
+
+/// Deletes a layer together with its annotations.
+#[tauri::command]
+pub fn delete_layer(
+    app: AppHandle,
+    store: State<'_, NotesStore>,
+    score_id: String,
+    layer_id: String,
+) -> Result<(), String> {
+    modify_notes(&app, &store, &score_id, |notes| delete(notes, &layer_id))
+}
+
+/// A layer read from another `.olscore`, with its PDFs matched to ours.
+struct ImportedLayer {
+    notes: NotesData,
+    layer: Layer,
+    /// Source PDF filename -> target PDF filename
+    pdf_map: HashMap<String, String>,
+    same_pdfs: bool,
+}
+
+impl ImportedLayer {
+    /// Reads `layer_id` from `source_path`, whose PDFs must match their
+    /// recorded hashes and share at least one with `target`.
+    fn read(source_path: &Path, target: &ScoreManifest, layer_id: &str) -> Result<Self, String> {
+        let source = read_verified_manifest_at(source_path)?;
+        let notes = read_notes_at(source_path)?;
+        let layer = notes
+            .layer(layer_id)
+            .cloned()
+            .ok_or_else(|| format!("Layer not found: {layer_id}"))?;
+
+        let pdf_map: HashMap<String, String> = source
+            .pdfs
+            .iter()
+            .filter_map(|src| {
+                let target = target.pdfs.iter().find(|t| t.sha256 == src.sha256)?;
+                Some((src.filename.clone(), target.filename.clone()))
+            })
+            .collect();
+        if pdf_map.is_empty() {
+            return Err("The scores share no PDFs".into());
+        }
+        let same_pdfs = source.pdfs.len() == target.pdfs.len()
+            && source
+                .pdfs
+                .iter()
+                .zip(&target.pdfs)
+                .all(|(s, t)| s.sha256 == t.sha256);
+
+        Ok(Self {
+            notes,
+            layer,
+            pdf_map,
+            same_pdfs,
+        })
+    }
+
+    /// Adds the layer and its annotations to `notes` under fresh ids.
+    fn add_to(&self, notes: &mut NotesData) -> Layer {
+        let layer_id = &self.layer.id;
+        let mut layer = self.layer.clone();
+        layer.id = Uuid::new_v4().to_string();
+        layer.locked = false;
+
+        for (filename, pdf_notes) in &self.notes.pages {
+            let Some(target_filename) = self.pdf_map.get(filename) else {
+                continue;
+            };
+            let target_pdf = notes.pages.entry(target_filename.clone()).or_default();
+            for (page, page_notes) in pdf_notes {
+                let target_page = target_pdf.entry(*page).or_default();
+                target_page.strokes.extend(
+                    page_notes
+                        .strokes
+                        .iter()
+                        .filter(|s| s.layer == *layer_id)
+                        .map(|s| {
+                            let mut s = s.clone();
+                            s.id = Uuid::new_v4().to_string();
+                            s.layer = layer.id.clone();
+                            s
+                        }),
+                );
+                target_page.text_notes.extend(
+                    page_notes
+                        .text_notes
+                        .iter()
+                        .filter(|n| n.layer == *layer_id)
+                        .map(|n| {
+                            let mut n = n.clone();
+                            n.id = Uuid::new_v4().to_string();
+                            n.layer = layer.id.clone();
+                            n
+                        }),
+                );
+            }
+        }
+        notes.navigation.extend(
+            self.notes
+                .navigation
+                .iter()
+                .filter(|m| m.layer == *layer_id)
+                .filter_map(|m| {
+                    let mut m = m.clone();
+                    m.anchor.pdf = self.pdf_map.get(&m.anchor.pdf)?.clone();
+                    if let NavMarkKind::Volta { end, .. } = &mut m.kind {
+                        end.pdf = self.pdf_map.get(&end.pdf)?.clone();
+                    }
+                    m.id = Uuid::new_v4().to_string();
+                    m.layer = layer.id.clone();
+                    Some(m)
+                }),
+        );
+        if self.same_pdfs {
+            notes.repeat_buttons.extend(
+                self.notes
+                    .repeat_buttons
+                    .iter()
+                    .filter(|b| b.layer == *layer_id)
+                    .map(|b| {
+                        let mut b = b.clone();
+                        b.id = Uuid::new_v4().to_string();
+                        b.layer = layer.id.clone();
+                        b
+                    }),
+            );
+        }
+        notes.layers.push(layer.clone());
+        layer
+    }
+}
+
+/// Copies one layer from another `.olscore` as a new layer. Page
+/// annotations and navigation marks are matched to this score's PDFs by
+/// SHA-256, after checking the source's PDFs against their hashes; repeat
+/// buttons, which use score-wide page numbers, only come along if both
+/// scores contain the same PDFs in the same order.
+#[tauri::command]
+pub fn import_layer(
+    app: AppHandle,
+    store: State<'_, NotesStore>,
+    score_id: String,
+    source_path: String,
+    layer_id: String,
+) -> Result<Layer, String> {
+    let target = read_manifest_at(&score_path(&app, &score_id)?)?;
+    let imported = ImportedLayer::read(Path::new(&source_path), &target, &layer_id)?;
+    modify_notes(&app, &store, &score_id, |notes| Ok(imported.add_to(notes)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use sha2::{Digest, Sha256};
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    const PART: &[u8] = b"%PDF-1.7 violin part";
+    const EXTRA: &[u8] = b"%PDF-1.7 piano reduction";
+
+    fn sha256(bytes: &[u8]) -> String {
+        format!("{:x}", Sha256::digest(bytes))
+    }
+
+    fn manifest(pdfs: &[(&str, &[u8])]) -> ScoreManifest {
+        let pdfs: Vec<_> = pdfs
+            .iter()
+            .map(|(name, bytes)| {
+                json!({"filename": name, "title": name, "page_count": 4, "sha256": sha256(bytes)})
+            })
+            .collect();
+        serde_json::from_value(json!({"format_version": "0.1", "pdfs": pdfs})).unwrap()
+    }
+
+    /// The default layer plus a teacher's layer with one of each kind of
+    /// annotation on each PDF.
+    fn teacher_notes(pdfs: &[&str]) -> NotesData {
+        let mut pages = serde_json::Map::new();
+        let mut navigation = Vec::new();
+        for pdf in pdfs {
+            pages.insert(
+                pdf.to_string(),
+                json!({"2": {
+                    "strokes": [
+                        {"id": format!("{pdf}-own"), "points": [], "color": "#000", "width": 2.0},
+                        {"id": format!("{pdf}-bowing"), "layer": "teacher", "points": [],
+                         "color": "#00f", "width": 2.0},
+                    ],
+                    "text_notes": [
+                        {"id": format!("{pdf}-text"), "layer": "teacher", "x": 0.1, "y": 0.2,
+                         "content": "dolce", "color": "#00f"},
+                    ],
+                }}),
+            );
+            navigation.push(json!({
+                "id": format!("{pdf}-segno"), "layer": "teacher", "type": "segno",
+                "anchor": {"pdf": pdf, "page": 2, "y": 0.5},
+            }));
+        }
+        serde_json::from_value(json!({
+            "format_version": "0.1",
+            "layers": [
+                {"id": "default", "name": "Default"},
+                {"id": "teacher", "name": "Teacher", "color": "#0000ff", "locked": true},
+            ],
+            "repeat_buttons": [
+                {"id": "r1", "layer": "teacher", "page": 3, "target_page": 1, "label": "Repeat",
+                 "offset_x": 0.9, "offset_y": 0.9, "size": 40},
+            ],
+            "navigation": navigation,
+            "pages": pages,
+        }))
+        .unwrap()
+    }
+
+    /// Writes an `.olscore` whose manifest records `pdfs` but whose PDF
+    /// entries hold `contents`.
+    fn archive(
+        dir: &Path,
+        pdfs: &[(&str, &[u8])],
+        contents: &[(&str, &[u8])],
+        notes: &NotesData,
+    ) -> PathBuf {
+        let path = dir.join("source.olscore");
+        let mut writer = zip::ZipWriter::new(std::fs::File::create(&path).unwrap());
+        let options = zip::write::SimpleFileOptions::default();
+        let mut add = |name: &str, data: &[u8]| {
+            writer.start_file(name, options).unwrap();
+            writer.write_all(data).unwrap();
+        };
+        add(
+            "manifest.json",
+            &serde_json::to_vec(&manifest(pdfs)).unwrap(),
+        );
+        add("notes.json", &serde_json::to_vec(notes).unwrap());
+        for (name, bytes) in contents {
+            add(&format!("pdfs/{name}"), bytes);
+        }
+        writer.finish().unwrap();
+        path
+    }
+
+    fn layer_of_strokes(notes: &NotesData, pdf: &str) -> Vec<(String, String)> {
+        notes.pages[pdf][&2]
+            .strokes
+            .iter()
+            .map(|s| (s.id.clone(), s.layer.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn toggles_flip_visibility_and_lock() {
+        let mut notes = NotesData::default();
+        assert_eq!(toggle_visibility(&mut notes, "default"), Ok(false));
+        assert_eq!(toggle_visibility(&mut notes, "default"), Ok(true));
+        assert_eq!(toggle_lock(&mut notes, "default"), Ok(true));
+        assert!(notes.layers[0].locked);
+        assert_eq!(toggle_lock(&mut notes, "default"), Ok(false));
+        assert!(toggle_visibility(&mut notes, "missing").is_err());
+    }
+
+    #[test]
+    fn merge_moves_every_annotation_and_removes_the_source() {
+        let mut notes = teacher_notes(&["part.pdf"]);
+        assert!(merge(&mut notes, "teacher", "default")
+            .unwrap_err()
+            .contains("locked"));
+
+        toggle_lock(&mut notes, "teacher").unwrap();
+        assert!(merge(&mut notes, "teacher", "teacher").is_err());
+        merge(&mut notes, "teacher", "default").unwrap();
+
+        let ids: Vec<_> = notes.layers.iter().map(|l| l.id.as_str()).collect();
+        assert_eq!(ids, ["default"]);
+        let page = &notes.pages["part.pdf"][&2];
+        assert!(page.strokes.iter().all(|s| s.layer == "default"));
+        assert_eq!(page.strokes.len(), 2);
+        assert_eq!(page.text_notes[0].layer, "default");
+        assert_eq!(notes.navigation[0].layer, "default");
+        assert_eq!(notes.repeat_buttons[0].layer, "default");
+    }
+
+    #[test]
+    fn delete_removes_only_its_annotations() {
+        let mut notes = teacher_notes(&["part.pdf"]);
+        assert!(delete(&mut notes, "teacher")
+            .unwrap_err()
+            .contains("locked"));
+
+        toggle_lock(&mut notes, "teacher").unwrap();
+        delete(&mut notes, "teacher").unwrap();
+        assert_eq!(
+            layer_of_strokes(&notes, "part.pdf"),
+            [("part.pdf-own".to_string(), "default".to_string())]
+        );
+        assert!(notes.pages["part.pdf"][&2].text_notes.is_empty());
+        assert!(notes.navigation.is_empty());
+        assert!(notes.repeat_buttons.is_empty());
+
+        let err = delete(&mut notes, "default").unwrap_err();
+        assert!(err.contains("last layer"), "{err}");
+    }
+
+    #[test]
+    fn import_matches_pdfs_by_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let pdfs = [("part.pdf", PART), ("extra.pdf", EXTRA)];
+        let source = archive(
+            dir.path(),
+            &pdfs,
+            &pdfs,
+            &teacher_notes(&["part.pdf", "extra.pdf"]),
+        );
+        // The same part under another name, without the piano reduction
+        let target = manifest(&[("violin.pdf", PART)]);
+
+        let imported = ImportedLayer::read(&source, &target, "teacher").unwrap();
+        let mut notes = NotesData::default();
+        let layer = imported.add_to(&mut notes);
+
+        assert_eq!(layer.name, "Teacher");
+        assert_ne!(layer.id, "teacher");
+        assert!(!layer.locked);
+        assert_eq!(notes.layers.len(), 2);
+        assert_eq!(notes.pages.keys().collect::<Vec<_>>(), ["violin.pdf"]);
+        let strokes = layer_of_strokes(&notes, "violin.pdf");
+        assert_eq!(strokes.len(), 1);
+        assert_eq!(strokes[0].1, layer.id);
+        assert_ne!(strokes[0].0, "part.pdf-bowing");
+        assert_eq!(notes.pages["violin.pdf"][&2].text_notes.len(), 1);
+        assert_eq!(notes.navigation.len(), 1);
+        assert_eq!(notes.navigation[0].anchor.pdf, "violin.pdf");
+        // Score page numbers differ once the PDF lists do
+        assert!(notes.repeat_buttons.is_empty());
+    }
+
+    #[test]
+    fn import_keeps_repeat_buttons_for_the_same_pdfs() {
+        let dir = tempfile::tempdir().unwrap();
+        let pdfs = [("part.pdf", PART)];
+        let source = archive(dir.path(), &pdfs, &pdfs, &teacher_notes(&["part.pdf"]));
+
+        let imported = ImportedLayer::read(&source, &manifest(&pdfs), "teacher").unwrap();
+        let mut notes = NotesData::default();
+        let layer = imported.add_to(&mut notes);
+        assert_eq!(notes.repeat_buttons.len(), 1);
+        assert_eq!(notes.repeat_buttons[0].layer, layer.id);
+    }
+
+    #[test]
+    fn import_rejects_mismatched_sources() {
+        let dir = tempfile::tempdir().unwrap();
+        let notes = teacher_notes(&["part.pdf"]);
+        let target = manifest(&[("violin.pdf", PART)]);
+
+        // Manifest claims the violin part, archive holds something else
+        let source = archive(
+            dir.path(),
+            &[("part.pdf", PART)],
+            &[("part.pdf", EXTRA)],
+            &notes,
+        );
+        let err = ImportedLayer::read(&source, &target, "teacher")
+            .err()
+            .unwrap();
+        assert!(err.contains("does not match"), "{err}");
+
+        let source = archive(dir.path(), &[("part.pdf", PART)], &[], &notes);
+        assert!(ImportedLayer::read(&source, &target, "teacher").is_err());
+
+        let source = archive(
+            dir.path(),
+            &[("extra.pdf", EXTRA)],
+            &[("extra.pdf", EXTRA)],
+            &notes,
+        );
+        let err = ImportedLayer::read(&source, &target, "teacher")
+            .err()
+            .unwrap();
+        assert!(err.contains("share no PDFs"), "{err}");
+
+        let source = archive(
+            dir.path(),
+            &[("part.pdf", PART)],
+            &[("part.pdf", PART)],
+            &notes,
+        );
+        let err = ImportedLayer::read(&source, &target, "bowings")
+            .err()
+            .unwrap();
+        assert!(err.contains("Layer not found"), "{err}");
+    }
+}
@@ -1,11 +1,39 @@
+mod audio;
+mod crop;
+mod display;
+mod history;
+mod images;
+mod input;
+mod layers;
+mod library;
+mod mapping;
+mod metronome;
+mod navigation;
+mod notes;
 mod olscore;
+mod pages;
+mod palm;
+mod pedal;
+mod practice;
+mod render;
+mod sources;
+mod stroke;
 mod stylus;
+mod systems;
+mod timeline;
+mod timestamp;
+mod touch;
+mod trace;
 
 #[tauri::command]
 fn get_cli_file_arg() -> Option<String> {
     std::env::args().nth(1).filter(|arg| {
         let lower = arg.to_lowercase();
-        lower.ends_with(".pdf") || lower.ends_with(".olscore")
+        lower.ends_with(".pdf")
+            || lower.ends_with(".olscore")
+            || images::IMAGE_EXTENSIONS
+                .iter()
+                .any(|ext| lower.ends_with(&format!(".{ext}")))
     })
 }
 
@@ -31,26 +59,144 @@ pub fn run() {
         .plugin(tauri_plugin_dialog::init())
         .plugin(tauri_plugin_fs::init())
         .plugin(tauri_plugin_keepawake::init())
+        .manage(input::InputService::default())
+        .manage(pedal::PedalState::default())
+        .manage(palm::PalmState::default())
+        .manage(trace::RecordingState::default())
+        .manage(notes::NotesStore::default())
+        .manage(timeline::TimelineState::default())
+        .manage(metronome::MetronomeState::default())
+        .manage(practice::PracticeState::default())
         .invoke_handler(tauri::generate_handler![
             get_cli_file_arg,
             stylus::list_input_devices,
             stylus::find_stylus_device,
+            stylus::get_device_axes,
             stylus::read_stylus_events,
             stylus::read_raw_events,
-            olscore::ensure_library,
+            input::start_stylus_stream,
+            input::stop_stylus_stream,
+            input::list_active_streams,
+            trace::start_input_recording,
+            trace::stop_input_recording,
+            trace::replay_input_trace,
+            touch::start_touch_stream,
+            touch::stop_touch_stream,
+            palm::get_palm_config,
+            palm::set_palm_config,
+            palm::calibrate_palm_rejection,
+            mapping::get_screen_geometry,
+            mapping::get_stylus_calibration,
+            mapping::set_stylus_calibration,
+            mapping::reset_stylus_calibration,
+            mapping::calibrate_stylus,
+            stroke::get_stroke_settings,
+            stroke::set_stroke_settings,
+            stroke::process_stroke,
+            stroke::stroke_outline,
+            pedal::list_pedal_devices,
+            pedal::get_pedal_config,
+            pedal::set_pedal_config,
+            pedal::start_pedal,
+            pedal::stop_pedal,
+            library::ensure_library,
+            library::list_libraries,
+            library::add_library,
+            library::remove_library,
+            library::rename_library,
+            library::set_default_library,
+            library::move_library,
+            library::list_library,
+            library::search_library,
             olscore::import_pdf,
+            images::import_images,
+            sources::attach_source,
+            sources::get_source,
+            sources::remove_source,
+            audio::add_audio,
+            audio::remove_audio,
+            audio::rename_audio,
+            audio::get_audio_path,
+            audio::set_audio_sync_points,
+            timeline::start_timeline,
+            timeline::timeline_tick,
+            timeline::stop_timeline,
+            timeline::start_tap_along,
+            timeline::tap_page_turn,
+            timeline::undo_tap,
+            timeline::finish_tap_along,
+            metronome::set_tempo_map,
+            metronome::get_tempo_at,
+            metronome::start_metronome,
+            metronome::stop_metronome,
+            metronome::adjust_metronome,
+            metronome::metronome_page_changed,
+            metronome::get_metronome_status,
+            practice::start_practice_session,
+            practice::practice_page_changed,
+            practice::end_practice_session,
+            practice::update_practice_session,
+            practice::delete_practice_session,
+            practice::get_practice_sessions,
+            practice::get_practice_totals,
+            practice::get_unpracticed_scores,
+            practice::export_practice_csv,
             olscore::open_score,
             olscore::get_extracted_pdf_path,
-            olscore::list_library,
             olscore::update_manifest,
             olscore::read_notes,
             olscore::save_notes,
+            notes::get_notes,
+            notes::add_annotation,
+            notes::update_annotation,
+            notes::update_annotations,
+            notes::delete_annotation,
+            notes::flush_notes,
+            notes::close_notes,
+            navigation::get_performance_order,
+            history::get_notes_history,
+            history::undo_notes,
+            history::redo_notes,
+            history::restore_notes,
+            layers::list_layers,
+            layers::create_layer,
+            layers::update_layer,
+            layers::toggle_layer_visibility,
+            layers::toggle_layer_lock,
+            layers::merge_layers,
+            layers::delete_layer,
+            layers::import_layer,
+            pages::get_page_sequence,
+            pages::get_display_pages,
+            pages::set_page_sequence,
+            pages::move_page,
+            pages::duplicate_page,
+            pages::hide_page,
+            pages::insert_page,
+            pages::set_page_view,
+            pages::reset_page_sequence,
+            crop::detect_crop,
+            crop::set_score_crop,
+            display::get_screens,
+            display::set_split_points,
+            systems::get_systems,
+            systems::split_at_systems,
         ])
         .setup(|app| {
             #[cfg(target_os = "linux")]
             disable_pinch_zoom(app);
             Ok(())
         })
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .build(tauri::generate_context!())
+        .expect("error while building tauri application")
+        .run(|app, event| {
+            if let tauri::RunEvent::Exit = event {
+                use tauri::{Emitter, Manager};
+                // Notes failures are emitted per score by flush_all itself
+                let _ = app.state::<notes::NotesStore>().flush_all(app);
+                if let Err(e) = app.state::<practice::PracticeState>().end_all(app) {
+                    let _ = app.emit("practice-save-error", e);
+                }
+            }
+        });
 }
@@ -0,0 +1,759 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::cmp::Reverse;
+use std::fs;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use tauri::{AppHandle, Emitter, Manager};
+use uuid::Uuid;
+
+use crate::notes::NotesStore;
+use crate::olscore::with_archive_locks;
+use crate::timestamp::StoredTimestamp;
+
+const DEFAULT_LIBRARY_ID: &str = "default";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LibraryEntry {
+    pub id: String,
+    pub title: String,
+    pub sha256: String,
+    #[serde(default)]
+    pub created_at: StoredTimestamp,
+    #[serde(default)]
+    pub last_opened_at: StoredTimestamp,
+    /// Set on query results only, never stored in `library.json`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub library_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LibraryIndex {
+    pub scores: Vec<LibraryEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LibraryLocation {
+    pub id: String,
+    pub name: String,
+    pub path: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LibraryConfig {
+    pub default_library: String,
+    pub libraries: Vec<LibraryLocation>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LibraryInfo {
+    #[serde(flatten)]
+    pub location: LibraryLocation,
+    pub is_default: bool,
+    /// False when the directory is missing, e.g. an unplugged SD card.
+    pub available: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LibraryMoveProgress {
+    pub library_id: String,
+    pub file: String,
+    pub done: usize,
+    pub total: usize,
+}
+
+fn config_path(app: &AppHandle) -> Result<PathBuf, String> {
+    let config_dir = app
+        .path()
+        .app_config_dir()
+        .map_err(|e| format!("Failed to get app config dir: {e}"))?;
+    Ok(config_dir.join("libraries.json"))
+}
+
+fn default_library_dir(app: &AppHandle) -> Result<PathBuf, String> {
+    let data_dir = app
+        .path()
+        .app_data_dir()
+        .map_err(|e| format!("Failed to get app data dir: {e}"))?;
+    Ok(data_dir.join("library"))
+}
+
+fn read_config(app: &AppHandle) -> Result<LibraryConfig, String> {
+    let path = config_path(app)?;
+    if !path.exists() {
+        return Ok(LibraryConfig {
+            default_library: DEFAULT_LIBRARY_ID.into(),
+            libraries: vec![LibraryLocation {
+                id: DEFAULT_LIBRARY_ID.into(),
+                name: "Library".into(),
+                path: default_library_dir(app)?.to_string_lossy().to_string(),
+            }],
+        });
+    }
+    let data =
+        fs::read_to_string(&path).map_err(|e| format!("Failed to read libraries.json: {e}"))?;
+    serde_json::from_str(&data).map_err(|e| format!("Failed to parse libraries.json: {e}"))
+}
+
+fn write_config(app: &AppHandle, config: &LibraryConfig) -> Result<(), String> {
+    let path = config_path(app)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|e| format!("Failed to create config dir: {e}"))?;
+    }
+    let data =
+        serde_json::to_string_pretty(config).map_err(|e| format!("Failed to serialize: {e}"))?;
+    fs::write(&path, data).map_err(|e| format!("Failed to write libraries.json: {e}"))
+}
+
+fn find_location<'a>(
+    config: &'a LibraryConfig,
+    library_id: &str,
+) -> Result<&'a LibraryLocation, String> {
+    config
+        .libraries
+        .iter()
+        .find(|l| l.id == library_id)
+        .ok_or_else(|| format!("Unknown library: {library_id}"))
+}
+
+/// Directory of the library new scores are imported into, unless the
+/// caller names one explicitly.
+pub fn target_library_dir(
+    app: &AppHandle,
+    library_id: Option<&str>,
+) -> Result<(String, PathBuf), String> {
+    let config = read_config(app)?;
+    let id = library_id.unwrap_or(&config.default_library);
+    let location = find_location(&config, id)?;
+    Ok((location.id.clone(), PathBuf::from(&location.path)))
+}
+
+/// Mounted libraries whose directory currently exists.
+pub fn available_libraries(app: &AppHandle) -> Result<Vec<(String, PathBuf)>, String> {
+    Ok(read_config(app)?
+        .libraries
+        .into_iter()
+        .map(|l| (l.id, PathBuf::from(l.path)))
+        .filter(|(_, dir)| dir.is_dir())
+        .collect())
+}
+
+/// Finds the library holding `score_id` among all mounted libraries.
+pub fn locate_score(app: &AppHandle, score_id: &str) -> Result<PathBuf, String> {
+    let filename = format!("{score_id}.olscore");
+    available_libraries(app)?
+        .into_iter()
+        .map(|(_, dir)| dir)
+        .find(|dir| dir.join(&filename).exists())
+        .ok_or_else(|| format!("Score not found in any library: {score_id}"))
+}
+
+fn library_index_path(lib_dir: &Path) -> PathBuf {
+    lib_dir.join("library.json")
+}
+
+pub fn read_library_index(lib_dir: &Path) -> Result<LibraryIndex, String> {
+    let path = library_index_path(lib_dir);
+    if !path.exists() {
+        return Ok(LibraryIndex { scores: Vec::new() });
+    }
+    let data =
+        fs::read_to_string(&path).map_err(|e| format!("Failed to read library.json: {e}"))?;
+    serde_json::from_str(&data).map_err(|e| format!("Failed to parse library.json: {e}"))
+}
+
+pub fn write_library_index(lib_dir: &Path, index: &LibraryIndex) -> Result<(), String> {
+    let path = library_index_path(lib_dir);
+    let data =
+        serde_json::to_string_pretty(index).map_err(|e| format!("Failed to serialize: {e}"))?;
+    fs::write(&path, data).map_err(|e| format!("Failed to write library.json: {e}"))
+}
+
+fn ensure_library_dir(lib_dir: &Path) -> Result<(), String> {
+    fs::create_dir_all(lib_dir).map_err(|e| format!("Failed to create library dir: {e}"))?;
+    if !library_index_path(lib_dir).exists() {
+        write_library_index(lib_dir, &LibraryIndex { scores: Vec::new() })?;
+    }
+    Ok(())
+}
+
+/// Collects entries from one library, or from every available one when
+/// `library_id` is `None`. Each entry is tagged with its source library.
+pub fn collect_entries(
+    app: &AppHandle,
+    library_id: Option<&str>,
+) -> Result<Vec<LibraryEntry>, String> {
+    let libraries = match library_id {
+        Some(id) => vec![target_library_dir(app, Some(id))?],
+        None => available_libraries(app)?,
+    };
+
+    let mut entries = Vec::new();
+    for (id, dir) in libraries {
+        for mut entry in read_library_index(&dir)?.scores {
+            entry.library_id = Some(id.clone());
+            entries.push(entry);
+        }
+    }
+    entries.sort_by_key(|e| Reverse(e.last_opened_at.timestamp()));
+    Ok(entries)
+}
+
+fn file_sha256(path: &Path) -> Result<String, String> {
+    let mut file = fs::File::open(path).map_err(|e| format!("Failed to open file: {e}"))?;
+    let mut hasher = Sha256::new();
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        let n = file
+            .read(&mut buf)
+            .map_err(|e| format!("Failed to read file: {e}"))?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+#[tauri::command]
+pub fn ensure_library(app: AppHandle) -> Result<String, String> {
+    let (_, lib_dir) = target_library_dir(&app, None)?;
+    ensure_library_dir(&lib_dir)?;
+
+    lib_dir
+        .to_str()
+        .map(String::from)
+        .ok_or_else(|| "Invalid library path".into())
+}
+
+#[tauri::command]
+pub fn list_libraries(app: AppHandle) -> Result<Vec<LibraryInfo>, String> {
+    let config = read_config(&app)?;
+    Ok(config
+        .libraries
+        .iter()
+        .map(|l| LibraryInfo {
+            location: l.clone(),
+            is_default: l.id == config.default_library,
+            available: Path::new(&l.path).is_dir(),
+        })
+        .collect())
+}
+
+#[tauri::command]
+pub fn add_library(app: AppHandle, name: String, path: String) -> Result<LibraryLocation, String> {
+    let mut config = read_config(&app)?;
+    if config
+        .libraries
+        .iter()
+        .any(|l| Path::new(&l.path) == Path::new(&path))
+    {
+        return Err(format!("Library already mounted: {path}"));
+    }
+
+    // An existing library (e.g. on a shared drive) is mounted as-is
+    ensure_library_dir(Path::new(&path))?;
+
+    let location = LibraryLocation {
+        id: Uuid::new_v4().to_string(),
+        name,
+        path,
+    };
+    config.libraries.push(location.clone());
+    write_config(&app, &config)?;
+    Ok(location)
+}
+
+#[tauri::command]
+pub fn remove_library(app: AppHandle, library_id: String) -> Result<(), String> {
+    let mut config = read_config(&app)?;
+    if config.default_library == library_id {
+        return Err("Cannot remove the default library".into());
+    }
+    find_location(&config, &library_id)?;
+
+    // Unmount only, the files stay where they are
+    config.libraries.retain(|l| l.id != library_id);
+    write_config(&app, &config)
+}
+
+#[tauri::command]
+pub fn rename_library(app: AppHandle, library_id: String, name: String) -> Result<(), String> {
+    let mut config = read_config(&app)?;
+    let location = config
+        .libraries
+        .iter_mut()
+        .find(|l| l.id == library_id)
+        .ok_or_else(|| format!("Unknown library: {library_id}"))?;
+    location.name = name;
+    write_config(&app, &config)
+}
+
+#[tauri::command]
+pub fn set_default_library(app: AppHandle, library_id: String) -> Result<(), String> {
+    let mut config = read_config(&app)?;
+    find_location(&config, &library_id)?;
+    config.default_library = library_id;
+    write_config(&app, &config)
+}
+
+/// Files under `dir`, relative to it and in a stable order, skipping
+/// leftover `.tmp` files from interrupted writes.
+fn library_files(dir: &Path) -> Result<Vec<PathBuf>, String> {
+    fn walk(root: &Path, dir: &Path, files: &mut Vec<PathBuf>) -> Result<(), String> {
+        let mut entries: Vec<PathBuf> = fs::read_dir(dir)
+            .map_err(|e| format!("Failed to read {}: {e}", dir.display()))?
+            .flatten()
+            .map(|e| e.path())
+            .collect();
+        entries.sort();
+        for path in entries {
+            if path.is_dir() {
+                walk(root, &path, files)?;
+            } else if !path.to_string_lossy().ends_with(".tmp") {
+                let relative = path.strip_prefix(root).map_err(|e| e.to_string())?;
+                files.push(relative.to_path_buf());
+            }
+        }
+        Ok(())
+    }
+
+    let mut files = Vec::new();
+    walk(dir, dir, &mut files)?;
+    Ok(files)
+}
+
+/// `path` with symlinks and `..` resolved, also when it doesn't exist
+/// yet: its deepest existing ancestor is canonicalized and the rest
+/// appended.
+fn resolve_path(path: &Path) -> Result<PathBuf, String> {
+    let path = std::path::absolute(path).map_err(|e| format!("Invalid path: {e}"))?;
+    let existing = path
+        .ancestors()
+        .find(|a| a.exists())
+        .ok_or_else(|| format!("Invalid path: {}", path.display()))?;
+    let mut resolved = existing
+        .canonicalize()
+        .map_err(|e| format!("Failed to resolve {}: {e}", existing.display()))?;
+    let rest = path.strip_prefix(existing).map_err(|e| e.to_string())?;
+    for component in rest.components() {
+        match component {
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            Component::Normal(name) => resolved.push(name),
+            _ => {}
+        }
+    }
+    Ok(resolved)
+}
+
+/// Copies every library file from `old_dir` to `new_dir`, keeping
+/// subdirectories, and verifies each copy by SHA-256. `new_dir` must be
+/// missing or empty. On failure the partial copy is removed. Returns the
+/// copied files relative to the library root.
+fn copy_library(
+    old_dir: &Path,
+    new_dir: &Path,
+    mut progress: impl FnMut(&Path, usize, usize),
+) -> Result<Vec<PathBuf>, String> {
+    if resolve_path(new_dir)?.starts_with(resolve_path(old_dir)?) {
+        return Err("Cannot move a library into itself".into());
+    }
+    if new_dir.exists() {
+        let mut contents =
+            fs::read_dir(new_dir).map_err(|e| format!("Failed to read destination: {e}"))?;
+        if contents.next().is_some() {
+            return Err(format!("Destination is not empty: {}", new_dir.display()));
+        }
+    }
+
+    let files = library_files(old_dir)?;
+    let created_dir = !new_dir.exists();
+    fs::create_dir_all(new_dir).map_err(|e| format!("Failed to create library dir: {e}"))?;
+
+    let result = (|| {
+        for (i, file) in files.iter().enumerate() {
+            progress(file, i, files.len());
+            let src = old_dir.join(file);
+            let dst = new_dir.join(file);
+            if let Some(parent) = dst.parent() {
+                fs::create_dir_all(parent)
+                    .map_err(|e| format!("Failed to create {}: {e}", parent.display()))?;
+            }
+            fs::copy(&src, &dst).map_err(|e| format!("Failed to copy {}: {e}", src.display()))?;
+            if file_sha256(&src)? != file_sha256(&dst)? {
+                return Err(format!("Verification failed for {}", src.display()));
+            }
+        }
+        Ok(())
+    })();
+
+    if let Err(e) = result {
+        // The destination was empty, so everything in it is ours.
+        if created_dir {
+            let _ = fs::remove_dir_all(new_dir);
+        } else if let Ok(entries) = fs::read_dir(new_dir) {
+            for path in entries.flatten().map(|e| e.path()) {
+                let _ = if path.is_dir() {
+                    fs::remove_dir_all(&path)
+                } else {
+                    fs::remove_file(&path)
+                };
+            }
+        }
+        return Err(e);
+    }
+    Ok(files)
+}
+
+/// Deletes the moved `files` from `dir`, then any directories left empty.
+/// Anything that was not moved, like stray `.tmp` files, stays.
+fn remove_library_files(dir: &Path, files: &[PathBuf]) {
+    for file in files {
+        let _ = fs::remove_file(dir.join(file));
+    }
+    let mut dirs: Vec<PathBuf> = files
+        .iter()
+        .flat_map(|f| f.ancestors().skip(1))
+        .map(|d| dir.join(d))
+        .collect();
+    dirs.sort();
+    dirs.dedup();
+    // Deepest first, ending with `dir` itself.
+    for d in dirs.iter().rev() {
+        let _ = fs::remove_dir(d);
+    }
+}
+
+/// Copies the library to `new_dir`, runs `switch` to point the config at
+/// it and removes the originals. Writers of the library's archives wait
+/// until the originals are gone, so none lands in a file already copied;
+/// they fail on the missing file instead and can retry at the new place.
+fn move_library_files(
+    old_dir: &Path,
+    new_dir: &Path,
+    progress: impl FnMut(&Path, usize, usize),
+    switch: impl FnOnce() -> Result<(), String>,
+) -> Result<Vec<PathBuf>, String> {
+    let archives: Vec<PathBuf> = library_files(old_dir)?
+        .into_iter()
+        .filter(|f| f.extension().is_some_and(|e| e == "olscore"))
+        .map(|f| old_dir.join(f))
+        .collect();
+    with_archive_locks(&archives, || {
+        let files = copy_library(old_dir, new_dir, progress)?;
+        switch()?;
+        remove_library_files(old_dir, &files);
+        Ok(files)
+    })
+}
+
+/// Moves a library to `new_path`, emitting `library-move-progress` per file.
+///
+/// Every file, including those in subdirectories, is copied and verified
+/// by SHA-256 before the config is switched over; the originals are only
+/// deleted after that. The destination must be empty. On failure the
+/// partial copy is removed and the library stays where it was.
+#[tauri::command]
+pub async fn move_library(
+    app: AppHandle,
+    library_id: String,
+    new_path: String,
+) -> Result<LibraryLocation, String> {
+    tauri::async_runtime::spawn_blocking(move || move_library_blocking(&app, library_id, new_path))
+        .await
+        .map_err(|e| format!("Failed to move library: {e}"))?
+}
+
+fn move_library_blocking(
+    app: &AppHandle,
+    library_id: String,
+    new_path: String,
+) -> Result<LibraryLocation, String> {
+    let mut config = read_config(app)?;
+    let old_dir = PathBuf::from(&find_location(&config, &library_id)?.path);
+    let new_dir = PathBuf::from(&new_path);
+
+    // Write out pending notes so the copy has them.
+    let notes = app.state::<NotesStore>();
+    notes.flush_all(app)?;
+
+    let mut location = None;
+    let progress = |file: &Path, done, total| {
+        let _ = app.emit(
+            "library-move-progress",
+            LibraryMoveProgress {
+                library_id: library_id.clone(),
+                file: file.to_string_lossy().to_string(),
+                done,
+                total,
+            },
+        );
+    };
+    let files = move_library_files(&old_dir, &new_dir, progress, || {
+        let entry = config
+            .libraries
+            .iter_mut()
+            .find(|l| l.id == library_id)
+            .ok_or_else(|| format!("Unknown library: {library_id}"))?;
+        entry.path = new_path;
+        location = Some(entry.clone());
+        write_config(app, &config)
+    })?;
+
+    let location = location.ok_or_else(|| format!("Unknown library: {library_id}"))?;
+
+    // Notes changed during the move couldn't be written to the old place;
+    // failures are emitted per score
+    notes.relocate(&old_dir, &new_dir)?;
+    let _ = notes.flush_all(app);
+
+    let _ = app.emit(
+        "library-move-progress",
+        LibraryMoveProgress {
+            library_id,
+            file: String::new(),
+            done: files.len(),
+            total: files.len(),
+        },
+    );
+
+    Ok(location)
+}
+
+#[tauri::command]
+pub fn list_library(
+    app: AppHandle,
+    library_id: Option<String>,
+) -> Result<Vec<LibraryEntry>, String> {
+    collect_entries(&app, library_id.as_deref())
+}
+
+#[tauri::command]
+pub fn search_library(
+    app: AppHandle,
+    query: String,
+    library_id: Option<String>,
+) -> Result<Vec<LibraryEntry>, String> {
+    let query = query.to_lowercase();
+    let mut entries = collect_entries(&app, library_id.as_deref())?;
+    entries.retain(|e| e.title.to_lowercase().contains(&query));
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::olscore::{read_archive_entry, replace_archive_entries};
+    use crate::timestamp::Timestamp;
+
+    fn write(path: &Path, contents: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    #[test]
+    fn unreadable_timestamps_survive_a_rewrite() {
+        let dir = tempfile::tempdir().unwrap();
+        write(
+            &dir.path().join("library.json"),
+            r#"{"scores": [
+                {"id": "a", "title": "A", "sha256": "1",
+                 "created_at": "2024-03-01T12:00:00+01:00",
+                 "last_opened_at": "2024-03-02T09:30:00.250Z"},
+                {"id": "b", "title": "B", "sha256": "2",
+                 "created_at": "last tuesday", "last_opened_at": 1709290800},
+                {"id": "c", "title": "C", "sha256": "3"}
+            ]}"#,
+        );
+
+        let index = read_library_index(dir.path()).unwrap();
+        let a = &index.scores[0];
+        assert_eq!(a.created_at.timestamp().to_string(), "2024-03-01T11:00:00Z");
+        assert_eq!(
+            a.last_opened_at.timestamp().to_string(),
+            "2024-03-02T09:30:00.25Z"
+        );
+        let b = &index.scores[1];
+        assert_eq!(
+            b.created_at,
+            StoredTimestamp::Invalid("last tuesday".into())
+        );
+        assert_eq!(b.created_at.timestamp(), Timestamp::default());
+        assert_eq!(b.last_opened_at, StoredTimestamp::default());
+        assert_eq!(b.title, "B");
+        assert_eq!(index.scores[2].created_at, StoredTimestamp::default());
+
+        write_library_index(dir.path(), &index).unwrap();
+        let rewritten = read_library_index(dir.path()).unwrap();
+        assert_eq!(
+            rewritten.scores[1].created_at,
+            StoredTimestamp::Invalid("last tuesday".into())
+        );
+        assert_eq!(rewritten.scores[0].created_at, a.created_at);
+    }
+
+    #[test]
+    fn copies_subdirectories_and_removes_originals() {
+        let root = tempfile::tempdir().unwrap();
+        let old_dir = root.path().join("old");
+        let new_dir = root.path().join("new");
+        write(&old_dir.join("library.json"), "{\"scores\":[]}");
+        write(&old_dir.join("a.olscore"), "a");
+        write(&old_dir.join("scans/2024/b.olscore"), "b");
+        write(&old_dir.join("a.olscore.1234.tmp"), "partial");
+
+        let mut seen = Vec::new();
+        let files = copy_library(&old_dir, &new_dir, |file, done, total| {
+            seen.push((file.to_path_buf(), done, total))
+        })
+        .unwrap();
+
+        assert_eq!(
+            files,
+            vec![
+                PathBuf::from("a.olscore"),
+                PathBuf::from("library.json"),
+                PathBuf::from("scans/2024/b.olscore"),
+            ]
+        );
+        assert_eq!(seen.len(), 3);
+        assert_eq!(seen[2], (PathBuf::from("scans/2024/b.olscore"), 2, 3));
+        assert_eq!(
+            fs::read_to_string(new_dir.join("scans/2024/b.olscore")).unwrap(),
+            "b"
+        );
+        assert!(!new_dir.join("a.olscore.1234.tmp").exists());
+
+        remove_library_files(&old_dir, &files);
+        assert!(!old_dir.join("scans").exists());
+        assert!(!old_dir.join("a.olscore").exists());
+        // The stray tmp file is left alone, and with it the directory.
+        assert!(old_dir.join("a.olscore.1234.tmp").exists());
+    }
+
+    #[test]
+    fn removes_emptied_library_dir() {
+        let root = tempfile::tempdir().unwrap();
+        let old_dir = root.path().join("old");
+        let new_dir = root.path().join("new");
+        write(&old_dir.join("sub/a.olscore"), "a");
+
+        let files = copy_library(&old_dir, &new_dir, |_, _, _| {}).unwrap();
+        remove_library_files(&old_dir, &files);
+        assert!(!old_dir.exists());
+        assert!(new_dir.join("sub/a.olscore").exists());
+    }
+
+    #[test]
+    fn accepts_empty_destination() {
+        let root = tempfile::tempdir().unwrap();
+        let old_dir = root.path().join("old");
+        let new_dir = root.path().join("new");
+        write(&old_dir.join("a.olscore"), "a");
+        fs::create_dir_all(&new_dir).unwrap();
+
+        copy_library(&old_dir, &new_dir, |_, _, _| {}).unwrap();
+        assert!(new_dir.join("a.olscore").exists());
+    }
+
+    #[test]
+    fn refuses_non_empty_destination() {
+        let root = tempfile::tempdir().unwrap();
+        let old_dir = root.path().join("old");
+        let new_dir = root.path().join("new");
+        write(&old_dir.join("a.olscore"), "a");
+        write(&new_dir.join("other.txt"), "keep me");
+
+        let err = copy_library(&old_dir, &new_dir, |_, _, _| {}).unwrap_err();
+        assert!(err.contains("not empty"), "{err}");
+        assert_eq!(
+            fs::read_to_string(new_dir.join("other.txt")).unwrap(),
+            "keep me"
+        );
+        assert!(!new_dir.join("a.olscore").exists());
+        assert!(old_dir.join("a.olscore").exists());
+    }
+
+    #[test]
+    fn refuses_moving_into_itself() {
+        let root = tempfile::tempdir().unwrap();
+        let old_dir = root.path().join("old");
+        write(&old_dir.join("a.olscore"), "a");
+
+        assert!(copy_library(&old_dir, &old_dir.join("inner"), |_, _, _| {}).is_err());
+        assert!(!old_dir.join("inner").exists());
+    }
+
+    #[test]
+    fn refuses_moving_into_itself_by_another_name() {
+        let root = tempfile::tempdir().unwrap();
+        let old_dir = root.path().join("old");
+        write(&old_dir.join("a.olscore"), "a");
+        let link = root.path().join("link");
+        std::os::unix::fs::symlink(&old_dir, &link).unwrap();
+
+        let dotted = root.path().join("elsewhere/../old/inner");
+        assert!(copy_library(&old_dir, &dotted, |_, _, _| {}).is_err());
+        assert!(copy_library(&old_dir, &link.join("inner"), |_, _, _| {}).is_err());
+        assert!(!old_dir.join("inner").exists());
+    }
+
+    #[test]
+    fn archive_writes_wait_for_the_move() {
+        let root = tempfile::tempdir().unwrap();
+        let old_dir = root.path().join("old");
+        let new_dir = root.path().join("new");
+        fs::create_dir_all(&old_dir).unwrap();
+        let archive = old_dir.join("a.olscore");
+        let mut zip = zip::ZipWriter::new(fs::File::create(&archive).unwrap());
+        zip.start_file("manifest.json", zip::write::SimpleFileOptions::default())
+            .unwrap();
+        zip.finish().unwrap();
+
+        let mut writer = None;
+        let files = move_library_files(
+            &old_dir,
+            &new_dir,
+            |_, _, _| {
+                let archive = archive.clone();
+                writer.get_or_insert_with(|| {
+                    std::thread::spawn(move || {
+                        replace_archive_entries(&archive, &[("notes.json", b"{}".to_vec())])
+                    })
+                });
+            },
+            || Ok(()),
+        )
+        .unwrap();
+
+        assert_eq!(files, vec![PathBuf::from("a.olscore")]);
+        // The write came too late for the copy, so it must fail rather
+        // than go to the file that was removed
+        assert!(writer.unwrap().join().unwrap().is_err());
+        assert!(!archive.exists());
+        let moved = new_dir.join("a.olscore");
+        assert!(read_archive_entry(&moved, "notes.json").unwrap().is_none());
+    }
+
+    #[test]
+    fn failed_copy_leaves_destination_clean() {
+        let root = tempfile::tempdir().unwrap();
+        let old_dir = root.path().join("old");
+        let new_dir = root.path().join("new");
+        write(&old_dir.join("a.olscore"), "a");
+        write(&old_dir.join("b.olscore"), "b");
+        // A directory where a file should go makes the second copy fail.
+        fs::create_dir_all(&new_dir).unwrap();
+
+        let result = copy_library(&old_dir, &new_dir, |file, _, _| {
+            if file == Path::new("b.olscore") {
+                fs::create_dir_all(new_dir.join("b.olscore/x")).unwrap();
+            }
+        });
+        assert!(result.is_err());
+        assert_eq!(fs::read_dir(&new_dir).unwrap().count(), 0);
+        assert!(old_dir.join("a.olscore").exists());
+    }
+}
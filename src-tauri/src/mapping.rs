@@ -0,0 +1,449 @@
+use evdev::Device;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use tauri::{AppHandle, Manager};
+
+use crate::stylus::{read_device_axes, DeviceAxes, StylusEvent, StylusEventKind};
+
+/// Display rotation, named after the xrandr orientations.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Rotation {
+    #[default]
+    Normal,
+    Right,
+    Inverted,
+    Left,
+}
+
+impl Rotation {
+    /// Same transform as the libinput calibration matrix for the rotation.
+    fn apply(self, x: f64, y: f64) -> (f64, f64) {
+        match self {
+            Rotation::Normal => (x, y),
+            Rotation::Right => (y, 1.0 - x),
+            Rotation::Inverted => (1.0 - x, 1.0 - y),
+            Rotation::Left => (1.0 - y, x),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DeviceCalibration {
+    /// Offsets in normalized screen units, added after rotation.
+    #[serde(default)]
+    pub offset_x: f64,
+    #[serde(default)]
+    pub offset_y: f64,
+    #[serde(default)]
+    pub rotation: Rotation,
+    /// Monitor the device is mapped to; the window's monitor if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub monitor: Option<String>,
+}
+
+/// Calibrations keyed by device name, since `/dev/input` nodes are not
+/// stable across reboots or replugs.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct CalibrationStore {
+    #[serde(default)]
+    devices: HashMap<String, DeviceCalibration>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CalibrationSample {
+    pub raw_x: i32,
+    pub raw_y: i32,
+    /// Where the user was asked to tap, in window CSS pixels.
+    pub window_x: f64,
+    pub window_y: f64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Rect {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+/// Monitor and window placement in physical desktop pixels.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct ScreenGeometry {
+    pub monitor: Rect,
+    pub window: Rect,
+    pub scale_factor: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MappedPoint {
+    /// Position on the mapped monitor in 0..1.
+    pub norm_x: f64,
+    pub norm_y: f64,
+    /// Position relative to the window's content area, in CSS pixels.
+    pub window_x: f64,
+    pub window_y: f64,
+    /// Pressure in 0..1, or 1 while touching a device without pressure.
+    pub pressure: f64,
+}
+
+/// Turns raw device units into monitor- and window-relative coordinates.
+pub struct CoordinateMapper {
+    axes: DeviceAxes,
+    calibration: DeviceCalibration,
+    geometry: Option<ScreenGeometry>,
+}
+
+impl CoordinateMapper {
+    pub fn new(axes: DeviceAxes, calibration: DeviceCalibration) -> Self {
+        Self {
+            axes,
+            calibration,
+            geometry: None,
+        }
+    }
+
+    /// Opens the device for its axis ranges and loads its stored calibration.
+    pub fn for_device(app: &AppHandle, device_path: &str) -> Result<Self, String> {
+        let device =
+            Device::open(device_path).map_err(|e| format!("Failed to open device: {e}"))?;
+        let calibration = load_calibration(app, &device_key(&device))?;
+        let mut mapper = Self::new(read_device_axes(&device), calibration);
+        mapper.refresh_geometry(app);
+        Ok(mapper)
+    }
+
+    /// Re-reads window and monitor placement, e.g. after the window moved.
+    pub fn refresh_geometry(&mut self, app: &AppHandle) {
+        self.geometry = screen_geometry(app, self.calibration.monitor.as_deref());
+    }
+
+    /// Device position in 0..1 monitor space without the calibration offset.
+    fn uncalibrated(&self, x: i32, y: i32) -> (f64, f64) {
+        let nx = self.axes.x.map_or(0.0, |a| a.normalize(x));
+        let ny = self.axes.y.map_or(0.0, |a| a.normalize(y));
+        self.calibration.rotation.apply(nx, ny)
+    }
+
+    pub fn normalized(&self, x: i32, y: i32) -> (f64, f64) {
+        let (nx, ny) = self.uncalibrated(x, y);
+        (
+            nx + self.calibration.offset_x,
+            ny + self.calibration.offset_y,
+        )
+    }
+
+    pub fn map(&self, event: &StylusEvent) -> MappedPoint {
+        let pressure = match self.axes.pressure {
+            Some(range) => range.normalize(event.pressure),
+            None if event.event_type == StylusEventKind::Up => 0.0,
+            None => 1.0,
+        };
+        self.map_raw(event.x, event.y, pressure)
+    }
+
+    /// Maps a raw position with an already normalized pressure.
+    pub fn map_raw(&self, x: i32, y: i32, pressure: f64) -> MappedPoint {
+        let (norm_x, norm_y) = self.normalized(x, y);
+        let (window_x, window_y) = match &self.geometry {
+            Some(g) => to_window(g, norm_x, norm_y),
+            None => (0.0, 0.0),
+        };
+        MappedPoint {
+            norm_x,
+            norm_y,
+            window_x,
+            window_y,
+            pressure,
+        }
+    }
+}
+
+fn to_window(g: &ScreenGeometry, norm_x: f64, norm_y: f64) -> (f64, f64) {
+    let screen_x = g.monitor.x + norm_x * g.monitor.width;
+    let screen_y = g.monitor.y + norm_y * g.monitor.height;
+    (
+        (screen_x - g.window.x) / g.scale_factor,
+        (screen_y - g.window.y) / g.scale_factor,
+    )
+}
+
+fn from_window(g: &ScreenGeometry, window_x: f64, window_y: f64) -> (f64, f64) {
+    let screen_x = g.window.x + window_x * g.scale_factor;
+    let screen_y = g.window.y + window_y * g.scale_factor;
+    (
+        (screen_x - g.monitor.x) / g.monitor.width,
+        (screen_y - g.monitor.y) / g.monitor.height,
+    )
+}
+
+fn screen_geometry(app: &AppHandle, monitor_name: Option<&str>) -> Option<ScreenGeometry> {
+    let window = app.get_webview_window("main")?;
+    let position = window.inner_position().ok()?;
+    let size = window.inner_size().ok()?;
+    let scale_factor = window.scale_factor().ok()?;
+
+    let monitor = match monitor_name {
+        Some(name) => window
+            .available_monitors()
+            .ok()?
+            .into_iter()
+            .find(|m| m.name().is_some_and(|n| n == name)),
+        None => None,
+    };
+    let monitor = match monitor {
+        Some(m) => m,
+        None => window.current_monitor().ok()??,
+    };
+
+    Some(ScreenGeometry {
+        monitor: Rect {
+            x: monitor.position().x as f64,
+            y: monitor.position().y as f64,
+            width: monitor.size().width as f64,
+            height: monitor.size().height as f64,
+        },
+        window: Rect {
+            x: position.x as f64,
+            y: position.y as f64,
+            width: size.width as f64,
+            height: size.height as f64,
+        },
+        scale_factor,
+    })
+}
+
+fn device_key(device: &Device) -> String {
+    device.name().unwrap_or("Unknown").to_string()
+}
+
+fn calibration_path(app: &AppHandle) -> Result<PathBuf, String> {
+    let config_dir = app
+        .path()
+        .app_config_dir()
+        .map_err(|e| format!("Failed to get app config dir: {e}"))?;
+    Ok(config_dir.join("stylus-calibration.json"))
+}
+
+fn read_store(app: &AppHandle) -> Result<CalibrationStore, String> {
+    let path = calibration_path(app)?;
+    if !path.exists() {
+        return Ok(CalibrationStore::default());
+    }
+    let data = fs::read_to_string(&path)
+        .map_err(|e| format!("Failed to read stylus-calibration.json: {e}"))?;
+    serde_json::from_str(&data).map_err(|e| format!("Failed to parse stylus-calibration.json: {e}"))
+}
+
+fn write_store(app: &AppHandle, store: &CalibrationStore) -> Result<(), String> {
+    let path = calibration_path(app)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|e| format!("Failed to create config dir: {e}"))?;
+    }
+    let data =
+        serde_json::to_string_pretty(store).map_err(|e| format!("Failed to serialize: {e}"))?;
+    fs::write(&path, data).map_err(|e| format!("Failed to write stylus-calibration.json: {e}"))
+}
+
+fn load_calibration(app: &AppHandle, key: &str) -> Result<DeviceCalibration, String> {
+    Ok(read_store(app)?.devices.remove(key).unwrap_or_default())
+}
+
+fn store_calibration(
+    app: &AppHandle,
+    key: String,
+    calibration: Option<DeviceCalibration>,
+) -> Result<(), String> {
+    let mut store = read_store(app)?;
+    match calibration {
+        Some(c) => store.devices.insert(key, c),
+        None => store.devices.remove(&key),
+    };
+    write_store(app, &store)
+}
+
+fn open_device_key(device_path: &str) -> Result<(Device, String), String> {
+    let device = Device::open(device_path).map_err(|e| format!("Failed to open device: {e}"))?;
+    let key = device_key(&device);
+    Ok((device, key))
+}
+
+#[tauri::command]
+pub fn get_screen_geometry(app: AppHandle) -> Option<ScreenGeometry> {
+    screen_geometry(&app, None)
+}
+
+#[tauri::command]
+pub fn get_stylus_calibration(
+    app: AppHandle,
+    device_path: String,
+) -> Result<DeviceCalibration, String> {
+    let (_, key) = open_device_key(&device_path)?;
+    load_calibration(&app, &key)
+}
+
+#[tauri::command]
+pub fn set_stylus_calibration(
+    app: AppHandle,
+    device_path: String,
+    calibration: DeviceCalibration,
+) -> Result<(), String> {
+    let (_, key) = open_device_key(&device_path)?;
+    store_calibration(&app, key, Some(calibration))
+}
+
+#[tauri::command]
+pub fn reset_stylus_calibration(app: AppHandle, device_path: String) -> Result<(), String> {
+    let (_, key) = open_device_key(&device_path)?;
+    store_calibration(&app, key, None)
+}
+
+/// Computes offsets from taps on known targets and stores them. Rotation
+/// and monitor are kept from the existing calibration.
+#[tauri::command]
+pub fn calibrate_stylus(
+    app: AppHandle,
+    device_path: String,
+    samples: Vec<CalibrationSample>,
+) -> Result<DeviceCalibration, String> {
+    if samples.is_empty() {
+        return Err("No calibration samples".into());
+    }
+    let (device, key) = open_device_key(&device_path)?;
+    let mut calibration = load_calibration(&app, &key)?;
+
+    let mapper = CoordinateMapper::new(read_device_axes(&device), calibration.clone());
+    let geometry = screen_geometry(&app, calibration.monitor.as_deref())
+        .ok_or_else(|| "Failed to get window geometry".to_string())?;
+
+    let (mut sum_x, mut sum_y) = (0.0, 0.0);
+    for sample in &samples {
+        let (raw_x, raw_y) = mapper.uncalibrated(sample.raw_x, sample.raw_y);
+        let (target_x, target_y) = from_window(&geometry, sample.window_x, sample.window_y);
+        sum_x += target_x - raw_x;
+        sum_y += target_y - raw_y;
+    }
+    calibration.offset_x = sum_x / samples.len() as f64;
+    calibration.offset_y = sum_y / samples.len() as f64;
+
+    store_calibration(&app, key, Some(calibration.clone()))?;
+    Ok(calibration)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stylus::AxisRange;
+
+    /// A 32000x18000 unit panel whose x range starts at 100, on the right
+    /// of two 1920x1080 monitors, with a HiDPI window inset into it.
+    fn mapper(calibration: DeviceCalibration) -> CoordinateMapper {
+        let range = |min, max| {
+            Some(AxisRange {
+                min,
+                max,
+                resolution: 100,
+            })
+        };
+        let axes = DeviceAxes {
+            x: range(100, 32_100),
+            y: range(0, 18_000),
+            ..DeviceAxes::default()
+        };
+        let mut mapper = CoordinateMapper::new(axes, calibration);
+        mapper.geometry = Some(ScreenGeometry {
+            monitor: Rect {
+                x: 1920.0,
+                y: 0.0,
+                width: 1920.0,
+                height: 1080.0,
+            },
+            window: Rect {
+                x: 2020.0,
+                y: 100.0,
+                width: 1600.0,
+                height: 900.0,
+            },
+            scale_factor: 2.0,
+        });
+        mapper
+    }
+
+    fn assert_maps(mapper: &CoordinateMapper, raw: (i32, i32), window: (f64, f64)) {
+        let point = mapper.map_raw(raw.0, raw.1, 1.0);
+        assert!(
+            (point.window_x - window.0).abs() < 1e-9 && (point.window_y - window.1).abs() < 1e-9,
+            "{raw:?} mapped to ({}, {}), expected {window:?}",
+            point.window_x,
+            point.window_y
+        );
+    }
+
+    // Panel corners in raw units, clockwise from the panel's own top left
+    const RAW_CORNERS: [(i32, i32); 4] = [(100, 0), (32_100, 0), (32_100, 18_000), (100, 18_000)];
+
+    // The monitor's corners in window CSS pixels
+    const TOP_LEFT: (f64, f64) = (-50.0, -50.0);
+    const TOP_RIGHT: (f64, f64) = (910.0, -50.0);
+    const BOTTOM_RIGHT: (f64, f64) = (910.0, 490.0);
+    const BOTTOM_LEFT: (f64, f64) = (-50.0, 490.0);
+
+    #[test]
+    fn panel_corners_land_on_monitor_corners_for_each_rotation() {
+        let cases = [
+            (
+                Rotation::Normal,
+                [TOP_LEFT, TOP_RIGHT, BOTTOM_RIGHT, BOTTOM_LEFT],
+            ),
+            // The panel's top edge runs up the monitor's left side
+            (
+                Rotation::Right,
+                [BOTTOM_LEFT, TOP_LEFT, TOP_RIGHT, BOTTOM_RIGHT],
+            ),
+            (
+                Rotation::Inverted,
+                [BOTTOM_RIGHT, BOTTOM_LEFT, TOP_LEFT, TOP_RIGHT],
+            ),
+            // The panel's top edge runs down the monitor's right side
+            (
+                Rotation::Left,
+                [TOP_RIGHT, BOTTOM_RIGHT, BOTTOM_LEFT, TOP_LEFT],
+            ),
+        ];
+        for (rotation, expected) in cases {
+            let mapper = mapper(DeviceCalibration {
+                rotation,
+                ..DeviceCalibration::default()
+            });
+            for (raw, window) in RAW_CORNERS.into_iter().zip(expected) {
+                assert_maps(&mapper, raw, window);
+            }
+        }
+    }
+
+    #[test]
+    fn calibration_offsets_shift_after_rotation() {
+        let mapper = mapper(DeviceCalibration {
+            offset_x: 0.25,
+            offset_y: -0.125,
+            rotation: Rotation::Right,
+            monitor: None,
+        });
+        // A quarter across the panel's top edge is a quarter up the
+        // monitor's left side, then shifted by the offsets
+        assert_maps(&mapper, (8_100, 0), (190.0, 287.5));
+        assert_maps(&mapper, (16_100, 9_000), (670.0, 152.5));
+
+        let point = mapper.map_raw(8_100, 0, 0.5);
+        assert_eq!((point.norm_x, point.norm_y), (0.25, 0.625));
+    }
+
+    #[test]
+    fn readings_outside_the_axis_range_stay_on_the_monitor() {
+        let mapper = mapper(DeviceCalibration::default());
+        assert_maps(&mapper, (0, -50), TOP_LEFT);
+        assert_maps(&mapper, (40_000, 20_000), BOTTOM_RIGHT);
+    }
+}
@@ -0,0 +1,904 @@
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{FromSample, SampleFormat, SizedSample};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::f32::consts::TAU;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tauri::{AppHandle, Emitter, State};
+
+use crate::notes::NotesStore;
+use crate::olscore::{modify_manifest, read_manifest_at, score_path, NotesData, ScoreManifest};
+
+const MIN_BPM: f64 = 10.0;
+const MAX_BPM: f64 = 400.0;
+const CLICK_SECONDS: f32 = 0.03;
+const CLICK_HZ: f32 = 1000.0;
+const ACCENT_HZ: f32 = 1600.0;
+/// How fast clicks fade out, per second.
+const CLICK_DECAY: f32 = 120.0;
+const NULL_SAMPLE_RATE: u32 = 48_000;
+const NULL_BLOCK: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct TimeSignature {
+    pub beats: u32,
+    /// Note value of one beat, e.g. 4 for quarters.
+    pub unit: u32,
+}
+
+impl Default for TimeSignature {
+    fn default() -> Self {
+        Self { beats: 4, unit: 4 }
+    }
+}
+
+/// A tempo in beats of the time signature's unit per minute.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Tempo {
+    pub bpm: f64,
+    #[serde(default)]
+    pub time_signature: TimeSignature,
+}
+
+impl Default for Tempo {
+    fn default() -> Self {
+        Self {
+            bpm: 100.0,
+            time_signature: TimeSignature::default(),
+        }
+    }
+}
+
+impl Tempo {
+    fn validate(&self) -> Result<(), String> {
+        if !(MIN_BPM..=MAX_BPM).contains(&self.bpm) {
+            return Err(format!("Tempo must be {MIN_BPM}-{MAX_BPM} BPM"));
+        }
+        let TimeSignature { beats, unit } = self.time_signature;
+        if beats == 0 || !unit.is_power_of_two() || unit > 64 {
+            return Err(format!("Invalid time signature: {beats}/{unit}"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TempoAnchor {
+    Page {
+        pdf: String,
+        page: u32,
+    },
+    /// The page carrying the named bookmark in notes.json.
+    Bookmark {
+        name: String,
+    },
+}
+
+/// A change of tempo, time signature or both from a page on.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TempoChange {
+    pub at: TempoAnchor,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bpm: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time_signature: Option<TimeSignature>,
+    /// Marking shown to the player, e.g. "Allegro".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TempoMap {
+    #[serde(flatten)]
+    pub default: Tempo,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub changes: Vec<TempoChange>,
+}
+
+impl TempoMap {
+    fn validate(&self) -> Result<(), String> {
+        self.default.validate()?;
+        for change in &self.changes {
+            Tempo {
+                bpm: change.bpm.unwrap_or(self.default.bpm),
+                time_signature: change.time_signature.unwrap_or(self.default.time_signature),
+            }
+            .validate()?;
+        }
+        Ok(())
+    }
+}
+
+/// Tempo in effect on a page: the default with every change met along the
+/// page sequence up to and including the page applied in turn. Pages left
+/// out of the sequence only get their own changes.
+pub fn tempo_at(
+    map: &TempoMap,
+    manifest: &ScoreManifest,
+    bookmarks: &HashMap<String, (String, u32)>,
+    pdf: &str,
+    page: u32,
+) -> Tempo {
+    let anchored = |change: &TempoChange, p: &str, n: u32| match &change.at {
+        TempoAnchor::Page { pdf, page } => pdf == p && *page == n,
+        TempoAnchor::Bookmark { name } => bookmarks
+            .get(name)
+            .is_some_and(|(pdf, page)| pdf == p && *page == n),
+    };
+    let sequence = manifest.page_sequence();
+    let passed: Vec<(&str, u32)> =
+        match sequence.iter().position(|r| r.pdf == pdf && r.page == page) {
+            Some(index) => sequence[..=index]
+                .iter()
+                .map(|r| (r.pdf.as_str(), r.page))
+                .collect(),
+            None => vec![(pdf, page)],
+        };
+
+    let mut tempo = map.default;
+    for (p, n) in passed {
+        for change in map.changes.iter().filter(|c| anchored(c, p, n)) {
+            if let Some(bpm) = change.bpm {
+                tempo.bpm = bpm;
+            }
+            if let Some(time_signature) = change.time_signature {
+                tempo.time_signature = time_signature;
+            }
+        }
+    }
+    tempo
+}
+
+/// Bookmark names and the first page carrying each along the page
+/// sequence, then on pages left out of it.
+fn bookmark_pages(notes: &NotesData, manifest: &ScoreManifest) -> HashMap<String, (String, u32)> {
+    let sequence = manifest
+        .page_sequence()
+        .into_iter()
+        .map(|r| (r.pdf, r.page));
+    let rest = notes
+        .pages
+        .iter()
+        .flat_map(|(pdf, pdf_notes)| pdf_notes.keys().map(move |page| (pdf.clone(), *page)));
+    let mut pages = HashMap::new();
+    for (pdf, page) in sequence.chain(rest) {
+        let Some(page_notes) = notes.pages.get(&pdf).and_then(|p| p.get(&page)) else {
+            continue;
+        };
+        for name in &page_notes.bookmarks {
+            pages
+                .entry(name.clone())
+                .or_insert_with(|| (pdf.clone(), page));
+        }
+    }
+    pages
+}
+
+/// Generates the click track sample by sample. Beats fall on exact
+/// (fractional) sample positions, so the tempo doesn't drift however the
+/// output is split into buffers.
+pub struct ClickGenerator {
+    sample_rate: u32,
+    tempo: Tempo,
+    /// Applied at the next beat, so a change never cuts a beat short.
+    pending: Option<Tempo>,
+    volume: f32,
+    until_beat: f64,
+    beat: u32,
+    /// Position in the current click and whether it is accented.
+    click: Option<(u32, bool)>,
+}
+
+impl ClickGenerator {
+    pub fn new(tempo: Tempo, volume: f32) -> Self {
+        Self {
+            sample_rate: NULL_SAMPLE_RATE,
+            tempo,
+            pending: None,
+            volume,
+            until_beat: 0.0,
+            beat: 0,
+            click: None,
+        }
+    }
+
+    /// Called by the backend before it starts pulling samples.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+    }
+
+    pub fn set_tempo(&mut self, tempo: Tempo) {
+        self.pending = Some(tempo);
+    }
+
+    pub fn set_volume(&mut self, volume: f32) {
+        self.volume = volume;
+    }
+
+    fn samples_per_beat(&self) -> f64 {
+        60.0 * self.sample_rate as f64 / self.tempo.bpm
+    }
+
+    /// Fills a mono buffer, calling `on_beat` with the offset and the beat
+    /// of the bar (0 = downbeat) of each beat started.
+    pub fn fill(&mut self, out: &mut [f32], mut on_beat: impl FnMut(usize, u32)) {
+        let click_length = (CLICK_SECONDS * self.sample_rate as f32) as u32;
+        for (offset, sample) in out.iter_mut().enumerate() {
+            if self.until_beat <= 0.0 {
+                if let Some(tempo) = self.pending.take() {
+                    if tempo.time_signature != self.tempo.time_signature {
+                        self.beat = 0;
+                    }
+                    self.tempo = tempo;
+                }
+                self.click = Some((0, self.beat == 0));
+                on_beat(offset, self.beat);
+                self.beat = (self.beat + 1) % self.tempo.time_signature.beats;
+                self.until_beat += self.samples_per_beat();
+            }
+            self.until_beat -= 1.0;
+
+            *sample = match self.click {
+                Some((n, accent)) if n < click_length => {
+                    self.click = Some((n + 1, accent));
+                    let t = n as f32 / self.sample_rate as f32;
+                    let hz = if accent { ACCENT_HZ } else { CLICK_HZ };
+                    (TAU * hz * t).sin() * (-CLICK_DECAY * t).exp() * self.volume
+                }
+                _ => 0.0,
+            };
+        }
+    }
+}
+
+/// Stops the output when dropped.
+pub struct OutputHandle {
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for OutputHandle {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Receives errors reported by the output while it plays.
+pub type ErrorCallback = Box<dyn FnMut(String) + Send>;
+
+/// Somewhere to play the click track.
+pub trait AudioBackend: Send + Sync {
+    /// Starts pulling samples from `generator` until the handle is dropped.
+    fn start(
+        &self,
+        generator: Arc<Mutex<ClickGenerator>>,
+        on_error: ErrorCallback,
+    ) -> Result<OutputHandle, String>;
+}
+
+/// Plays through the system's default output device.
+pub struct CpalBackend;
+
+fn build_stream<T: SizedSample + FromSample<f32>>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    generator: Arc<Mutex<ClickGenerator>>,
+    mut on_error: ErrorCallback,
+) -> Result<cpal::Stream, String> {
+    let channels = config.channels as usize;
+    let mut mono = Vec::new();
+    device
+        .build_output_stream(
+            config,
+            move |data: &mut [T], _| {
+                mono.resize(data.len() / channels, 0.0);
+                match generator.lock() {
+                    Ok(mut generator) => generator.fill(&mut mono, |_, _| {}),
+                    Err(_) => mono.fill(0.0),
+                }
+                for (frame, &sample) in data.chunks_mut(channels).zip(&mono) {
+                    frame.fill(T::from_sample(sample));
+                }
+            },
+            move |e| on_error(e.to_string()),
+            None,
+        )
+        .map_err(|e| format!("Failed to open audio output: {e}"))
+}
+
+impl AudioBackend for CpalBackend {
+    fn start(
+        &self,
+        generator: Arc<Mutex<ClickGenerator>>,
+        on_error: ErrorCallback,
+    ) -> Result<OutputHandle, String> {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let (ready, started) = mpsc::channel();
+        // Streams can't move between threads, so one thread owns it
+        let thread = thread::spawn(move || {
+            let open = || {
+                let device = cpal::default_host()
+                    .default_output_device()
+                    .ok_or("No audio output device")?;
+                let supported = device
+                    .default_output_config()
+                    .map_err(|e| format!("Failed to query audio output: {e}"))?;
+                let config = supported.config();
+                if let Ok(mut generator) = generator.lock() {
+                    generator.set_sample_rate(config.sample_rate.0);
+                }
+                let generator = generator.clone();
+                let stream = match supported.sample_format() {
+                    SampleFormat::F32 => build_stream::<f32>(&device, &config, generator, on_error),
+                    SampleFormat::I16 => build_stream::<i16>(&device, &config, generator, on_error),
+                    SampleFormat::U16 => build_stream::<u16>(&device, &config, generator, on_error),
+                    format => Err(format!("Unsupported sample format: {format}")),
+                }?;
+                stream
+                    .play()
+                    .map_err(|e| format!("Failed to start audio output: {e}"))?;
+                Ok::<_, String>(stream)
+            };
+            match open() {
+                Ok(stream) => {
+                    let _ = ready.send(Ok(()));
+                    let _ = stopped.recv();
+                    drop(stream);
+                }
+                Err(e) => {
+                    let _ = ready.send(Err(e));
+                }
+            }
+        });
+        started
+            .recv()
+            .map_err(|_| "Audio output thread failed".to_string())??;
+        Ok(OutputHandle {
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+}
+
+/// Pulls and discards samples in real time, for running without a sound
+/// device.
+pub struct NullBackend;
+
+impl AudioBackend for NullBackend {
+    fn start(
+        &self,
+        generator: Arc<Mutex<ClickGenerator>>,
+        _on_error: ErrorCallback,
+    ) -> Result<OutputHandle, String> {
+        let (stop, stopped) = mpsc::channel::<()>();
+        if let Ok(mut generator) = generator.lock() {
+            generator.set_sample_rate(NULL_SAMPLE_RATE);
+        }
+        let frames = (NULL_SAMPLE_RATE as u128 * NULL_BLOCK.as_millis() / 1000) as usize;
+        let thread = thread::spawn(move || {
+            let mut buffer = vec![0.0; frames];
+            while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(NULL_BLOCK) {
+                match generator.lock() {
+                    Ok(mut generator) => generator.fill(&mut buffer, |_, _| {}),
+                    Err(_) => return,
+                }
+            }
+        });
+        Ok(OutputHandle {
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct MetronomeError {
+    score_id: String,
+    error: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MetronomeStatus {
+    pub score_id: String,
+    /// The tempo being played, with `scale` applied.
+    pub tempo: Tempo,
+    /// Follows the tempo of the page shown.
+    pub following: bool,
+    pub scale: f64,
+    pub volume: f32,
+}
+
+/// Changes to a running metronome. Setting a tempo or time signature stops
+/// following the pages unless `follow` says otherwise; a page's tempo is
+/// picked up again on the next page change.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct MetronomeAdjust {
+    pub bpm: Option<f64>,
+    pub time_signature: Option<TimeSignature>,
+    /// Practice speed as a fraction of the marked tempo.
+    pub scale: Option<f64>,
+    pub volume: Option<f32>,
+    pub follow: Option<bool>,
+}
+
+struct Running {
+    generator: Arc<Mutex<ClickGenerator>>,
+    _output: OutputHandle,
+    score_id: String,
+    /// Tempo before scaling.
+    tempo: Tempo,
+    following: bool,
+    scale: f64,
+    volume: f32,
+}
+
+impl Running {
+    fn status(&self) -> MetronomeStatus {
+        MetronomeStatus {
+            score_id: self.score_id.clone(),
+            tempo: Tempo {
+                bpm: (self.tempo.bpm * self.scale).clamp(MIN_BPM, MAX_BPM),
+                ..self.tempo
+            },
+            following: self.following,
+            scale: self.scale,
+            volume: self.volume,
+        }
+    }
+
+    /// Hands the current settings to the generator.
+    fn apply(&self) -> Result<MetronomeStatus, String> {
+        let status = self.status();
+        let mut generator = self.generator.lock().map_err(|e| e.to_string())?;
+        generator.set_tempo(status.tempo);
+        generator.set_volume(self.volume);
+        Ok(status)
+    }
+}
+
+pub struct MetronomeState {
+    backend: Box<dyn AudioBackend>,
+    running: Mutex<Option<Running>>,
+}
+
+impl MetronomeState {
+    pub fn with_backend(backend: impl AudioBackend + 'static) -> Self {
+        Self {
+            backend: Box::new(backend),
+            running: Mutex::new(None),
+        }
+    }
+}
+
+impl Default for MetronomeState {
+    /// Without an output device, e.g. when headless, the metronome still
+    /// runs and follows pages but is silent.
+    fn default() -> Self {
+        match cpal::default_host().default_output_device() {
+            Some(_) => Self::with_backend(CpalBackend),
+            None => Self::with_backend(NullBackend),
+        }
+    }
+}
+
+fn score_tempo_at(
+    app: &AppHandle,
+    store: &NotesStore,
+    score_id: &str,
+    pdf: &str,
+    page: u32,
+) -> Result<Tempo, String> {
+    let manifest = read_manifest_at(&score_path(app, score_id)?)?;
+    let Some(map) = &manifest.tempo else {
+        return Ok(Tempo::default());
+    };
+    let notes = store.snapshot(app, score_id)?.notes;
+    let bookmarks = bookmark_pages(&notes, &manifest);
+    Ok(tempo_at(map, &manifest, &bookmarks, pdf, page))
+}
+
+#[tauri::command]
+pub fn set_tempo_map(
+    app: AppHandle,
+    score_id: String,
+    tempo: Option<TempoMap>,
+) -> Result<ScoreManifest, String> {
+    if let Some(map) = &tempo {
+        map.validate()?;
+    }
+    modify_manifest(&score_path(&app, &score_id)?, |manifest| {
+        manifest.tempo = tempo;
+        Ok(())
+    })
+}
+
+#[tauri::command]
+pub fn get_tempo_at(
+    app: AppHandle,
+    store: State<'_, NotesStore>,
+    score_id: String,
+    pdf: String,
+    page: u32,
+) -> Result<Tempo, String> {
+    score_tempo_at(&app, &store, &score_id, &pdf, page)
+}
+
+/// Starts the metronome at the tempo of the given page, replacing one
+/// already running.
+#[tauri::command]
+pub fn start_metronome(
+    app: AppHandle,
+    store: State<'_, NotesStore>,
+    state: State<'_, MetronomeState>,
+    score_id: String,
+    pdf: String,
+    page: u32,
+    volume: Option<f32>,
+) -> Result<MetronomeStatus, String> {
+    let tempo = score_tempo_at(&app, &store, &score_id, &pdf, page)?;
+    let volume = volume.unwrap_or(1.0).clamp(0.0, 1.0);
+    let mut running = state.running.lock().map_err(|e| e.to_string())?;
+    // Stop the old output first so two never play at once
+    running.take();
+
+    let generator = Arc::new(Mutex::new(ClickGenerator::new(tempo, volume)));
+    // Output errors are emitted as `metronome-error`; the click keeps going
+    let errors = (app.clone(), score_id.clone());
+    let on_error: ErrorCallback = Box::new(move |error| {
+        let (app, score_id) = &errors;
+        let error = MetronomeError {
+            score_id: score_id.clone(),
+            error,
+        };
+        let _ = app.emit("metronome-error", error);
+    });
+    let output = state.backend.start(generator.clone(), on_error)?;
+    let started = running.insert(Running {
+        generator,
+        _output: output,
+        score_id,
+        tempo,
+        following: true,
+        scale: 1.0,
+        volume,
+    });
+    started.apply()
+}
+
+#[tauri::command]
+pub fn stop_metronome(state: State<'_, MetronomeState>) -> Result<bool, String> {
+    Ok(state
+        .running
+        .lock()
+        .map_err(|e| e.to_string())?
+        .take()
+        .is_some())
+}
+
+#[tauri::command]
+pub fn adjust_metronome(
+    state: State<'_, MetronomeState>,
+    adjust: MetronomeAdjust,
+) -> Result<MetronomeStatus, String> {
+    let mut running = state.running.lock().map_err(|e| e.to_string())?;
+    let running = running.as_mut().ok_or("Metronome not running")?;
+    let mut tempo = running.tempo;
+    if let Some(bpm) = adjust.bpm {
+        tempo.bpm = bpm;
+    }
+    if let Some(time_signature) = adjust.time_signature {
+        tempo.time_signature = time_signature;
+    }
+    tempo.validate()?;
+    if let Some(scale) = adjust.scale {
+        if !(0.1..=2.0).contains(&scale) {
+            return Err("Tempo scale must be between 0.1 and 2".into());
+        }
+        running.scale = scale;
+    }
+
+    let manual = adjust.bpm.is_some() || adjust.time_signature.is_some();
+    running.following = adjust.follow.unwrap_or(running.following && !manual);
+    running.tempo = tempo;
+    if let Some(volume) = adjust.volume {
+        running.volume = volume.clamp(0.0, 1.0);
+    }
+    running.apply()
+}
+
+/// Tells the metronome the page shown changed. When following, it moves
+/// to that page's tempo at the next beat.
+#[tauri::command]
+pub fn metronome_page_changed(
+    app: AppHandle,
+    store: State<'_, NotesStore>,
+    state: State<'_, MetronomeState>,
+    score_id: String,
+    pdf: String,
+    page: u32,
+) -> Result<Option<MetronomeStatus>, String> {
+    let mut running = state.running.lock().map_err(|e| e.to_string())?;
+    let Some(running) = running.as_mut() else {
+        return Ok(None);
+    };
+    if running.following && running.score_id == score_id {
+        running.tempo = score_tempo_at(&app, &store, &score_id, &pdf, page)?;
+    }
+    running.apply().map(Some)
+}
+
+#[tauri::command]
+pub fn get_metronome_status(
+    state: State<'_, MetronomeState>,
+) -> Result<Option<MetronomeStatus>, String> {
+    let running = state.running.lock().map_err(|e| e.to_string())?;
+    Ok(running.as_ref().map(Running::status))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::olscore::{PageNotes, PageRef, PdfEntry};
+
+    /// Pulls a fixed number of samples in uneven blocks as soon as it is
+    /// started, recording where beats fall.
+    struct CaptureBackend {
+        sample_rate: u32,
+        frames: usize,
+        samples: Arc<Mutex<Vec<f32>>>,
+        beats: Arc<Mutex<Vec<(usize, u32)>>>,
+    }
+
+    impl CaptureBackend {
+        fn new(sample_rate: u32, seconds: u32) -> Self {
+            Self {
+                sample_rate,
+                frames: (sample_rate * seconds) as usize,
+                samples: Default::default(),
+                beats: Default::default(),
+            }
+        }
+    }
+
+    impl AudioBackend for CaptureBackend {
+        fn start(
+            &self,
+            generator: Arc<Mutex<ClickGenerator>>,
+            _on_error: ErrorCallback,
+        ) -> Result<OutputHandle, String> {
+            let mut generator = generator.lock().map_err(|e| e.to_string())?;
+            generator.set_sample_rate(self.sample_rate);
+            let (mut samples, mut beats) =
+                (self.samples.lock().unwrap(), self.beats.lock().unwrap());
+            let mut block = 1;
+            while samples.len() < self.frames {
+                let start = samples.len();
+                let mut buffer = vec![0.0; block.min(self.frames - start)];
+                generator.fill(&mut buffer, |offset, beat| {
+                    beats.push((start + offset, beat))
+                });
+                samples.extend(buffer);
+                block = block * 7 % 1009 + 1;
+            }
+            Ok(OutputHandle {
+                stop: None,
+                thread: None,
+            })
+        }
+    }
+
+    fn tempo(bpm: f64, beats: u32) -> Tempo {
+        Tempo {
+            bpm,
+            time_signature: TimeSignature { beats, unit: 4 },
+        }
+    }
+
+    fn capture(backend: &CaptureBackend, tempo: Tempo) -> Vec<(usize, u32)> {
+        let generator = Arc::new(Mutex::new(ClickGenerator::new(tempo, 1.0)));
+        backend.start(generator, Box::new(|_| {})).unwrap();
+        backend.beats.lock().unwrap().clone()
+    }
+
+    #[test]
+    fn clicks_fall_on_exact_sample_offsets() {
+        for (bpm, sample_rate) in [(120.0, 48_000), (77.0, 48_000), (133.3, 44_100)] {
+            let backend = CaptureBackend::new(sample_rate, 10);
+            let offsets: Vec<usize> = capture(&backend, tempo(bpm, 4))
+                .into_iter()
+                .map(|(offset, _)| offset)
+                .collect();
+            let per_beat = 60.0 * sample_rate as f64 / bpm;
+            let expected: Vec<usize> = (0..)
+                .map(|beat| (beat as f64 * per_beat).ceil() as usize)
+                .take_while(|&offset| offset < backend.frames)
+                .collect();
+            assert_eq!(offsets, expected, "{bpm} BPM at {sample_rate} Hz");
+        }
+    }
+
+    #[test]
+    fn null_backend_plays_in_real_time_until_dropped() {
+        let generator = Arc::new(Mutex::new(ClickGenerator::new(tempo(120.0, 4), 1.0)));
+        let output = NullBackend
+            .start(generator.clone(), Box::new(|_| {}))
+            .unwrap();
+        thread::sleep(NULL_BLOCK * 10);
+        drop(output);
+
+        let generator = generator.lock().unwrap();
+        assert_eq!(generator.sample_rate, NULL_SAMPLE_RATE);
+        // The first beat has been played and samples pulled past it
+        let played = generator.samples_per_beat() - generator.until_beat;
+        assert!(played > 0.0, "no samples pulled");
+        assert!(generator.click.is_some());
+    }
+
+    #[test]
+    fn accent_falls_on_the_first_beat_of_each_bar() {
+        let backend = CaptureBackend::new(48_000, 5);
+        let beats = capture(&backend, tempo(180.0, 3));
+        assert_eq!(
+            beats.iter().map(|&(_, beat)| beat).collect::<Vec<_>>(),
+            [0, 1, 2].repeat(5)
+        );
+
+        // The first sample after a beat starts the click's sine
+        let samples = backend.samples.lock().unwrap();
+        let first = |hz: f32| (TAU * hz / 48_000.0).sin() * (-CLICK_DECAY / 48_000.0).exp();
+        for (offset, beat) in beats {
+            let hz = if beat == 0 { ACCENT_HZ } else { CLICK_HZ };
+            assert!(
+                (samples[offset + 1] - first(hz)).abs() < 1e-6,
+                "beat at {offset}"
+            );
+        }
+    }
+
+    #[test]
+    fn time_signature_change_restarts_the_bar() {
+        let mut generator = ClickGenerator::new(tempo(120.0, 4), 1.0);
+        generator.set_sample_rate(1_000);
+        let mut beats = Vec::new();
+        let mut buffer = vec![0.0; 1_100];
+        generator.fill(&mut buffer, |_, beat| beats.push(beat));
+        generator.set_tempo(tempo(120.0, 3));
+        generator.fill(&mut buffer, |_, beat| beats.push(beat));
+        assert_eq!(beats, [0, 1, 2, 0, 1]);
+    }
+
+    fn manifest(page_order: Option<Vec<PageRef>>) -> ScoreManifest {
+        let pdf = |filename: &str| PdfEntry {
+            filename: filename.into(),
+            title: filename.into(),
+            page_count: 3,
+            sha256: String::new(),
+        };
+        ScoreManifest {
+            format_version: "0.1".into(),
+            pdfs: vec![pdf("a.pdf"), pdf("b.pdf")],
+            title: None,
+            composer: None,
+            tags: Vec::new(),
+            display: Default::default(),
+            created_at: None,
+            modified_at: None,
+            pdf_metadata: None,
+            page_order,
+            split_points: Default::default(),
+            sources: Vec::new(),
+            audio: Vec::new(),
+            tempo: None,
+        }
+    }
+
+    fn change(at: TempoAnchor, bpm: Option<f64>, beats: Option<u32>) -> TempoChange {
+        TempoChange {
+            at,
+            bpm,
+            time_signature: beats.map(|beats| TimeSignature { beats, unit: 4 }),
+            label: None,
+        }
+    }
+
+    fn page(pdf: &str, page: u32) -> TempoAnchor {
+        TempoAnchor::Page {
+            pdf: pdf.into(),
+            page,
+        }
+    }
+
+    #[test]
+    fn tempo_changes_apply_along_the_page_sequence() {
+        let map = TempoMap {
+            default: tempo(100.0, 4),
+            changes: vec![
+                change(page("a.pdf", 1), Some(80.0), None),
+                change(
+                    TempoAnchor::Bookmark {
+                        name: "Trio".into(),
+                    },
+                    None,
+                    Some(3),
+                ),
+                change(page("b.pdf", 2), Some(140.0), None),
+            ],
+        };
+        let bookmarks = HashMap::from([("Trio".to_string(), ("b.pdf".to_string(), 0))]);
+        let manifest = manifest(None);
+        let at = |pdf: &str, page: u32| tempo_at(&map, &manifest, &bookmarks, pdf, page);
+
+        assert_eq!(at("a.pdf", 0), tempo(100.0, 4));
+        assert_eq!(at("a.pdf", 2), tempo(80.0, 4));
+        // The bookmark keeps the tempo from before and only changes the metre
+        assert_eq!(at("b.pdf", 1), tempo(80.0, 3));
+        assert_eq!(at("b.pdf", 2), tempo(140.0, 3));
+    }
+
+    #[test]
+    fn tempo_follows_a_reordered_sequence() {
+        let map = TempoMap {
+            default: tempo(100.0, 4),
+            changes: vec![
+                change(page("a.pdf", 1), Some(80.0), None),
+                change(page("b.pdf", 0), Some(60.0), Some(2)),
+            ],
+        };
+        let bookmarks = HashMap::new();
+        // b.pdf page 0 comes first; a.pdf page 1 is left out
+        let order = vec![
+            PageRef::new("b.pdf".into(), 0),
+            PageRef::new("a.pdf".into(), 0),
+            PageRef::new("a.pdf".into(), 2),
+        ];
+        let manifest = manifest(Some(order));
+        let at = |pdf: &str, page: u32| tempo_at(&map, &manifest, &bookmarks, pdf, page);
+
+        assert_eq!(at("a.pdf", 2), tempo(60.0, 2));
+        // Pages outside the sequence only get their own changes
+        assert_eq!(at("a.pdf", 1), tempo(80.0, 4));
+    }
+
+    #[test]
+    fn bookmarks_resolve_along_the_page_sequence() {
+        let bookmarked = |names: &[&str]| PageNotes {
+            bookmarks: names.iter().map(|name| name.to_string()).collect(),
+            ..Default::default()
+        };
+        let mut notes = NotesData::default();
+        notes.pages.insert(
+            "a.pdf".into(),
+            [(0, bookmarked(&["Coda"])), (1, bookmarked(&["Trio"]))].into(),
+        );
+        notes.pages.insert(
+            "b.pdf".into(),
+            [
+                (0, bookmarked(&["Coda", "Trio"])),
+                (2, bookmarked(&["Da capo"])),
+            ]
+            .into(),
+        );
+
+        let unordered = bookmark_pages(&notes, &manifest(None));
+        assert_eq!(unordered["Coda"], ("a.pdf".to_string(), 0));
+
+        // b.pdf page 0 is played first; b.pdf page 2 is left out
+        let order = vec![
+            PageRef::new("b.pdf".into(), 0),
+            PageRef::new("a.pdf".into(), 0),
+            PageRef::new("a.pdf".into(), 1),
+        ];
+        let reordered = bookmark_pages(&notes, &manifest(Some(order)));
+        assert_eq!(reordered["Coda"], ("b.pdf".to_string(), 0));
+        assert_eq!(reordered["Trio"], ("b.pdf".to_string(), 0));
+        assert_eq!(reordered["Da capo"], ("b.pdf".to_string(), 2));
+    }
+}
@@ -0,0 +1,480 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tauri::{AppHandle, State};
+
+use crate::notes::NotesStore;
+use crate::olscore::{read_manifest_at, score_path, PageRef};
+
+/// Where a mark sits: a PDF page and the vertical position on it (0 = top).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NavAnchor {
+    pub pdf: String,
+    pub page: u32,
+    #[serde(default)]
+    pub y: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum NavMarkKind {
+    RepeatStart,
+    /// Jumps back to the matching `RepeatStart` (or the previous repeat end
+    /// or the beginning) until the section has been played `times` times.
+    RepeatEnd {
+        #[serde(default = "default_times")]
+        times: u32,
+    },
+    /// 1st/2nd... ending running up to `end`; skipped on other passes.
+    Volta {
+        endings: Vec<u32>,
+        end: NavAnchor,
+    },
+    Segno,
+    Coda,
+    Fine,
+    /// D.C., back to the beginning.
+    DaCapo,
+    /// D.S., back to the segno.
+    DalSegno,
+    /// Jumps to the coda once a D.C. or D.S. has been taken.
+    ToCoda,
+}
+
+fn default_times() -> u32 {
+    2
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NavMark {
+    pub id: String,
+    #[serde(default = "crate::olscore::default_layer_id")]
+    pub layer: String,
+    pub anchor: NavAnchor,
+    #[serde(flatten)]
+    pub kind: NavMarkKind,
+    /// Visits on which the mark acts, counting from 1. Empty uses the
+    /// kind's default, e.g. D.C. only on the first visit.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub passes: Vec<u32>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct NavigationOptions {
+    /// Take repeats again after a D.C. or D.S. (off by tradition).
+    #[serde(default)]
+    pub repeats_after_jump: bool,
+}
+
+/// One page in playing order. `page` is the PDF's own page number,
+/// `score_page` its position in the score's page sequence.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PerformancePage {
+    pub pdf: String,
+    pub page: u32,
+    pub score_page: u32,
+}
+
+/// Guards against mark setups that never reach the end.
+const MAX_JUMPS: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+struct Position {
+    score_page: u32,
+    y: f64,
+}
+
+struct Layout<'a> {
+    sequence: &'a [PageRef],
+    /// Where each page first appears in the sequence.
+    positions: HashMap<(&'a str, u32), u32>,
+    total: u32,
+}
+
+impl<'a> Layout<'a> {
+    fn new(sequence: &'a [PageRef]) -> Self {
+        let mut positions = HashMap::new();
+        for (index, page) in sequence.iter().enumerate() {
+            positions
+                .entry((page.pdf.as_str(), page.page))
+                .or_insert(index as u32);
+        }
+        Self {
+            sequence,
+            positions,
+            total: sequence.len() as u32,
+        }
+    }
+
+    fn position(&self, anchor: &NavAnchor) -> Option<Position> {
+        let score_page = self.positions.get(&(anchor.pdf.as_str(), anchor.page))?;
+        Some(Position {
+            score_page: *score_page,
+            y: anchor.y,
+        })
+    }
+
+    fn page(&self, score_page: u32) -> Option<PerformancePage> {
+        let page = self.sequence.get(score_page as usize)?;
+        Some(PerformancePage {
+            pdf: page.pdf.clone(),
+            page: page.page,
+            score_page,
+        })
+    }
+}
+
+/// Plays through the marks and returns the positions in the page sequence
+/// in the order they are turned to. Marks on pages left out of the
+/// sequence are ignored; on repeated pages they act on the first copy.
+pub fn performance_order(
+    sequence: &[PageRef],
+    marks: &[NavMark],
+    options: &NavigationOptions,
+) -> Result<Vec<u32>, String> {
+    let layout = Layout::new(sequence);
+    let mut marks: Vec<(Position, &NavMark)> = marks
+        .iter()
+        .filter_map(|m| Some((layout.position(&m.anchor)?, m)))
+        .collect();
+    marks.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+
+    let last_ending = marks
+        .iter()
+        .filter_map(|(_, m)| match &m.kind {
+            NavMarkKind::Volta { endings, .. } => endings.iter().max().copied(),
+            _ => None,
+        })
+        .max()
+        .unwrap_or(1);
+
+    let mut order: Vec<u32> = Vec::new();
+    let emit_to = |from: u32, to: u32, order: &mut Vec<u32>| {
+        for page in (from..=to).take_while(|page| *page < layout.total) {
+            if order.last() != Some(&page) {
+                order.push(page);
+            }
+        }
+    };
+
+    let mut visits: HashMap<&str, u32> = HashMap::new();
+    let mut current_page = 0;
+    let mut next = 0;
+    let mut repeat_pass = 1;
+    // Pass of the section that just ended, for its 2nd and later endings
+    let mut closing_pass = None;
+    // Set once a D.C. or D.S. has been taken
+    let mut jumped = false;
+    let mut jumps = 0;
+
+    while next < marks.len() {
+        let index = next;
+        let (position, mark) = marks[index];
+        next += 1;
+
+        let visit = visits.entry(mark.id.as_str()).or_default();
+        *visit += 1;
+        let visit = *visit;
+        let acts = |default: bool| {
+            if mark.passes.is_empty() {
+                default
+            } else {
+                mark.passes.contains(&visit)
+            }
+        };
+
+        let target = match &mark.kind {
+            NavMarkKind::RepeatStart => {
+                repeat_pass = 1;
+                closing_pass = None;
+                None
+            }
+            NavMarkKind::RepeatEnd { times } => {
+                let repeats = !jumped || options.repeats_after_jump;
+                closing_pass = None;
+                if repeats && acts(repeat_pass < *times) {
+                    repeat_pass += 1;
+                    // Resume after the start mark so it doesn't reset the pass
+                    let start = marks[..index].iter().rposition(|(_, m)| {
+                        matches!(
+                            m.kind,
+                            NavMarkKind::RepeatStart | NavMarkKind::RepeatEnd { .. }
+                        )
+                    });
+                    Some(match start {
+                        Some(i) => (marks[i].0.score_page, i + 1),
+                        None => (0, 0),
+                    })
+                } else {
+                    // Played through, so a following section starts afresh
+                    closing_pass = Some(repeat_pass);
+                    repeat_pass = 1;
+                    None
+                }
+            }
+            NavMarkKind::Volta { endings, end } => {
+                // A 1st ending opens a new section; later ones close the last
+                if endings.contains(&1) {
+                    closing_pass = None;
+                }
+                let pass = if jumped && !options.repeats_after_jump {
+                    last_ending
+                } else {
+                    closing_pass.unwrap_or(repeat_pass)
+                };
+                match layout.position(end) {
+                    Some(end) if acts(!endings.contains(&pass)) => {
+                        let i = marks.partition_point(|(p, _)| *p < end);
+                        Some((end.score_page, i.max(index + 1)))
+                    }
+                    _ => None,
+                }
+            }
+            NavMarkKind::Segno | NavMarkKind::Coda => None,
+            NavMarkKind::Fine => {
+                if acts(jumped) {
+                    emit_to(current_page, position.score_page, &mut order);
+                    return Ok(order);
+                }
+                None
+            }
+            NavMarkKind::DaCapo if acts(visit == 1) => {
+                jumped = true;
+                Some((0, 0))
+            }
+            NavMarkKind::DalSegno if acts(visit == 1) => {
+                jumped = true;
+                let segno = marks
+                    .iter()
+                    .position(|(_, m)| m.kind == NavMarkKind::Segno)
+                    .ok_or("D.S. without a segno")?;
+                Some((marks[segno].0.score_page, segno))
+            }
+            NavMarkKind::ToCoda if acts(jumped) => {
+                let coda = marks
+                    .iter()
+                    .position(|(_, m)| m.kind == NavMarkKind::Coda)
+                    .ok_or("To Coda without a coda")?;
+                Some((marks[coda].0.score_page, coda))
+            }
+            NavMarkKind::DaCapo | NavMarkKind::DalSegno | NavMarkKind::ToCoda => None,
+        };
+
+        if let Some((page, mark_index)) = target {
+            jumps += 1;
+            if jumps > MAX_JUMPS {
+                return Err("Navigation marks loop without reaching the end".into());
+            }
+            emit_to(current_page, position.score_page, &mut order);
+            current_page = page;
+            next = mark_index;
+        }
+    }
+    emit_to(current_page, layout.total.saturating_sub(1), &mut order);
+    Ok(order)
+}
+
+#[tauri::command]
+pub fn get_performance_order(
+    app: AppHandle,
+    store: State<'_, NotesStore>,
+    score_id: String,
+    options: Option<NavigationOptions>,
+) -> Result<Vec<PerformancePage>, String> {
+    let manifest = read_manifest_at(&score_path(&app, &score_id)?)?;
+    let notes = store.snapshot(&app, &score_id)?.notes;
+    let sequence = manifest.page_sequence();
+    let layout = Layout::new(&sequence);
+    let order = performance_order(&sequence, &notes.navigation, &options.unwrap_or_default())?;
+    Ok(order.into_iter().filter_map(|p| layout.page(p)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::olscore::{new_score_manifest, write_new_score, ScoreManifest};
+    use crate::render::count_pages;
+
+    fn sequence(pages: u32) -> Vec<PageRef> {
+        (0..pages)
+            .map(|p| PageRef::new("score.pdf".into(), p))
+            .collect()
+    }
+
+    fn anchor(page: u32, y: f64) -> NavAnchor {
+        NavAnchor {
+            pdf: "score.pdf".into(),
+            page,
+            y,
+        }
+    }
+
+    fn mark(id: &str, page: u32, y: f64, kind: NavMarkKind) -> NavMark {
+        NavMark {
+            id: id.into(),
+            layer: "default".into(),
+            anchor: anchor(page, y),
+            kind,
+            passes: Vec::new(),
+        }
+    }
+
+    fn repeat_end(id: &str, page: u32) -> NavMark {
+        mark(id, page, 1.0, NavMarkKind::RepeatEnd { times: 2 })
+    }
+
+    fn volta(id: &str, ending: u32, page: u32) -> NavMark {
+        let kind = NavMarkKind::Volta {
+            endings: vec![ending],
+            end: anchor(page, 1.0),
+        };
+        mark(id, page, 0.0, kind)
+    }
+
+    fn order(pages: u32, marks: &[NavMark]) -> Vec<u32> {
+        performance_order(&sequence(pages), marks, &NavigationOptions::default()).unwrap()
+    }
+
+    #[test]
+    fn section_after_a_repeat_is_repeated_without_a_start_mark() {
+        let marks = [repeat_end("a", 1), repeat_end("b", 3)];
+        assert_eq!(order(4, &marks), [0, 1, 0, 1, 2, 3, 1, 2, 3]);
+    }
+
+    #[test]
+    fn voltas_play_each_ending_on_its_pass() {
+        let marks = [
+            mark("start", 0, 0.0, NavMarkKind::RepeatStart),
+            volta("first", 1, 1),
+            repeat_end("end", 1),
+            volta("second", 2, 2),
+            mark("start2", 3, 0.0, NavMarkKind::RepeatStart),
+            repeat_end("end2", 4),
+        ];
+        assert_eq!(order(6, &marks), [0, 1, 0, 1, 2, 3, 4, 3, 4, 5]);
+    }
+
+    #[test]
+    fn first_ending_after_a_plain_repeat_is_played() {
+        let first = NavMarkKind::Volta {
+            endings: vec![1],
+            end: anchor(2, 1.0),
+        };
+        let marks = [
+            repeat_end("plain", 0),
+            mark("first", 1, 0.5, first),
+            repeat_end("end", 2),
+            volta("second", 2, 3),
+        ];
+        assert_eq!(order(5, &marks), [0, 1, 2, 0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn da_capo_al_fine_skips_repeats() {
+        let marks = [
+            repeat_end("repeat", 1),
+            mark("fine", 2, 1.0, NavMarkKind::Fine),
+            mark("dc", 3, 1.0, NavMarkKind::DaCapo),
+        ];
+        assert_eq!(order(4, &marks), [0, 1, 0, 1, 2, 3, 0, 1, 2]);
+
+        let options = NavigationOptions {
+            repeats_after_jump: true,
+        };
+        assert_eq!(
+            performance_order(&sequence(4), &marks, &options).unwrap(),
+            [0, 1, 0, 1, 2, 3, 0, 1, 0, 1, 2]
+        );
+    }
+
+    #[test]
+    fn dal_segno_al_coda() {
+        let marks = [
+            mark("segno", 1, 0.0, NavMarkKind::Segno),
+            mark("to-coda", 2, 1.0, NavMarkKind::ToCoda),
+            mark("ds", 3, 1.0, NavMarkKind::DalSegno),
+            mark("coda", 4, 0.0, NavMarkKind::Coda),
+        ];
+        assert_eq!(order(6, &marks), [0, 1, 2, 3, 1, 2, 4, 5]);
+    }
+
+    #[test]
+    fn dal_segno_without_segno_fails() {
+        let marks = [mark("ds", 0, 1.0, NavMarkKind::DalSegno)];
+        assert!(performance_order(&sequence(1), &marks, &NavigationOptions::default()).is_err());
+    }
+
+    #[test]
+    fn order_follows_the_page_sequence() {
+        let page = |p| PageRef::new("score.pdf".into(), p);
+        // Page 2 is hidden, page 3 shown twice and page 1 moved to the end
+        let sequence = [page(0), page(3), page(3), page(1)];
+        let marks = [
+            repeat_end("hidden", 2),
+            repeat_end("twice", 3),
+            mark("fine", 1, 1.0, NavMarkKind::Fine),
+        ];
+        let order = performance_order(&sequence, &marks, &NavigationOptions::default()).unwrap();
+        assert_eq!(order, [0, 1, 0, 1, 2, 3]);
+
+        let layout = Layout::new(&sequence);
+        let pages: Vec<_> = order.iter().filter_map(|p| layout.page(*p)).collect();
+        assert_eq!(pages[1].page, 3);
+        assert_eq!(pages[5].page, 1);
+        assert_eq!(pages[5].score_page, 3);
+    }
+
+    #[test]
+    fn empty_sequence_has_no_pages() {
+        let marks = [repeat_end("a", 0)];
+        assert_eq!(order(0, &marks), Vec::<u32>::new());
+    }
+
+    /// The manifest a real import writes, read back from the archive.
+    fn imported_manifest(page_count: u32) -> ScoreManifest {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("score.olscore");
+        let manifest = new_score_manifest("score.pdf", "abc", page_count);
+        write_new_score(&path, &manifest, b"%PDF-1.7").unwrap();
+        read_manifest_at(&path).unwrap()
+    }
+
+    #[test]
+    fn imported_score_plays_every_page() {
+        let manifest = imported_manifest(3);
+        let marks = [repeat_end("a", 1)];
+        let order = performance_order(
+            &manifest.page_sequence(),
+            &marks,
+            &NavigationOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(order, [0, 1, 0, 1, 2]);
+    }
+
+    /// Counts the pages with pdfium, as `import_pdf` does; run with
+    /// `--ignored` where the library is installed.
+    #[test]
+    #[ignore = "needs pdfium"]
+    fn imported_pdf_plays_every_page() {
+        use pdfium_render::prelude::*;
+
+        let pdfium = Pdfium::new(Pdfium::bind_to_system_library().unwrap());
+        let mut document = pdfium.create_new_pdf().unwrap();
+        for _ in 0..3 {
+            document
+                .pages_mut()
+                .create_page_at_end(PdfPagePaperSize::a4())
+                .unwrap();
+        }
+        let bytes = document.save_to_bytes().unwrap();
+
+        let manifest = imported_manifest(count_pages(&pdfium, &bytes).unwrap());
+        let order = performance_order(
+            &manifest.page_sequence(),
+            &[repeat_end("a", 1)],
+            &NavigationOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(order, [0, 1, 0, 1, 2]);
+    }
+}
@@ -0,0 +1,708 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tauri::{AppHandle, Emitter, State};
+
+use crate::history;
+use crate::navigation::NavMark;
+use crate::olscore::{read_notes_at, score_path, NotesData, RepeatButton, Stroke, TextNote};
+
+/// Quiet period after the last change before notes are written out.
+const FLUSH_DELAY: Duration = Duration::from_secs(1);
+/// Upper bound on how long continuous editing can hold back a write.
+const MAX_FLUSH_DELAY: Duration = Duration::from_secs(10);
+
+/// An annotation together with where it lives in the notes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Annotation {
+    RepeatButton(RepeatButton),
+    NavMark(NavMark),
+    Stroke {
+        pdf: String,
+        page: u32,
+        #[serde(flatten)]
+        stroke: Stroke,
+    },
+    TextNote {
+        pdf: String,
+        page: u32,
+        #[serde(flatten)]
+        note: TextNote,
+    },
+}
+
+impl Annotation {
+    fn id(&self) -> &str {
+        match self {
+            Annotation::RepeatButton(b) => &b.id,
+            Annotation::NavMark(m) => &m.id,
+            Annotation::Stroke { stroke, .. } => &stroke.id,
+            Annotation::TextNote { note, .. } => &note.id,
+        }
+    }
+
+    fn layer(&self) -> &str {
+        match self {
+            Annotation::RepeatButton(b) => &b.layer,
+            Annotation::NavMark(m) => &m.layer,
+            Annotation::Stroke { stroke, .. } => &stroke.layer,
+            Annotation::TextNote { note, .. } => &note.layer,
+        }
+    }
+}
+
+fn check_editable(notes: &NotesData, layer_id: &str) -> Result<(), String> {
+    match notes.layer(layer_id) {
+        Some(layer) if layer.locked => Err(format!("Layer is locked: {}", layer.name)),
+        Some(_) => Ok(()),
+        None => Err(format!("Layer not found: {layer_id}")),
+    }
+}
+
+fn insert(notes: &mut NotesData, annotation: Annotation) {
+    match annotation {
+        Annotation::RepeatButton(b) => notes.repeat_buttons.push(b),
+        Annotation::NavMark(m) => notes.navigation.push(m),
+        Annotation::Stroke { pdf, page, stroke } => notes
+            .pages
+            .entry(pdf)
+            .or_default()
+            .entry(page)
+            .or_default()
+            .strokes
+            .push(stroke),
+        Annotation::TextNote { pdf, page, note } => notes
+            .pages
+            .entry(pdf)
+            .or_default()
+            .entry(page)
+            .or_default()
+            .text_notes
+            .push(note),
+    }
+}
+
+/// Layer of the annotation with `id`, if it exists.
+fn layer_of<'a>(notes: &'a NotesData, id: &str) -> Option<&'a str> {
+    if let Some(b) = notes.repeat_buttons.iter().find(|b| b.id == id) {
+        return Some(&b.layer);
+    }
+    if let Some(m) = notes.navigation.iter().find(|m| m.id == id) {
+        return Some(&m.layer);
+    }
+    notes
+        .pages
+        .values()
+        .flat_map(|pdf| pdf.values())
+        .find_map(|page| {
+            let stroke = page.strokes.iter().find(|s| s.id == id).map(|s| &s.layer);
+            stroke.or_else(|| {
+                page.text_notes
+                    .iter()
+                    .find(|n| n.id == id)
+                    .map(|n| &n.layer)
+            })
+        })
+        .map(String::as_str)
+}
+
+fn remove(notes: &mut NotesData, id: &str) {
+    notes.repeat_buttons.retain(|b| b.id != id);
+    notes.navigation.retain(|m| m.id != id);
+    for page in notes.pages.values_mut().flat_map(|pdf| pdf.values_mut()) {
+        page.strokes.retain(|s| s.id != id);
+        page.text_notes.retain(|n| n.id != id);
+    }
+}
+
+/// Swaps in `annotation` where its previous version sits, keeping the
+/// drawing order. Returns it back if it moved to another page.
+fn replace_in_place(notes: &mut NotesData, annotation: Annotation) -> Option<Annotation> {
+    match annotation {
+        Annotation::RepeatButton(b) => {
+            if let Some(slot) = notes.repeat_buttons.iter_mut().find(|x| x.id == b.id) {
+                *slot = b;
+                return None;
+            }
+            Some(Annotation::RepeatButton(b))
+        }
+        Annotation::NavMark(m) => {
+            if let Some(slot) = notes.navigation.iter_mut().find(|x| x.id == m.id) {
+                *slot = m;
+                return None;
+            }
+            Some(Annotation::NavMark(m))
+        }
+        Annotation::Stroke { pdf, page, stroke } => {
+            let slot = notes
+                .pages
+                .get_mut(&pdf)
+                .and_then(|p| p.get_mut(&page))
+                .and_then(|p| p.strokes.iter_mut().find(|x| x.id == stroke.id));
+            match slot {
+                Some(slot) => {
+                    *slot = stroke;
+                    None
+                }
+                None => Some(Annotation::Stroke { pdf, page, stroke }),
+            }
+        }
+        Annotation::TextNote { pdf, page, note } => {
+            let slot = notes
+                .pages
+                .get_mut(&pdf)
+                .and_then(|p| p.get_mut(&page))
+                .and_then(|p| p.text_notes.iter_mut().find(|x| x.id == note.id));
+            match slot {
+                Some(slot) => {
+                    *slot = note;
+                    None
+                }
+                None => Some(Annotation::TextNote { pdf, page, note }),
+            }
+        }
+    }
+}
+
+fn add(notes: &mut NotesData, annotation: Annotation) -> Result<(), String> {
+    check_editable(notes, annotation.layer())?;
+    if layer_of(notes, annotation.id()).is_some() {
+        return Err(format!("Annotation already exists: {}", annotation.id()));
+    }
+    insert(notes, annotation);
+    Ok(())
+}
+
+fn update(notes: &mut NotesData, annotation: Annotation) -> Result<(), String> {
+    let layer = layer_of(notes, annotation.id())
+        .ok_or_else(|| format!("Annotation not found: {}", annotation.id()))?;
+    check_editable(notes, layer)?;
+    check_editable(notes, annotation.layer())?;
+    if let Some(moved) = replace_in_place(notes, annotation) {
+        remove(notes, moved.id());
+        insert(notes, moved);
+    }
+    Ok(())
+}
+
+fn delete(notes: &mut NotesData, id: &str) -> Result<(), String> {
+    let layer = layer_of(notes, id).ok_or_else(|| format!("Annotation not found: {id}"))?;
+    check_editable(notes, layer)?;
+    remove(notes, id);
+    Ok(())
+}
+
+/// Notes of one score as seen by the frontend. `revision` goes up with
+/// every change, so a UI holding an older one knows it is stale.
+#[derive(Debug, Clone, Serialize)]
+pub struct NotesSnapshot {
+    pub revision: u64,
+    pub notes: NotesData,
+}
+
+struct OpenNotes {
+    path: PathBuf,
+    notes: NotesData,
+    revision: u64,
+    dirty: bool,
+    /// Set once the score is closed; holders of a stale handle reopen it.
+    closed: bool,
+}
+
+impl OpenNotes {
+    /// Applies `f` to a copy of the notes and keeps the result if it
+    /// succeeds, bumping the revision. Fails without changes if
+    /// `expected_revision` is stale.
+    fn apply<T>(
+        &mut self,
+        expected_revision: Option<u64>,
+        f: impl FnOnce(&mut NotesData) -> Result<T, String>,
+    ) -> Result<(T, u64), String> {
+        if let Some(expected) = expected_revision {
+            if expected != self.revision {
+                return Err(format!(
+                    "Notes changed: revision {} is now {}",
+                    expected, self.revision
+                ));
+            }
+        }
+        let mut notes = self.notes.clone();
+        let value = f(&mut notes)?;
+        self.notes = notes;
+        self.revision += 1;
+        self.dirty = true;
+        Ok((value, self.revision))
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        if self.dirty {
+            history::commit_notes(&self.path, &self.notes)?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+}
+
+type Handle = Arc<Mutex<OpenNotes>>;
+/// Each score has its own lock, so writing one archive does not hold up
+/// edits to the others.
+type OpenMap = HashMap<String, Handle>;
+
+#[derive(Debug, Clone, Serialize)]
+struct FlushError {
+    score_id: String,
+    error: String,
+}
+
+/// In-memory notes of open scores. Changes are applied here and written
+/// to the archive after a short debounce, on close and on exit, so each
+/// flush becomes one undo step.
+#[derive(Default)]
+pub struct NotesStore {
+    open: Arc<Mutex<OpenMap>>,
+    flusher: Mutex<Option<mpsc::Sender<String>>>,
+}
+
+impl NotesStore {
+    fn with_open<T>(
+        &self,
+        app: &AppHandle,
+        score_id: &str,
+        f: impl FnOnce(&mut OpenNotes) -> Result<T, String>,
+    ) -> Result<T, String> {
+        loop {
+            let handle = {
+                let mut open = self.open.lock().map_err(|e| e.to_string())?;
+                match open.get(score_id) {
+                    Some(handle) => handle.clone(),
+                    None => {
+                        let path = score_path(app, score_id)?;
+                        let notes = read_notes_at(&path)?;
+                        let handle = Arc::new(Mutex::new(OpenNotes {
+                            path,
+                            notes,
+                            revision: 1,
+                            dirty: false,
+                            closed: false,
+                        }));
+                        open.insert(score_id.to_string(), handle.clone());
+                        handle
+                    }
+                }
+            };
+            let mut entry = handle.lock().map_err(|e| e.to_string())?;
+            if !entry.closed {
+                return f(&mut entry);
+            }
+        }
+    }
+
+    /// Handles of all open scores, so they can be locked one at a time
+    /// without holding the store.
+    fn handles(&self) -> Result<Vec<(String, Handle)>, String> {
+        let open = self.open.lock().map_err(|e| e.to_string())?;
+        Ok(open
+            .iter()
+            .map(|(score_id, handle)| (score_id.clone(), handle.clone()))
+            .collect())
+    }
+
+    pub fn snapshot(&self, app: &AppHandle, score_id: &str) -> Result<NotesSnapshot, String> {
+        self.with_open(app, score_id, |entry| {
+            Ok(NotesSnapshot {
+                revision: entry.revision,
+                notes: entry.notes.clone(),
+            })
+        })
+    }
+
+    /// Applies `f` to the cached notes, see `OpenNotes::apply`.
+    pub fn modify<T>(
+        &self,
+        app: &AppHandle,
+        score_id: &str,
+        expected_revision: Option<u64>,
+        f: impl FnOnce(&mut NotesData) -> Result<T, String>,
+    ) -> Result<(T, u64), String> {
+        let result = self.with_open(app, score_id, |entry| entry.apply(expected_revision, f))?;
+        self.schedule_flush(app, score_id);
+        Ok(result)
+    }
+
+    /// Runs `f` with the archive path and the cached notes, which may hold
+    /// unflushed changes, and caches the notes it returns. `f` must write
+    /// both, so operations like undo record pending edits in the same
+    /// archive rewrite instead of flushing separately first.
+    pub fn replace_from_archive(
+        &self,
+        app: &AppHandle,
+        score_id: &str,
+        f: impl FnOnce(&Path, &NotesData) -> Result<NotesData, String>,
+    ) -> Result<NotesSnapshot, String> {
+        self.with_open(app, score_id, |entry| {
+            entry.notes = f(&entry.path, &entry.notes)?;
+            entry.dirty = false;
+            entry.revision += 1;
+            Ok(NotesSnapshot {
+                revision: entry.revision,
+                notes: entry.notes.clone(),
+            })
+        })
+    }
+
+    pub fn flush(&self, app: &AppHandle, score_id: &str) -> Result<(), String> {
+        self.with_open(app, score_id, |entry| entry.flush())
+    }
+
+    fn schedule_flush(&self, app: &AppHandle, score_id: &str) {
+        let Ok(mut flusher) = self.flusher.lock() else {
+            return;
+        };
+        let tx = flusher.get_or_insert_with(|| spawn_flusher(app.clone(), self.open.clone()));
+        let _ = tx.send(score_id.to_string());
+    }
+
+    /// Writes out and forgets a score's notes.
+    pub fn close(&self, score_id: &str) -> Result<(), String> {
+        let handle = self
+            .open
+            .lock()
+            .map_err(|e| e.to_string())?
+            .remove(score_id);
+        if let Some(handle) = handle {
+            let mut entry = handle.lock().map_err(|e| e.to_string())?;
+            entry.closed = true;
+            entry.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Writes out all pending changes, e.g. on exit. Failures are also
+    /// emitted as `notes-flush-error`, one per score.
+    pub fn flush_all(&self, app: &AppHandle) -> Result<(), String> {
+        let mut errors = Vec::new();
+        for (score_id, handle) in self.handles()? {
+            let result = handle
+                .lock()
+                .map_err(|e| e.to_string())
+                .and_then(|mut entry| entry.flush());
+            if let Err(error) = result {
+                errors.push(format!("{score_id}: {error}"));
+                let _ = app.emit("notes-flush-error", FlushError { score_id, error });
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join("; "))
+        }
+    }
+
+    /// Points open scores under `old_dir` at their copies in `new_dir`
+    /// after a library move. They are marked dirty so changes made while
+    /// the files were being copied reach the new archives too.
+    pub fn relocate(&self, old_dir: &Path, new_dir: &Path) -> Result<(), String> {
+        for (_, handle) in self.handles()? {
+            let mut entry = handle.lock().map_err(|e| e.to_string())?;
+            if let Ok(relative) = entry.path.strip_prefix(old_dir) {
+                entry.path = new_dir.join(relative);
+                entry.dirty = true;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes dirty notes once they have been quiet for `FLUSH_DELAY`.
+/// Failures are emitted as `notes-flush-error` and retried on the next
+/// change or on close.
+fn spawn_flusher(app: AppHandle, open: Arc<Mutex<OpenMap>>) -> mpsc::Sender<String> {
+    let (tx, rx) = mpsc::channel::<String>();
+    std::thread::spawn(move || {
+        // Score id -> (first unflushed change, deadline)
+        let mut pending: HashMap<String, (Instant, Instant)> = HashMap::new();
+        loop {
+            let message = match pending.values().map(|(_, deadline)| *deadline).min() {
+                Some(deadline) => {
+                    rx.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                }
+                None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+
+            let now = Instant::now();
+            match message {
+                Ok(score_id) => {
+                    let (first, _) = pending.get(&score_id).copied().unwrap_or((now, now));
+                    let deadline = (now + FLUSH_DELAY).min(first + MAX_FLUSH_DELAY);
+                    pending.insert(score_id, (first, deadline));
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+
+            let due: Vec<String> = pending
+                .iter()
+                .filter(|(_, (_, deadline))| *deadline <= now)
+                .map(|(score_id, _)| score_id.clone())
+                .collect();
+            for score_id in due {
+                pending.remove(&score_id);
+                // Only the score's own lock is held while its archive is written
+                let handle = match open.lock() {
+                    Ok(open) => open.get(&score_id).cloned(),
+                    Err(_) => return,
+                };
+                let Some(handle) = handle else {
+                    continue;
+                };
+                let Ok(mut entry) = handle.lock() else {
+                    continue;
+                };
+                if let Err(error) = entry.flush() {
+                    let _ = app.emit("notes-flush-error", FlushError { score_id, error });
+                }
+            }
+        }
+    });
+    tx
+}
+
+#[tauri::command]
+pub fn get_notes(
+    app: AppHandle,
+    store: State<'_, NotesStore>,
+    score_id: String,
+) -> Result<NotesSnapshot, String> {
+    store.snapshot(&app, &score_id)
+}
+
+/// Returns the new revision.
+#[tauri::command]
+pub fn add_annotation(
+    app: AppHandle,
+    store: State<'_, NotesStore>,
+    score_id: String,
+    annotation: Annotation,
+    expected_revision: Option<u64>,
+) -> Result<u64, String> {
+    store
+        .modify(&app, &score_id, expected_revision, |notes| {
+            add(notes, annotation)
+        })
+        .map(|(_, revision)| revision)
+}
+
+/// Replaces the annotation with the same ID; it may move to another page
+/// or layer. Returns the new revision.
+#[tauri::command]
+pub fn update_annotation(
+    app: AppHandle,
+    store: State<'_, NotesStore>,
+    score_id: String,
+    annotation: Annotation,
+    expected_revision: Option<u64>,
+) -> Result<u64, String> {
+    store
+        .modify(&app, &score_id, expected_revision, |notes| {
+            update(notes, annotation)
+        })
+        .map(|(_, revision)| revision)
+}
+
+/// Replaces several annotations as one change: all of them or none.
+/// Returns the new revision.
+#[tauri::command]
+pub fn update_annotations(
+    app: AppHandle,
+    store: State<'_, NotesStore>,
+    score_id: String,
+    annotations: Vec<Annotation>,
+    expected_revision: Option<u64>,
+) -> Result<u64, String> {
+    store
+        .modify(&app, &score_id, expected_revision, |notes| {
+            annotations
+                .into_iter()
+                .try_for_each(|annotation| update(notes, annotation))
+        })
+        .map(|(_, revision)| revision)
+}
+
+/// Returns the new revision.
+#[tauri::command]
+pub fn delete_annotation(
+    app: AppHandle,
+    store: State<'_, NotesStore>,
+    score_id: String,
+    id: String,
+    expected_revision: Option<u64>,
+) -> Result<u64, String> {
+    store
+        .modify(&app, &score_id, expected_revision, |notes| {
+            delete(notes, &id)
+        })
+        .map(|(_, revision)| revision)
+}
+
+#[tauri::command]
+pub fn flush_notes(
+    app: AppHandle,
+    store: State<'_, NotesStore>,
+    score_id: String,
+) -> Result<(), String> {
+    store.flush(&app, &score_id)
+}
+
+#[tauri::command]
+pub fn close_notes(store: State<'_, NotesStore>, score_id: String) -> Result<(), String> {
+    store.close(&score_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::olscore::{notes_json, replace_archive_entries};
+    use serde_json::json;
+    use std::io::Write;
+
+    fn open_notes(dir: &Path) -> OpenNotes {
+        let path = dir.join("score.olscore");
+        let mut writer = zip::ZipWriter::new(std::fs::File::create(&path).unwrap());
+        writer
+            .start_file("notes.json", zip::write::SimpleFileOptions::default())
+            .unwrap();
+        writer
+            .write_all(&notes_json(&NotesData::default()).unwrap())
+            .unwrap();
+        writer.finish().unwrap();
+        OpenNotes {
+            notes: read_notes_at(&path).unwrap(),
+            path,
+            revision: 1,
+            dirty: false,
+            closed: false,
+        }
+    }
+
+    fn stroke(id: &str, page: u32) -> Annotation {
+        serde_json::from_value(json!({
+            "type": "stroke", "pdf": "score.pdf", "page": page,
+            "id": id, "points": [], "color": "#000000", "width": 2.0,
+        }))
+        .unwrap()
+    }
+
+    fn repeat_button(id: &str, target_page: u32) -> Annotation {
+        serde_json::from_value(json!({
+            "type": "repeat_button", "id": id, "page": 3, "target_page": target_page,
+            "label": "Repeat", "offset_x": 0.9, "offset_y": 0.9, "size": 40,
+        }))
+        .unwrap()
+    }
+
+    /// Stroke ids by page of `score.pdf`.
+    fn strokes(notes: &NotesData) -> Vec<(u32, &str)> {
+        notes
+            .pages
+            .get("score.pdf")
+            .into_iter()
+            .flatten()
+            .flat_map(|(page, p)| p.strokes.iter().map(|s| (*page, s.id.as_str())))
+            .collect()
+    }
+
+    #[test]
+    fn patches_apply_in_turn_and_bump_the_revision() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut entry = open_notes(dir.path());
+
+        let (_, revision) = entry.apply(Some(1), |n| add(n, stroke("s1", 1))).unwrap();
+        assert_eq!(revision, 2);
+        assert!(entry.dirty);
+        entry.apply(Some(2), |n| add(n, stroke("s2", 1))).unwrap();
+        assert_eq!(strokes(&entry.notes), [(1, "s1"), (1, "s2")]);
+
+        // Moving to another page; in place otherwise, keeping drawing order
+        entry
+            .apply(Some(3), |n| update(n, stroke("s1", 4)))
+            .unwrap();
+        assert_eq!(strokes(&entry.notes), [(1, "s2"), (4, "s1")]);
+        entry
+            .apply(Some(4), |n| update(n, stroke("s2", 1)))
+            .unwrap();
+        assert_eq!(strokes(&entry.notes), [(1, "s2"), (4, "s1")]);
+
+        let (_, revision) = entry.apply(None, |n| delete(n, "s2")).unwrap();
+        assert_eq!(revision, 6);
+        assert_eq!(strokes(&entry.notes), [(4, "s1")]);
+    }
+
+    #[test]
+    fn stale_revision_is_rejected_without_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut entry = open_notes(dir.path());
+        entry.apply(Some(1), |n| add(n, stroke("s1", 1))).unwrap();
+        entry.dirty = false;
+
+        // A second client still holding revision 1
+        let err = entry.apply(Some(1), |n| delete(n, "s1")).unwrap_err();
+        assert!(err.contains("revision 1 is now 2"), "{err}");
+        assert_eq!(entry.revision, 2);
+        assert!(!entry.dirty);
+        assert_eq!(strokes(&entry.notes), [(1, "s1")]);
+    }
+
+    #[test]
+    fn failed_patch_leaves_the_notes_untouched() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut entry = open_notes(dir.path());
+        entry
+            .apply(None, |n| add(n, repeat_button("r1", 1)))
+            .unwrap();
+
+        let err = entry
+            .apply(None, |n| add(n, repeat_button("r1", 2)))
+            .unwrap_err();
+        assert!(err.contains("already exists"), "{err}");
+        assert!(entry.apply(None, |n| delete(n, "missing")).is_err());
+
+        // A batch fails as a whole, even after an update that succeeded
+        let err = entry
+            .apply(None, |n| {
+                [repeat_button("r1", 5), repeat_button("r2", 5)]
+                    .into_iter()
+                    .try_for_each(|a| update(n, a))
+            })
+            .unwrap_err();
+        assert!(err.contains("not found: r2"), "{err}");
+        assert_eq!(entry.revision, 2);
+        assert_eq!(entry.notes.repeat_buttons[0].target_page, 1);
+
+        entry.notes.layers[0].locked = true;
+        let err = entry.apply(None, |n| add(n, stroke("s1", 1))).unwrap_err();
+        assert!(err.contains("locked"), "{err}");
+        assert!(entry.notes.pages.is_empty());
+    }
+
+    #[test]
+    fn flush_writes_pending_changes_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut entry = open_notes(dir.path());
+        entry.apply(None, |n| add(n, stroke("s1", 1))).unwrap();
+
+        entry.flush().unwrap();
+        assert!(!entry.dirty);
+        let stored = read_notes_at(&entry.path).unwrap();
+        assert_eq!(strokes(&stored), [(1, "s1")]);
+
+        // Nothing pending, so an outside change to the archive stays
+        let outside = notes_json(&NotesData::default()).unwrap();
+        replace_archive_entries(&entry.path, &[("notes.json", outside)]).unwrap();
+        entry.flush().unwrap();
+        assert!(strokes(&read_notes_at(&entry.path).unwrap()).is_empty());
+    }
+}
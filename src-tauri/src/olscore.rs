@@ -1,13 +1,24 @@
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs;
 use std::io::{Read, Write};
-use std::path::PathBuf;
-use std::time::SystemTime;
-use tauri::{AppHandle, Manager};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, Mutex};
+use tauri::{AppHandle, Manager, State};
 use uuid::Uuid;
 use zip::write::FileOptions;
 
+use crate::audio::AudioEntry;
+use crate::library::{self, read_library_index, write_library_index, LibraryEntry};
+use crate::metronome::TempoMap;
+use crate::navigation::NavMark;
+use crate::notes::NotesStore;
+use crate::render;
+use crate::stroke::StrokePoint;
+use crate::timestamp::Timestamp;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PdfEntry {
     pub filename: String,
@@ -16,16 +27,119 @@ pub struct PdfEntry {
     pub sha256: String,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// How pages are laid out on screen.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DisplayMode {
+    /// Dual in landscape, single in portrait.
+    #[default]
+    Auto,
+    Single,
+    Dual,
+    /// Turns in two steps: the top of the next page replaces the top of the
+    /// current one first, cut at a split point.
+    HalfPage,
+    VerticalScroll,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct DisplaySettings {
     #[serde(default)]
     pub starts_on_left: bool,
+    #[serde(default)]
+    pub default_spread: DisplayMode,
+    /// Crop for every page without its own.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crop: Option<CropRect>,
+    /// Replaces `crop` on even pages (counting from 1 in each PDF), for book
+    /// scans whose left and right pages have different margins.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub even_crop: Option<CropRect>,
 }
 
-impl Default for DisplaySettings {
-    fn default() -> Self {
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SourceFormat {
+    MusicXml,
+    /// Compressed MusicXML.
+    Mxl,
+    Mei,
+}
+
+/// Metadata read from a source file.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SourceMetadata {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub composer: Option<String>,
+    #[serde(default)]
+    pub parts: Vec<String>,
+    /// e.g. "Eb major", from the first key signature.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    /// e.g. "3/4", from the first time signature.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub time: Option<String>,
+    #[serde(default)]
+    pub measures: u32,
+}
+
+/// An encoded score (MusicXML or MEI) stored under `sources/`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SourceEntry {
+    pub filename: String,
+    pub format: SourceFormat,
+    pub sha256: String,
+    #[serde(default)]
+    pub metadata: SourceMetadata,
+}
+
+/// A region of a page in fractions of its width and height, measured on the
+/// unrotated page.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct CropRect {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+impl CropRect {
+    /// Whether the rect is non-empty and lies within the page.
+    pub fn is_valid(&self) -> bool {
+        self.x >= 0.0
+            && self.y >= 0.0
+            && self.width > 0.0
+            && self.height > 0.0
+            && self.x + self.width <= 1.0
+            && self.y + self.height <= 1.0
+    }
+}
+
+/// One entry of the page sequence: a physical PDF page and how to show it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PageRef {
+    pub pdf: String,
+    pub page: u32,
+    /// Clockwise, in multiples of 90 degrees.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub rotation: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crop: Option<CropRect>,
+}
+
+fn is_zero(value: &u32) -> bool {
+    *value == 0
+}
+
+impl PageRef {
+    pub fn new(pdf: String, page: u32) -> Self {
         Self {
-            starts_on_left: false,
+            pdf,
+            page,
+            rotation: 0,
+            crop: None,
         }
     }
 }
@@ -43,86 +157,153 @@ pub struct ScoreManifest {
     #[serde(default)]
     pub display: DisplaySettings,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub created_at: Option<String>,
+    pub created_at: Option<Timestamp>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub modified_at: Option<String>,
+    pub modified_at: Option<Timestamp>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub pdf_metadata: Option<serde_json::Value>,
+    /// Pages as the viewer shows them; every page of every PDF in order if
+    /// unset. Pages can be left out, repeated, rotated and cropped here
+    /// without touching the PDFs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub page_order: Option<Vec<PageRef>>,
+    /// Where a page may be cut for half-page turns and scrolling, usually
+    /// between systems: fractions of the page height, by PDF and page.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub split_points: BTreeMap<String, BTreeMap<u32, Vec<f64>>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sources: Vec<SourceEntry>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub audio: Vec<AudioEntry>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tempo: Option<TempoMap>,
+}
+
+impl ScoreManifest {
+    /// The crop of a sequence entry: its own, else the score's.
+    pub fn crop_for(&self, page: &PageRef) -> Option<CropRect> {
+        if page.crop.is_some() {
+            return page.crop;
+        }
+        // Page 0 is the first, odd page
+        if page.page % 2 == 1 && self.display.even_crop.is_some() {
+            return self.display.even_crop;
+        }
+        self.display.crop
+    }
+
+    /// The page sequence, falling back to all physical pages.
+    pub fn page_sequence(&self) -> Vec<PageRef> {
+        match &self.page_order {
+            Some(order) => order.clone(),
+            None => self
+                .pdfs
+                .iter()
+                .flat_map(|pdf| (0..pdf.page_count).map(|p| PageRef::new(pdf.filename.clone(), p)))
+                .collect(),
+        }
+    }
+}
+
+/// Manifest fields the frontend edits. Only the given ones change, so a
+/// stale copy can't revert what backend commands wrote in the meantime.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ManifestUpdate {
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub composer: Option<String>,
+    #[serde(default)]
+    pub tags: Option<Vec<String>>,
+    #[serde(default)]
+    pub starts_on_left: Option<bool>,
+    #[serde(default)]
+    pub default_spread: Option<DisplayMode>,
+    #[serde(default)]
+    pub pdf_metadata: Option<serde_json::Value>,
+}
+
+impl ManifestUpdate {
+    pub fn apply(self, manifest: &mut ScoreManifest) {
+        if let Some(title) = self.title {
+            manifest.title = Some(title);
+        }
+        if let Some(composer) = self.composer {
+            manifest.composer = Some(composer);
+        }
+        if let Some(tags) = self.tags {
+            manifest.tags = tags;
+        }
+        if let Some(starts_on_left) = self.starts_on_left {
+            manifest.display.starts_on_left = starts_on_left;
+        }
+        if let Some(mode) = self.default_spread {
+            manifest.display.default_spread = mode;
+        }
+        if let Some(metadata) = self.pdf_metadata {
+            manifest.pdf_metadata = Some(metadata);
+        }
+    }
+}
+
+pub const DEFAULT_LAYER_ID: &str = "default";
+
+pub fn default_layer_id() -> String {
+    DEFAULT_LAYER_ID.into()
 }
 
+/// A named set of annotations, e.g. the teacher's markings or one
+/// conductor's bowings.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct LibraryEntry {
+pub struct Layer {
     pub id: String,
-    pub title: String,
-    pub sha256: String,
-    pub created_at: String,
-    pub last_opened_at: String,
+    pub name: String,
+    #[serde(default = "default_true")]
+    pub visible: bool,
+    #[serde(default = "default_opacity")]
+    pub opacity: f64,
+    /// Locked layers are shown but can't be edited, merged or deleted.
+    #[serde(default)]
+    pub locked: bool,
+    /// Defaults for new annotations drawn on this layer.
+    #[serde(default = "default_color")]
+    pub color: String,
+    #[serde(default = "default_width")]
+    pub width: f64,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct LibraryIndex {
-    pub scores: Vec<LibraryEntry>,
-}
-
-fn iso8601_now() -> String {
-    let duration = SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)
-        .unwrap_or_default();
-    let secs = duration.as_secs();
-    let days = secs / 86400;
-    let time_secs = secs % 86400;
-    let hours = time_secs / 3600;
-    let minutes = (time_secs % 3600) / 60;
-    let seconds = time_secs % 60;
-
-    // Simple date calculation from days since epoch
-    let mut y = 1970i64;
-    let mut remaining_days = days as i64;
-    loop {
-        let year_days = if y % 4 == 0 && (y % 100 != 0 || y % 400 == 0) {
-            366
-        } else {
-            365
-        };
-        if remaining_days < year_days {
-            break;
-        }
-        remaining_days -= year_days;
-        y += 1;
-    }
-    let leap = y % 4 == 0 && (y % 100 != 0 || y % 400 == 0);
-    let month_days = [
-        31,
-        if leap { 29 } else { 28 },
-        31,
-        30,
-        31,
-        30,
-        31,
-        31,
-        30,
-        31,
-        30,
-        31,
-    ];
-    let mut m = 0usize;
-    for md in &month_days {
-        if remaining_days < *md as i64 {
-            break;
+fn default_true() -> bool {
+    true
+}
+
+fn default_opacity() -> f64 {
+    1.0
+}
+
+fn default_color() -> String {
+    "#ff0000".into()
+}
+
+fn default_width() -> f64 {
+    2.0
+}
+
+impl Layer {
+    pub fn new(id: String, name: String) -> Self {
+        Self {
+            id,
+            name,
+            visible: true,
+            opacity: default_opacity(),
+            locked: false,
+            color: default_color(),
+            width: default_width(),
         }
-        remaining_days -= *md as i64;
-        m += 1;
     }
+}
 
-    format!(
-        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
-        y,
-        m + 1,
-        remaining_days + 1,
-        hours,
-        minutes,
-        seconds
-    )
+fn default_layers() -> Vec<Layer> {
+    vec![Layer::new(default_layer_id(), "Default".into())]
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -136,55 +317,104 @@ pub struct RepeatButton {
     #[serde(alias = "y")]
     pub offset_y: f64,
     pub size: u32,
+    #[serde(default = "default_layer_id")]
+    pub layer: String,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StrokeTool {
+    #[default]
+    Pen,
+    Highlighter,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Stroke {
+    pub id: String,
+    #[serde(default = "default_layer_id")]
+    pub layer: String,
+    pub points: Vec<StrokePoint>,
+    pub color: String,
+    pub width: f64,
+    #[serde(default)]
+    pub tool: StrokeTool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TextNote {
+    pub id: String,
+    #[serde(default = "default_layer_id")]
+    pub layer: String,
+    pub x: f64,
+    pub y: f64,
+    pub content: String,
+    pub color: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PageNotes {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub strokes: Vec<Stroke>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub text_notes: Vec<TextNote>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub bookmarks: Vec<String>,
 }
 
+/// Annotations of one PDF, keyed by zero-based page number.
+pub type PdfNotes = BTreeMap<u32, PageNotes>;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NotesData {
     pub format_version: String,
+    /// Never empty; notes from before layers existed get a default layer.
+    #[serde(default = "default_layers")]
+    pub layers: Vec<Layer>,
     #[serde(default)]
     pub repeat_buttons: Vec<RepeatButton>,
+    /// Repeats, voltas and jumps that define the playing order.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub navigation: Vec<NavMark>,
+    /// Keyed by PDF filename within the archive.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub pages: BTreeMap<String, PdfNotes>,
 }
 
 impl Default for NotesData {
     fn default() -> Self {
         Self {
             format_version: "0.1".into(),
+            layers: default_layers(),
             repeat_buttons: Vec::new(),
+            navigation: Vec::new(),
+            pages: BTreeMap::new(),
         }
     }
 }
 
-fn library_dir(app: &AppHandle) -> Result<PathBuf, String> {
-    let data_dir = app
-        .path()
-        .app_data_dir()
-        .map_err(|e| format!("Failed to get app data dir: {e}"))?;
-    Ok(data_dir.join("library"))
-}
-
-fn library_index_path(app: &AppHandle) -> Result<PathBuf, String> {
-    Ok(library_dir(app)?.join("library.json"))
-}
+impl NotesData {
+    pub fn layer(&self, id: &str) -> Option<&Layer> {
+        self.layers.iter().find(|l| l.id == id)
+    }
 
-fn read_library_index(app: &AppHandle) -> Result<LibraryIndex, String> {
-    let path = library_index_path(app)?;
-    if !path.exists() {
-        return Ok(LibraryIndex {
-            scores: Vec::new(),
-        });
+    pub fn layer_mut(&mut self, id: &str) -> Option<&mut Layer> {
+        self.layers.iter_mut().find(|l| l.id == id)
     }
-    let data = fs::read_to_string(&path).map_err(|e| format!("Failed to read library.json: {e}"))?;
-    serde_json::from_str(&data).map_err(|e| format!("Failed to parse library.json: {e}"))
-}
 
-fn write_library_index(app: &AppHandle, index: &LibraryIndex) -> Result<(), String> {
-    let path = library_index_path(app)?;
-    let data =
-        serde_json::to_string_pretty(index).map_err(|e| format!("Failed to serialize: {e}"))?;
-    fs::write(&path, data).map_err(|e| format!("Failed to write library.json: {e}"))
+    /// Calls `f` with the layer id of every annotation, removing those for
+    /// which it returns false.
+    pub fn retain_annotations(&mut self, mut f: impl FnMut(&mut String) -> bool) {
+        self.repeat_buttons.retain_mut(|b| f(&mut b.layer));
+        self.navigation.retain_mut(|m| f(&mut m.layer));
+        for page in self.pages.values_mut().flat_map(|pdf| pdf.values_mut()) {
+            page.strokes.retain_mut(|s| f(&mut s.layer));
+            page.text_notes.retain_mut(|n| f(&mut n.layer));
+        }
+    }
 }
 
-fn cache_dir_for_score(app: &AppHandle, score_id: &str) -> Result<PathBuf, String> {
+pub fn cache_dir_for_score(app: &AppHandle, score_id: &str) -> Result<PathBuf, String> {
     let cache_dir = app
         .path()
         .app_cache_dir()
@@ -192,17 +422,28 @@ fn cache_dir_for_score(app: &AppHandle, score_id: &str) -> Result<PathBuf, Strin
     Ok(cache_dir.join("extracted").join(score_id))
 }
 
-fn score_path(app: &AppHandle, score_id: &str) -> Result<PathBuf, String> {
-    Ok(library_dir(app)?.join(format!("{score_id}.olscore")))
+pub fn score_path(app: &AppHandle, score_id: &str) -> Result<PathBuf, String> {
+    Ok(library::locate_score(app, score_id)?.join(format!("{score_id}.olscore")))
 }
 
-fn extract_pdf_if_needed(
+pub fn extract_pdf_if_needed(
     app: &AppHandle,
     score_id: &str,
     pdf_filename: &str,
+) -> Result<PathBuf, String> {
+    extract_entry_if_needed(app, score_id, &format!("pdfs/{pdf_filename}"), pdf_filename)
+}
+
+/// Extracts an archive entry to the score's cache dir as `cache_name`,
+/// unless that was done before.
+pub fn extract_entry_if_needed(
+    app: &AppHandle,
+    score_id: &str,
+    archive_path: &str,
+    cache_name: &str,
 ) -> Result<PathBuf, String> {
     let extract_dir = cache_dir_for_score(app, score_id)?;
-    let extracted_path = extract_dir.join(pdf_filename);
+    let extracted_path = extract_dir.join(cache_name);
 
     if extracted_path.exists() {
         return Ok(extracted_path);
@@ -214,104 +455,144 @@ fn extract_pdf_if_needed(
     let mut archive =
         zip::ZipArchive::new(file).map_err(|e| format!("Failed to read ZIP: {e}"))?;
 
-    let archive_path = format!("pdfs/{pdf_filename}");
     let mut entry = archive
-        .by_name(&archive_path)
-        .map_err(|e| format!("PDF not found in archive: {e}"))?;
+        .by_name(archive_path)
+        .map_err(|e| format!("{archive_path} not found in archive: {e}"))?;
 
-    fs::create_dir_all(&extract_dir)
-        .map_err(|e| format!("Failed to create extract dir: {e}"))?;
+    if let Some(parent) = extracted_path.parent() {
+        fs::create_dir_all(parent).map_err(|e| format!("Failed to create extract dir: {e}"))?;
+    }
 
     let mut out =
         fs::File::create(&extracted_path).map_err(|e| format!("Failed to create file: {e}"))?;
-    std::io::copy(&mut entry, &mut out).map_err(|e| format!("Failed to extract PDF: {e}"))?;
+    std::io::copy(&mut entry, &mut out).map_err(|e| format!("Failed to extract entry: {e}"))?;
 
     Ok(extracted_path)
 }
 
 #[tauri::command]
-pub fn ensure_library(app: AppHandle) -> Result<String, String> {
-    let lib_dir = library_dir(&app)?;
-    fs::create_dir_all(&lib_dir).map_err(|e| format!("Failed to create library dir: {e}"))?;
-
-    let index_path = library_index_path(&app)?;
-    if !index_path.exists() {
-        write_library_index(
-            &app,
-            &LibraryIndex {
-                scores: Vec::new(),
-            },
-        )?;
-    }
-
-    lib_dir
-        .to_str()
-        .map(String::from)
-        .ok_or_else(|| "Invalid library path".into())
-}
-
-#[tauri::command]
-pub fn import_pdf(app: AppHandle, source_path: String) -> Result<LibraryEntry, String> {
+pub fn import_pdf(
+    app: AppHandle,
+    source_path: String,
+    library_id: Option<String>,
+) -> Result<LibraryEntry, String> {
     let pdf_bytes =
         fs::read(&source_path).map_err(|e| format!("Failed to read PDF: {e}"))?;
+    let pdf_filename = std::path::Path::new(&source_path)
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or("score.pdf")
+        .to_string();
+    let page_count = render::page_count(&app, &pdf_bytes)?;
+    import_pdf_bytes(&app, pdf_bytes, pdf_filename, page_count, library_id)
+}
 
+/// Stores a PDF as a new score, or returns the existing entry if the same
+/// PDF is already in a library.
+pub fn import_pdf_bytes(
+    app: &AppHandle,
+    pdf_bytes: Vec<u8>,
+    pdf_filename: String,
+    page_count: u32,
+    library_id: Option<String>,
+) -> Result<LibraryEntry, String> {
     let mut hasher = Sha256::new();
     hasher.update(&pdf_bytes);
     let sha256 = format!("{:x}", hasher.finalize());
 
-    // Dedup check
-    let mut index = read_library_index(&app)?;
-    if let Some(pos) = index.scores.iter().position(|s| s.sha256 == sha256) {
-        index.scores[pos].last_opened_at = iso8601_now();
-        let entry = index.scores[pos].clone();
-        write_library_index(&app, &index)?;
-        return Ok(entry);
+    // Dedup check across all mounted libraries
+    for (id, dir) in library::available_libraries(app)? {
+        let mut index = read_library_index(&dir)?;
+        if let Some(pos) = index.scores.iter().position(|s| s.sha256 == sha256) {
+            index.scores[pos].last_opened_at = Timestamp::now().into();
+            let mut entry = index.scores[pos].clone();
+            write_library_index(&dir, &index)?;
+            entry.library_id = Some(id);
+            return Ok(entry);
+        }
     }
 
+    let (library_id, lib_dir) = library::target_library_dir(app, library_id.as_deref())?;
+
     let score_id = Uuid::new_v4().to_string();
-    let now = iso8601_now();
+    let now = Timestamp::now();
+    let manifest = new_score_manifest(&pdf_filename, &sha256, page_count);
+    let title = manifest.title.clone().unwrap_or_default();
+    fs::create_dir_all(&lib_dir).map_err(|e| format!("Failed to create library dir: {e}"))?;
+    write_new_score(
+        &lib_dir.join(format!("{score_id}.olscore")),
+        &manifest,
+        &pdf_bytes,
+    )?;
 
-    let pdf_filename = std::path::Path::new(&source_path)
-        .file_name()
-        .and_then(|n| n.to_str())
-        .unwrap_or("score.pdf")
-        .to_string();
+    // Extract PDF to cache for immediate use
+    extract_pdf_if_needed(app, &score_id, &pdf_filename)?;
+
+    let entry = LibraryEntry {
+        id: score_id,
+        title,
+        sha256: sha256.clone(),
+        created_at: now.into(),
+        last_opened_at: now.into(),
+        library_id: None,
+    };
+
+    let mut index = read_library_index(&lib_dir)?;
+    index.scores.push(entry.clone());
+    write_library_index(&lib_dir, &index)?;
+
+    Ok(LibraryEntry {
+        library_id: Some(library_id),
+        ..entry
+    })
+}
 
+/// The manifest of a new score holding a single PDF.
+pub fn new_score_manifest(pdf_filename: &str, sha256: &str, page_count: u32) -> ScoreManifest {
+    let now = Timestamp::now();
     let title = pdf_filename
         .strip_suffix(".pdf")
         .or_else(|| pdf_filename.strip_suffix(".PDF"))
-        .unwrap_or(&pdf_filename)
+        .unwrap_or(pdf_filename)
         .to_string();
 
-    let manifest = ScoreManifest {
+    ScoreManifest {
         format_version: "0.1".into(),
         pdfs: vec![PdfEntry {
-            filename: pdf_filename.clone(),
+            filename: pdf_filename.to_string(),
             title: title.clone(),
-            page_count: 0, // Frontend determines this via PDF.js
-            sha256: sha256.clone(),
+            page_count,
+            sha256: sha256.to_string(),
         }],
-        title: Some(title.clone()),
+        title: Some(title),
         composer: None,
         tags: Vec::new(),
         display: DisplaySettings::default(),
-        created_at: Some(now.clone()),
-        modified_at: Some(now.clone()),
+        created_at: Some(now),
+        modified_at: Some(now),
         pdf_metadata: None,
-    };
-
-    // Create .olscore ZIP
-    let olscore_path = score_path(&app, &score_id)?;
-    let lib_dir = library_dir(&app)?;
-    fs::create_dir_all(&lib_dir).map_err(|e| format!("Failed to create library dir: {e}"))?;
+        page_order: None,
+        split_points: BTreeMap::new(),
+        sources: Vec::new(),
+        audio: Vec::new(),
+        tempo: None,
+    }
+}
 
+/// Creates an `.olscore` ZIP holding the manifest and its one PDF.
+pub fn write_new_score(
+    olscore_path: &Path,
+    manifest: &ScoreManifest,
+    pdf_bytes: &[u8],
+) -> Result<(), String> {
+    let pdf_filename = &manifest.pdfs.first().ok_or("Manifest has no PDF")?.filename;
     let file =
-        fs::File::create(&olscore_path).map_err(|e| format!("Failed to create .olscore: {e}"))?;
+        fs::File::create(olscore_path).map_err(|e| format!("Failed to create .olscore: {e}"))?;
     let mut zip = zip::ZipWriter::new(file);
     let options: FileOptions<'_, ()> =
         FileOptions::default().compression_method(zip::CompressionMethod::Stored);
 
-    let manifest_json = serde_json::to_string_pretty(&manifest)
+    let manifest_json = serde_json::to_string_pretty(manifest)
         .map_err(|e| format!("Failed to serialize manifest: {e}"))?;
     zip.start_file("manifest.json", options)
         .map_err(|e| format!("Failed to write manifest: {e}"))?;
@@ -321,58 +602,52 @@ pub fn import_pdf(app: AppHandle, source_path: String) -> Result<LibraryEntry, S
     let pdf_archive_path = format!("pdfs/{pdf_filename}");
     zip.start_file(&pdf_archive_path, options)
         .map_err(|e| format!("Failed to add PDF to archive: {e}"))?;
-    zip.write_all(&pdf_bytes)
+    zip.write_all(pdf_bytes)
         .map_err(|e| format!("Failed to write PDF data: {e}"))?;
 
     zip.finish()
         .map_err(|e| format!("Failed to finalize ZIP: {e}"))?;
-
-    // Extract PDF to cache for immediate use
-    extract_pdf_if_needed(&app, &score_id, &pdf_filename)?;
-
-    let entry = LibraryEntry {
-        id: score_id,
-        title,
-        sha256: sha256.clone(),
-        created_at: now.clone(),
-        last_opened_at: now,
-    };
-
-    index.scores.push(entry.clone());
-    write_library_index(&app, &index)?;
-
-    Ok(entry)
+    Ok(())
 }
 
 #[tauri::command]
 pub fn open_score(app: AppHandle, score_id: String) -> Result<ScoreManifest, String> {
     let olscore_path = score_path(&app, &score_id)?;
-    let file =
-        fs::File::open(&olscore_path).map_err(|e| format!("Failed to open .olscore: {e}"))?;
-    let mut archive =
-        zip::ZipArchive::new(file).map_err(|e| format!("Failed to read ZIP: {e}"))?;
-
-    let manifest: ScoreManifest = {
-        let mut entry = archive
-            .by_name("manifest.json")
-            .map_err(|e| format!("manifest.json not found: {e}"))?;
-        let mut buf = String::new();
-        entry
-            .read_to_string(&mut buf)
-            .map_err(|e| format!("Failed to read manifest: {e}"))?;
-        serde_json::from_str(&buf).map_err(|e| format!("Failed to parse manifest: {e}"))?
-    };
+    let mut manifest = read_manifest_at(&olscore_path)?;
 
     // Extract PDFs to cache
     for pdf in &manifest.pdfs {
         extract_pdf_if_needed(&app, &score_id, &pdf.filename)?;
     }
 
+    // Scores imported before page counts were stored have 0. A PDF pdfium
+    // can't read keeps it, as the viewer may still manage to show it.
+    let counts: HashMap<String, u32> = manifest
+        .pdfs
+        .iter()
+        .filter(|pdf| pdf.page_count == 0)
+        .filter_map(|pdf| {
+            let sizes = render::page_sizes(&app, &score_id, &pdf.filename).ok()?;
+            Some((pdf.filename.clone(), sizes.len() as u32))
+        })
+        .collect();
+    if !counts.is_empty() {
+        manifest = modify_manifest(&olscore_path, |manifest| {
+            for pdf in &mut manifest.pdfs {
+                if let Some(count) = counts.get(&pdf.filename) {
+                    pdf.page_count = *count;
+                }
+            }
+            Ok(())
+        })?;
+    }
+
     // Update last_opened_at
-    let mut index = read_library_index(&app)?;
+    let lib_dir = library::locate_score(&app, &score_id)?;
+    let mut index = read_library_index(&lib_dir)?;
     if let Some(entry) = index.scores.iter_mut().find(|s| s.id == score_id) {
-        entry.last_opened_at = iso8601_now();
-        write_library_index(&app, &index)?;
+        entry.last_opened_at = Timestamp::now().into();
+        write_library_index(&lib_dir, &index)?;
     }
 
     Ok(manifest)
@@ -390,160 +665,469 @@ pub fn get_extracted_pdf_path(
         .ok_or_else(|| "Invalid path".into())
 }
 
-#[tauri::command]
-pub fn list_library(app: AppHandle) -> Result<Vec<LibraryEntry>, String> {
-    let mut index = read_library_index(&app)?;
-    index
-        .scores
-        .sort_by(|a, b| b.last_opened_at.cmp(&a.last_opened_at));
-    Ok(index.scores)
-}
-
 #[tauri::command]
 pub fn update_manifest(
     app: AppHandle,
     score_id: String,
-    manifest: ScoreManifest,
-) -> Result<(), String> {
+    update: ManifestUpdate,
+) -> Result<ScoreManifest, String> {
     let olscore_path = score_path(&app, &score_id)?;
+    let manifest = modify_manifest(&olscore_path, |manifest| {
+        update.apply(manifest);
+        Ok(())
+    })?;
+    sync_library_title(&app, &score_id, &manifest)?;
+    Ok(manifest)
+}
 
-    // Read all existing entries
-    let file =
-        fs::File::open(&olscore_path).map_err(|e| format!("Failed to open .olscore: {e}"))?;
-    let mut archive =
-        zip::ZipArchive::new(file).map_err(|e| format!("Failed to read ZIP: {e}"))?;
-
-    let tmp_path = olscore_path.with_extension("olscore.tmp");
-    let tmp_file =
-        fs::File::create(&tmp_path).map_err(|e| format!("Failed to create temp file: {e}"))?;
-    let mut writer = zip::ZipWriter::new(tmp_file);
-    let options: FileOptions<'_, ()> =
-        FileOptions::default().compression_method(zip::CompressionMethod::Stored);
-
-    // Copy all entries except manifest.json
-    for i in 0..archive.len() {
-        let mut entry = archive
-            .by_index(i)
-            .map_err(|e| format!("Failed to read entry: {e}"))?;
-        if entry.name() == "manifest.json" {
-            continue;
+/// Copies the manifest's title to the library index.
+pub fn sync_library_title(
+    app: &AppHandle,
+    score_id: &str,
+    manifest: &ScoreManifest,
+) -> Result<(), String> {
+    if let Some(title) = &manifest.title {
+        let lib_dir = library::locate_score(app, score_id)?;
+        let mut index = read_library_index(&lib_dir)?;
+        if let Some(entry) = index.scores.iter_mut().find(|s| s.id == score_id) {
+            if entry.title != *title {
+                entry.title = title.clone();
+                write_library_index(&lib_dir, &index)?;
+            }
         }
-        let mut buf = Vec::new();
-        entry
-            .read_to_end(&mut buf)
-            .map_err(|e| format!("Failed to read entry data: {e}"))?;
-        writer
-            .start_file(entry.name(), options)
-            .map_err(|e| format!("Failed to start file: {e}"))?;
-        writer
-            .write_all(&buf)
-            .map_err(|e| format!("Failed to write entry: {e}"))?;
     }
 
-    // Write updated manifest
-    let mut updated_manifest = manifest;
-    updated_manifest.modified_at = Some(iso8601_now());
-    let manifest_json = serde_json::to_string_pretty(&updated_manifest)
-        .map_err(|e| format!("Failed to serialize manifest: {e}"))?;
-    writer
-        .start_file("manifest.json", options)
-        .map_err(|e| format!("Failed to write manifest: {e}"))?;
-    writer
-        .write_all(manifest_json.as_bytes())
-        .map_err(|e| format!("Failed to write manifest data: {e}"))?;
+    Ok(())
+}
 
-    writer
-        .finish()
-        .map_err(|e| format!("Failed to finalize ZIP: {e}"))?;
+/// Reads the manifest of any `.olscore`, including ones outside the library.
+pub fn read_manifest_at(olscore_path: &Path) -> Result<ScoreManifest, String> {
+    let file = fs::File::open(olscore_path).map_err(|e| format!("Failed to open .olscore: {e}"))?;
+    let mut archive = zip::ZipArchive::new(file).map_err(|e| format!("Failed to read ZIP: {e}"))?;
 
-    fs::rename(&tmp_path, &olscore_path)
-        .map_err(|e| format!("Failed to replace .olscore: {e}"))?;
+    let mut entry = archive
+        .by_name("manifest.json")
+        .map_err(|e| format!("manifest.json not found: {e}"))?;
+    let mut buf = String::new();
+    entry
+        .read_to_string(&mut buf)
+        .map_err(|e| format!("Failed to read manifest: {e}"))?;
+    serde_json::from_str(&buf).map_err(|e| format!("Failed to parse manifest: {e}"))
+}
 
-    // Sync title to library index
-    if let Some(title) = &updated_manifest.title {
-        let mut index = read_library_index(&app)?;
-        if let Some(entry) = index.scores.iter_mut().find(|s| s.id == score_id) {
-            if entry.title != *title {
-                entry.title = title.clone();
-                write_library_index(&app, &index)?;
-            }
+/// Like `read_manifest_at`, but also checks every PDF against its recorded
+/// SHA-256, for archives that didn't come from the library.
+pub fn read_verified_manifest_at(olscore_path: &Path) -> Result<ScoreManifest, String> {
+    let manifest = read_manifest_at(olscore_path)?;
+    let file = fs::File::open(olscore_path).map_err(|e| format!("Failed to open .olscore: {e}"))?;
+    let mut archive = zip::ZipArchive::new(file).map_err(|e| format!("Failed to read ZIP: {e}"))?;
+    for pdf in &manifest.pdfs {
+        let mut entry = archive
+            .by_name(&format!("pdfs/{}", pdf.filename))
+            .map_err(|e| format!("PDF not found in archive: {}: {e}", pdf.filename))?;
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut entry, &mut hasher)
+            .map_err(|e| format!("Failed to read PDF {}: {e}", pdf.filename))?;
+        if format!("{:x}", hasher.finalize()) != pdf.sha256 {
+            return Err(format!("PDF does not match its manifest: {}", pdf.filename));
         }
     }
+    Ok(manifest)
+}
 
-    Ok(())
+/// Reads the manifest, applies `f` and writes it back, all under the
+/// archive lock so edits made in between aren't lost. Nothing is written
+/// if `f` fails.
+pub fn modify_manifest(
+    olscore_path: &Path,
+    f: impl FnOnce(&mut ScoreManifest) -> Result<(), String>,
+) -> Result<ScoreManifest, String> {
+    modify_archive(olscore_path, |manifest| {
+        f(manifest)?;
+        Ok(ArchiveChanges::default())
+    })
 }
 
-#[tauri::command]
-pub fn read_notes(app: AppHandle, score_id: String) -> Result<NotesData, String> {
-    let olscore_path = score_path(&app, &score_id)?;
-    let file =
-        fs::File::open(&olscore_path).map_err(|e| format!("Failed to open .olscore: {e}"))?;
-    let mut archive =
-        zip::ZipArchive::new(file).map_err(|e| format!("Failed to read ZIP: {e}"))?;
+/// Entries to change along with the manifest.
+#[derive(Debug, Default)]
+pub struct ArchiveChanges {
+    pub remove: Vec<String>,
+    pub entries: Vec<(String, Vec<u8>)>,
+}
+
+/// Like `modify_manifest`, for edits that also add or remove entries, e.g.
+/// an attached file. Entries and manifest are written in one rewrite, so
+/// the manifest never refers to a missing entry.
+pub fn modify_archive(
+    olscore_path: &Path,
+    f: impl FnOnce(&mut ScoreManifest) -> Result<ArchiveChanges, String>,
+) -> Result<ScoreManifest, String> {
+    with_archive_lock(olscore_path, || {
+        let mut manifest = read_manifest_at(olscore_path)?;
+        let changes = f(&mut manifest)?;
+        manifest.modified_at = Some(Timestamp::now());
+        let manifest_json = serde_json::to_vec_pretty(&manifest)
+            .map_err(|e| format!("Failed to serialize manifest: {e}"))?;
+
+        let remove: Vec<&str> = changes.remove.iter().map(String::as_str).collect();
+        let (names, data): (Vec<String>, Vec<Vec<u8>>) = changes.entries.into_iter().unzip();
+        let entries: Vec<(&str, Vec<u8>)> = names
+            .iter()
+            .map(String::as_str)
+            .zip(data)
+            .chain(std::iter::once(("manifest.json", manifest_json)))
+            .collect();
+        rewrite_archive(olscore_path, &remove, &entries)?;
+        Ok(manifest)
+    })
+}
+
+/// Reads a text entry of an `.olscore`, or `None` if the archive lacks it.
+pub fn read_archive_entry(olscore_path: &Path, name: &str) -> Result<Option<String>, String> {
+    let file = fs::File::open(olscore_path).map_err(|e| format!("Failed to open .olscore: {e}"))?;
+    let mut archive = zip::ZipArchive::new(file).map_err(|e| format!("Failed to read ZIP: {e}"))?;
 
-    let result = match archive.by_name("notes.json") {
+    let result = match archive.by_name(name) {
         Ok(mut entry) => {
             let mut buf = String::new();
             entry
                 .read_to_string(&mut buf)
-                .map_err(|e| format!("Failed to read notes.json: {e}"))?;
-            serde_json::from_str(&buf).map_err(|e| format!("Failed to parse notes.json: {e}"))
+                .map_err(|e| format!("Failed to read {name}: {e}"))?;
+            Some(buf)
         }
-        Err(_) => Ok(NotesData::default()),
+        Err(_) => None,
     };
+    Ok(result)
+}
+
+/// Rewrites an `.olscore` with `entries` added or replaced, keeping all
+/// other entries. Goes through a temp file so a failed write leaves the
+/// original intact.
+pub fn replace_archive_entries(
+    olscore_path: &Path,
+    entries: &[(&str, Vec<u8>)],
+) -> Result<(), String> {
+    rewrite_archive(olscore_path, &[], entries)
+}
+
+/// One lock per archive path, shared by everything that rewrites archives.
+static ARCHIVE_LOCKS: LazyLock<Mutex<HashMap<PathBuf, Arc<Mutex<()>>>>> =
+    LazyLock::new(Default::default);
+
+thread_local! {
+    /// Archives whose lock this thread holds, so nested writes don't block.
+    static HELD_ARCHIVES: RefCell<HashSet<PathBuf>> = RefCell::default();
+}
+
+/// Forgets a held archive again, even if `f` panics.
+struct Held<'a>(&'a Path);
+
+impl Drop for Held<'_> {
+    fn drop(&mut self) {
+        HELD_ARCHIVES.with(|held| held.borrow_mut().remove(self.0));
+    }
+}
+
+/// Runs `f` while no other thread writes `olscore_path`. Reads followed
+/// by a write, like manifest edits, go inside so no change is lost in
+/// between. Calls nest on the same thread.
+pub fn with_archive_lock<T>(
+    olscore_path: &Path,
+    f: impl FnOnce() -> Result<T, String>,
+) -> Result<T, String> {
+    if HELD_ARCHIVES.with(|held| held.borrow().contains(olscore_path)) {
+        return f();
+    }
+    let lock = ARCHIVE_LOCKS
+        .lock()
+        .map_err(|e| e.to_string())?
+        .entry(olscore_path.to_path_buf())
+        .or_default()
+        .clone();
+    let result = {
+        let _guard = lock.lock().map_err(|e| e.to_string())?;
+        HELD_ARCHIVES.with(|held| held.borrow_mut().insert(olscore_path.to_path_buf()));
+        let _held = Held(olscore_path);
+        f()
+    };
+
+    // Forget the lock once nobody else holds or waits for it
+    let mut locks = ARCHIVE_LOCKS.lock().map_err(|e| e.to_string())?;
+    if Arc::strong_count(&lock) == 2 {
+        locks.remove(olscore_path);
+    }
     result
 }
 
-#[tauri::command]
-pub fn save_notes(app: AppHandle, score_id: String, notes: NotesData) -> Result<(), String> {
-    let olscore_path = score_path(&app, &score_id)?;
+/// Runs `f` holding the locks of all `paths`, which must be sorted so
+/// two callers never wait on each other.
+pub fn with_archive_locks<T>(
+    paths: &[PathBuf],
+    f: impl FnOnce() -> Result<T, String>,
+) -> Result<T, String> {
+    match paths.split_first() {
+        Some((first, rest)) => with_archive_lock(first, || with_archive_locks(rest, f)),
+        None => f(),
+    }
+}
 
-    let file =
-        fs::File::open(&olscore_path).map_err(|e| format!("Failed to open .olscore: {e}"))?;
-    let mut archive =
-        zip::ZipArchive::new(file).map_err(|e| format!("Failed to read ZIP: {e}"))?;
+/// Rewrites the archive under its lock. The archive is opened only once
+/// the lock is held, so entries written by a concurrent rewrite are kept.
+fn rewrite_archive(
+    olscore_path: &Path,
+    remove: &[&str],
+    entries: &[(&str, Vec<u8>)],
+) -> Result<(), String> {
+    with_archive_lock(olscore_path, || {
+        let tmp_path = olscore_path.with_extension("olscore.tmp");
+        let result = write_rewritten(olscore_path, &tmp_path, remove, entries);
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp_path);
+        }
+        result
+    })
+}
+
+fn write_rewritten(
+    olscore_path: &Path,
+    tmp_path: &Path,
+    remove: &[&str],
+    entries: &[(&str, Vec<u8>)],
+) -> Result<(), String> {
+    let file = fs::File::open(olscore_path).map_err(|e| format!("Failed to open .olscore: {e}"))?;
+    let mut archive = zip::ZipArchive::new(file).map_err(|e| format!("Failed to read ZIP: {e}"))?;
 
-    let tmp_path = olscore_path.with_extension("olscore.tmp");
     let tmp_file =
-        fs::File::create(&tmp_path).map_err(|e| format!("Failed to create temp file: {e}"))?;
+        fs::File::create(tmp_path).map_err(|e| format!("Failed to create temp file: {e}"))?;
     let mut writer = zip::ZipWriter::new(tmp_file);
     let options: FileOptions<'_, ()> =
         FileOptions::default().compression_method(zip::CompressionMethod::Stored);
 
+    // Kept entries are copied as stored bytes, so PDFs and audio are
+    // streamed across rather than read into memory.
     for i in 0..archive.len() {
-        let mut entry = archive
-            .by_index(i)
+        let entry = archive
+            .by_index_raw(i)
             .map_err(|e| format!("Failed to read entry: {e}"))?;
-        if entry.name() == "notes.json" {
+        if remove.contains(&entry.name()) || entries.iter().any(|(name, _)| entry.name() == *name) {
             continue;
         }
-        let mut buf = Vec::new();
-        entry
-            .read_to_end(&mut buf)
-            .map_err(|e| format!("Failed to read entry data: {e}"))?;
         writer
-            .start_file(entry.name(), options)
-            .map_err(|e| format!("Failed to start file: {e}"))?;
-        writer
-            .write_all(&buf)
-            .map_err(|e| format!("Failed to write entry: {e}"))?;
+            .raw_copy_file(entry)
+            .map_err(|e| format!("Failed to copy entry: {e}"))?;
     }
 
-    let notes_json = serde_json::to_string_pretty(&notes)
-        .map_err(|e| format!("Failed to serialize notes: {e}"))?;
-    writer
-        .start_file("notes.json", options)
-        .map_err(|e| format!("Failed to write notes.json: {e}"))?;
-    writer
-        .write_all(notes_json.as_bytes())
-        .map_err(|e| format!("Failed to write notes data: {e}"))?;
+    for (name, data) in entries {
+        writer
+            .start_file(*name, options)
+            .map_err(|e| format!("Failed to write {name}: {e}"))?;
+        writer
+            .write_all(data)
+            .map_err(|e| format!("Failed to write {name} data: {e}"))?;
+    }
 
     writer
         .finish()
         .map_err(|e| format!("Failed to finalize ZIP: {e}"))?;
 
-    fs::rename(&tmp_path, &olscore_path)
-        .map_err(|e| format!("Failed to replace .olscore: {e}"))?;
+    fs::rename(tmp_path, olscore_path).map_err(|e| format!("Failed to replace .olscore: {e}"))?;
 
     Ok(())
 }
+
+pub fn read_notes_at(olscore_path: &Path) -> Result<NotesData, String> {
+    match read_archive_entry(olscore_path, "notes.json")? {
+        Some(data) => {
+            serde_json::from_str(&data).map_err(|e| format!("Failed to parse notes.json: {e}"))
+        }
+        None => Ok(NotesData::default()),
+    }
+}
+
+pub fn notes_json(notes: &NotesData) -> Result<Vec<u8>, String> {
+    serde_json::to_vec_pretty(notes).map_err(|e| format!("Failed to serialize notes: {e}"))
+}
+
+#[tauri::command]
+pub fn read_notes(
+    app: AppHandle,
+    store: State<'_, NotesStore>,
+    score_id: String,
+) -> Result<NotesData, String> {
+    Ok(store.snapshot(&app, &score_id)?.notes)
+}
+
+/// Replaces the whole notes document. Prefer the annotation patch commands,
+/// which don't resend everything. Returns the new revision.
+#[tauri::command]
+pub fn save_notes(
+    app: AppHandle,
+    store: State<'_, NotesStore>,
+    score_id: String,
+    notes: NotesData,
+) -> Result<u64, String> {
+    store
+        .modify(&app, &score_id, None, |current| {
+            *current = notes;
+            Ok(())
+        })
+        .map(|(_, revision)| revision)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn concurrent_rewrites_keep_each_others_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("score.olscore");
+        let mut writer = zip::ZipWriter::new(fs::File::create(&path).unwrap());
+        writer
+            .start_file("manifest.json", FileOptions::<'_, ()>::default())
+            .unwrap();
+        writer.write_all(b"{}").unwrap();
+        writer.finish().unwrap();
+
+        let threads: Vec<_> = (0..8)
+            .map(|i| {
+                let path = path.clone();
+                std::thread::spawn(move || {
+                    let name = format!("entry-{i}.json");
+                    replace_archive_entries(&path, &[(&name, vec![b'0' + i])]).unwrap();
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        for i in 0..8u8 {
+            let entry = read_archive_entry(&path, &format!("entry-{i}.json")).unwrap();
+            assert_eq!(entry, Some(i.to_string()));
+        }
+        assert!(read_archive_entry(&path, "manifest.json")
+            .unwrap()
+            .is_some());
+        assert!(!path.with_extension("olscore.tmp").exists());
+        assert!(!ARCHIVE_LOCKS.lock().unwrap().contains_key(&path));
+    }
+
+    fn new_score(dir: &Path, page_count: u32) -> PathBuf {
+        let path = dir.join("score.olscore");
+        let manifest = new_score_manifest("Etude.pdf", "abc", page_count);
+        write_new_score(&path, &manifest, b"%PDF-1.7").unwrap();
+        path
+    }
+
+    #[test]
+    fn new_scores_list_every_page_in_their_sequence() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest = read_manifest_at(&new_score(dir.path(), 3)).unwrap();
+        assert_eq!(manifest.title.as_deref(), Some("Etude"));
+        assert_eq!(
+            manifest.page_sequence(),
+            (0..3)
+                .map(|p| PageRef::new("Etude.pdf".into(), p))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn concurrent_manifest_edits_are_all_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = new_score(dir.path(), 1);
+
+        let threads: Vec<_> = (0..8)
+            .map(|i| {
+                let path = path.clone();
+                std::thread::spawn(move || {
+                    modify_manifest(&path, |m| {
+                        m.tags.push(format!("tag-{i}"));
+                        Ok(())
+                    })
+                    .unwrap();
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let mut tags = read_manifest_at(&path).unwrap().tags;
+        tags.sort();
+        assert_eq!(tags, (0..8).map(|i| format!("tag-{i}")).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn failed_manifest_edit_writes_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = new_score(dir.path(), 1);
+        let before = read_archive_entry(&path, "manifest.json").unwrap();
+
+        let result = modify_manifest(&path, |m| {
+            m.title = Some("Changed".into());
+            Err("rejected".into())
+        });
+        assert_eq!(result.unwrap_err(), "rejected");
+        assert_eq!(read_archive_entry(&path, "manifest.json").unwrap(), before);
+    }
+
+    #[test]
+    fn archive_entries_change_with_the_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = new_score(dir.path(), 1);
+
+        modify_archive(&path, |m| {
+            m.tags.push("with-source".into());
+            Ok(ArchiveChanges {
+                entries: vec![("sources/a.mei".into(), b"<mei/>".to_vec())],
+                ..ArchiveChanges::default()
+            })
+        })
+        .unwrap();
+        assert_eq!(
+            read_archive_entry(&path, "sources/a.mei")
+                .unwrap()
+                .as_deref(),
+            Some("<mei/>")
+        );
+        assert_eq!(read_manifest_at(&path).unwrap().tags, ["with-source"]);
+
+        modify_archive(&path, |m| {
+            m.tags.clear();
+            Ok(ArchiveChanges {
+                remove: vec!["sources/a.mei".into()],
+                ..ArchiveChanges::default()
+            })
+        })
+        .unwrap();
+        assert_eq!(read_archive_entry(&path, "sources/a.mei").unwrap(), None);
+        assert!(read_manifest_at(&path).unwrap().tags.is_empty());
+        assert!(read_archive_entry(&path, "pdfs/Etude.pdf")
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn manifest_update_keeps_backend_fields() {
+        let mut manifest = new_score_manifest("Etude.pdf", "abc", 2);
+        manifest.page_order = Some(vec![PageRef::new("Etude.pdf".into(), 1)]);
+        manifest.display.crop = Some(CropRect {
+            x: 0.1,
+            y: 0.1,
+            width: 0.8,
+            height: 0.8,
+        });
+
+        let update: ManifestUpdate =
+            serde_json::from_str(r#"{"starts_on_left": true, "title": "Study"}"#).unwrap();
+        update.apply(&mut manifest);
+
+        assert!(manifest.display.starts_on_left);
+        assert_eq!(manifest.title.as_deref(), Some("Study"));
+        assert_eq!(manifest.page_sequence().len(), 1);
+        assert!(manifest.display.crop.is_some());
+        assert_eq!(manifest.pdfs[0].page_count, 2);
+    }
+}
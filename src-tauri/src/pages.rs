@@ -0,0 +1,172 @@
+use tauri::AppHandle;
+
+use crate::olscore::{
+    modify_manifest, read_manifest_at, score_path, CropRect, PageRef, ScoreManifest,
+};
+
+fn check_page(manifest: &ScoreManifest, page: &PageRef) -> Result<(), String> {
+    let pdf = manifest
+        .pdfs
+        .iter()
+        .find(|p| p.filename == page.pdf)
+        .ok_or_else(|| format!("PDF not in score: {}", page.pdf))?;
+    // A count of 0 means the viewer hasn't reported it yet
+    if pdf.page_count > 0 && page.page >= pdf.page_count {
+        return Err(format!(
+            "Page {} out of range for {} ({} pages)",
+            page.page, page.pdf, pdf.page_count
+        ));
+    }
+    if !page.rotation.is_multiple_of(90) || page.rotation >= 360 {
+        return Err(format!("Invalid rotation: {}", page.rotation));
+    }
+    if page.crop.is_some_and(|crop| !crop.is_valid()) {
+        return Err("Crop must lie within the page".into());
+    }
+    Ok(())
+}
+
+fn check_index(sequence: &[PageRef], index: usize) -> Result<(), String> {
+    if index >= sequence.len() {
+        return Err(format!("Index {index} out of range"));
+    }
+    Ok(())
+}
+
+/// Applies `f` to the score's page sequence and stores the result.
+fn modify_sequence(
+    app: &AppHandle,
+    score_id: &str,
+    f: impl FnOnce(&mut Vec<PageRef>) -> Result<(), String>,
+) -> Result<Vec<PageRef>, String> {
+    let manifest = modify_manifest(&score_path(app, score_id)?, |manifest| {
+        let mut sequence = manifest.page_sequence();
+        f(&mut sequence)?;
+        for page in &sequence {
+            check_page(manifest, page)?;
+        }
+        manifest.page_order = Some(sequence);
+        Ok(())
+    })?;
+    Ok(manifest.page_sequence())
+}
+
+#[tauri::command]
+pub fn get_page_sequence(app: AppHandle, score_id: String) -> Result<Vec<PageRef>, String> {
+    Ok(read_manifest_at(&score_path(&app, &score_id)?)?.page_sequence())
+}
+
+/// The sequence as the viewer should render it, with score-wide crops
+/// filled in.
+#[tauri::command]
+pub fn get_display_pages(app: AppHandle, score_id: String) -> Result<Vec<PageRef>, String> {
+    let manifest = read_manifest_at(&score_path(&app, &score_id)?)?;
+    Ok(manifest
+        .page_sequence()
+        .into_iter()
+        .map(|page| PageRef {
+            crop: manifest.crop_for(&page),
+            ..page
+        })
+        .collect())
+}
+
+#[tauri::command]
+pub fn set_page_sequence(
+    app: AppHandle,
+    score_id: String,
+    pages: Vec<PageRef>,
+) -> Result<Vec<PageRef>, String> {
+    modify_sequence(&app, &score_id, |sequence| {
+        *sequence = pages;
+        Ok(())
+    })
+}
+
+#[tauri::command]
+pub fn move_page(
+    app: AppHandle,
+    score_id: String,
+    from: usize,
+    to: usize,
+) -> Result<Vec<PageRef>, String> {
+    modify_sequence(&app, &score_id, |sequence| {
+        check_index(sequence, from)?;
+        check_index(sequence, to)?;
+        let page = sequence.remove(from);
+        sequence.insert(to, page);
+        Ok(())
+    })
+}
+
+/// Inserts a copy of the entry at `index` right after it, or at `to`.
+#[tauri::command]
+pub fn duplicate_page(
+    app: AppHandle,
+    score_id: String,
+    index: usize,
+    to: Option<usize>,
+) -> Result<Vec<PageRef>, String> {
+    modify_sequence(&app, &score_id, |sequence| {
+        check_index(sequence, index)?;
+        let to = to.unwrap_or(index + 1);
+        if to > sequence.len() {
+            return Err(format!("Index {to} out of range"));
+        }
+        sequence.insert(to, sequence[index].clone());
+        Ok(())
+    })
+}
+
+/// Removes an entry from the sequence; the PDF page itself is kept.
+#[tauri::command]
+pub fn hide_page(app: AppHandle, score_id: String, index: usize) -> Result<Vec<PageRef>, String> {
+    modify_sequence(&app, &score_id, |sequence| {
+        check_index(sequence, index)?;
+        sequence.remove(index);
+        Ok(())
+    })
+}
+
+/// Inserts a physical page, e.g. to bring back a hidden one.
+#[tauri::command]
+pub fn insert_page(
+    app: AppHandle,
+    score_id: String,
+    index: usize,
+    page: PageRef,
+) -> Result<Vec<PageRef>, String> {
+    modify_sequence(&app, &score_id, |sequence| {
+        if index > sequence.len() {
+            return Err(format!("Index {index} out of range"));
+        }
+        sequence.insert(index, page);
+        Ok(())
+    })
+}
+
+#[tauri::command]
+pub fn set_page_view(
+    app: AppHandle,
+    score_id: String,
+    index: usize,
+    rotation: u32,
+    crop: Option<CropRect>,
+) -> Result<Vec<PageRef>, String> {
+    modify_sequence(&app, &score_id, |sequence| {
+        check_index(sequence, index)?;
+        sequence[index].rotation = rotation;
+        sequence[index].crop = crop;
+        Ok(())
+    })
+}
+
+/// Goes back to every page of every PDF in order.
+#[tauri::command]
+pub fn reset_page_sequence(app: AppHandle, score_id: String) -> Result<Vec<PageRef>, String> {
+    let manifest = modify_manifest(&score_path(&app, &score_id)?, |manifest| {
+        manifest.page_order = None;
+        Ok(())
+    })?;
+    Ok(manifest.page_sequence())
+}
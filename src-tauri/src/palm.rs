@@ -0,0 +1,534 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use tauri::{AppHandle, Manager, State};
+
+use crate::stylus::{StylusEvent, StylusEventKind};
+use crate::touch::{TouchEvent, TouchEventKind};
+
+/// Contact size limits for one touch panel, in the panel's own units.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct ContactThreshold {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_touch_major: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_width_major: Option<i32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PalmConfig {
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// How long touches stay suppressed after the pen leaves proximity.
+    #[serde(default = "default_grace_ms")]
+    pub grace_ms: u64,
+    /// Pen silence after which it counts as gone even without a
+    /// proximity-out, e.g. when the pen was unplugged mid-hover.
+    #[serde(default = "default_proximity_timeout_ms")]
+    pub proximity_timeout_ms: u64,
+    /// Per-panel thresholds keyed by device name.
+    #[serde(default)]
+    pub thresholds: HashMap<String, ContactThreshold>,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_grace_ms() -> u64 {
+    500
+}
+
+fn default_proximity_timeout_ms() -> u64 {
+    2000
+}
+
+impl Default for PalmConfig {
+    fn default() -> Self {
+        Self {
+            enabled: default_enabled(),
+            grace_ms: default_grace_ms(),
+            proximity_timeout_ms: default_proximity_timeout_ms(),
+            thresholds: HashMap::new(),
+        }
+    }
+}
+
+/// Pen state as last seen by any stylus stream.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PenProximity {
+    pub in_proximity: bool,
+    /// Timestamp of the last pen event, in kernel microseconds.
+    pub last_seen_us: u64,
+}
+
+impl PenProximity {
+    pub fn update(&mut self, event: &StylusEvent) {
+        self.last_seen_us = event.timestamp_us;
+        // A lifted pen is usually still hovering over the palm's side of
+        // the page. Pens without proximity reporting never send
+        // ProximityOut and are left to the timeout.
+        self.in_proximity = event.event_type != StylusEventKind::ProximityOut;
+    }
+
+    /// The stylus stream stopped, so no proximity-out will follow.
+    pub fn reset(&mut self) {
+        self.in_proximity = false;
+    }
+
+    fn suppresses(&self, at_us: u64, config: &PalmConfig) -> bool {
+        let since_us = at_us.saturating_sub(self.last_seen_us);
+        (self.in_proximity && since_us < config.proximity_timeout_ms * 1000)
+            || since_us < config.grace_ms * 1000
+    }
+}
+
+/// Drops touch contacts that are probably a resting palm.
+///
+/// A contact is rejected if it lands while the pen is in proximity (or
+/// within the grace period after), or if it is ever larger than the panel's
+/// threshold. Contacts rejected after their `Down` was delivered get a
+/// `Cancel` so the frontend can discard them instead of seeing a tap.
+pub struct PalmFilter {
+    config: PalmConfig,
+    threshold: ContactThreshold,
+    rejected: HashSet<i32>,
+    delivered: HashSet<i32>,
+}
+
+impl PalmFilter {
+    pub fn new(config: PalmConfig, device_name: &str) -> Self {
+        let threshold = config
+            .thresholds
+            .get(device_name)
+            .copied()
+            .unwrap_or_default();
+        Self {
+            config,
+            threshold,
+            rejected: HashSet::new(),
+            delivered: HashSet::new(),
+        }
+    }
+
+    fn oversized(&self, ev: &TouchEvent) -> bool {
+        self.threshold
+            .max_touch_major
+            .is_some_and(|max| ev.touch_major > max)
+            || self
+                .threshold
+                .max_width_major
+                .is_some_and(|max| ev.width_major > max)
+    }
+
+    pub fn filter(&mut self, mut ev: TouchEvent, pen: PenProximity) -> Option<TouchEvent> {
+        if !self.config.enabled {
+            return Some(ev);
+        }
+        let id = ev.contact_id;
+        let ended = ev.event_type == TouchEventKind::Up;
+
+        if self.rejected.contains(&id) {
+            if ended {
+                self.rejected.remove(&id);
+            }
+            return None;
+        }
+
+        let reject = self.oversized(&ev)
+            || (ev.event_type == TouchEventKind::Down
+                && pen.suppresses(ev.timestamp_us, &self.config));
+        if reject {
+            let was_delivered = self.delivered.remove(&id);
+            if !ended {
+                self.rejected.insert(id);
+            }
+            if was_delivered {
+                ev.event_type = TouchEventKind::Cancel;
+                return Some(ev);
+            }
+            return None;
+        }
+
+        if ended {
+            self.delivered.remove(&id);
+        } else {
+            self.delivered.insert(id);
+        }
+        Some(ev)
+    }
+}
+
+/// Palm rejection config plus the pen proximity shared between the pen
+/// and touch streams, which usually come from separate devices.
+#[derive(Default)]
+pub struct PalmState {
+    config: RwLock<Option<PalmConfig>>,
+    pen: Arc<Mutex<PenProximity>>,
+}
+
+impl PalmState {
+    pub fn config(&self, app: &AppHandle) -> Result<PalmConfig, String> {
+        if let Some(config) = self.config.read().map_err(|e| e.to_string())?.as_ref() {
+            return Ok(config.clone());
+        }
+        let mut slot = self.config.write().map_err(|e| e.to_string())?;
+        Ok(slot.get_or_insert_with(|| read_config(app)).clone())
+    }
+
+    fn set_config(&self, app: &AppHandle, config: PalmConfig) -> Result<(), String> {
+        write_config(app, &config)?;
+        *self.config.write().map_err(|e| e.to_string())? = Some(config);
+        Ok(())
+    }
+
+    pub fn pen(&self) -> Arc<Mutex<PenProximity>> {
+        self.pen.clone()
+    }
+}
+
+fn config_path(app: &AppHandle) -> Result<PathBuf, String> {
+    let config_dir = app
+        .path()
+        .app_config_dir()
+        .map_err(|e| format!("Failed to get app config dir: {e}"))?;
+    Ok(config_dir.join("palm-rejection.json"))
+}
+
+fn read_config(app: &AppHandle) -> PalmConfig {
+    config_path(app)
+        .ok()
+        .and_then(|path| fs::read_to_string(path).ok())
+        .and_then(|data| serde_json::from_str(&data).ok())
+        .unwrap_or_default()
+}
+
+fn write_config(app: &AppHandle, config: &PalmConfig) -> Result<(), String> {
+    let path = config_path(app)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|e| format!("Failed to create config dir: {e}"))?;
+    }
+    let data =
+        serde_json::to_string_pretty(config).map_err(|e| format!("Failed to serialize: {e}"))?;
+    fs::write(&path, data).map_err(|e| format!("Failed to write palm-rejection.json: {e}"))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ContactSample {
+    pub touch_major: i32,
+    pub width_major: i32,
+}
+
+/// Headroom over the largest fingertip seen during calibration.
+const THRESHOLD_MARGIN: f64 = 1.5;
+
+#[tauri::command]
+pub fn get_palm_config(app: AppHandle, state: State<'_, PalmState>) -> Result<PalmConfig, String> {
+    state.config(&app)
+}
+
+/// Takes effect for touch streams started afterwards.
+#[tauri::command]
+pub fn set_palm_config(
+    app: AppHandle,
+    state: State<'_, PalmState>,
+    config: PalmConfig,
+) -> Result<(), String> {
+    state.set_config(&app, config)
+}
+
+/// Derives a panel's thresholds from fingertip touches, e.g. collected
+/// while asking the user to tap a few targets.
+#[tauri::command]
+pub fn calibrate_palm_rejection(
+    app: AppHandle,
+    state: State<'_, PalmState>,
+    device_path: String,
+    samples: Vec<ContactSample>,
+) -> Result<ContactThreshold, String> {
+    let device =
+        evdev::Device::open(&device_path).map_err(|e| format!("Failed to open device: {e}"))?;
+    let name = device.name().unwrap_or("Unknown").to_string();
+
+    let limit = |values: Vec<i32>| {
+        let max = values.into_iter().filter(|&v| v > 0).max()?;
+        Some((max as f64 * THRESHOLD_MARGIN).ceil() as i32)
+    };
+    let threshold = ContactThreshold {
+        max_touch_major: limit(samples.iter().map(|s| s.touch_major).collect()),
+        max_width_major: limit(samples.iter().map(|s| s.width_major).collect()),
+    };
+
+    let mut config = state.config(&app)?;
+    config.thresholds.insert(name, threshold);
+    state.set_config(&app, config)?;
+    Ok(threshold)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::StylusPipeline;
+    use crate::mapping::CoordinateMapper;
+    use crate::stylus::DeviceAxes;
+    use crate::touch::{MultitouchParser, TouchPipeline};
+    use crate::trace::{read_trace, replay_touch, TraceEvent};
+    use std::path::Path;
+
+    const EV_SYN: u16 = 0;
+    const EV_KEY: u16 = 1;
+    const EV_ABS: u16 = 3;
+    const ABS_X: u16 = 0;
+    const ABS_MT_SLOT: u16 = 47;
+    const ABS_MT_TOUCH_MAJOR: u16 = 48;
+    const ABS_MT_POSITION_X: u16 = 53;
+    const ABS_MT_TRACKING_ID: u16 = 57;
+    const BTN_TOOL_PEN: u16 = 320;
+    /// Traces start at a realistic kernel time rather than zero.
+    const START_US: u64 = 1_700_000_000_000_000;
+
+    #[derive(Clone, Copy)]
+    enum Source {
+        Pen,
+        Touch,
+    }
+
+    /// Events from a pen and a touch panel, interleaved by kernel time.
+    #[derive(Default)]
+    struct Trace(Vec<(Source, TraceEvent)>);
+
+    impl Trace {
+        fn frame(mut self, source: Source, ms: u64, events: &[(u16, u16, i32)]) -> Self {
+            let timestamp_us = START_US + ms * 1000;
+            let frame = events
+                .iter()
+                .chain(&[(EV_SYN, 0, 0)])
+                .map(|&(t, c, v)| TraceEvent {
+                    timestamp_us,
+                    event_type: t,
+                    code: c,
+                    value: v,
+                });
+            self.0.extend(frame.map(|e| (source, e)));
+            self
+        }
+
+        fn pen(self, ms: u64, events: &[(u16, u16, i32)]) -> Self {
+            self.frame(Source::Pen, ms, events)
+        }
+
+        fn pen_in(self, ms: u64) -> Self {
+            self.pen(ms, &[(EV_KEY, BTN_TOOL_PEN, 1), (EV_ABS, ABS_X, 500)])
+        }
+
+        fn pen_hover(self, ms: u64) -> Self {
+            self.pen(ms, &[(EV_ABS, ABS_X, 500 + ms as i32)])
+        }
+
+        fn pen_out(self, ms: u64) -> Self {
+            self.pen(ms, &[(EV_KEY, BTN_TOOL_PEN, 0)])
+        }
+
+        fn touch_down(self, ms: u64, id: i32, major: i32) -> Self {
+            self.frame(
+                Source::Touch,
+                ms,
+                &[
+                    (EV_ABS, ABS_MT_SLOT, 0),
+                    (EV_ABS, ABS_MT_TRACKING_ID, id),
+                    (EV_ABS, ABS_MT_POSITION_X, 100),
+                    (EV_ABS, ABS_MT_TOUCH_MAJOR, major),
+                ],
+            )
+        }
+
+        fn touch_move(self, ms: u64, major: i32) -> Self {
+            self.frame(
+                Source::Touch,
+                ms,
+                &[
+                    (EV_ABS, ABS_MT_SLOT, 0),
+                    (EV_ABS, ABS_MT_POSITION_X, 100 + ms as i32),
+                    (EV_ABS, ABS_MT_TOUCH_MAJOR, major),
+                ],
+            )
+        }
+
+        fn touch_up(self, ms: u64) -> Self {
+            self.frame(
+                Source::Touch,
+                ms,
+                &[(EV_ABS, ABS_MT_SLOT, 0), (EV_ABS, ABS_MT_TRACKING_ID, -1)],
+            )
+        }
+
+        /// Touch events that get through the live pen and touch pipelines,
+        /// which share the pen proximity.
+        fn run(
+            &self,
+            config: PalmConfig,
+            reset_pen_at_ms: Option<u64>,
+        ) -> Vec<(i32, TouchEventKind)> {
+            let pen = Arc::new(Mutex::new(PenProximity::default()));
+            let mapper = || CoordinateMapper::new(DeviceAxes::default(), Default::default());
+            let mut stylus = StylusPipeline::new(mapper(), pen.clone(), None);
+            let mut touch = TouchPipeline::new(
+                MultitouchParser::new(2),
+                PalmFilter::new(config, "panel"),
+                mapper(),
+                DeviceAxes::default(),
+                pen.clone(),
+                None,
+            );
+            let mut out = Vec::new();
+            for (source, e) in &self.0 {
+                if reset_pen_at_ms.is_some_and(|ms| e.timestamp_us >= START_US + ms * 1000) {
+                    pen.lock().unwrap().reset();
+                }
+                match source {
+                    Source::Pen => {
+                        stylus.feed_raw(e.event_type, e.code, e.value, e.timestamp_us);
+                    }
+                    Source::Touch => {
+                        let frame = touch.feed_raw(e.event_type, e.code, e.value, e.timestamp_us);
+                        out.extend(frame.events.iter().map(|ev| (ev.contact_id, ev.event_type)));
+                    }
+                }
+            }
+            out
+        }
+    }
+
+    fn with_threshold(max_touch_major: i32) -> PalmConfig {
+        let mut config = PalmConfig::default();
+        config.thresholds.insert(
+            "panel".into(),
+            ContactThreshold {
+                max_touch_major: Some(max_touch_major),
+                max_width_major: None,
+            },
+        );
+        config
+    }
+
+    #[test]
+    fn rejects_touch_while_pen_hovers() {
+        let trace = Trace::default()
+            .pen_in(0)
+            .pen_hover(10)
+            .touch_down(15, 1, 5)
+            .touch_move(20, 5)
+            .touch_up(30)
+            .pen_out(40);
+        assert!(trace.run(PalmConfig::default(), None).is_empty());
+    }
+
+    #[test]
+    fn grace_period_after_proximity_out() {
+        let trace = Trace::default()
+            .pen_in(0)
+            .pen_out(100)
+            .touch_down(400, 1, 5)
+            .touch_up(420)
+            .touch_down(700, 2, 5)
+            .touch_up(720);
+        assert_eq!(
+            trace.run(PalmConfig::default(), None),
+            vec![(2, TouchEventKind::Down), (2, TouchEventKind::Up)]
+        );
+    }
+
+    #[test]
+    fn oversized_contact_is_cancelled() {
+        let trace = Trace::default()
+            .touch_down(0, 1, 10)
+            .touch_move(10, 40)
+            .touch_move(20, 10)
+            .touch_up(30);
+        assert_eq!(
+            trace.run(with_threshold(20), None),
+            vec![(1, TouchEventKind::Down), (1, TouchEventKind::Cancel)]
+        );
+    }
+
+    #[test]
+    fn missing_proximity_out_expires() {
+        // The pen stream dies mid-hover and never reports leaving
+        let trace = Trace::default()
+            .pen_in(0)
+            .pen_hover(50)
+            .touch_down(1000, 1, 5)
+            .touch_up(1020)
+            .touch_down(2100, 2, 5)
+            .touch_up(2120);
+        assert_eq!(
+            trace.run(PalmConfig::default(), None),
+            vec![(2, TouchEventKind::Down), (2, TouchEventKind::Up)]
+        );
+    }
+
+    #[test]
+    fn lifted_pen_held_still_stays_in_proximity() {
+        const BTN_TOUCH: u16 = 330;
+        let trace = Trace::default()
+            .pen_in(0)
+            .pen(20, &[(EV_KEY, BTN_TOUCH, 1)])
+            .pen_hover(40)
+            .pen(60, &[(EV_KEY, BTN_TOUCH, 0)])
+            // No pen events while it hovers motionless above the page
+            .touch_down(700, 1, 5)
+            .touch_up(720)
+            .pen_out(900)
+            .touch_down(1500, 2, 5)
+            .touch_up(1520);
+        assert_eq!(
+            trace.run(PalmConfig::default(), None),
+            vec![(2, TouchEventKind::Down), (2, TouchEventKind::Up)]
+        );
+    }
+
+    #[test]
+    fn stopping_the_stylus_reader_clears_proximity() {
+        let trace = Trace::default()
+            .pen_in(0)
+            .pen_hover(50)
+            .touch_down(300, 1, 5)
+            .touch_up(320)
+            .touch_down(700, 2, 5)
+            .touch_up(720);
+        // Reset at 60 ms, then only the 500 ms grace applies
+        assert_eq!(
+            trace.run(PalmConfig::default(), Some(60)),
+            vec![(2, TouchEventKind::Down), (2, TouchEventKind::Up)]
+        );
+    }
+
+    #[test]
+    fn recorded_palm_rest_while_writing() {
+        let fixture = |name: &str| {
+            let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("tests/fixtures")
+                .join(name);
+            read_trace(&path.to_string_lossy()).unwrap()
+        };
+        let pen = fixture("palm-rest-pen.json");
+        let touch = fixture("palm-rest-touch.json");
+
+        // Contact 601 is the heel of the hand landing as the pen comes into
+        // range. 602 brushes the page 600 ms after the pen lifted and was
+        // held still above it, past the grace period but still in
+        // proximity. Only 603, tapped after the pen left, gets through.
+        let replay = replay_touch(&touch, Some(&pen), &PalmConfig::default());
+        let contacts: HashSet<i32> = replay.events.iter().map(|e| e.contact_id).collect();
+        assert_eq!(contacts, HashSet::from([603]));
+        assert_eq!(replay.events[0].event_type, TouchEventKind::Down);
+        assert_eq!(replay.events.last().unwrap().event_type, TouchEventKind::Up);
+
+        // Without the pen recording every contact is a touch
+        let replay = replay_touch(&touch, None, &PalmConfig::default());
+        let contacts: HashSet<i32> = replay.events.iter().map(|e| e.contact_id).collect();
+        assert_eq!(contacts, HashSet::from([601, 602, 603]));
+    }
+}
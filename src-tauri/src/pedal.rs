@@ -0,0 +1,642 @@
+use evdev::{Device, InputEvent, Key};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tauri::{AppHandle, Emitter, Manager, State};
+
+use crate::input::InputService;
+use crate::stylus::{DeviceCapabilities, DeviceKind, InputDeviceInfo};
+
+const EV_KEY: u16 = 1;
+
+// Keys sent by page turners out of the box
+const KEY_ENTER: u16 = 28;
+const KEY_SPACE: u16 = 57;
+const KEY_UP: u16 = 103;
+const KEY_PAGEUP: u16 = 104;
+const KEY_LEFT: u16 = 105;
+const KEY_RIGHT: u16 = 106;
+const KEY_DOWN: u16 = 108;
+const KEY_PAGEDOWN: u16 = 109;
+
+const PAGE_TURN_KEYS: [u16; 6] = [
+    KEY_UP,
+    KEY_PAGEUP,
+    KEY_LEFT,
+    KEY_RIGHT,
+    KEY_DOWN,
+    KEY_PAGEDOWN,
+];
+
+/// Name fragments of known page-turner vendors.
+const KNOWN_PEDALS: [&str; 5] = ["airturn", "pageflip", "page flip", "page turner", "pedal"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PedalAction {
+    NextPage,
+    PrevPage,
+    NextScore,
+    PrevScore,
+    FirstPage,
+    LastPage,
+    ToggleAnnotation,
+    ToggleFullscreen,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PressKind {
+    Tap,
+    LongPress,
+    DoubleTap,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PedalBinding {
+    pub key: u16,
+    pub press: PressKind,
+    pub action: PedalAction,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PedalConfig {
+    pub bindings: Vec<PedalBinding>,
+    #[serde(default = "default_long_press_ms")]
+    pub long_press_ms: u64,
+    #[serde(default = "default_double_tap_ms")]
+    pub double_tap_ms: u64,
+    /// Take the device exclusively so the webview doesn't see the keys too.
+    /// Only applies to devices classified as pedals; a keyboard bound as a
+    /// pedal is never grabbed, so it keeps working everywhere else.
+    #[serde(default = "default_grab")]
+    pub grab: bool,
+}
+
+fn default_long_press_ms() -> u64 {
+    600
+}
+
+fn default_double_tap_ms() -> u64 {
+    300
+}
+
+fn default_grab() -> bool {
+    true
+}
+
+impl Default for PedalConfig {
+    fn default() -> Self {
+        let tap = |key, action| PedalBinding {
+            key,
+            press: PressKind::Tap,
+            action,
+        };
+        Self {
+            bindings: vec![
+                tap(KEY_PAGEDOWN, PedalAction::NextPage),
+                tap(KEY_RIGHT, PedalAction::NextPage),
+                tap(KEY_DOWN, PedalAction::NextPage),
+                tap(KEY_SPACE, PedalAction::NextPage),
+                tap(KEY_ENTER, PedalAction::NextPage),
+                tap(KEY_PAGEUP, PedalAction::PrevPage),
+                tap(KEY_LEFT, PedalAction::PrevPage),
+                tap(KEY_UP, PedalAction::PrevPage),
+            ],
+            long_press_ms: default_long_press_ms(),
+            double_tap_ms: default_double_tap_ms(),
+            grab: default_grab(),
+        }
+    }
+}
+
+impl PedalConfig {
+    pub fn action(&self, key: u16, press: PressKind) -> Option<PedalAction> {
+        self.bindings
+            .iter()
+            .find(|b| b.key == key && b.press == press)
+            .map(|b| b.action)
+    }
+
+    fn is_bound(&self, key: u16, press: PressKind) -> bool {
+        self.action(key, press).is_some()
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PedalActionEvent {
+    pub device_path: String,
+    pub key: u16,
+    pub press: PressKind,
+    pub action: PedalAction,
+}
+
+#[derive(Debug, Default)]
+struct KeyState {
+    down_at: Option<Instant>,
+    long_fired: bool,
+    /// Release time of a tap that may still become a double tap.
+    pending_tap: Option<Instant>,
+}
+
+/// Turns key down/up into tap, long-press and double-tap gestures.
+///
+/// Gestures that aren't bound for a key are not waited for, so a pedal
+/// with only tap bindings fires on release with no added latency.
+pub struct GestureRecognizer {
+    keys: HashMap<u16, KeyState>,
+}
+
+impl GestureRecognizer {
+    pub fn new() -> Self {
+        Self {
+            keys: HashMap::new(),
+        }
+    }
+
+    pub fn press(&mut self, key: u16, now: Instant) {
+        let state = self.keys.entry(key).or_default();
+        state.down_at = Some(now);
+        state.long_fired = false;
+    }
+
+    pub fn release(&mut self, key: u16, now: Instant, config: &PedalConfig) -> Option<PressKind> {
+        let state = self.keys.entry(key).or_default();
+        let down_at = state.down_at.take()?;
+        if state.long_fired {
+            return None;
+        }
+
+        let long_press = Duration::from_millis(config.long_press_ms);
+        if config.is_bound(key, PressKind::LongPress) && now - down_at >= long_press {
+            state.pending_tap = None;
+            return Some(PressKind::LongPress);
+        }
+
+        if !config.is_bound(key, PressKind::DoubleTap) {
+            return Some(PressKind::Tap);
+        }
+        match state.pending_tap.take() {
+            Some(_) => Some(PressKind::DoubleTap),
+            None => {
+                state.pending_tap = Some(now);
+                None
+            }
+        }
+    }
+
+    /// Resolves gestures that complete by time passing: a held key becoming
+    /// a long press, or a single tap whose double-tap window ran out.
+    pub fn tick(&mut self, now: Instant, config: &PedalConfig) -> Vec<(u16, PressKind)> {
+        let long_press = Duration::from_millis(config.long_press_ms);
+        let double_tap = Duration::from_millis(config.double_tap_ms);
+        let mut fired = Vec::new();
+
+        for (&key, state) in self.keys.iter_mut() {
+            if let Some(down_at) = state.down_at {
+                if !state.long_fired
+                    && config.is_bound(key, PressKind::LongPress)
+                    && now - down_at >= long_press
+                {
+                    state.long_fired = true;
+                    state.pending_tap = None;
+                    fired.push((key, PressKind::LongPress));
+                }
+            }
+            if let Some(released_at) = state.pending_tap {
+                if state.down_at.is_none() && now - released_at >= double_tap {
+                    state.pending_tap = None;
+                    fired.push((key, PressKind::Tap));
+                }
+            }
+        }
+        fired
+    }
+
+    /// Earliest time `tick` could fire something, if anything is pending.
+    pub fn next_deadline(&self, config: &PedalConfig) -> Option<Instant> {
+        let long_press = Duration::from_millis(config.long_press_ms);
+        let double_tap = Duration::from_millis(config.double_tap_ms);
+        self.keys
+            .iter()
+            .filter_map(|(&key, state)| match (state.down_at, state.pending_tap) {
+                (Some(down_at), _)
+                    if !state.long_fired && config.is_bound(key, PressKind::LongPress) =>
+                {
+                    Some(down_at + long_press)
+                }
+                (None, Some(released_at)) => Some(released_at + double_tap),
+                _ => None,
+            })
+            .min()
+    }
+}
+
+impl Default for GestureRecognizer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Keyboard-class device that looks like a page turner: a known vendor, or
+/// a handful of navigation keys without letters, pointer axes or a pen.
+pub fn is_pedal(name: &str, caps: &DeviceCapabilities) -> bool {
+    if caps.keys.is_empty() {
+        return false;
+    }
+    let name = name.to_lowercase();
+    if KNOWN_PEDALS.iter().any(|n| name.contains(n)) {
+        return true;
+    }
+
+    if !caps.abs_axes.is_empty() || caps.has_rel {
+        return false;
+    }
+    let has_turn_keys = PAGE_TURN_KEYS.iter().any(|&k| caps.has_key(k));
+    let has_letters = (Key::KEY_Q.0..=Key::KEY_P.0).any(|k| caps.has_key(k));
+    has_turn_keys && !has_letters
+}
+
+#[derive(Default)]
+pub struct PedalState {
+    config: RwLock<Option<Arc<RwLock<PedalConfig>>>>,
+}
+
+impl PedalState {
+    /// Shared config, loaded from disk on first use. Running readers hold a
+    /// clone so binding changes apply without restarting them.
+    pub fn config(&self, app: &AppHandle) -> Result<Arc<RwLock<PedalConfig>>, String> {
+        if let Some(config) = self.config.read().map_err(|e| e.to_string())?.as_ref() {
+            return Ok(config.clone());
+        }
+        let mut slot = self.config.write().map_err(|e| e.to_string())?;
+        let config = slot.get_or_insert_with(|| Arc::new(RwLock::new(read_config(app))));
+        Ok(config.clone())
+    }
+}
+
+fn config_path(app: &AppHandle) -> Result<PathBuf, String> {
+    let config_dir = app
+        .path()
+        .app_config_dir()
+        .map_err(|e| format!("Failed to get app config dir: {e}"))?;
+    Ok(config_dir.join("pedals.json"))
+}
+
+fn read_config(app: &AppHandle) -> PedalConfig {
+    config_path(app)
+        .ok()
+        .and_then(|path| fs::read_to_string(path).ok())
+        .and_then(|data| serde_json::from_str(&data).ok())
+        .unwrap_or_default()
+}
+
+fn write_config(app: &AppHandle, config: &PedalConfig) -> Result<(), String> {
+    let path = config_path(app)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|e| format!("Failed to create config dir: {e}"))?;
+    }
+    let data =
+        serde_json::to_string_pretty(config).map_err(|e| format!("Failed to serialize: {e}"))?;
+    fs::write(&path, data).map_err(|e| format!("Failed to write pedals.json: {e}"))
+}
+
+#[derive(Debug, PartialEq)]
+enum KeyChange {
+    Down(u16),
+    Up(u16),
+}
+
+fn key_change(ev: &InputEvent) -> Option<KeyChange> {
+    if ev.event_type().0 != EV_KEY {
+        return None;
+    }
+    // Autorepeat (value 2) is ignored, long press is timed instead
+    match ev.value() {
+        1 => Some(KeyChange::Down(ev.code())),
+        0 => Some(KeyChange::Up(ev.code())),
+        _ => None,
+    }
+}
+
+fn should_grab(config: &PedalConfig, device_path: &str) -> bool {
+    config.grab
+        && Device::open(device_path).is_ok_and(|device| {
+            let name = device.name().unwrap_or_default();
+            DeviceCapabilities::of(&device).classify(name) == DeviceKind::Pedal
+        })
+}
+
+/// Runs the recognizer on its own thread so long-press and double-tap
+/// timeouts fire even while the device is silent.
+fn spawn_recognizer(
+    app: AppHandle,
+    device_path: String,
+    config: Arc<RwLock<PedalConfig>>,
+    rx: mpsc::Receiver<KeyChange>,
+) {
+    std::thread::spawn(move || {
+        let mut recognizer = GestureRecognizer::new();
+        loop {
+            let snapshot = match config.read() {
+                Ok(c) => c.clone(),
+                Err(_) => return,
+            };
+            let message = match recognizer.next_deadline(&snapshot) {
+                Some(deadline) => {
+                    rx.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                }
+                None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+
+            let now = Instant::now();
+            let mut fired = Vec::new();
+            match message {
+                Ok(KeyChange::Down(key)) => recognizer.press(key, now),
+                Ok(KeyChange::Up(key)) => {
+                    if let Some(press) = recognizer.release(key, now, &snapshot) {
+                        fired.push((key, press));
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+            fired.extend(recognizer.tick(now, &snapshot));
+
+            for (key, press) in fired {
+                if let Some(action) = snapshot.action(key, press) {
+                    let _ = app.emit(
+                        "pedal-action",
+                        PedalActionEvent {
+                            device_path: device_path.clone(),
+                            key,
+                            press,
+                            action,
+                        },
+                    );
+                }
+            }
+        }
+    });
+}
+
+#[tauri::command]
+pub fn list_pedal_devices() -> Vec<InputDeviceInfo> {
+    crate::stylus::list_input_devices()
+        .into_iter()
+        .filter(|d| d.kind == DeviceKind::Pedal)
+        .collect()
+}
+
+#[tauri::command]
+pub fn get_pedal_config(
+    app: AppHandle,
+    state: State<'_, PedalState>,
+) -> Result<PedalConfig, String> {
+    let config = state.config(&app)?;
+    let config = config.read().map_err(|e| e.to_string())?;
+    Ok(config.clone())
+}
+
+#[tauri::command]
+pub fn set_pedal_config(
+    app: AppHandle,
+    state: State<'_, PedalState>,
+    config: PedalConfig,
+) -> Result<(), String> {
+    write_config(&app, &config)?;
+    let shared = state.config(&app)?;
+    *shared.write().map_err(|e| e.to_string())? = config;
+    Ok(())
+}
+
+/// Starts listening to a pedal. Actions arrive as `pedal-action` events
+/// whether or not the window has focus.
+#[tauri::command]
+pub fn start_pedal(
+    app: AppHandle,
+    service: State<'_, InputService>,
+    state: State<'_, PedalState>,
+    device_path: String,
+) -> Result<(), String> {
+    let config = state.config(&app)?;
+    let grab = should_grab(&*config.read().map_err(|e| e.to_string())?, &device_path);
+    let (tx, rx) = mpsc::channel();
+    spawn_recognizer(app.clone(), device_path.clone(), config, rx);
+
+    let key = format!("pedal:{device_path}");
+    service.start(app, key, device_path, grab, move |ev| {
+        match key_change(ev) {
+            Some(change) => tx.send(change).is_ok(),
+            None => true,
+        }
+    })
+}
+
+#[tauri::command]
+pub fn stop_pedal(service: State<'_, InputService>, device_path: String) -> Result<bool, String> {
+    // Dropping the reader closes the channel, which ends the recognizer
+    service.stop(&format!("pedal:{device_path}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use evdev::uinput::{VirtualDevice, VirtualDeviceBuilder};
+    use evdev::{AttributeSet, EventType};
+    use std::thread::sleep;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    /// Defaults plus a long press on page down and a double tap on page up.
+    fn config() -> PedalConfig {
+        let mut config = PedalConfig::default();
+        config.bindings.push(PedalBinding {
+            key: KEY_PAGEDOWN,
+            press: PressKind::LongPress,
+            action: PedalAction::LastPage,
+        });
+        config.bindings.push(PedalBinding {
+            key: KEY_PAGEUP,
+            press: PressKind::DoubleTap,
+            action: PedalAction::FirstPage,
+        });
+        config
+    }
+
+    #[test]
+    fn tap_fires_on_release_without_double_tap_binding() {
+        let (config, t0) = (config(), Instant::now());
+        let mut recognizer = GestureRecognizer::new();
+        recognizer.press(KEY_PAGEDOWN, t0);
+        assert_eq!(
+            recognizer.release(KEY_PAGEDOWN, t0 + 80 * MS, &config),
+            Some(PressKind::Tap)
+        );
+        assert_eq!(recognizer.next_deadline(&config), None);
+    }
+
+    #[test]
+    fn long_press_fires_while_held() {
+        let (config, t0) = (config(), Instant::now());
+        let mut recognizer = GestureRecognizer::new();
+        recognizer.press(KEY_PAGEDOWN, t0);
+        assert_eq!(recognizer.next_deadline(&config), Some(t0 + 600 * MS));
+        assert!(recognizer.tick(t0 + 599 * MS, &config).is_empty());
+        assert_eq!(
+            recognizer.tick(t0 + 600 * MS, &config),
+            vec![(KEY_PAGEDOWN, PressKind::LongPress)]
+        );
+        assert_eq!(
+            recognizer.release(KEY_PAGEDOWN, t0 + 900 * MS, &config),
+            None
+        );
+    }
+
+    #[test]
+    fn double_tap_and_delayed_single_tap() {
+        let (config, t0) = (config(), Instant::now());
+        let mut recognizer = GestureRecognizer::new();
+        recognizer.press(KEY_PAGEUP, t0);
+        assert_eq!(recognizer.release(KEY_PAGEUP, t0 + 50 * MS, &config), None);
+        recognizer.press(KEY_PAGEUP, t0 + 150 * MS);
+        assert_eq!(
+            recognizer.release(KEY_PAGEUP, t0 + 200 * MS, &config),
+            Some(PressKind::DoubleTap)
+        );
+
+        recognizer.press(KEY_PAGEUP, t0 + 1000 * MS);
+        assert_eq!(
+            recognizer.release(KEY_PAGEUP, t0 + 1050 * MS, &config),
+            None
+        );
+        assert!(recognizer.tick(t0 + 1300 * MS, &config).is_empty());
+        assert_eq!(
+            recognizer.tick(t0 + 1350 * MS, &config),
+            vec![(KEY_PAGEUP, PressKind::Tap)]
+        );
+    }
+
+    fn open_node(pedal: &mut VirtualDevice) -> (Device, String) {
+        // udev creates the node shortly after the device appears
+        for _ in 0..50 {
+            let node = pedal
+                .enumerate_dev_nodes_blocking()
+                .unwrap()
+                .flatten()
+                .find(|p| p.to_string_lossy().contains("event"));
+            if let Some(device) = node.as_deref().and_then(|p| Device::open(p).ok()) {
+                return (device, node.unwrap().to_string_lossy().to_string());
+            }
+            sleep(20 * MS);
+        }
+        panic!("Virtual pedal has no event node");
+    }
+
+    /// Sends one key change through the virtual device, reads it back as
+    /// the reader would and feeds it to the recognizer.
+    fn send(
+        pedal: &mut VirtualDevice,
+        device: &mut Device,
+        recognizer: &mut GestureRecognizer,
+        config: &PedalConfig,
+        key: u16,
+        value: i32,
+    ) -> Vec<(u16, PressKind)> {
+        pedal
+            .emit(&[InputEvent::new(EventType::KEY, key, value)])
+            .unwrap();
+        loop {
+            let changes: Vec<KeyChange> = device
+                .fetch_events()
+                .unwrap()
+                .filter_map(|ev| key_change(&ev))
+                .collect();
+            if let Some(change) = changes.into_iter().next() {
+                let now = Instant::now();
+                return match change {
+                    KeyChange::Down(key) => {
+                        recognizer.press(key, now);
+                        Vec::new()
+                    }
+                    KeyChange::Up(key) => recognizer
+                        .release(key, now, config)
+                        .into_iter()
+                        .map(|p| (key, p))
+                        .collect(),
+                };
+            }
+        }
+    }
+
+    /// Waits for the recognizer's next deadline and ticks it.
+    fn settle(recognizer: &mut GestureRecognizer, config: &PedalConfig) -> Vec<(u16, PressKind)> {
+        if let Some(deadline) = recognizer.next_deadline(config) {
+            sleep(deadline.saturating_duration_since(Instant::now()) + 5 * MS);
+        }
+        recognizer.tick(Instant::now(), config)
+    }
+
+    /// Drives a uinput page turner through tap, long press and double tap.
+    /// Needs write access to `/dev/uinput`; run with `--ignored`.
+    #[test]
+    #[ignore = "needs /dev/uinput"]
+    fn virtual_pedal_gestures() {
+        let mut keys = AttributeSet::<Key>::new();
+        keys.insert(Key::KEY_PAGEUP);
+        keys.insert(Key::KEY_PAGEDOWN);
+        let mut pedal = VirtualDeviceBuilder::new()
+            .unwrap()
+            .name("Virtual Page Turner")
+            .with_keys(&keys)
+            .unwrap()
+            .build()
+            .unwrap();
+        let (mut device, node) = open_node(&mut pedal);
+
+        let caps = DeviceCapabilities::of(&device);
+        assert_eq!(
+            caps.classify(device.name().unwrap_or_default()),
+            DeviceKind::Pedal
+        );
+        let config = config();
+        assert!(should_grab(&config, &node));
+        device.grab().unwrap();
+
+        let mut recognizer = GestureRecognizer::new();
+        let r = &mut recognizer;
+        let (p, d, c) = (&mut pedal, &mut device, &config);
+
+        // Tap
+        assert!(send(p, d, r, c, KEY_PAGEDOWN, 1).is_empty());
+        sleep(50 * MS);
+        let fired = send(p, d, r, c, KEY_PAGEDOWN, 0);
+        assert_eq!(fired, vec![(KEY_PAGEDOWN, PressKind::Tap)]);
+        assert_eq!(
+            c.action(KEY_PAGEDOWN, PressKind::Tap),
+            Some(PedalAction::NextPage)
+        );
+
+        // Long press fires while the key is still down
+        send(p, d, r, c, KEY_PAGEDOWN, 1);
+        assert_eq!(settle(r, c), vec![(KEY_PAGEDOWN, PressKind::LongPress)]);
+        assert!(send(p, d, r, c, KEY_PAGEDOWN, 0).is_empty());
+
+        // Double tap
+        send(p, d, r, c, KEY_PAGEUP, 1);
+        assert!(send(p, d, r, c, KEY_PAGEUP, 0).is_empty());
+        sleep(50 * MS);
+        send(p, d, r, c, KEY_PAGEUP, 1);
+        let fired = send(p, d, r, c, KEY_PAGEUP, 0);
+        assert_eq!(fired, vec![(KEY_PAGEUP, PressKind::DoubleTap)]);
+
+        // A lone tap on a double-tap key fires once the window runs out
+        send(p, d, r, c, KEY_PAGEUP, 1);
+        assert!(send(p, d, r, c, KEY_PAGEUP, 0).is_empty());
+        assert_eq!(settle(r, c), vec![(KEY_PAGEUP, PressKind::Tap)]);
+    }
+}
@@ -0,0 +1,629 @@
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tauri::{AppHandle, Manager, State};
+use uuid::Uuid;
+
+use crate::library::{collect_entries, LibraryEntry};
+use crate::timestamp::Timestamp;
+
+/// Sessions shorter than this, e.g. a score opened by mistake, aren't kept.
+const MIN_SESSION_SECS: u64 = 30;
+
+/// Time spent on one page during a session.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PageVisit {
+    pub pdf: String,
+    pub page: u32,
+    pub seconds: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PracticeSession {
+    pub id: String,
+    pub score_id: String,
+    /// Title when practiced, so the log still reads well after a removal.
+    pub title: String,
+    pub started_at: Timestamp,
+    pub ended_at: Timestamp,
+    pub seconds: u64,
+    /// In order of first visit.
+    #[serde(default)]
+    pub pages: Vec<PageVisit>,
+    /// 1 to 5.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rating: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub notes: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PracticeLog {
+    pub sessions: Vec<PracticeSession>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PracticeTotal {
+    pub score_id: String,
+    pub title: String,
+    pub sessions: usize,
+    pub seconds: u64,
+    pub last_practiced_at: Timestamp,
+    pub average_rating: Option<f64>,
+}
+
+struct ActiveSession {
+    score_id: String,
+    title: String,
+    started_at: Timestamp,
+    pages: Vec<PageVisit>,
+    /// The page shown and since when.
+    current: Option<(String, u32, Timestamp)>,
+}
+
+impl ActiveSession {
+    /// Credits the time since the last page change to the page shown.
+    fn leave_page(&mut self, now: Timestamp) {
+        let Some((pdf, page, since)) = self.current.take() else {
+            return;
+        };
+        let seconds = now.seconds_since(since);
+        match self
+            .pages
+            .iter_mut()
+            .find(|v| v.pdf == pdf && v.page == page)
+        {
+            Some(visit) => visit.seconds += seconds,
+            None => self.pages.push(PageVisit { pdf, page, seconds }),
+        }
+    }
+
+    fn finish(mut self, id: String, now: Timestamp) -> PracticeSession {
+        self.leave_page(now);
+        PracticeSession {
+            id,
+            score_id: self.score_id,
+            title: self.title,
+            started_at: self.started_at,
+            ended_at: now,
+            seconds: now.seconds_since(self.started_at),
+            pages: self.pages,
+            rating: None,
+            notes: None,
+        }
+    }
+}
+
+/// Sessions in progress, keyed by session id. They are only written to the
+/// log when they end.
+#[derive(Default)]
+pub struct PracticeState {
+    active: Mutex<HashMap<String, ActiveSession>>,
+    /// Held while practice.json is read and rewritten, so concurrent edits
+    /// don't drop each other's changes.
+    log: Mutex<()>,
+}
+
+impl PracticeState {
+    /// Reads the log at `path`, applies `f` and writes it back unless `f`
+    /// fails.
+    fn modify_log_at<T>(
+        &self,
+        path: &Path,
+        f: impl FnOnce(&mut PracticeLog) -> Result<T, String>,
+    ) -> Result<T, String> {
+        let _guard = self.log.lock().map_err(|e| e.to_string())?;
+        let mut log = read_log_at(path)?;
+        let value = f(&mut log)?;
+        write_log_at(path, &log)?;
+        Ok(value)
+    }
+
+    fn modify_log<T>(
+        &self,
+        app: &AppHandle,
+        f: impl FnOnce(&mut PracticeLog) -> Result<T, String>,
+    ) -> Result<T, String> {
+        self.modify_log_at(&log_path(app)?, f)
+    }
+
+    /// Ends every session in progress, e.g. on exit.
+    pub fn end_all(&self, app: &AppHandle) -> Result<(), String> {
+        let now = Timestamp::now();
+        let sessions: Vec<PracticeSession> = self
+            .active
+            .lock()
+            .map_err(|e| e.to_string())?
+            .drain()
+            .map(|(id, active)| active.finish(id, now))
+            .filter(|s| s.seconds >= MIN_SESSION_SECS)
+            .collect();
+        if sessions.is_empty() {
+            return Ok(());
+        }
+        self.modify_log(app, |log| {
+            log.sessions.extend(sessions);
+            Ok(())
+        })
+    }
+}
+
+fn log_path(app: &AppHandle) -> Result<PathBuf, String> {
+    let data_dir = app
+        .path()
+        .app_data_dir()
+        .map_err(|e| format!("Failed to get app data dir: {e}"))?;
+    Ok(data_dir.join("practice.json"))
+}
+
+fn read_log(app: &AppHandle) -> Result<PracticeLog, String> {
+    read_log_at(&log_path(app)?)
+}
+
+fn read_log_at(path: &Path) -> Result<PracticeLog, String> {
+    if !path.exists() {
+        return Ok(PracticeLog::default());
+    }
+    let data =
+        fs::read_to_string(path).map_err(|e| format!("Failed to read practice.json: {e}"))?;
+    serde_json::from_str(&data).map_err(|e| format!("Failed to parse practice.json: {e}"))
+}
+
+/// Writes through a temp file, so readers never see a half-written log.
+fn write_log_at(path: &Path, log: &PracticeLog) -> Result<(), String> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|e| format!("Failed to create data dir: {e}"))?;
+    }
+    let data =
+        serde_json::to_string_pretty(log).map_err(|e| format!("Failed to serialize: {e}"))?;
+    let tmp_path = path.with_extension("json.tmp");
+    fs::write(&tmp_path, data).map_err(|e| format!("Failed to write practice.json: {e}"))?;
+    fs::rename(&tmp_path, path).map_err(|e| {
+        let _ = fs::remove_file(&tmp_path);
+        format!("Failed to replace practice.json: {e}")
+    })
+}
+
+/// Sessions that started within `[since, until)`, oldest first.
+fn sessions_between(
+    log: PracticeLog,
+    since: Option<Timestamp>,
+    until: Option<Timestamp>,
+) -> Vec<PracticeSession> {
+    let mut sessions: Vec<PracticeSession> = log
+        .sessions
+        .into_iter()
+        .filter(|s| {
+            since.is_none_or(|t| s.started_at >= t) && until.is_none_or(|t| s.started_at < t)
+        })
+        .collect();
+    sessions.sort_by_key(|s| s.started_at);
+    sessions
+}
+
+fn check_rating(rating: Option<u8>) -> Result<(), String> {
+    match rating {
+        Some(r) if !(1..=5).contains(&r) => Err("Rating must be 1 to 5".into()),
+        _ => Ok(()),
+    }
+}
+
+/// Quotes a field where needed. Text that a spreadsheet would run as a
+/// formula gets a leading `'`.
+fn csv_field(value: &str) -> String {
+    let value = if value.starts_with(['=', '+', '-', '@']) {
+        format!("'{value}")
+    } else {
+        value.to_string()
+    };
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+/// Starts timing practice of a score and returns the session id.
+#[tauri::command]
+pub fn start_practice_session(
+    app: AppHandle,
+    state: State<'_, PracticeState>,
+    score_id: String,
+    pdf: Option<String>,
+    page: Option<u32>,
+) -> Result<String, String> {
+    let title = collect_entries(&app, None)?
+        .into_iter()
+        .find(|e| e.id == score_id)
+        .map(|e| e.title)
+        .ok_or_else(|| format!("Score not found: {score_id}"))?;
+    let now = Timestamp::now();
+    let id = Uuid::new_v4().to_string();
+    state.active.lock().map_err(|e| e.to_string())?.insert(
+        id.clone(),
+        ActiveSession {
+            score_id,
+            title,
+            started_at: now,
+            pages: Vec::new(),
+            current: pdf.zip(page).map(|(pdf, page)| (pdf, page, now)),
+        },
+    );
+    Ok(id)
+}
+
+#[tauri::command]
+pub fn practice_page_changed(
+    state: State<'_, PracticeState>,
+    session_id: String,
+    pdf: String,
+    page: u32,
+) -> Result<(), String> {
+    let mut active = state.active.lock().map_err(|e| e.to_string())?;
+    let session = active
+        .get_mut(&session_id)
+        .ok_or_else(|| format!("No practice session: {session_id}"))?;
+    let now = Timestamp::now();
+    session.leave_page(now);
+    session.current = Some((pdf, page, now));
+    Ok(())
+}
+
+/// Ends a session and adds it to the log. Returns `None` if it was too
+/// short to keep.
+#[tauri::command]
+pub fn end_practice_session(
+    app: AppHandle,
+    state: State<'_, PracticeState>,
+    session_id: String,
+    rating: Option<u8>,
+    notes: Option<String>,
+) -> Result<Option<PracticeSession>, String> {
+    check_rating(rating)?;
+    let active = state
+        .active
+        .lock()
+        .map_err(|e| e.to_string())?
+        .remove(&session_id)
+        .ok_or_else(|| format!("No practice session: {session_id}"))?;
+    let mut session = active.finish(session_id, Timestamp::now());
+    if session.seconds < MIN_SESSION_SECS {
+        return Ok(None);
+    }
+    session.rating = rating;
+    session.notes = notes.filter(|n| !n.trim().is_empty());
+
+    state.modify_log(&app, |log| {
+        log.sessions.push(session.clone());
+        Ok(())
+    })?;
+    Ok(Some(session))
+}
+
+/// Sets the rating and notes of a logged session, e.g. after the fact.
+#[tauri::command]
+pub fn update_practice_session(
+    app: AppHandle,
+    state: State<'_, PracticeState>,
+    session_id: String,
+    rating: Option<u8>,
+    notes: Option<String>,
+) -> Result<PracticeSession, String> {
+    check_rating(rating)?;
+    state.modify_log(&app, |log| {
+        let session = log
+            .sessions
+            .iter_mut()
+            .find(|s| s.id == session_id)
+            .ok_or_else(|| format!("Practice session not found: {session_id}"))?;
+        session.rating = rating;
+        session.notes = notes.filter(|n| !n.trim().is_empty());
+        Ok(session.clone())
+    })
+}
+
+#[tauri::command]
+pub fn delete_practice_session(
+    app: AppHandle,
+    state: State<'_, PracticeState>,
+    session_id: String,
+) -> Result<(), String> {
+    state.modify_log(&app, |log| {
+        let count = log.sessions.len();
+        log.sessions.retain(|s| s.id != session_id);
+        if log.sessions.len() == count {
+            return Err(format!("Practice session not found: {session_id}"));
+        }
+        Ok(())
+    })
+}
+
+/// Logged sessions, optionally of one score and started within
+/// `[since, until)`.
+#[tauri::command]
+pub fn get_practice_sessions(
+    app: AppHandle,
+    score_id: Option<String>,
+    since: Option<Timestamp>,
+    until: Option<Timestamp>,
+) -> Result<Vec<PracticeSession>, String> {
+    let mut sessions = sessions_between(read_log(&app)?, since, until);
+    if let Some(score_id) = score_id {
+        sessions.retain(|s| s.score_id == score_id);
+    }
+    Ok(sessions)
+}
+
+/// Time practiced per score for sessions started within `[since, until)`,
+/// most practiced first. "This week" is up to the caller, who knows the
+/// local start of the week.
+#[tauri::command]
+pub fn get_practice_totals(
+    app: AppHandle,
+    since: Option<Timestamp>,
+    until: Option<Timestamp>,
+) -> Result<Vec<PracticeTotal>, String> {
+    Ok(practice_totals(sessions_between(
+        read_log(&app)?,
+        since,
+        until,
+    )))
+}
+
+/// Totals per score of sessions given oldest first, most practiced first.
+fn practice_totals(sessions: Vec<PracticeSession>) -> Vec<PracticeTotal> {
+    let mut totals: Vec<PracticeTotal> = Vec::new();
+    let mut ratings: HashMap<String, Vec<u8>> = HashMap::new();
+    for session in sessions {
+        if let Some(rating) = session.rating {
+            ratings
+                .entry(session.score_id.clone())
+                .or_default()
+                .push(rating);
+        }
+        match totals.iter_mut().find(|t| t.score_id == session.score_id) {
+            Some(total) => {
+                total.sessions += 1;
+                total.seconds += session.seconds;
+                // Sessions come oldest first
+                total.title = session.title;
+                total.last_practiced_at = session.started_at;
+            }
+            None => totals.push(PracticeTotal {
+                score_id: session.score_id,
+                title: session.title,
+                sessions: 1,
+                seconds: session.seconds,
+                last_practiced_at: session.started_at,
+                average_rating: None,
+            }),
+        }
+    }
+    for total in &mut totals {
+        total.average_rating = ratings
+            .get(&total.score_id)
+            .map(|r| r.iter().map(|&n| n as f64).sum::<f64>() / r.len() as f64);
+    }
+    totals.sort_by_key(|t| Reverse(t.seconds));
+    totals
+}
+
+/// Scores not practiced in the last `days` days, including those never
+/// practiced, longest neglected first.
+#[tauri::command]
+pub fn get_unpracticed_scores(
+    app: AppHandle,
+    days: u32,
+    library_id: Option<String>,
+) -> Result<Vec<LibraryEntry>, String> {
+    let cutoff = Timestamp::now().minus_days(days);
+    let entries = collect_entries(&app, library_id.as_deref())?;
+    Ok(unpracticed(entries, read_log(&app)?, cutoff))
+}
+
+/// Entries last practiced before `cutoff` or never, never practiced first.
+fn unpracticed(
+    mut entries: Vec<LibraryEntry>,
+    log: PracticeLog,
+    cutoff: Timestamp,
+) -> Vec<LibraryEntry> {
+    let mut last: HashMap<String, Timestamp> = HashMap::new();
+    for session in log.sessions {
+        last.entry(session.score_id)
+            .and_modify(|t| *t = (*t).max(session.started_at))
+            .or_insert(session.started_at);
+    }
+
+    entries.retain(|e| last.get(&e.id).is_none_or(|&t| t < cutoff));
+    entries.sort_by_key(|e| last.get(&e.id).copied());
+    entries
+}
+
+/// Writes the sessions started within `[since, until)` as CSV, one row per
+/// session, and returns how many were written.
+#[tauri::command]
+pub fn export_practice_csv(
+    app: AppHandle,
+    path: String,
+    since: Option<Timestamp>,
+    until: Option<Timestamp>,
+) -> Result<usize, String> {
+    let sessions = sessions_between(read_log(&app)?, since, until);
+    let mut csv = String::from("started_at,ended_at,score,minutes,pages_visited,rating,notes\n");
+    for session in &sessions {
+        let row = [
+            session.started_at.to_string(),
+            session.ended_at.to_string(),
+            session.title.clone(),
+            format!("{:.1}", session.seconds as f64 / 60.0),
+            session.pages.len().to_string(),
+            session.rating.map(|r| r.to_string()).unwrap_or_default(),
+            session.notes.clone().unwrap_or_default(),
+        ];
+        let row: Vec<String> = row.iter().map(|f| csv_field(f)).collect();
+        csv.push_str(&row.join(","));
+        csv.push('\n');
+    }
+    fs::write(&path, csv).map_err(|e| format!("Failed to write CSV: {e}"))?;
+    Ok(sessions.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    fn at(value: &str) -> Timestamp {
+        Timestamp::parse(value).unwrap()
+    }
+
+    fn session(
+        score_id: &str,
+        started_at: &str,
+        seconds: u64,
+        rating: Option<u8>,
+    ) -> PracticeSession {
+        PracticeSession {
+            id: Uuid::new_v4().to_string(),
+            score_id: score_id.into(),
+            title: score_id.to_uppercase(),
+            started_at: at(started_at),
+            ended_at: at(started_at),
+            seconds,
+            pages: Vec::new(),
+            rating,
+            notes: None,
+        }
+    }
+
+    fn entry(id: &str) -> LibraryEntry {
+        LibraryEntry {
+            id: id.into(),
+            title: id.into(),
+            sha256: String::new(),
+            created_at: Default::default(),
+            last_opened_at: Default::default(),
+            library_id: None,
+        }
+    }
+
+    #[test]
+    fn sessions_between_is_half_open_and_oldest_first() {
+        let log = PracticeLog {
+            sessions: vec![
+                session("b", "2024-03-03T09:00:00Z", 60, None),
+                session("a", "2024-03-01T09:00:00Z", 60, None),
+                session("c", "2024-03-02T09:00:00+02:00", 60, None),
+                session("d", "2024-03-04T00:00:00Z", 60, None),
+            ],
+        };
+        let ids = |sessions: Vec<PracticeSession>| -> Vec<String> {
+            sessions.into_iter().map(|s| s.score_id).collect()
+        };
+        assert_eq!(
+            ids(sessions_between(log.clone(), None, None)),
+            ["a", "c", "b", "d"]
+        );
+        assert_eq!(
+            ids(sessions_between(
+                log,
+                Some(at("2024-03-02T07:00:00Z")),
+                Some(at("2024-03-04T00:00:00Z"))
+            )),
+            ["c", "b"]
+        );
+    }
+
+    #[test]
+    fn totals_add_up_per_score_most_practiced_first() {
+        let totals = practice_totals(vec![
+            session("a", "2024-03-01T09:00:00Z", 600, Some(2)),
+            session("b", "2024-03-01T10:00:00Z", 900, None),
+            session("a", "2024-03-02T09:00:00Z", 600, Some(5)),
+            session("a", "2024-03-03T09:00:00Z", 60, None),
+        ]);
+        assert_eq!(totals.len(), 2);
+        let (a, b) = (&totals[0], &totals[1]);
+        assert_eq!((a.score_id.as_str(), a.sessions, a.seconds), ("a", 3, 1260));
+        assert_eq!(a.last_practiced_at, at("2024-03-03T09:00:00Z"));
+        assert_eq!(a.average_rating, Some(3.5));
+        assert_eq!((b.score_id.as_str(), b.sessions, b.seconds), ("b", 1, 900));
+        assert_eq!(b.average_rating, None);
+    }
+
+    #[test]
+    fn unpracticed_scores_are_longest_neglected_first() {
+        let log = PracticeLog {
+            sessions: vec![
+                session("recent", "2024-03-09T09:00:00Z", 60, None),
+                session("old", "2024-02-01T09:00:00Z", 60, None),
+                session("older", "2024-01-01T09:00:00Z", 60, None),
+                // Practiced again since, so not neglected
+                session("recent", "2024-01-01T09:00:00Z", 60, None),
+            ],
+        };
+        let entries = ["recent", "old", "never", "older"].map(entry).to_vec();
+        let ids: Vec<String> = unpracticed(entries, log, at("2024-03-01T00:00:00Z"))
+            .into_iter()
+            .map(|e| e.id)
+            .collect();
+        assert_eq!(ids, ["never", "older", "old"]);
+    }
+
+    #[test]
+    fn csv_fields_are_quoted_and_formulas_defused() {
+        assert_eq!(csv_field("Etude"), "Etude");
+        assert_eq!(csv_field(""), "");
+        assert_eq!(csv_field("Bach, J. S."), "\"Bach, J. S.\"");
+        assert_eq!(csv_field("the \"slow\" bit"), "\"the \"\"slow\"\" bit\"");
+        assert_eq!(csv_field("bar 12\nagain"), "\"bar 12\nagain\"");
+        assert_eq!(csv_field("=HYPERLINK(\"x\")"), "\"'=HYPERLINK(\"\"x\"\")\"");
+        assert_eq!(csv_field("+1"), "'+1");
+        assert_eq!(csv_field("-2"), "'-2");
+        assert_eq!(csv_field("@sum"), "'@sum");
+        // Only a leading sign counts
+        assert_eq!(csv_field("a-b"), "a-b");
+    }
+
+    #[test]
+    fn concurrent_log_edits_are_all_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("practice.json");
+        let state = Arc::new(PracticeState::default());
+        let threads: Vec<_> = (0..8)
+            .map(|n| {
+                let (state, path) = (state.clone(), path.clone());
+                thread::spawn(move || {
+                    state
+                        .modify_log_at(&path, |log| {
+                            log.sessions.push(session(
+                                &n.to_string(),
+                                "2024-03-01T09:00:00Z",
+                                60,
+                                None,
+                            ));
+                            Ok(())
+                        })
+                        .unwrap()
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(read_log_at(&path).unwrap().sessions.len(), 8);
+        assert!(!path.with_extension("json.tmp").exists());
+
+        // A failed edit leaves the log as it was
+        let failed = state.modify_log_at(&path, |log| {
+            log.sessions.clear();
+            Err::<(), _>("no".to_string())
+        });
+        assert!(failed.is_err());
+        assert_eq!(read_log_at(&path).unwrap().sessions.len(), 8);
+    }
+}
@@ -0,0 +1,121 @@
+use image::DynamicImage;
+use pdfium_render::prelude::*;
+use tauri::{AppHandle, Manager};
+
+use crate::olscore::extract_pdf_if_needed;
+
+/// A grayscale page rendering, row by row.
+pub struct PageImage {
+    pub luma: Vec<u8>,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl PageImage {
+    pub fn is_dark(&self, x: usize, y: usize, threshold: u8) -> bool {
+        self.luma[y * self.width + x] < threshold
+    }
+}
+
+/// Binds the pdfium library bundled with the app, or the system's.
+fn bind_pdfium(app: &AppHandle) -> Result<Pdfium, String> {
+    let bundled = app.path().resource_dir().ok().and_then(|dir| {
+        Pdfium::bind_to_library(Pdfium::pdfium_platform_library_name_at_path(&dir)).ok()
+    });
+    let bindings = match bundled {
+        Some(bindings) => bindings,
+        None => {
+            Pdfium::bind_to_system_library().map_err(|e| format!("Failed to load pdfium: {e}"))?
+        }
+    };
+    Ok(Pdfium::new(bindings))
+}
+
+/// Renders every page of one of the score's PDFs `width` pixels wide and
+/// hands them to `f` in order.
+pub fn render_pages(
+    app: &AppHandle,
+    score_id: &str,
+    pdf_filename: &str,
+    width: i32,
+    mut f: impl FnMut(u32, PageImage) -> Result<(), String>,
+) -> Result<(), String> {
+    let pdfium = bind_pdfium(app)?;
+    let path = extract_pdf_if_needed(app, score_id, pdf_filename)?;
+    let document = pdfium
+        .load_pdf_from_file(&path, None)
+        .map_err(|e| format!("Failed to open {pdf_filename}: {e}"))?;
+    let config = PdfRenderConfig::new().set_target_width(width);
+    for (index, page) in document.pages().iter().enumerate() {
+        let image = page
+            .render_with_config(&config)
+            .map_err(|e| format!("Failed to render page {index}: {e}"))?
+            .as_image()
+            .to_luma8();
+        let (width, height) = (image.width() as usize, image.height() as usize);
+        f(
+            index as u32,
+            PageImage {
+                luma: image.into_raw(),
+                width,
+                height,
+            },
+        )?;
+    }
+    Ok(())
+}
+
+/// Page sizes in points of one of the score's PDFs.
+pub fn page_sizes(
+    app: &AppHandle,
+    score_id: &str,
+    pdf_filename: &str,
+) -> Result<Vec<(f64, f64)>, String> {
+    let pdfium = bind_pdfium(app)?;
+    let path = extract_pdf_if_needed(app, score_id, pdf_filename)?;
+    let document = pdfium
+        .load_pdf_from_file(&path, None)
+        .map_err(|e| format!("Failed to open {pdf_filename}: {e}"))?;
+    Ok(document
+        .pages()
+        .iter()
+        .map(|p| (p.width().value as f64, p.height().value as f64))
+        .collect())
+}
+
+/// Number of pages of a PDF that isn't stored yet, e.g. one being imported.
+pub fn page_count(app: &AppHandle, pdf_bytes: &[u8]) -> Result<u32, String> {
+    count_pages(&bind_pdfium(app)?, pdf_bytes)
+}
+
+pub fn count_pages(pdfium: &Pdfium, pdf_bytes: &[u8]) -> Result<u32, String> {
+    let document = pdfium
+        .load_pdf_from_byte_slice(pdf_bytes, None)
+        .map_err(|e| format!("Failed to open PDF: {e}"))?;
+    Ok(document.pages().len() as u32)
+}
+
+/// Width of generated pages: A4, with the height following each image.
+const GENERATED_PAGE_WIDTH: f32 = 595.0;
+
+/// Builds a PDF with one full-page image per page.
+pub fn images_to_pdf(app: &AppHandle, images: &[DynamicImage]) -> Result<Vec<u8>, String> {
+    let pdfium = bind_pdfium(app)?;
+    let mut document = pdfium
+        .create_new_pdf()
+        .map_err(|e| format!("Failed to create PDF: {e}"))?;
+    for (index, image) in images.iter().enumerate() {
+        let width = PdfPoints::new(GENERATED_PAGE_WIDTH);
+        let height = width * (image.height() as f32 / image.width() as f32);
+        let object = PdfPageImageObject::new_with_size(&document, image, width, height)
+            .map_err(|e| format!("Failed to embed image {index}: {e}"))?;
+        document
+            .pages_mut()
+            .create_page_at_end(PdfPagePaperSize::from_points(width, height))
+            .and_then(|mut page| page.objects_mut().add_image_object(object))
+            .map_err(|e| format!("Failed to add page {index}: {e}"))?;
+    }
+    document
+        .save_to_bytes()
+        .map_err(|e| format!("Failed to write PDF: {e}"))
+}
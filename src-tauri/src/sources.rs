@@ -0,0 +1,581 @@
+use roxmltree::{Document, Node, ParsingOptions};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::{Cursor, Read};
+use std::path::Path;
+use tauri::AppHandle;
+
+use crate::olscore::{
+    modify_archive, read_manifest_at, score_path, sync_library_title, ArchiveChanges,
+    ScoreManifest, SourceEntry, SourceFormat, SourceMetadata,
+};
+
+const MEI_NS: &str = "http://www.music-encoding.org/ns/mei";
+
+fn archive_name(filename: &str) -> String {
+    format!("sources/{filename}")
+}
+
+fn format_of(filename: &str) -> Result<SourceFormat, String> {
+    let lower = filename.to_lowercase();
+    if lower.ends_with(".mxl") {
+        Ok(SourceFormat::Mxl)
+    } else if lower.ends_with(".musicxml") || lower.ends_with(".xml") {
+        Ok(SourceFormat::MusicXml)
+    } else if lower.ends_with(".mei") {
+        Ok(SourceFormat::Mei)
+    } else {
+        Err(format!("Unsupported source file: {filename}"))
+    }
+}
+
+/// Name of a key from its number of sharps (negative for flats).
+fn key_name(fifths: i32, minor: bool) -> Option<String> {
+    const MAJOR: [&str; 15] = [
+        "Cb", "Gb", "Db", "Ab", "Eb", "Bb", "F", "C", "G", "D", "A", "E", "B", "F#", "C#",
+    ];
+    const MINOR: [&str; 15] = [
+        "Ab", "Eb", "Bb", "F", "C", "G", "D", "A", "E", "B", "F#", "C#", "G#", "D#", "A#",
+    ];
+    let index = usize::try_from(fifths + 7).ok()?;
+    let (names, mode) = if minor {
+        (MINOR, "minor")
+    } else {
+        (MAJOR, "major")
+    };
+    Some(format!("{} {mode}", names.get(index)?))
+}
+
+fn parse(xml: &str) -> Result<Document<'_>, String> {
+    let options = ParsingOptions {
+        allow_dtd: true,
+        ..ParsingOptions::default()
+    };
+    Document::parse_with_options(xml, options).map_err(|e| format!("Failed to parse XML: {e}"))
+}
+
+fn descendant<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+    node.descendants().find(|n| n.has_tag_name(name))
+}
+
+fn child_text(node: Node, name: &str) -> Option<String> {
+    node.children()
+        .find(|n| n.has_tag_name(name))
+        .and_then(|n| n.text())
+        .map(|t| t.trim().to_string())
+        .filter(|t| !t.is_empty())
+}
+
+fn text(node: Node) -> Option<String> {
+    let text: String = node
+        .descendants()
+        .filter(|n| n.is_text())
+        .filter_map(|n| n.text())
+        .collect();
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    (!text.is_empty()).then_some(text)
+}
+
+fn musicxml_metadata(xml: &str) -> Result<SourceMetadata, String> {
+    let document = parse(xml)?;
+    let root = document.root_element();
+    if !matches!(root.tag_name().name(), "score-partwise" | "score-timewise") {
+        return Err("Not a MusicXML score".into());
+    }
+
+    let title = descendant(root, "work")
+        .and_then(|w| child_text(w, "work-title"))
+        .or_else(|| child_text(root, "movement-title"));
+    let composer = descendant(root, "identification").and_then(|id| {
+        id.children()
+            .find(|n| n.has_tag_name("creator") && n.attribute("type") == Some("composer"))
+            .and_then(text)
+    });
+    let parts = descendant(root, "part-list")
+        .map(|list| {
+            list.children()
+                .filter(|n| n.has_tag_name("score-part"))
+                .filter_map(|p| child_text(p, "part-name").or(p.attribute("id").map(String::from)))
+                .collect()
+        })
+        .unwrap_or_default();
+    let key = descendant(root, "key").and_then(|k| {
+        let fifths = child_text(k, "fifths")?.parse().ok()?;
+        key_name(fifths, child_text(k, "mode").as_deref() == Some("minor"))
+    });
+    let time = descendant(root, "time").and_then(|t| {
+        Some(format!(
+            "{}/{}",
+            child_text(t, "beats")?,
+            child_text(t, "beat-type")?
+        ))
+    });
+    // Partwise scores repeat the measures in every part
+    let measures = match root.tag_name().name() {
+        "score-partwise" => root
+            .children()
+            .find(|n| n.has_tag_name("part"))
+            .map_or(0, |p| {
+                p.children().filter(|n| n.has_tag_name("measure")).count()
+            }),
+        _ => root
+            .children()
+            .filter(|n| n.has_tag_name("measure"))
+            .count(),
+    };
+
+    Ok(SourceMetadata {
+        title,
+        composer,
+        parts,
+        key,
+        time,
+        measures: measures as u32,
+    })
+}
+
+/// MEI writes key signatures as e.g. "3f" or "2s".
+fn mei_key(signature: &str, mode: Option<&str>) -> Option<String> {
+    let (count, accidental) = signature.split_at(signature.len().checked_sub(1)?);
+    let count: i32 = if count.is_empty() {
+        0
+    } else {
+        count.parse().ok()?
+    };
+    let fifths = match accidental {
+        "s" => count,
+        "f" => -count,
+        "0" => 0,
+        _ => return None,
+    };
+    key_name(fifths, mode == Some("minor"))
+}
+
+fn mei_metadata(xml: &str) -> Result<SourceMetadata, String> {
+    let document = parse(xml)?;
+    let root = document.root_element();
+    if root.tag_name().namespace() != Some(MEI_NS) {
+        return Err("Not an MEI document".into());
+    }
+    let title_stmt = descendant(root, "titleStmt");
+    let title = title_stmt
+        .and_then(|t| t.children().find(|n| n.has_tag_name("title")))
+        .and_then(text);
+    let composer = title_stmt.and_then(|t| {
+        t.descendants()
+            .find(|n| {
+                n.has_tag_name("composer")
+                    || (n.has_tag_name("persName") && n.attribute("role") == Some("composer"))
+            })
+            .and_then(text)
+    });
+
+    let music = descendant(root, "music");
+    let parts = music
+        .map(|m| {
+            m.descendants()
+                .filter(|n| n.has_tag_name("staffDef"))
+                .filter_map(|s| {
+                    s.attribute("label").map(String::from).or_else(|| {
+                        s.children()
+                            .find(|n| n.has_tag_name("label"))
+                            .and_then(text)
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let score_def = music.and_then(|m| descendant(m, "scoreDef"));
+    let key = score_def.and_then(|d| match d.attribute("key.sig") {
+        Some(sig) => mei_key(sig, d.attribute("key.mode")),
+        None => {
+            descendant(d, "keySig").and_then(|k| mei_key(k.attribute("sig")?, k.attribute("mode")))
+        }
+    });
+    let time = score_def.and_then(|d| {
+        let meter = |node: Node, count: &str, unit: &str| {
+            Some(format!(
+                "{}/{}",
+                node.attribute(count)?,
+                node.attribute(unit)?
+            ))
+        };
+        meter(d, "meter.count", "meter.unit")
+            .or_else(|| descendant(d, "meterSig").and_then(|m| meter(m, "count", "unit")))
+    });
+    let measures = music.map_or(0, |m| {
+        m.descendants()
+            .filter(|n| n.has_tag_name("measure"))
+            .count()
+    });
+
+    Ok(SourceMetadata {
+        title,
+        composer,
+        parts,
+        key,
+        time,
+        measures: measures as u32,
+    })
+}
+
+/// The score document inside an `.mxl`, as named by its container file.
+fn mxl_root(bytes: &[u8]) -> Result<String, String> {
+    let mut archive =
+        zip::ZipArchive::new(Cursor::new(bytes)).map_err(|e| format!("Failed to read MXL: {e}"))?;
+    let read = |archive: &mut zip::ZipArchive<Cursor<&[u8]>>, name: &str| {
+        let mut entry = archive
+            .by_name(name)
+            .map_err(|e| format!("{name} not found in MXL: {e}"))?;
+        let mut buf = String::new();
+        entry
+            .read_to_string(&mut buf)
+            .map_err(|e| format!("Failed to read {name}: {e}"))?;
+        Ok::<_, String>(buf)
+    };
+
+    let container = read(&mut archive, "META-INF/container.xml")?;
+    let document = parse(&container)?;
+    let root_path = descendant(document.root_element(), "rootfile")
+        .and_then(|r| r.attribute("full-path"))
+        .ok_or("MXL container names no score")?
+        .to_string();
+    read(&mut archive, &root_path)
+}
+
+/// The XML text of a source file; `.mxl` files are unpacked.
+fn source_xml(bytes: &[u8], format: SourceFormat) -> Result<String, String> {
+    match format {
+        SourceFormat::Mxl => mxl_root(bytes),
+        SourceFormat::MusicXml | SourceFormat::Mei => {
+            String::from_utf8(bytes.to_vec()).map_err(|e| format!("Source is not UTF-8: {e}"))
+        }
+    }
+}
+
+fn metadata(xml: &str, format: SourceFormat) -> Result<SourceMetadata, String> {
+    match format {
+        SourceFormat::MusicXml | SourceFormat::Mxl => musicxml_metadata(xml),
+        SourceFormat::Mei => mei_metadata(xml),
+    }
+}
+
+fn read_source_bytes(olscore_path: &Path, filename: &str) -> Result<Vec<u8>, String> {
+    let file = fs::File::open(olscore_path).map_err(|e| format!("Failed to open .olscore: {e}"))?;
+    let mut archive = zip::ZipArchive::new(file).map_err(|e| format!("Failed to read ZIP: {e}"))?;
+    let mut entry = archive
+        .by_name(&archive_name(filename))
+        .map_err(|e| format!("Source not found in archive: {e}"))?;
+    let mut buf = Vec::new();
+    entry
+        .read_to_end(&mut buf)
+        .map_err(|e| format!("Failed to read source: {e}"))?;
+    Ok(buf)
+}
+
+/// Adds a MusicXML, MXL or MEI file to the score. Its title and composer
+/// fill in missing manifest fields, or replace them with `use_metadata`.
+#[tauri::command]
+pub fn attach_source(
+    app: AppHandle,
+    score_id: String,
+    source_path: String,
+    use_metadata: bool,
+) -> Result<ScoreManifest, String> {
+    let filename = Path::new(&source_path)
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or("Invalid source path")?
+        .to_string();
+    let format = format_of(&filename)?;
+    let bytes = fs::read(&source_path).map_err(|e| format!("Failed to read source: {e}"))?;
+    let metadata = metadata(&source_xml(&bytes, format)?, format)?;
+
+    let mut hasher = Sha256::new();
+    hasher.update(&bytes);
+    let sha256 = format!("{:x}", hasher.finalize());
+
+    let manifest = modify_archive(&score_path(&app, &score_id)?, |manifest| {
+        if manifest.sources.iter().any(|s| s.filename == filename) {
+            return Err(format!("A source named {filename} is already attached"));
+        }
+
+        if use_metadata || manifest.title.is_none() {
+            manifest.title = metadata.title.clone().or(manifest.title.take());
+        }
+        if use_metadata || manifest.composer.is_none() {
+            manifest.composer = metadata.composer.clone().or(manifest.composer.take());
+        }
+        manifest.sources.push(SourceEntry {
+            filename: filename.clone(),
+            format,
+            sha256,
+            metadata,
+        });
+        Ok(ArchiveChanges {
+            entries: vec![(archive_name(&filename), bytes)],
+            ..ArchiveChanges::default()
+        })
+    })?;
+    sync_library_title(&app, &score_id, &manifest)?;
+    Ok(manifest)
+}
+
+/// The XML of an attached source, unpacked if it is an `.mxl`.
+#[tauri::command]
+pub fn get_source(app: AppHandle, score_id: String, filename: String) -> Result<String, String> {
+    let olscore_path = score_path(&app, &score_id)?;
+    let manifest = read_manifest_at(&olscore_path)?;
+    let entry = manifest
+        .sources
+        .iter()
+        .find(|s| s.filename == filename)
+        .ok_or_else(|| format!("No source named {filename}"))?;
+    source_xml(&read_source_bytes(&olscore_path, &filename)?, entry.format)
+}
+
+#[tauri::command]
+pub fn remove_source(
+    app: AppHandle,
+    score_id: String,
+    filename: String,
+) -> Result<ScoreManifest, String> {
+    modify_archive(&score_path(&app, &score_id)?, |manifest| {
+        let before = manifest.sources.len();
+        manifest.sources.retain(|s| s.filename != filename);
+        if manifest.sources.len() == before {
+            return Err(format!("No source named {filename}"));
+        }
+        Ok(ArchiveChanges {
+            remove: vec![archive_name(&filename)],
+            ..ArchiveChanges::default()
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use zip::write::FileOptions;
+
+    const PARTWISE: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE score-partwise PUBLIC "-//Recordare//DTD MusicXML 4.0 Partwise//EN"
+  "http://www.musicxml.org/dtds/partwise.dtd">
+<score-partwise version="4.0">
+  <work><work-title>Nocturne</work-title></work>
+  <movement-title>Ignored</movement-title>
+  <identification>
+    <creator type="lyricist">Someone Else</creator>
+    <creator type="composer">Frédéric
+      Chopin</creator>
+  </identification>
+  <part-list>
+    <score-part id="P1"><part-name>Violin</part-name></score-part>
+    <part-group type="start"/>
+    <score-part id="P2"><part-name> </part-name></score-part>
+  </part-list>
+  <part id="P1">
+    <measure number="1">
+      <attributes>
+        <key><fifths>-3</fifths><mode>minor</mode></key>
+        <time><beats>3</beats><beat-type>4</beat-type></time>
+      </attributes>
+    </measure>
+    <measure number="2"/>
+    <measure number="3"/>
+  </part>
+  <part id="P2">
+    <measure number="1"/>
+    <measure number="2"/>
+    <measure number="3"/>
+  </part>
+</score-partwise>"#;
+
+    const TIMEWISE: &str = r#"<score-timewise version="4.0">
+  <movement-title>Étude</movement-title>
+  <part-list>
+    <score-part id="P1"><part-name>Piano</part-name></score-part>
+  </part-list>
+  <measure number="1">
+    <part id="P1">
+      <attributes>
+        <key><fifths>2</fifths></key>
+        <time><beats>6</beats><beat-type>8</beat-type></time>
+      </attributes>
+    </part>
+  </measure>
+  <measure number="2"><part id="P1"/></measure>
+  <measure number="3"><part id="P1"/></measure>
+  <measure number="4"><part id="P1"/></measure>
+</score-timewise>"#;
+
+    const MEI: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<mei xmlns="http://www.music-encoding.org/ns/mei" meiversion="5.0">
+  <meiHead>
+    <fileDesc>
+      <titleStmt>
+        <title>Sonata <rend>in</rend> B flat</title>
+        <respStmt><persName role="composer">Franz Schubert</persName></respStmt>
+      </titleStmt>
+    </fileDesc>
+  </meiHead>
+  <music><body><mdiv><score>
+    <scoreDef key.sig="2f" meter.count="3" meter.unit="4">
+      <staffGrp>
+        <staffDef n="1" label="Right hand"/>
+        <staffDef n="2"><label>Left hand</label></staffDef>
+      </staffGrp>
+    </scoreDef>
+    <section>
+      <measure n="1"/>
+      <measure n="2"/>
+    </section>
+  </score></mdiv></body></music>
+</mei>"#;
+
+    const MEI_ELEMENTS: &str = r#"<mei xmlns="http://www.music-encoding.org/ns/mei">
+  <meiHead><fileDesc><titleStmt>
+    <title>Lament</title>
+    <composer>Anonymous</composer>
+  </titleStmt></fileDesc></meiHead>
+  <music><body><mdiv><score>
+    <scoreDef>
+      <keySig sig="4s" mode="minor"/>
+      <meterSig count="2" unit="2"/>
+      <staffGrp><staffDef n="1"/></staffGrp>
+    </scoreDef>
+    <section><measure n="1"/></section>
+  </score></mdiv></body></music>
+</mei>"#;
+
+    fn mxl(entries: &[(&str, &str)]) -> Vec<u8> {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, content) in entries {
+            writer
+                .start_file(*name, FileOptions::<'_, ()>::default())
+                .unwrap();
+            writer.write_all(content.as_bytes()).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    const CONTAINER: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<container>
+  <rootfiles>
+    <rootfile full-path="scores/nocturne.musicxml" media-type="application/vnd.recordare.musicxml+xml"/>
+  </rootfiles>
+</container>"#;
+
+    #[test]
+    fn key_names_from_fifths() {
+        assert_eq!(key_name(0, false).as_deref(), Some("C major"));
+        assert_eq!(key_name(0, true).as_deref(), Some("A minor"));
+        assert_eq!(key_name(-2, false).as_deref(), Some("Bb major"));
+        assert_eq!(key_name(-6, true).as_deref(), Some("Eb minor"));
+        assert_eq!(key_name(7, false).as_deref(), Some("C# major"));
+        assert_eq!(key_name(-7, false).as_deref(), Some("Cb major"));
+        assert_eq!(key_name(8, false), None);
+        assert_eq!(key_name(-8, true), None);
+    }
+
+    #[test]
+    fn mei_key_signatures() {
+        assert_eq!(mei_key("0", None).as_deref(), Some("C major"));
+        assert_eq!(mei_key("3s", None).as_deref(), Some("A major"));
+        assert_eq!(mei_key("3f", Some("minor")).as_deref(), Some("C minor"));
+        assert_eq!(mei_key("7f", Some("major")).as_deref(), Some("Cb major"));
+        assert_eq!(mei_key("", None), None);
+        assert_eq!(mei_key("2x", None), None);
+        assert_eq!(mei_key("9s", None), None);
+    }
+
+    #[test]
+    fn partwise_musicxml() {
+        let metadata = musicxml_metadata(PARTWISE).unwrap();
+        assert_eq!(metadata.title.as_deref(), Some("Nocturne"));
+        assert_eq!(metadata.composer.as_deref(), Some("Frédéric Chopin"));
+        // A blank part name falls back to the part's id
+        assert_eq!(metadata.parts, ["Violin", "P2"]);
+        assert_eq!(metadata.key.as_deref(), Some("C minor"));
+        assert_eq!(metadata.time.as_deref(), Some("3/4"));
+        assert_eq!(metadata.measures, 3);
+    }
+
+    #[test]
+    fn timewise_musicxml() {
+        let metadata = musicxml_metadata(TIMEWISE).unwrap();
+        assert_eq!(metadata.title.as_deref(), Some("Étude"));
+        assert_eq!(metadata.composer, None);
+        assert_eq!(metadata.parts, ["Piano"]);
+        assert_eq!(metadata.key.as_deref(), Some("D major"));
+        assert_eq!(metadata.time.as_deref(), Some("6/8"));
+        assert_eq!(metadata.measures, 4);
+    }
+
+    #[test]
+    fn other_documents_are_not_musicxml() {
+        assert!(musicxml_metadata(MEI).is_err());
+        assert!(musicxml_metadata("<score-partwise>").is_err());
+    }
+
+    #[test]
+    fn mei_with_attributes() {
+        let metadata = mei_metadata(MEI).unwrap();
+        assert_eq!(metadata.title.as_deref(), Some("Sonata in B flat"));
+        assert_eq!(metadata.composer.as_deref(), Some("Franz Schubert"));
+        assert_eq!(metadata.parts, ["Right hand", "Left hand"]);
+        assert_eq!(metadata.key.as_deref(), Some("Bb major"));
+        assert_eq!(metadata.time.as_deref(), Some("3/4"));
+        assert_eq!(metadata.measures, 2);
+    }
+
+    #[test]
+    fn mei_with_elements() {
+        let metadata = mei_metadata(MEI_ELEMENTS).unwrap();
+        assert_eq!(metadata.title.as_deref(), Some("Lament"));
+        assert_eq!(metadata.composer.as_deref(), Some("Anonymous"));
+        assert!(metadata.parts.is_empty());
+        assert_eq!(metadata.key.as_deref(), Some("C# minor"));
+        assert_eq!(metadata.time.as_deref(), Some("2/2"));
+        assert_eq!(metadata.measures, 1);
+    }
+
+    #[test]
+    fn mei_needs_its_namespace() {
+        assert!(mei_metadata("<mei><music/></mei>").is_err());
+        assert!(mei_metadata(PARTWISE).is_err());
+    }
+
+    #[test]
+    fn mxl_is_read_through_its_container() {
+        let bytes = mxl(&[
+            ("META-INF/container.xml", CONTAINER),
+            ("scores/nocturne.musicxml", PARTWISE),
+            ("scores/other.musicxml", TIMEWISE),
+        ]);
+        assert_eq!(mxl_root(&bytes).unwrap(), PARTWISE);
+
+        let xml = source_xml(&bytes, SourceFormat::Mxl).unwrap();
+        let metadata = metadata(&xml, SourceFormat::Mxl).unwrap();
+        assert_eq!(metadata.title.as_deref(), Some("Nocturne"));
+    }
+
+    #[test]
+    fn broken_mxl_is_rejected() {
+        assert!(mxl_root(b"not a zip").is_err());
+        assert!(mxl_root(&mxl(&[("scores/nocturne.musicxml", PARTWISE)])).is_err());
+        assert!(mxl_root(&mxl(&[("META-INF/container.xml", "<container/>")])).is_err());
+        assert!(mxl_root(&mxl(&[("META-INF/container.xml", CONTAINER)])).is_err());
+    }
+
+    #[test]
+    fn formats_by_extension() {
+        assert_eq!(format_of("a.MXL").unwrap(), SourceFormat::Mxl);
+        assert_eq!(format_of("a.musicxml").unwrap(), SourceFormat::MusicXml);
+        assert_eq!(format_of("a.xml").unwrap(), SourceFormat::MusicXml);
+        assert_eq!(format_of("a.mei").unwrap(), SourceFormat::Mei);
+        assert!(format_of("a.mid").is_err());
+    }
+}
@@ -0,0 +1,556 @@
+use serde::{Deserialize, Serialize};
+use std::f64::consts::PI;
+use std::fs;
+use std::path::PathBuf;
+use tauri::{AppHandle, Manager};
+
+/// A sample as delivered by the pen stream, in page coordinates.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct InputPoint {
+    pub x: f64,
+    pub y: f64,
+    /// Normalized pressure in 0..1.
+    pub pressure: f64,
+    pub timestamp_us: u64,
+}
+
+/// A stored stroke point, serialized as `[x, y, pressure]` in notes.json.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(from = "[f64; 3]", into = "[f64; 3]")]
+pub struct StrokePoint {
+    pub x: f64,
+    pub y: f64,
+    pub pressure: f64,
+}
+
+impl From<[f64; 3]> for StrokePoint {
+    fn from([x, y, pressure]: [f64; 3]) -> Self {
+        Self { x, y, pressure }
+    }
+}
+
+impl From<StrokePoint> for [f64; 3] {
+    fn from(p: StrokePoint) -> Self {
+        [p.x, p.y, p.pressure]
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SmoothingMethod {
+    None,
+    /// Speed-adaptive low-pass: steady when slow, responsive when fast.
+    #[default]
+    OneEuro,
+    /// Interpolating spline through the raw samples.
+    CatmullRom,
+}
+
+/// Parameters of the 1€ filter (Casiez et al., 2012).
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct OneEuroParams {
+    /// Cutoff in Hz at zero speed; lower removes more jitter.
+    pub min_cutoff: f64,
+    /// How fast the cutoff rises with speed; higher reduces lag.
+    pub beta: f64,
+    /// Cutoff in Hz for the speed estimate.
+    pub d_cutoff: f64,
+}
+
+impl Default for OneEuroParams {
+    fn default() -> Self {
+        Self {
+            min_cutoff: 1.0,
+            beta: 0.05,
+            d_cutoff: 1.0,
+        }
+    }
+}
+
+/// Maps device pressure to stored pressure: `min + (max - min) * p^gamma`.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct PressureCurve {
+    pub gamma: f64,
+    pub min: f64,
+    pub max: f64,
+}
+
+impl Default for PressureCurve {
+    fn default() -> Self {
+        Self {
+            gamma: 1.0,
+            min: 0.0,
+            max: 1.0,
+        }
+    }
+}
+
+impl PressureCurve {
+    pub fn apply(&self, pressure: f64) -> f64 {
+        let p = pressure.clamp(0.0, 1.0).powf(self.gamma.max(0.01));
+        self.min + (self.max - self.min) * p
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StrokeSettings {
+    #[serde(default)]
+    pub smoothing: SmoothingMethod,
+    #[serde(default)]
+    pub one_euro: OneEuroParams,
+    /// Points inserted between samples with Catmull-Rom smoothing.
+    #[serde(default = "default_spline_segments")]
+    pub spline_segments: u32,
+    #[serde(default)]
+    pub pressure_curve: PressureCurve,
+    /// Simplification tolerance in page units; 0 keeps every point.
+    #[serde(default = "default_tolerance")]
+    pub tolerance: f64,
+    /// Pressure deviation that also keeps a point during simplification.
+    #[serde(default = "default_pressure_tolerance")]
+    pub pressure_tolerance: f64,
+}
+
+fn default_spline_segments() -> u32 {
+    4
+}
+
+fn default_tolerance() -> f64 {
+    0.5
+}
+
+fn default_pressure_tolerance() -> f64 {
+    0.05
+}
+
+impl Default for StrokeSettings {
+    fn default() -> Self {
+        Self {
+            smoothing: SmoothingMethod::default(),
+            one_euro: OneEuroParams::default(),
+            spline_segments: default_spline_segments(),
+            pressure_curve: PressureCurve::default(),
+            tolerance: default_tolerance(),
+            pressure_tolerance: default_pressure_tolerance(),
+        }
+    }
+}
+
+struct LowPass {
+    last: Option<f64>,
+}
+
+impl LowPass {
+    fn filter(&mut self, value: f64, alpha: f64) -> f64 {
+        let out = match self.last {
+            Some(last) => last + alpha * (value - last),
+            None => value,
+        };
+        self.last = Some(out);
+        out
+    }
+}
+
+fn smoothing_alpha(cutoff: f64, dt: f64) -> f64 {
+    let tau = 1.0 / (2.0 * PI * cutoff);
+    1.0 / (1.0 + tau / dt)
+}
+
+/// 1€ filter for one coordinate.
+struct OneEuroFilter {
+    params: OneEuroParams,
+    value: LowPass,
+    speed: LowPass,
+    last_raw: Option<f64>,
+}
+
+impl OneEuroFilter {
+    fn new(params: OneEuroParams) -> Self {
+        Self {
+            params,
+            value: LowPass { last: None },
+            speed: LowPass { last: None },
+            last_raw: None,
+        }
+    }
+
+    fn filter(&mut self, value: f64, dt: f64) -> f64 {
+        let raw_speed = match self.last_raw {
+            Some(last) => (value - last) / dt,
+            None => 0.0,
+        };
+        self.last_raw = Some(value);
+        let speed = self
+            .speed
+            .filter(raw_speed, smoothing_alpha(self.params.d_cutoff, dt));
+        let cutoff = self.params.min_cutoff + self.params.beta * speed.abs();
+        self.value.filter(value, smoothing_alpha(cutoff, dt))
+    }
+}
+
+/// Fallback sample interval when timestamps repeat, matching a 200 Hz pen.
+const DEFAULT_DT: f64 = 0.005;
+
+fn one_euro(points: &[InputPoint], params: OneEuroParams) -> Vec<StrokePoint> {
+    let mut fx = OneEuroFilter::new(params);
+    let mut fy = OneEuroFilter::new(params);
+    let mut last_ts = None;
+    points
+        .iter()
+        .map(|p| {
+            let dt = match last_ts {
+                Some(last) if p.timestamp_us > last => (p.timestamp_us - last) as f64 / 1e6,
+                _ => DEFAULT_DT,
+            };
+            last_ts = Some(p.timestamp_us);
+            StrokePoint {
+                x: fx.filter(p.x, dt),
+                y: fy.filter(p.y, dt),
+                pressure: p.pressure,
+            }
+        })
+        .collect()
+}
+
+fn catmull_rom(points: &[StrokePoint], segments: u32) -> Vec<StrokePoint> {
+    if points.len() < 3 || segments == 0 {
+        return points.to_vec();
+    }
+    let at = |i: isize| points[i.clamp(0, points.len() as isize - 1) as usize];
+    let mut out = Vec::with_capacity(points.len() * (segments as usize + 1));
+    for i in 0..points.len() as isize - 1 {
+        let (p0, p1, p2, p3) = (at(i - 1), at(i), at(i + 1), at(i + 2));
+        for s in 0..segments {
+            let t = s as f64 / segments as f64;
+            let (t2, t3) = (t * t, t * t * t);
+            let blend = |a: f64, b: f64, c: f64, d: f64| {
+                0.5 * (2.0 * b
+                    + (c - a) * t
+                    + (2.0 * a - 5.0 * b + 4.0 * c - d) * t2
+                    + (3.0 * b - a - 3.0 * c + d) * t3)
+            };
+            out.push(StrokePoint {
+                x: blend(p0.x, p1.x, p2.x, p3.x),
+                y: blend(p0.y, p1.y, p2.y, p3.y),
+                pressure: p1.pressure + (p2.pressure - p1.pressure) * t,
+            });
+        }
+    }
+    out.extend(points.last());
+    out
+}
+
+/// Ramer-Douglas-Peucker that also keeps points where pressure departs
+/// from the straight-line interpolation, so width changes survive.
+pub fn simplify(
+    points: &[StrokePoint],
+    tolerance: f64,
+    pressure_tolerance: f64,
+) -> Vec<StrokePoint> {
+    if points.len() < 3 || tolerance <= 0.0 {
+        return points.to_vec();
+    }
+    let mut keep = vec![false; points.len()];
+    keep[0] = true;
+    keep[points.len() - 1] = true;
+
+    let mut stack = vec![(0, points.len() - 1)];
+    while let Some((first, last)) = stack.pop() {
+        let (a, b) = (points[first], points[last]);
+        let (dx, dy) = (b.x - a.x, b.y - a.y);
+        let len_sq = dx * dx + dy * dy;
+
+        let mut worst = (0.0, first);
+        for (i, p) in points.iter().enumerate().take(last).skip(first + 1) {
+            let t = if len_sq > 0.0 {
+                (((p.x - a.x) * dx + (p.y - a.y) * dy) / len_sq).clamp(0.0, 1.0)
+            } else {
+                0.0
+            };
+            let (px, py) = (a.x + t * dx, a.y + t * dy);
+            let distance = ((p.x - px).powi(2) + (p.y - py).powi(2)).sqrt();
+            let pressure = a.pressure + t * (b.pressure - a.pressure);
+            let mut error = distance / tolerance;
+            if pressure_tolerance > 0.0 {
+                error = error.max((p.pressure - pressure).abs() / pressure_tolerance);
+            }
+            if error > worst.0 {
+                worst = (error, i);
+            }
+        }
+        if worst.0 > 1.0 {
+            keep[worst.1] = true;
+            stack.push((first, worst.1));
+            stack.push((worst.1, last));
+        }
+    }
+    points
+        .iter()
+        .zip(keep)
+        .filter_map(|(p, keep)| keep.then_some(*p))
+        .collect()
+}
+
+/// Pressure curve, then smoothing, then simplification.
+pub fn process(points: &[InputPoint], settings: &StrokeSettings) -> Vec<StrokePoint> {
+    let mapped: Vec<InputPoint> = points
+        .iter()
+        .map(|p| InputPoint {
+            pressure: settings.pressure_curve.apply(p.pressure),
+            ..*p
+        })
+        .collect();
+    let as_stroke = |p: &InputPoint| StrokePoint {
+        x: p.x,
+        y: p.y,
+        pressure: p.pressure,
+    };
+    let smoothed = match settings.smoothing {
+        SmoothingMethod::None => mapped.iter().map(as_stroke).collect(),
+        SmoothingMethod::OneEuro => one_euro(&mapped, settings.one_euro),
+        SmoothingMethod::CatmullRom => {
+            let raw: Vec<StrokePoint> = mapped.iter().map(as_stroke).collect();
+            catmull_rom(&raw, settings.spline_segments)
+        }
+    };
+    simplify(&smoothed, settings.tolerance, settings.pressure_tolerance)
+}
+
+/// Segments used for round caps and single-point dots.
+const CAP_SEGMENTS: usize = 8;
+/// Thinnest line drawn at zero pressure, as a fraction of the full width.
+const MIN_WIDTH_FRACTION: f64 = 0.2;
+
+/// Closed outline polygon of a variable-width stroke with round caps.
+/// Width at each point scales with its pressure.
+pub fn outline(points: &[StrokePoint], width: f64) -> Vec<[f64; 2]> {
+    let radius = |p: &StrokePoint| {
+        let fraction = MIN_WIDTH_FRACTION + (1.0 - MIN_WIDTH_FRACTION) * p.pressure.clamp(0.0, 1.0);
+        width * fraction / 2.0
+    };
+    let Some(first) = points.first() else {
+        return Vec::new();
+    };
+    if points.len() == 1 {
+        let r = radius(first);
+        return (0..CAP_SEGMENTS * 2)
+            .map(|i| {
+                let angle = i as f64 * PI / CAP_SEGMENTS as f64;
+                [first.x + r * angle.cos(), first.y + r * angle.sin()]
+            })
+            .collect();
+    }
+
+    // Unit normal at each point from the direction through its neighbours
+    let normals: Vec<(f64, f64)> = (0..points.len())
+        .map(|i| {
+            let a = points[i.saturating_sub(1)];
+            let b = points[(i + 1).min(points.len() - 1)];
+            let (dx, dy) = (b.x - a.x, b.y - a.y);
+            let len = (dx * dx + dy * dy).sqrt();
+            if len > 0.0 {
+                (-dy / len, dx / len)
+            } else {
+                (0.0, 0.0)
+            }
+        })
+        .collect();
+
+    let side = |i: usize, sign: f64| {
+        let p = &points[i];
+        let r = radius(p) * sign;
+        [p.x + normals[i].0 * r, p.y + normals[i].1 * r]
+    };
+    let cap = |i: usize, from: (f64, f64)| {
+        let p = &points[i];
+        let r = radius(p);
+        let start = from.1.atan2(from.0);
+        (1..CAP_SEGMENTS).map(move |s| {
+            let angle = start - s as f64 * PI / CAP_SEGMENTS as f64;
+            [p.x + r * angle.cos(), p.y + r * angle.sin()]
+        })
+    };
+
+    let last = points.len() - 1;
+    let mut polygon = Vec::with_capacity(points.len() * 2 + CAP_SEGMENTS * 2);
+    polygon.extend((0..=last).map(|i| side(i, 1.0)));
+    polygon.extend(cap(last, normals[last]));
+    polygon.extend((0..=last).rev().map(|i| side(i, -1.0)));
+    polygon.extend(cap(0, (-normals[0].0, -normals[0].1)));
+    polygon
+}
+
+fn settings_path(app: &AppHandle) -> Result<PathBuf, String> {
+    let config_dir = app
+        .path()
+        .app_config_dir()
+        .map_err(|e| format!("Failed to get app config dir: {e}"))?;
+    Ok(config_dir.join("stroke-settings.json"))
+}
+
+fn read_settings(app: &AppHandle) -> StrokeSettings {
+    settings_path(app)
+        .ok()
+        .and_then(|path| fs::read_to_string(path).ok())
+        .and_then(|data| serde_json::from_str(&data).ok())
+        .unwrap_or_default()
+}
+
+#[tauri::command]
+pub fn get_stroke_settings(app: AppHandle) -> StrokeSettings {
+    read_settings(&app)
+}
+
+#[tauri::command]
+pub fn set_stroke_settings(app: AppHandle, settings: StrokeSettings) -> Result<(), String> {
+    let path = settings_path(&app)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|e| format!("Failed to create config dir: {e}"))?;
+    }
+    let data =
+        serde_json::to_string_pretty(&settings).map_err(|e| format!("Failed to serialize: {e}"))?;
+    fs::write(&path, data).map_err(|e| format!("Failed to write stroke-settings.json: {e}"))
+}
+
+/// Turns raw pen samples into the points stored in notes.json.
+#[tauri::command]
+pub fn process_stroke(app: AppHandle, points: Vec<InputPoint>) -> Vec<StrokePoint> {
+    process(&points, &read_settings(&app))
+}
+
+#[tauri::command]
+pub fn stroke_outline(points: Vec<StrokePoint>, width: f64) -> Vec<[f64; 2]> {
+    outline(&points, width)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(x: f64, y: f64, pressure: f64) -> StrokePoint {
+        StrokePoint { x, y, pressure }
+    }
+
+    fn distance_to_segment(p: StrokePoint, a: StrokePoint, b: StrokePoint) -> f64 {
+        let (dx, dy) = (b.x - a.x, b.y - a.y);
+        let len_sq = dx * dx + dy * dy;
+        let t = if len_sq > 0.0 {
+            (((p.x - a.x) * dx + (p.y - a.y) * dy) / len_sq).clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+        ((p.x - a.x - t * dx).powi(2) + (p.y - a.y - t * dy).powi(2)).sqrt()
+    }
+
+    fn wave() -> Vec<StrokePoint> {
+        (0..200)
+            .map(|i| {
+                let x = i as f64 * 0.5;
+                point(x, (x / 8.0).sin() * 10.0, 0.5)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn simplify_keeps_endpoints() {
+        let points = wave();
+        for tolerance in [0.01, 0.5, 5.0, 100.0] {
+            let simplified = simplify(&points, tolerance, 0.0);
+            assert_eq!(simplified.first(), points.first());
+            assert_eq!(simplified.last(), points.last());
+        }
+        let line: Vec<_> = (0..10).map(|i| point(i as f64, 0.0, 0.5)).collect();
+        assert_eq!(simplify(&line, 0.1, 0.0), vec![line[0], line[9]]);
+    }
+
+    #[test]
+    fn simplify_respects_tolerance() {
+        let points = wave();
+        for tolerance in [0.05, 0.5, 2.0] {
+            let simplified = simplify(&points, tolerance, 0.0);
+            assert!(simplified.len() < points.len());
+            // Every dropped point lies within tolerance of the kept polyline
+            let mut kept = simplified.iter().peekable();
+            let mut a = *kept.next().unwrap();
+            let mut b = **kept.peek().unwrap();
+            for &p in &points {
+                if p == b {
+                    a = *kept.next().unwrap();
+                    match kept.peek() {
+                        Some(&&next) => b = next,
+                        None => break,
+                    }
+                    continue;
+                }
+                assert!(
+                    distance_to_segment(p, a, b) <= tolerance,
+                    "{p:?} is off by more than {tolerance}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn simplify_keeps_pressure_changes() {
+        let mut points: Vec<_> = (0..11).map(|i| point(i as f64, 0.0, 0.5)).collect();
+        points[5].pressure = 0.9;
+        assert_eq!(simplify(&points, 0.1, 0.0).len(), 2);
+        // The peak survives, with the shoulders that keep the ramp narrow
+        assert_eq!(
+            simplify(&points, 0.1, 0.1),
+            vec![points[0], points[4], points[5], points[6], points[10]]
+        );
+    }
+
+    #[test]
+    fn catmull_rom_passes_through_control_points() {
+        let control = vec![
+            point(0.0, 0.0, 0.1),
+            point(10.0, 5.0, 0.4),
+            point(20.0, -3.0, 0.8),
+            point(25.0, 12.0, 0.3),
+            point(40.0, 0.0, 0.6),
+        ];
+        let segments = 6;
+        let curve = catmull_rom(&control, segments);
+        assert_eq!(curve.len(), (control.len() - 1) * segments as usize + 1);
+        for (i, c) in control.iter().enumerate() {
+            let p = curve[i * segments as usize];
+            assert!((p.x - c.x).abs() < 1e-9 && (p.y - c.y).abs() < 1e-9, "{i}");
+            assert_eq!(p.pressure, c.pressure);
+        }
+    }
+
+    fn samples(values: impl IntoIterator<Item = f64>) -> Vec<InputPoint> {
+        values
+            .into_iter()
+            .enumerate()
+            .map(|(i, v)| InputPoint {
+                x: v,
+                y: -v,
+                pressure: 0.5,
+                timestamp_us: i as u64 * 5000,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn one_euro_holds_constant_input() {
+        let filtered = one_euro(&samples([42.0; 50]), OneEuroParams::default());
+        assert!(filtered.iter().all(|p| p.x == 42.0 && p.y == -42.0));
+    }
+
+    #[test]
+    fn one_euro_converges_after_a_step() {
+        let input = samples((0..400).map(|i| if i < 10 { 0.0 } else { 10.0 }));
+        let filtered = one_euro(&input, OneEuroParams::default());
+
+        // Smoothed rather than jumping, and never overshooting
+        assert!(filtered[10].x > 0.0 && filtered[10].x < 10.0);
+        for pair in filtered[10..].windows(2) {
+            assert!(pair[1].x >= pair[0].x && pair[1].x <= 10.0);
+        }
+        assert!((filtered.last().unwrap().x - 10.0).abs() < 1e-3);
+        assert!((filtered.last().unwrap().y + 10.0).abs() < 1e-3);
+    }
+}
@@ -1,15 +1,164 @@
-use evdev::Device;
-use serde::Serialize;
+use evdev::{AbsoluteAxisType, Device, EventType, InputEvent};
+use serde::{Deserialize, Serialize};
 use std::os::fd::AsRawFd;
+use std::time::SystemTime;
 
-#[derive(Debug, Clone, Serialize)]
+use crate::mapping::MappedPoint;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceKind {
+    Pen,
+    Eraser,
+    Touchscreen,
+    Touchpad,
+    Pedal,
+    Keyboard,
+    Other,
+}
+
+/// Event codes a device reports, as read from the kernel's capability bits.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DeviceCapabilities {
+    pub keys: Vec<u16>,
+    pub abs_axes: Vec<u16>,
+    pub properties: Vec<u16>,
+    #[serde(default)]
+    pub has_rel: bool,
+}
+
+impl DeviceCapabilities {
+    pub fn of(device: &Device) -> Self {
+        Self {
+            keys: device
+                .supported_keys()
+                .map(|keys| keys.iter().map(|k| k.0).collect())
+                .unwrap_or_default(),
+            abs_axes: device
+                .supported_absolute_axes()
+                .map(|axes| axes.iter().map(|a| a.0).collect())
+                .unwrap_or_default(),
+            properties: device.properties().iter().map(|p| p.0).collect(),
+            has_rel: device.supported_events().contains(EventType::RELATIVE),
+        }
+    }
+
+    pub fn has_key(&self, code: u16) -> bool {
+        self.keys.contains(&code)
+    }
+
+    pub fn has_abs(&self, code: u16) -> bool {
+        self.abs_axes.contains(&code)
+    }
+
+    pub fn has_prop(&self, prop: u16) -> bool {
+        self.properties.contains(&prop)
+    }
+
+    /// Classifies by capabilities rather than by name. Combined pen
+    /// devices report both tools and count as `Pen`, with `has_eraser` set.
+    pub fn classify(&self, name: &str) -> DeviceKind {
+        let direct = self.has_prop(INPUT_PROP_DIRECT);
+        let multitouch = self.has_abs(ABS_MT_SLOT) || self.has_abs(ABS_MT_POSITION_X);
+
+        if self.has_key(BTN_TOOL_PEN) {
+            DeviceKind::Pen
+        } else if self.has_key(BTN_TOOL_RUBBER) {
+            DeviceKind::Eraser
+        } else if multitouch && direct {
+            DeviceKind::Touchscreen
+        } else if multitouch || self.has_key(BTN_TOOL_FINGER) {
+            DeviceKind::Touchpad
+        } else if self.has_abs(ABS_X) && self.has_key(BTN_TOUCH) && direct {
+            // Single-touch screens without MT axes
+            DeviceKind::Touchscreen
+        } else if crate::pedal::is_pedal(name, self) {
+            DeviceKind::Pedal
+        } else if (KEY_Q..=KEY_P).any(|k| self.has_key(k)) {
+            DeviceKind::Keyboard
+        } else {
+            DeviceKind::Other
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct InputDeviceInfo {
     pub name: String,
     pub path: String,
+    pub kind: DeviceKind,
+    pub has_eraser: bool,
+    pub axes: DeviceAxes,
+    /// Names of supported `BTN_*` codes, e.g. `BTN_STYLUS`.
+    pub buttons: Vec<String>,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    // Kept for the debug overlay, derived from `kind`
     pub is_pen: bool,
     pub is_touch: bool,
 }
 
+impl InputDeviceInfo {
+    pub fn of(device: &Device, path: &str) -> Self {
+        let name = device.name().unwrap_or("Unknown").to_string();
+        let caps = DeviceCapabilities::of(device);
+        let kind = caps.classify(&name);
+        let input_id = device.input_id();
+        Self {
+            path: path.to_string(),
+            kind,
+            has_eraser: caps.has_key(BTN_TOOL_RUBBER),
+            axes: read_device_axes(device),
+            buttons: caps
+                .keys
+                .iter()
+                .filter(|&&k| k >= BTN_MISC)
+                .map(|&k| format!("{:?}", evdev::Key(k)))
+                .collect(),
+            vendor_id: input_id.vendor(),
+            product_id: input_id.product(),
+            is_pen: matches!(kind, DeviceKind::Pen | DeviceKind::Eraser),
+            is_touch: kind == DeviceKind::Touchscreen,
+            name,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StylusEventKind {
+    /// Tool entered sensing range (`BTN_TOOL_*` pressed).
+    ProximityIn,
+    /// Tool moved while in range but not touching.
+    Hover,
+    Down,
+    Move,
+    Up,
+    /// Barrel button state changed; `buttons` holds the new state.
+    Button,
+    /// Tool left sensing range.
+    ProximityOut,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ToolType {
+    #[default]
+    Pen,
+    Eraser,
+    Finger,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ButtonState {
+    /// `BTN_STYLUS`, the lower barrel button.
+    pub primary: bool,
+    /// `BTN_STYLUS2`, the upper barrel button.
+    pub secondary: bool,
+    /// `BTN_STYLUS3`, present on some Wacom pens.
+    pub tertiary: bool,
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub struct StylusEvent {
     pub x: i32,
@@ -18,7 +167,45 @@ pub struct StylusEvent {
     pub tilt_x: i32,
     pub tilt_y: i32,
     pub touch_major: i32,
-    pub event_type: String, // "down", "move", "up"
+    /// Hover height from `ABS_DISTANCE`, 0 if the device doesn't report it.
+    pub distance: i32,
+    pub tool: ToolType,
+    pub buttons: ButtonState,
+    pub event_type: StylusEventKind,
+    /// Kernel event time in microseconds since the Unix epoch.
+    pub timestamp_us: u64,
+    /// Screen-space position, filled in when the stream knows the axis ranges.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mapped: Option<MappedPoint>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct AxisRange {
+    pub min: i32,
+    pub max: i32,
+    /// Units per millimetre (units per radian for tilt), 0 if unknown.
+    pub resolution: i32,
+}
+
+impl AxisRange {
+    /// Maps `value` into 0..1, clamping out-of-range readings.
+    pub fn normalize(&self, value: i32) -> f64 {
+        if self.max <= self.min {
+            return 0.0;
+        }
+        let span = (self.max - self.min) as f64;
+        ((value - self.min) as f64 / span).clamp(0.0, 1.0)
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DeviceAxes {
+    pub x: Option<AxisRange>,
+    pub y: Option<AxisRange>,
+    pub pressure: Option<AxisRange>,
+    pub tilt_x: Option<AxisRange>,
+    pub tilt_y: Option<AxisRange>,
+    pub distance: Option<AxisRange>,
 }
 
 // Linux input event codes
@@ -30,15 +217,30 @@ const EV_ABS: u16 = 3;
 const ABS_X: u16 = 0;
 const ABS_Y: u16 = 1;
 const ABS_PRESSURE: u16 = 24;
+const ABS_DISTANCE: u16 = 25;
 const ABS_TILT_X: u16 = 26;
 const ABS_TILT_Y: u16 = 27;
+const ABS_MT_SLOT: u16 = 47;
 const ABS_MT_TOUCH_MAJOR: u16 = 48;
 const ABS_MT_POSITION_X: u16 = 53;
 const ABS_MT_POSITION_Y: u16 = 54;
 const ABS_MT_TRACKING_ID: u16 = 57;
+const ABS_MT_TOOL_Y: u16 = 61;
 
 // Key codes
+const KEY_Q: u16 = 16;
+const KEY_P: u16 = 25;
+const BTN_MISC: u16 = 0x100;
+const BTN_TOOL_PEN: u16 = 320;
+const BTN_TOOL_RUBBER: u16 = 321;
+const BTN_TOOL_FINGER: u16 = 325;
 const BTN_TOUCH: u16 = 330;
+const BTN_STYLUS: u16 = 331;
+const BTN_STYLUS2: u16 = 332;
+const BTN_STYLUS3: u16 = 329;
+
+// Input properties
+const INPUT_PROP_DIRECT: u16 = 1;
 
 #[derive(Debug, Clone, Serialize)]
 pub struct RawInputEvent {
@@ -58,23 +260,7 @@ pub fn list_input_devices() -> Vec<InputDeviceInfo> {
                 let filename = filename.to_string_lossy();
                 if filename.starts_with("event") {
                     if let Ok(device) = Device::open(&path) {
-                        let name = device.name().unwrap_or("Unknown").to_string();
-                        let name_lower = name.to_lowercase();
-                        let is_pen = name_lower.contains("pen")
-                            || name_lower.contains("stylus");
-                        let is_touchpad = name_lower.contains("touchpad");
-                        let is_mouse = name_lower.contains("mouse");
-                        let is_touch = !is_touchpad && !is_mouse && (
-                            name_lower.contains("touch")
-                            || name_lower.contains("finger")
-                            || name_lower.contains("elan")
-                        );
-                        devices.push(InputDeviceInfo {
-                            name,
-                            path: path.to_string_lossy().to_string(),
-                            is_pen,
-                            is_touch,
-                        });
+                        devices.push(InputDeviceInfo::of(&device, &path.to_string_lossy()));
                     }
                 }
             }
@@ -88,10 +274,269 @@ pub fn list_input_devices() -> Vec<InputDeviceInfo> {
 pub fn find_stylus_device() -> Option<InputDeviceInfo> {
     // First try to find a pen, then fall back to touch for testing
     let devices = list_input_devices();
-    devices.iter().find(|d| d.is_pen).cloned()
+    devices.iter().find(|d| d.kind == DeviceKind::Pen).cloned()
+        .or_else(|| devices.iter().find(|d| d.is_pen).cloned())
         .or_else(|| devices.iter().find(|d| d.is_touch).cloned())
 }
 
+/// Reads axis ranges from the kernel. Touchscreens without `ABS_X` fall
+/// back to the multitouch position axes.
+pub fn read_device_axes(device: &Device) -> DeviceAxes {
+    let (Some(supported), Ok(state)) = (device.supported_absolute_axes(), device.get_abs_state())
+    else {
+        return DeviceAxes::default();
+    };
+    let axis = |code: u16| {
+        supported.contains(AbsoluteAxisType(code)).then(|| {
+            let info = &state[code as usize];
+            AxisRange {
+                min: info.minimum,
+                max: info.maximum,
+                resolution: info.resolution,
+            }
+        })
+    };
+
+    DeviceAxes {
+        x: axis(ABS_X).or_else(|| axis(ABS_MT_POSITION_X)),
+        y: axis(ABS_Y).or_else(|| axis(ABS_MT_POSITION_Y)),
+        pressure: axis(ABS_PRESSURE),
+        tilt_x: axis(ABS_TILT_X),
+        tilt_y: axis(ABS_TILT_Y),
+        distance: axis(ABS_DISTANCE),
+    }
+}
+
+fn is_mt_axis(code: u16) -> bool {
+    (ABS_MT_SLOT..=ABS_MT_TOOL_Y).contains(&code)
+}
+
+pub fn event_timestamp_us(ev: &InputEvent) -> u64 {
+    ev.timestamp()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or_default()
+}
+
+/// Folds raw evdev events into `StylusEvent`s, one frame at a time.
+///
+/// Changes are collected until `SYN_REPORT` and then emitted in a fixed
+/// order: proximity-in, button, down/move/hover/up, proximity-out. When
+/// the tool changes within a frame, e.g. a pen flipped to its eraser, the
+/// old tool's up and proximity-out come first.
+/// On multitouch panels only slot 0 is followed; use
+/// `touch::MultitouchParser` to track every contact.
+pub struct StylusParser {
+    current: StylusEvent,
+    mt_slot: i32,
+    in_proximity: bool,
+    is_touching: bool,
+    // Per-frame changes, cleared on SYN_REPORT
+    pending_proximity_in: bool,
+    /// Tool that left sensing range this frame.
+    pending_proximity_out: Option<ToolType>,
+    pending_down: bool,
+    pending_up: bool,
+    buttons_changed: bool,
+    moved: bool,
+}
+
+impl StylusParser {
+    pub fn new() -> Self {
+        Self {
+            current: StylusEvent {
+                x: 0,
+                y: 0,
+                pressure: 0,
+                tilt_x: 0,
+                tilt_y: 0,
+                touch_major: 0,
+                distance: 0,
+                tool: ToolType::default(),
+                buttons: ButtonState::default(),
+                event_type: StylusEventKind::Move,
+                timestamp_us: 0,
+                mapped: None,
+            },
+            mt_slot: 0,
+            in_proximity: false,
+            is_touching: false,
+            pending_proximity_in: false,
+            pending_proximity_out: None,
+            pending_down: false,
+            pending_up: false,
+            buttons_changed: false,
+            moved: false,
+        }
+    }
+
+    pub fn feed(&mut self, ev: &InputEvent) -> Vec<StylusEvent> {
+        self.feed_raw(
+            ev.event_type().0,
+            ev.code(),
+            ev.value(),
+            event_timestamp_us(ev),
+        )
+    }
+
+    pub fn feed_raw(
+        &mut self,
+        ev_type: u16,
+        code: u16,
+        value: i32,
+        timestamp_us: u64,
+    ) -> Vec<StylusEvent> {
+        self.current.timestamp_us = timestamp_us;
+
+        match ev_type {
+            EV_ABS if code == ABS_MT_SLOT => self.mt_slot = value,
+            EV_ABS if is_mt_axis(code) && self.mt_slot != 0 => {}
+            EV_ABS => {
+                self.moved = true;
+                match code {
+                    // Regular absolute axes (stylus)
+                    ABS_X => self.current.x = value,
+                    ABS_Y => self.current.y = value,
+                    ABS_PRESSURE => self.current.pressure = value,
+                    ABS_DISTANCE => self.current.distance = value,
+                    ABS_TILT_X => self.current.tilt_x = value,
+                    ABS_TILT_Y => self.current.tilt_y = value,
+                    // Multitouch axes (touchscreen)
+                    ABS_MT_POSITION_X => self.current.x = value,
+                    ABS_MT_POSITION_Y => self.current.y = value,
+                    ABS_MT_TOUCH_MAJOR => self.current.touch_major = value,
+                    ABS_MT_TRACKING_ID => {
+                        self.current.tool = ToolType::Finger;
+                        if value == -1 {
+                            // Touch lifted
+                            self.release();
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            EV_KEY => match code {
+                BTN_TOOL_PEN | BTN_TOOL_RUBBER if value == 1 => {
+                    let tool = tool_of(code);
+                    if self.in_proximity && self.current.tool != tool {
+                        // New tool reported before the old one left
+                        self.leave();
+                    }
+                    if !self.in_proximity {
+                        self.in_proximity = true;
+                        self.pending_proximity_in = true;
+                    }
+                    self.current.tool = tool;
+                }
+                // Old tool leaving after the new one was reported
+                BTN_TOOL_PEN | BTN_TOOL_RUBBER if self.current.tool != tool_of(code) => {}
+                BTN_TOOL_PEN | BTN_TOOL_RUBBER => self.leave(),
+                BTN_TOUCH if value == 0 => self.release(),
+                BTN_TOUCH if !self.is_touching => {
+                    self.pending_down = true;
+                    self.is_touching = true;
+                }
+                BTN_STYLUS | BTN_STYLUS2 | BTN_STYLUS3 => {
+                    let pressed = value != 0;
+                    let buttons = &mut self.current.buttons;
+                    match code {
+                        BTN_STYLUS => buttons.primary = pressed,
+                        BTN_STYLUS2 => buttons.secondary = pressed,
+                        _ => buttons.tertiary = pressed,
+                    }
+                    self.buttons_changed = true;
+                }
+                _ => {}
+            },
+            EV_SYN => return self.flush_frame(),
+            _ => {}
+        }
+        Vec::new()
+    }
+
+    fn release(&mut self) {
+        if self.is_touching || self.pending_down {
+            self.is_touching = false;
+            self.pending_down = false;
+            self.pending_up = true;
+        }
+    }
+
+    fn leave(&mut self) {
+        self.in_proximity = false;
+        self.pending_proximity_out = Some(self.current.tool);
+        self.release();
+    }
+
+    /// Sync event = end of frame
+    fn flush_frame(&mut self) -> Vec<StylusEvent> {
+        let mut events = Vec::new();
+
+        if self.pending_proximity_in {
+            if let Some(tool) = self.pending_proximity_out.take() {
+                if std::mem::take(&mut self.pending_up) {
+                    events.push(self.emit_as(StylusEventKind::Up, tool));
+                }
+                events.push(self.emit_as(StylusEventKind::ProximityOut, tool));
+            }
+        }
+        if std::mem::take(&mut self.pending_proximity_in) {
+            events.push(self.emit(StylusEventKind::ProximityIn));
+        }
+        if std::mem::take(&mut self.buttons_changed) {
+            events.push(self.emit(StylusEventKind::Button));
+        }
+        let moved = std::mem::take(&mut self.moved);
+        if std::mem::take(&mut self.pending_down) {
+            events.push(self.emit(StylusEventKind::Down));
+        } else if std::mem::take(&mut self.pending_up) {
+            events.push(self.emit(StylusEventKind::Up));
+        } else if self.is_touching && (self.current.x > 0 || self.current.y > 0) {
+            events.push(self.emit(StylusEventKind::Move));
+        } else if self.in_proximity && moved {
+            events.push(self.emit(StylusEventKind::Hover));
+        }
+        if let Some(tool) = self.pending_proximity_out.take() {
+            events.push(self.emit_as(StylusEventKind::ProximityOut, tool));
+        }
+
+        events
+    }
+
+    fn emit(&mut self, event_type: StylusEventKind) -> StylusEvent {
+        self.current.event_type = event_type;
+        self.current.clone()
+    }
+
+    /// Like `emit`, but for `tool`, which may no longer be the current one.
+    fn emit_as(&mut self, event_type: StylusEventKind, tool: ToolType) -> StylusEvent {
+        let mut event = self.emit(event_type);
+        event.tool = tool;
+        event
+    }
+}
+
+fn tool_of(code: u16) -> ToolType {
+    if code == BTN_TOOL_RUBBER {
+        ToolType::Eraser
+    } else {
+        ToolType::Pen
+    }
+}
+
+impl Default for StylusParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[tauri::command]
+pub fn get_device_axes(device_path: String) -> Result<DeviceAxes, String> {
+    let device = Device::open(&device_path)
+        .map_err(|e| format!("Failed to open device: {}", e))?;
+    Ok(read_device_axes(&device))
+}
+
 #[tauri::command]
 pub fn read_stylus_events(device_path: String) -> Result<Vec<StylusEvent>, String> {
     let mut device = Device::open(&device_path)
@@ -105,17 +550,7 @@ pub fn read_stylus_events(device_path: String) -> Result<Vec<StylusEvent>, Strin
     }
 
     let mut events = Vec::new();
-    let mut current = StylusEvent {
-        x: 0,
-        y: 0,
-        pressure: 0,
-        tilt_x: 0,
-        tilt_y: 0,
-        touch_major: 0,
-        event_type: "move".to_string(),
-    };
-    let mut is_touching = false;
-    let mut pending_down = false;
+    let mut parser = StylusParser::new();
 
     let start = std::time::Instant::now();
     let timeout = std::time::Duration::from_secs(3);
@@ -123,59 +558,7 @@ pub fn read_stylus_events(device_path: String) -> Result<Vec<StylusEvent>, Strin
     while start.elapsed() < timeout && events.len() < 100 {
         match device.fetch_events() {
             Ok(evs) => {
-                for ev in evs {
-                    let ev_type = ev.event_type().0;
-                    let code = ev.code();
-                    let value = ev.value();
-
-                    match ev_type {
-                        EV_ABS => match code {
-                            // Regular absolute axes (stylus)
-                            ABS_X => current.x = value,
-                            ABS_Y => current.y = value,
-                            ABS_PRESSURE => current.pressure = value,
-                            ABS_TILT_X => current.tilt_x = value,
-                            ABS_TILT_Y => current.tilt_y = value,
-                            // Multitouch axes (touchscreen)
-                            ABS_MT_POSITION_X => current.x = value,
-                            ABS_MT_POSITION_Y => current.y = value,
-                            ABS_MT_TOUCH_MAJOR => current.touch_major = value,
-                            ABS_MT_TRACKING_ID => {
-                                if value == -1 {
-                                    // Touch lifted
-                                    current.event_type = "up".to_string();
-                                    events.push(current.clone());
-                                    is_touching = false;
-                                }
-                            }
-                            _ => {}
-                        },
-                        EV_KEY => {
-                            if code == BTN_TOUCH {
-                                if value == 1 {
-                                    pending_down = true;
-                                    is_touching = true;
-                                } else {
-                                    current.event_type = "up".to_string();
-                                    events.push(current.clone());
-                                    is_touching = false;
-                                }
-                            }
-                        },
-                        EV_SYN => {
-                            // Sync event = end of frame
-                            if pending_down {
-                                current.event_type = "down".to_string();
-                                events.push(current.clone());
-                                pending_down = false;
-                            } else if is_touching && (current.x > 0 || current.y > 0) {
-                                current.event_type = "move".to_string();
-                                events.push(current.clone());
-                            }
-                        }
-                        _ => {}
-                    }
-                }
+                events.extend(evs.flat_map(|ev| parser.feed(&ev)));
             }
             Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
                 std::thread::sleep(std::time::Duration::from_millis(10));
@@ -227,3 +610,97 @@ pub fn read_raw_events(device_path: String) -> Result<Vec<RawInputEvent>, String
 
     Ok(events)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds `(type, code, value)` events and returns what each frame emits.
+    fn run(
+        parser: &mut StylusParser,
+        events: &[(u16, u16, i32)],
+    ) -> Vec<(StylusEventKind, ToolType)> {
+        events
+            .iter()
+            .flat_map(|&(ty, code, value)| parser.feed_raw(ty, code, value, 0))
+            .map(|e| (e.event_type, e.tool))
+            .collect()
+    }
+
+    fn pen_in(parser: &mut StylusParser) {
+        run(
+            parser,
+            &[
+                (EV_KEY, BTN_TOOL_PEN, 1),
+                (EV_ABS, ABS_X, 100),
+                (EV_SYN, 0, 0),
+            ],
+        );
+    }
+
+    #[test]
+    fn flip_to_eraser_leaves_before_entering() {
+        use StylusEventKind::*;
+        // Kernels differ in whether the old tool is released first
+        for order in [
+            [(EV_KEY, BTN_TOOL_PEN, 0), (EV_KEY, BTN_TOOL_RUBBER, 1)],
+            [(EV_KEY, BTN_TOOL_RUBBER, 1), (EV_KEY, BTN_TOOL_PEN, 0)],
+        ] {
+            let mut parser = StylusParser::new();
+            pen_in(&mut parser);
+            let events = run(&mut parser, &[order[0], order[1], (EV_SYN, 0, 0)]);
+            assert_eq!(
+                events,
+                vec![
+                    (ProximityOut, ToolType::Pen),
+                    (ProximityIn, ToolType::Eraser)
+                ],
+                "{order:?}"
+            );
+
+            let events = run(&mut parser, &[(EV_KEY, BTN_TOOL_RUBBER, 0), (EV_SYN, 0, 0)]);
+            assert_eq!(events, vec![(ProximityOut, ToolType::Eraser)]);
+        }
+    }
+
+    #[test]
+    fn flip_while_touching_lifts_old_tool() {
+        use StylusEventKind::*;
+        let mut parser = StylusParser::new();
+        pen_in(&mut parser);
+        run(&mut parser, &[(EV_KEY, BTN_TOUCH, 1), (EV_SYN, 0, 0)]);
+
+        let events = run(
+            &mut parser,
+            &[
+                (EV_KEY, BTN_TOOL_PEN, 0),
+                (EV_KEY, BTN_TOOL_RUBBER, 1),
+                (EV_SYN, 0, 0),
+            ],
+        );
+        assert_eq!(
+            events,
+            vec![
+                (Up, ToolType::Pen),
+                (ProximityOut, ToolType::Pen),
+                (ProximityIn, ToolType::Eraser),
+            ]
+        );
+    }
+
+    #[test]
+    fn plain_proximity_out_is_last() {
+        use StylusEventKind::*;
+        let mut parser = StylusParser::new();
+        pen_in(&mut parser);
+        let events = run(
+            &mut parser,
+            &[
+                (EV_ABS, ABS_X, 120),
+                (EV_KEY, BTN_TOOL_PEN, 0),
+                (EV_SYN, 0, 0),
+            ],
+        );
+        assert_eq!(events, vec![(ProximityOut, ToolType::Pen)]);
+    }
+}
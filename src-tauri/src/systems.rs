@@ -0,0 +1,626 @@
+use serde::{Deserialize, Serialize};
+use tauri::AppHandle;
+
+use crate::olscore::{
+    modify_manifest, read_archive_entry, read_manifest_at, replace_archive_entries, score_path,
+    CropRect, ScoreManifest,
+};
+use crate::render::{render_pages, PageImage};
+
+/// Bumped whenever detection changes, so cached results are redone.
+const ANALYSIS_VERSION: u32 = 1;
+const RENDER_WIDTH: i32 = 1200;
+const INK_THRESHOLD: u8 = 160;
+/// A row is a staff line candidate if this much of the page width is ink.
+const MIN_LINE_FRACTION: f64 = 0.25;
+/// Allowed deviation of a line gap from the staff's mean gap.
+const SPACING_TOLERANCE: f64 = 0.25;
+/// How much of the gap between two staves a connecting barline or brace
+/// must cover.
+const MIN_CONNECTION: f64 = 0.9;
+/// Systems reach this many line gaps beyond their outer staff lines.
+const SYSTEM_MARGIN: f64 = 2.5;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Staff {
+    /// Line centres as fractions of the page height, top to bottom.
+    pub lines: Vec<f64>,
+    pub left: f64,
+    pub right: f64,
+    pub confidence: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct System {
+    pub bounds: CropRect,
+    pub staves: Vec<Staff>,
+    pub confidence: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PageSystems {
+    pub page: u32,
+    pub systems: Vec<System>,
+}
+
+/// Detection results for one PDF, cached in the archive by its hash.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SystemAnalysis {
+    pub version: u32,
+    pub sha256: String,
+    pub pages: Vec<PageSystems>,
+}
+
+/// A run of staff line candidate rows, in pixels.
+#[derive(Debug, Clone, Copy)]
+struct Line {
+    top: usize,
+    bottom: usize,
+    left: usize,
+    right: usize,
+}
+
+impl Line {
+    fn centre(&self) -> f64 {
+        (self.top + self.bottom) as f64 / 2.0
+    }
+}
+
+/// Pixel bounds of a detected staff.
+#[derive(Debug, Clone)]
+struct StaffBox {
+    lines: Vec<Line>,
+    spacing: f64,
+    left: usize,
+    right: usize,
+    confidence: f64,
+}
+
+impl StaffBox {
+    fn top(&self) -> usize {
+        self.lines[0].top
+    }
+
+    fn bottom(&self) -> usize {
+        self.lines[self.lines.len() - 1].bottom
+    }
+}
+
+/// Longest horizontal ink run in a row, bridging gaps of up to `max_gap`.
+fn longest_run(image: &PageImage, y: usize, max_gap: usize) -> (usize, usize) {
+    let mut best = (0, 0);
+    let mut start = None;
+    let mut last_ink = 0;
+    for x in 0..image.width {
+        if !image.is_dark(x, y, INK_THRESHOLD) {
+            continue;
+        }
+        match start {
+            Some(s) if x - last_ink <= max_gap + 1 => {
+                if x - s > best.1 - best.0 {
+                    best = (s, x);
+                }
+            }
+            _ => start = Some(x),
+        }
+        last_ink = x;
+    }
+    best
+}
+
+/// Finds horizontal lines from the row projection profile.
+fn find_lines(image: &PageImage) -> Vec<Line> {
+    let min_count = (image.width as f64 * MIN_LINE_FRACTION) as usize;
+    let max_gap = image.width / 200 + 1;
+    let is_line = |y: usize| {
+        let (left, right) = longest_run(image, y, max_gap);
+        right - left >= min_count
+    };
+
+    let mut lines: Vec<Line> = Vec::new();
+    for y in 0..image.height {
+        if !is_line(y) {
+            continue;
+        }
+        match lines.last_mut() {
+            Some(line) if line.bottom + 1 == y => line.bottom = y,
+            _ => lines.push(Line {
+                top: y,
+                bottom: y,
+                left: 0,
+                right: 0,
+            }),
+        }
+    }
+    for line in &mut lines {
+        let (left, right) = longest_run(image, (line.top + line.bottom) / 2, max_gap);
+        line.left = left;
+        line.right = right;
+    }
+    lines
+}
+
+/// Takes runs of five evenly spaced lines as staves.
+fn group_staves(lines: &[Line], height: usize) -> Vec<StaffBox> {
+    let mut staves = Vec::new();
+    let mut i = 0;
+    while i + 5 <= lines.len() {
+        let group = &lines[i..i + 5];
+        let gaps: Vec<f64> = group
+            .windows(2)
+            .map(|w| w[1].centre() - w[0].centre())
+            .collect();
+        let spacing = gaps.iter().sum::<f64>() / gaps.len() as f64;
+        let deviation = gaps
+            .iter()
+            .map(|g| (g - spacing).abs() / spacing)
+            .fold(0.0, f64::max);
+        let thickness = group
+            .iter()
+            .map(|l| l.bottom - l.top + 1)
+            .max()
+            .unwrap_or(1);
+        let plausible = spacing > 2.0 * thickness as f64 && spacing < height as f64 / 20.0;
+        if !plausible || deviation > SPACING_TOLERANCE {
+            i += 1;
+            continue;
+        }
+
+        let left = group.iter().map(|l| l.left).min().unwrap_or(0);
+        let right = group.iter().map(|l| l.right).max().unwrap_or(0);
+        // Lines of one staff should span the same width
+        let overlap = group.iter().map(|l| l.right - l.left).min().unwrap_or(0) as f64
+            / (right - left).max(1) as f64;
+        staves.push(StaffBox {
+            lines: group.to_vec(),
+            spacing,
+            left,
+            right,
+            confidence: (1.0 - deviation / SPACING_TOLERANCE * 0.5) * overlap,
+        });
+        i += 5;
+    }
+    staves
+}
+
+/// Whether a barline, brace or bracket joins two staves near their left
+/// ends.
+fn connected(image: &PageImage, upper: &StaffBox, lower: &StaffBox) -> bool {
+    let (top, bottom) = (upper.bottom() + 1, lower.top());
+    if bottom <= top {
+        return true;
+    }
+    let reach = (upper.spacing * 2.0) as usize;
+    let from = upper.left.min(lower.left).saturating_sub(reach);
+    let to = (upper.left.max(lower.left) + reach).min(image.width);
+    (from..to).any(|x| {
+        let ink = (top..bottom)
+            .filter(|&y| image.is_dark(x, y, INK_THRESHOLD))
+            .count();
+        ink as f64 >= (bottom - top) as f64 * MIN_CONNECTION
+    })
+}
+
+/// Detects the staves on a page and groups them into systems.
+pub fn detect_systems(image: &PageImage) -> Vec<System> {
+    let staves = group_staves(&find_lines(image), image.height);
+    let mut groups: Vec<Vec<StaffBox>> = Vec::new();
+    for staff in staves {
+        match groups.last_mut() {
+            Some(group) if connected(image, &group[group.len() - 1], &staff) => group.push(staff),
+            _ => groups.push(vec![staff]),
+        }
+    }
+
+    let (width, height) = (image.width as f64, image.height as f64);
+    let extents: Vec<(f64, f64)> = groups
+        .iter()
+        .map(|g| {
+            let margin = g[0].spacing * SYSTEM_MARGIN;
+            (
+                g[0].top() as f64 - margin,
+                g[g.len() - 1].bottom() as f64 + margin,
+            )
+        })
+        .collect();
+
+    groups
+        .iter()
+        .enumerate()
+        .map(|(i, group)| {
+            // Don't reach past the middle of the gap to a neighbour
+            let top = match i {
+                0 => extents[i].0.max(0.0),
+                _ => extents[i].0.max(
+                    (group[0].top() + groups[i - 1].last().map_or(0, |s| s.bottom())) as f64 / 2.0,
+                ),
+            };
+            let bottom = match groups.get(i + 1) {
+                Some(next) => extents[i]
+                    .1
+                    .min((group[group.len() - 1].bottom() + next[0].top()) as f64 / 2.0),
+                None => extents[i].1.min(height),
+            };
+            let left = group.iter().map(|s| s.left).min().unwrap_or(0) as f64;
+            let right = group.iter().map(|s| s.right).max().unwrap_or(0) as f64;
+            let staves: Vec<Staff> = group
+                .iter()
+                .map(|s| Staff {
+                    lines: s.lines.iter().map(|l| l.centre() / height).collect(),
+                    left: s.left as f64 / width,
+                    right: s.right as f64 / width,
+                    confidence: s.confidence,
+                })
+                .collect();
+            System {
+                bounds: CropRect {
+                    x: left / width,
+                    y: top / height,
+                    width: (right - left) / width,
+                    height: (bottom - top) / height,
+                },
+                confidence: staves.iter().map(|s| s.confidence).sum::<f64>() / staves.len() as f64,
+                staves,
+            }
+        })
+        .collect()
+}
+
+/// Cut lines halfway between neighbouring systems.
+pub fn split_points(systems: &[System]) -> Vec<f64> {
+    systems
+        .windows(2)
+        .map(|pair| {
+            let above = pair[0].staves.last().and_then(|s| s.lines.last());
+            let below = pair[1].staves.first().and_then(|s| s.lines.first());
+            match (above, below) {
+                (Some(a), Some(b)) => (a + b) / 2.0,
+                _ => pair[1].bounds.y,
+            }
+        })
+        .collect()
+}
+
+fn cache_entry(sha256: &str) -> String {
+    format!("analysis/systems-{sha256}.json")
+}
+
+/// Detects the systems of one PDF, reusing the archive's cached results.
+fn analyze(
+    app: &AppHandle,
+    score_id: &str,
+    manifest: &ScoreManifest,
+    pdf: &str,
+) -> Result<SystemAnalysis, String> {
+    let entry = manifest
+        .pdfs
+        .iter()
+        .find(|p| p.filename == pdf)
+        .ok_or_else(|| format!("PDF not in score: {pdf}"))?;
+    let olscore_path = score_path(app, score_id)?;
+    let name = cache_entry(&entry.sha256);
+    if let Some(json) = read_archive_entry(&olscore_path, &name)? {
+        if let Ok(cached) = serde_json::from_str::<SystemAnalysis>(&json) {
+            if cached.version == ANALYSIS_VERSION {
+                return Ok(cached);
+            }
+        }
+    }
+
+    let mut pages = Vec::new();
+    render_pages(app, score_id, pdf, RENDER_WIDTH, |page, image| {
+        pages.push(PageSystems {
+            page,
+            systems: detect_systems(&image),
+        });
+        Ok(())
+    })?;
+    let analysis = SystemAnalysis {
+        version: ANALYSIS_VERSION,
+        sha256: entry.sha256.clone(),
+        pages,
+    };
+    let json = serde_json::to_vec_pretty(&analysis)
+        .map_err(|e| format!("Failed to serialize analysis: {e}"))?;
+    replace_archive_entries(&olscore_path, &[(&name, json)])?;
+    Ok(analysis)
+}
+
+#[tauri::command]
+pub async fn get_systems(
+    app: AppHandle,
+    score_id: String,
+    pdf: String,
+) -> Result<SystemAnalysis, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let manifest = read_manifest_at(&score_path(&app, &score_id)?)?;
+        analyze(&app, &score_id, &manifest, &pdf)
+    })
+    .await
+    .map_err(|e| format!("Failed to detect systems: {e}"))?
+}
+
+/// Adds split points between the systems of each analysed PDF. PDFs that
+/// were removed or replaced since the analysis are left out.
+fn merge_split_points(
+    manifest: &mut ScoreManifest,
+    analyses: Vec<(String, SystemAnalysis)>,
+    overwrite: bool,
+) {
+    for (pdf, analysis) in analyses {
+        if !manifest
+            .pdfs
+            .iter()
+            .any(|p| p.filename == pdf && p.sha256 == analysis.sha256)
+        {
+            continue;
+        }
+        let pages = manifest.split_points.entry(pdf).or_default();
+        for page in analysis.pages {
+            let points = split_points(&page.systems);
+            if points.is_empty() || (!overwrite && pages.contains_key(&page.page)) {
+                continue;
+            }
+            pages.insert(page.page, points);
+        }
+    }
+    manifest.split_points.retain(|_, pages| !pages.is_empty());
+}
+
+/// Sets split points between the detected systems of every page. Pages
+/// that already have split points keep them unless `overwrite` is set.
+#[tauri::command]
+pub async fn split_at_systems(
+    app: AppHandle,
+    score_id: String,
+    overwrite: bool,
+) -> Result<ScoreManifest, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let olscore_path = score_path(&app, &score_id)?;
+        let manifest = read_manifest_at(&olscore_path)?;
+        let mut analyses = Vec::new();
+        for pdf in &manifest.pdfs {
+            analyses.push((
+                pdf.filename.clone(),
+                analyze(&app, &score_id, &manifest, &pdf.filename)?,
+            ));
+        }
+        // Rendering can take minutes, so merge into the manifest as it is now
+        modify_manifest(&olscore_path, |manifest| {
+            merge_split_points(manifest, analyses, overwrite);
+            Ok(())
+        })
+    })
+    .await
+    .map_err(|e| format!("Failed to split at systems: {e}"))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::images::{otsu_threshold, rotate, skew_angle};
+    use image::{GrayImage, Luma};
+
+    const WIDTH: u32 = 1200;
+    const HEIGHT: u32 = 1600;
+    const PAPER: u8 = 225;
+    const INK: u8 = 40;
+    const SPACING: u32 = 12;
+    /// Gap between the staves of one system.
+    const STAFF_GAP: u32 = 60;
+
+    /// Rows of a staff whose top line starts at `top`, each 2 pixels thick.
+    fn staff_rows(top: u32) -> impl Iterator<Item = u32> {
+        (0..5).flat_map(move |line| [top + line * SPACING, top + line * SPACING + 1])
+    }
+
+    fn staff_height() -> u32 {
+        4 * SPACING + 2
+    }
+
+    fn blank() -> GrayImage {
+        GrayImage::from_pixel(WIDTH, HEIGHT, Luma([PAPER]))
+    }
+
+    /// Draws a system of `staves` staves, joined by a barline at `left`.
+    fn draw_system(image: &mut GrayImage, top: u32, left: u32, right: u32, staves: u32) {
+        for staff in 0..staves {
+            for y in staff_rows(top + staff * (staff_height() + STAFF_GAP)) {
+                for x in left..right {
+                    image.put_pixel(x, y, Luma([INK]));
+                }
+            }
+        }
+        let bottom = top + staves * staff_height() + (staves - 1) * STAFF_GAP;
+        for y in top..bottom {
+            for x in left..left + 2 {
+                image.put_pixel(x, y, Luma([INK]));
+            }
+        }
+    }
+
+    /// Three systems of two staves at these tops, spanning 100..1100.
+    const TOPS: [u32; 3] = [200, 600, 1000];
+
+    fn clean() -> GrayImage {
+        let mut image = blank();
+        for top in TOPS {
+            draw_system(&mut image, top, 100, 1100, 2);
+        }
+        image
+    }
+
+    /// Salt and pepper plus gray jitter from a fixed LCG.
+    fn add_noise(image: &mut GrayImage) {
+        let mut state = 0x2545_f491_u32;
+        for pixel in image.pixels_mut() {
+            state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            let roll = state >> 24;
+            let jitter = ((state >> 8) & 0x1f) as i32 - 16;
+            pixel[0] = match roll {
+                0..=2 => 0,
+                3..=5 => 255,
+                _ => (pixel[0] as i32 + jitter).clamp(0, 255) as u8,
+            };
+        }
+    }
+
+    fn page(image: &GrayImage) -> PageImage {
+        PageImage {
+            luma: image.as_raw().clone(),
+            width: image.width() as usize,
+            height: image.height() as usize,
+        }
+    }
+
+    fn assert_near(actual: f64, expected_px: f64, size: u32, tolerance_px: f64) {
+        let expected = expected_px / size as f64;
+        assert!(
+            (actual - expected).abs() <= tolerance_px / size as f64,
+            "{actual} is not within {tolerance_px}px of {expected}"
+        );
+    }
+
+    /// Checks the edges of a two-staff system with its top line at `top`.
+    fn assert_system(system: &System, top: u32, left: u32, right: u32, tolerance_px: f64) {
+        let margin = SPACING as f64 * SYSTEM_MARGIN;
+        let bottom = top + 2 * staff_height() + STAFF_GAP - 1;
+        let bounds = &system.bounds;
+        assert_near(bounds.x, left as f64, WIDTH, tolerance_px);
+        assert_near(
+            bounds.x + bounds.width,
+            (right - 1) as f64,
+            WIDTH,
+            tolerance_px,
+        );
+        assert_near(bounds.y, top as f64 - margin, HEIGHT, tolerance_px);
+        assert_near(
+            bounds.y + bounds.height,
+            bottom as f64 + margin,
+            HEIGHT,
+            tolerance_px,
+        );
+    }
+
+    #[test]
+    fn clean_page() {
+        let image = clean();
+        let threshold = otsu_threshold(&image);
+        assert!((INK..PAPER).contains(&threshold), "threshold {threshold}");
+        assert_eq!(skew_angle(&image), 0.0);
+
+        let systems = detect_systems(&page(&image));
+        assert_eq!(systems.len(), 3);
+        for (system, top) in systems.iter().zip(TOPS) {
+            assert_eq!(system.staves.len(), 2);
+            assert!(system.confidence > 0.9, "confidence {}", system.confidence);
+            assert_system(system, top, 100, 1100, 1.0);
+        }
+
+        let splits = split_points(&systems);
+        assert_eq!(splits.len(), 2);
+        assert!(splits[0] > systems[0].bounds.y + systems[0].bounds.height);
+        assert!(splits[0] < systems[1].bounds.y);
+    }
+
+    #[test]
+    fn skewed_page_is_straightened() {
+        let skewed = rotate(&clean(), 1.5);
+        let angle = skew_angle(&skewed);
+        assert!((angle + 1.5).abs() <= 0.2, "angle {angle}");
+
+        let systems = detect_systems(&page(&rotate(&skewed, angle)));
+        assert_eq!(systems.len(), 3);
+        for (system, top) in systems.iter().zip(TOPS) {
+            assert_eq!(system.staves.len(), 2);
+            assert_system(system, top, 100, 1100, 6.0);
+        }
+    }
+
+    #[test]
+    fn noisy_page() {
+        let mut image = clean();
+        add_noise(&mut image);
+        let threshold = otsu_threshold(&image);
+        assert!((INK..PAPER).contains(&threshold), "threshold {threshold}");
+        assert_eq!(skew_angle(&image), 0.0);
+
+        let systems = detect_systems(&page(&image));
+        assert_eq!(systems.len(), 3);
+        // Specks next to a line end are bridged like gaps in the line
+        let bridged = (WIDTH / 200 + 2) as f64;
+        for (system, top) in systems.iter().zip(TOPS) {
+            assert_eq!(system.staves.len(), 2);
+            assert_system(system, top, 100, 1100, bridged);
+        }
+    }
+
+    #[test]
+    fn two_column_page() {
+        // Columns are staggered, so each system comes out on its own
+        let mut image = blank();
+        let columns = [(80, 560), (640, 1120)];
+        let placed: Vec<(u32, (u32, u32))> = (0..4)
+            .map(|i| (150 + i * 340, columns[i as usize % 2]))
+            .collect();
+        for &(top, (left, right)) in &placed {
+            draw_system(&mut image, top, left, right, 2);
+        }
+        assert_eq!(skew_angle(&image), 0.0);
+
+        let systems = detect_systems(&page(&image));
+        assert_eq!(systems.len(), 4);
+        for (system, &(top, (left, right))) in systems.iter().zip(&placed) {
+            assert_eq!(system.staves.len(), 2);
+            assert_system(system, top, left, right, 1.0);
+        }
+    }
+
+    #[test]
+    fn split_points_merge_into_the_current_manifest() {
+        let systems = detect_systems(&page(&clean()));
+        let analysis = |sha256: &str| SystemAnalysis {
+            version: ANALYSIS_VERSION,
+            sha256: sha256.into(),
+            pages: (0..2)
+                .map(|page| PageSystems {
+                    page,
+                    systems: systems.clone(),
+                })
+                .collect(),
+        };
+        let mut manifest = crate::olscore::new_score_manifest("score.pdf", "abc", 2);
+        manifest
+            .split_points
+            .insert("score.pdf".into(), [(1, vec![0.5])].into());
+        // Changed by another command while the pages were analysed
+        manifest.tags.push("edited".into());
+
+        let mut merged = manifest.clone();
+        merge_split_points(
+            &mut merged,
+            vec![("score.pdf".into(), analysis("abc"))],
+            false,
+        );
+        let pages = &merged.split_points["score.pdf"];
+        assert_eq!(pages[&0].len(), 2);
+        assert_eq!(pages[&1], [0.5]);
+        assert_eq!(merged.tags, ["edited"]);
+
+        merge_split_points(
+            &mut merged,
+            vec![("score.pdf".into(), analysis("abc"))],
+            true,
+        );
+        assert_eq!(merged.split_points["score.pdf"][&1].len(), 2);
+
+        // A PDF replaced since the analysis keeps its points
+        let mut replaced = manifest.clone();
+        merge_split_points(
+            &mut replaced,
+            vec![("score.pdf".into(), analysis("old"))],
+            true,
+        );
+        assert_eq!(replaced.split_points, manifest.split_points);
+    }
+}
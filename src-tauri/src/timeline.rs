@@ -0,0 +1,425 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tauri::{AppHandle, Emitter, State};
+
+use crate::audio::{find_entry, save_sync_points, AudioEntry, AudioSyncPoint};
+use crate::olscore::{read_manifest_at, score_path, CropRect, PageRef};
+
+/// Where playback is in the score.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TimelinePosition {
+    pub time: f64,
+    pub pdf: String,
+    pub page: u32,
+    pub region: Option<CropRect>,
+    /// Estimated vertical position of the playhead on the page (0 = top).
+    pub y: f64,
+    /// Index of the sync point in effect.
+    pub point: usize,
+    /// When playback next moves to another page, if it does.
+    pub next_turn: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PageTurnEvent {
+    pub score_id: String,
+    pub audio_id: String,
+    pub position: TimelinePosition,
+}
+
+/// Where a sync point's region starts and ends vertically.
+fn region_span(point: &AudioSyncPoint) -> (f64, f64) {
+    point.region.map_or((0.0, 1.0), |r| (r.y, r.y + r.height))
+}
+
+fn same_page(a: &AudioSyncPoint, b: &AudioSyncPoint) -> bool {
+    a.pdf == b.pdf && a.page == b.page
+}
+
+/// Position at `time` given sync points sorted by time. Before the first
+/// point playback is taken to be at it; between points the playhead moves
+/// linearly down the page.
+pub fn position_at(points: &[AudioSyncPoint], time: f64) -> Option<TimelinePosition> {
+    let index = points
+        .iter()
+        .rposition(|p| p.time <= time)
+        .unwrap_or_default();
+    let current = points.get(index)?;
+    let next = points.get(index + 1);
+
+    let (top, bottom) = region_span(current);
+    let y = match next {
+        Some(next) if next.time > current.time && time > current.time => {
+            let progress = ((time - current.time) / (next.time - current.time)).min(1.0);
+            // Run towards the next point's region on the same page, or
+            // the end of the current one before a turn
+            let end = if same_page(current, next) {
+                region_span(next).0.max(top)
+            } else {
+                bottom
+            };
+            top + (end - top) * progress
+        }
+        _ => top,
+    };
+    let next_turn = points[index + 1..]
+        .iter()
+        .find(|p| !same_page(current, p))
+        .map(|p| p.time);
+
+    Some(TimelinePosition {
+        time,
+        pdf: current.pdf.clone(),
+        page: current.page,
+        region: current.region,
+        y,
+        point: index,
+        next_turn,
+    })
+}
+
+struct Playback {
+    points: Vec<AudioSyncPoint>,
+    page: Option<(String, u32)>,
+}
+
+/// Sync points recorded by tapping along with playback.
+struct TapCapture {
+    sequence: Vec<PageRef>,
+    index: usize,
+    points: Vec<AudioSyncPoint>,
+}
+
+impl TapCapture {
+    /// Starts at `index` in the sequence at `time`.
+    fn start(sequence: Vec<PageRef>, index: usize, time: f64) -> Result<Self, String> {
+        if index >= sequence.len() {
+            return Err(format!("Page index out of range: {index}"));
+        }
+        let mut capture = Self {
+            sequence,
+            index,
+            points: Vec::new(),
+        };
+        capture.points.push(capture.point(time.max(0.0)));
+        Ok(capture)
+    }
+
+    /// Turns to the next page at `time`.
+    fn tap(&mut self, time: f64) -> Result<AudioSyncPoint, String> {
+        if self.index + 1 >= self.sequence.len() {
+            return Err("Already on the last page".into());
+        }
+        // Taps can't go back in time, e.g. after seeking backwards
+        let last = self.points.last().map_or(0.0, |p| p.time);
+        if time.is_nan() || time < last {
+            return Err("Tap is before the previous page turn".into());
+        }
+
+        self.index += 1;
+        let point = self.point(time);
+        self.points.push(point.clone());
+        Ok(point)
+    }
+
+    /// Takes back the last tap, but never the starting point.
+    fn undo(&mut self) -> Option<AudioSyncPoint> {
+        if self.points.len() <= 1 {
+            return None;
+        }
+        self.index -= 1;
+        self.points.pop()
+    }
+
+    fn point(&self, time: f64) -> AudioSyncPoint {
+        let page = &self.sequence[self.index];
+        AudioSyncPoint {
+            time,
+            pdf: page.pdf.clone(),
+            page: page.page,
+            region: None,
+            measure: None,
+        }
+    }
+}
+
+/// Active playback timelines and tap-along captures, keyed by score and
+/// recording.
+#[derive(Default)]
+pub struct TimelineState {
+    playing: Mutex<HashMap<String, Playback>>,
+    capturing: Mutex<HashMap<String, TapCapture>>,
+}
+
+fn timeline_key(score_id: &str, audio_id: &str) -> String {
+    format!("{score_id}:{audio_id}")
+}
+
+fn load_audio(app: &AppHandle, score_id: &str, audio_id: &str) -> Result<AudioEntry, String> {
+    let mut manifest = read_manifest_at(&score_path(app, score_id)?)?;
+    find_entry(&mut manifest.audio, audio_id).cloned()
+}
+
+/// Loads a recording's sync points for playback. Page turns are then
+/// driven by `timeline_tick`.
+#[tauri::command]
+pub fn start_timeline(
+    app: AppHandle,
+    state: State<'_, TimelineState>,
+    score_id: String,
+    audio_id: String,
+) -> Result<(), String> {
+    let entry = load_audio(&app, &score_id, &audio_id)?;
+    if entry.sync_points.is_empty() {
+        return Err(format!("Recording has no sync points: {}", entry.name));
+    }
+    state.playing.lock().map_err(|e| e.to_string())?.insert(
+        timeline_key(&score_id, &audio_id),
+        Playback {
+            points: entry.sync_points,
+            page: None,
+        },
+    );
+    Ok(())
+}
+
+/// Advances the timeline to the frontend's playback time. Emits a
+/// `timeline-page-turn` event whenever the page changes, including on the
+/// first tick and after seeking.
+#[tauri::command]
+pub fn timeline_tick(
+    app: AppHandle,
+    state: State<'_, TimelineState>,
+    score_id: String,
+    audio_id: String,
+    time: f64,
+) -> Result<TimelinePosition, String> {
+    let mut playing = state.playing.lock().map_err(|e| e.to_string())?;
+    let playback = playing
+        .get_mut(&timeline_key(&score_id, &audio_id))
+        .ok_or_else(|| format!("Timeline not started: {audio_id}"))?;
+    let position =
+        position_at(&playback.points, time.max(0.0)).ok_or("Recording has no sync points")?;
+
+    let page = (position.pdf.clone(), position.page);
+    if playback.page.as_ref() != Some(&page) {
+        playback.page = Some(page);
+        let _ = app.emit(
+            "timeline-page-turn",
+            PageTurnEvent {
+                score_id,
+                audio_id,
+                position: position.clone(),
+            },
+        );
+    }
+    Ok(position)
+}
+
+#[tauri::command]
+pub fn stop_timeline(
+    state: State<'_, TimelineState>,
+    score_id: String,
+    audio_id: String,
+) -> Result<bool, String> {
+    Ok(state
+        .playing
+        .lock()
+        .map_err(|e| e.to_string())?
+        .remove(&timeline_key(&score_id, &audio_id))
+        .is_some())
+}
+
+/// Starts recording sync points by tapping along with playback. The
+/// capture starts at `start_index` in the page sequence (the first page by
+/// default) at `time`, and each tap turns to the next page.
+#[tauri::command]
+pub fn start_tap_along(
+    app: AppHandle,
+    state: State<'_, TimelineState>,
+    score_id: String,
+    audio_id: String,
+    start_index: Option<usize>,
+    time: Option<f64>,
+) -> Result<AudioSyncPoint, String> {
+    let mut manifest = read_manifest_at(&score_path(&app, &score_id)?)?;
+    find_entry(&mut manifest.audio, &audio_id)?;
+    let capture = TapCapture::start(
+        manifest.page_sequence(),
+        start_index.unwrap_or(0),
+        time.unwrap_or(0.0),
+    )?;
+    let point = capture.points[0].clone();
+    state
+        .capturing
+        .lock()
+        .map_err(|e| e.to_string())?
+        .insert(timeline_key(&score_id, &audio_id), capture);
+    Ok(point)
+}
+
+/// Records a page turn at `time` and returns the new sync point.
+#[tauri::command]
+pub fn tap_page_turn(
+    state: State<'_, TimelineState>,
+    score_id: String,
+    audio_id: String,
+    time: f64,
+) -> Result<AudioSyncPoint, String> {
+    let mut capturing = state.capturing.lock().map_err(|e| e.to_string())?;
+    let capture = capturing
+        .get_mut(&timeline_key(&score_id, &audio_id))
+        .ok_or_else(|| format!("Not tapping along: {audio_id}"))?;
+    capture.tap(time)
+}
+
+/// Takes back the last tap. The starting point can't be undone.
+#[tauri::command]
+pub fn undo_tap(
+    state: State<'_, TimelineState>,
+    score_id: String,
+    audio_id: String,
+) -> Result<Option<AudioSyncPoint>, String> {
+    let mut capturing = state.capturing.lock().map_err(|e| e.to_string())?;
+    let capture = capturing
+        .get_mut(&timeline_key(&score_id, &audio_id))
+        .ok_or_else(|| format!("Not tapping along: {audio_id}"))?;
+    Ok(capture.undo())
+}
+
+/// Ends a tap-along capture. With `save` the taps replace the recording's
+/// sync points; otherwise they are dropped and the entry is left as is.
+#[tauri::command]
+pub fn finish_tap_along(
+    app: AppHandle,
+    state: State<'_, TimelineState>,
+    score_id: String,
+    audio_id: String,
+    save: bool,
+) -> Result<AudioEntry, String> {
+    let capture = state
+        .capturing
+        .lock()
+        .map_err(|e| e.to_string())?
+        .remove(&timeline_key(&score_id, &audio_id))
+        .ok_or_else(|| format!("Not tapping along: {audio_id}"))?;
+    if save {
+        save_sync_points(&app, &score_id, &audio_id, capture.points)
+    } else {
+        load_audio(&app, &score_id, &audio_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::olscore::new_score_manifest;
+
+    fn point(time: f64, page: u32, region: Option<(f64, f64)>) -> AudioSyncPoint {
+        AudioSyncPoint {
+            time,
+            pdf: "score.pdf".into(),
+            page,
+            region: region.map(|(y, height)| CropRect {
+                x: 0.0,
+                y,
+                width: 1.0,
+                height,
+            }),
+            measure: None,
+        }
+    }
+
+    /// Two systems on page 0, then page 1 from 20s.
+    fn points() -> Vec<AudioSyncPoint> {
+        vec![
+            point(2.0, 0, Some((0.1, 0.3))),
+            point(10.0, 0, Some((0.5, 0.3))),
+            point(20.0, 1, None),
+        ]
+    }
+
+    fn assert_y(position: &TimelinePosition, y: f64) {
+        assert!((position.y - y).abs() < 1e-9, "y {} != {y}", position.y);
+    }
+
+    #[test]
+    fn before_the_first_point_playback_waits_at_it() {
+        let position = position_at(&points(), 0.5).unwrap();
+        assert_eq!((position.page, position.point), (0, 0));
+        assert_y(&position, 0.1);
+        assert_eq!(position.next_turn, Some(20.0));
+        assert!(position_at(&[], 1.0).is_none());
+    }
+
+    #[test]
+    fn playhead_moves_towards_the_next_region_on_the_page() {
+        let position = position_at(&points(), 6.0).unwrap();
+        assert_eq!(position.point, 0);
+        assert_eq!(position.region, points()[0].region);
+        // Halfway from the top of the first system to the top of the second
+        assert_y(&position, 0.3);
+    }
+
+    #[test]
+    fn playhead_runs_to_the_region_end_before_a_turn() {
+        let position = position_at(&points(), 15.0).unwrap();
+        assert_eq!((position.page, position.point), (0, 1));
+        assert_y(&position, 0.65);
+        assert_eq!(position.next_turn, Some(20.0));
+
+        let turned = position_at(&points(), 25.0).unwrap();
+        assert_eq!((turned.page, turned.point), (1, 2));
+        assert_y(&turned, 0.0);
+        assert_eq!(turned.next_turn, None);
+    }
+
+    #[test]
+    fn next_turn_skips_points_on_the_same_page() {
+        let mut points = points();
+        points.push(point(30.0, 1, Some((0.5, 0.2))));
+        points.push(point(40.0, 0, None));
+        assert_eq!(position_at(&points, 21.0).unwrap().next_turn, Some(40.0));
+        assert_eq!(position_at(&points, 45.0).unwrap().next_turn, None);
+    }
+
+    #[test]
+    fn duplicate_times_use_the_last_point() {
+        let points = [
+            point(0.0, 0, None),
+            point(5.0, 0, Some((0.2, 0.2))),
+            point(5.0, 1, Some((0.4, 0.2))),
+            point(9.0, 2, None),
+        ];
+        let position = position_at(&points, 5.0).unwrap();
+        assert_eq!((position.page, position.point), (1, 2));
+        assert_y(&position, 0.4);
+        // Just before the jump there is no division by a zero interval
+        let before = position_at(&points, 4.999).unwrap();
+        assert_eq!(before.point, 0);
+        assert!(before.y.is_finite());
+        assert_eq!(before.next_turn, Some(5.0));
+    }
+
+    #[test]
+    fn tapping_along_an_imported_score() {
+        let manifest = new_score_manifest("score.pdf", "abc", 3);
+        let mut capture = TapCapture::start(manifest.page_sequence(), 0, -1.0).unwrap();
+        assert_eq!(capture.points[0].time, 0.0);
+
+        assert_eq!(capture.tap(12.5).unwrap().page, 1);
+        assert!(capture.tap(10.0).is_err());
+        assert!(capture.tap(f64::NAN).is_err());
+        assert_eq!(capture.tap(30.0).unwrap().page, 2);
+        assert!(capture.tap(40.0).is_err());
+
+        assert_eq!(capture.undo().unwrap().page, 2);
+        assert_eq!(capture.undo().unwrap().page, 1);
+        assert!(capture.undo().is_none());
+        assert_eq!(capture.tap(14.0).unwrap().page, 1);
+        assert_eq!(capture.points.len(), 2);
+
+        assert!(TapCapture::start(manifest.page_sequence(), 3, 0.0).is_err());
+    }
+}
@@ -0,0 +1,220 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+use time::format_description::well_known::Rfc3339;
+use time::{Duration, OffsetDateTime, UtcOffset};
+
+/// A point in time, kept in UTC. Reads any RFC 3339 timestamp, with an
+/// offset and fractional seconds, and writes it back in UTC, so stored
+/// timestamps compare and sort by the instant they name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp(OffsetDateTime);
+
+impl Timestamp {
+    /// The current time, to the millisecond.
+    pub fn now() -> Self {
+        let now = OffsetDateTime::now_utc();
+        let millis = now.nanosecond() / 1_000_000 * 1_000_000;
+        Self(now.replace_nanosecond(millis).unwrap_or(now))
+    }
+
+    pub fn parse(value: &str) -> Result<Self, String> {
+        OffsetDateTime::parse(value, &Rfc3339)
+            .map(|t| Self(t.to_offset(UtcOffset::UTC)))
+            .map_err(|e| format!("Invalid timestamp {value:?}: {e}"))
+    }
+
+    /// Whole seconds from `earlier` to this, zero if `earlier` is later.
+    pub fn seconds_since(&self, earlier: Timestamp) -> u64 {
+        (self.0 - earlier.0).whole_seconds().max(0) as u64
+    }
+
+    pub fn minus_days(&self, days: u32) -> Self {
+        Self(
+            self.0
+                .checked_sub(Duration::days(days as i64))
+                .unwrap_or(OffsetDateTime::UNIX_EPOCH),
+        )
+    }
+}
+
+/// The Unix epoch, standing in for timestamps that are missing.
+impl Default for Timestamp {
+    fn default() -> Self {
+        Self(OffsetDateTime::UNIX_EPOCH)
+    }
+}
+
+/// A stored timestamp that shouldn't fail a whole file when one entry
+/// holds something unreadable, e.g. from an older version or a hand edit.
+/// Unreadable strings are kept as found, so writing the file back doesn't
+/// lose them, and sort as the Unix epoch; other values read as missing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StoredTimestamp {
+    Valid(Timestamp),
+    Invalid(String),
+}
+
+impl StoredTimestamp {
+    /// The instant named, the Unix epoch if unreadable.
+    pub fn timestamp(&self) -> Timestamp {
+        match self {
+            Self::Valid(timestamp) => *timestamp,
+            Self::Invalid(_) => Timestamp::default(),
+        }
+    }
+}
+
+impl Default for StoredTimestamp {
+    fn default() -> Self {
+        Self::Valid(Timestamp::default())
+    }
+}
+
+impl From<Timestamp> for StoredTimestamp {
+    fn from(timestamp: Timestamp) -> Self {
+        Self::Valid(timestamp)
+    }
+}
+
+impl Serialize for StoredTimestamp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Self::Valid(timestamp) => timestamp.serialize(serializer),
+            Self::Invalid(value) => serializer.serialize_str(value),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for StoredTimestamp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = serde_json::Value::deserialize(deserializer)?;
+        Ok(match value {
+            serde_json::Value::String(value) => match Timestamp::parse(&value) {
+                Ok(timestamp) => Self::Valid(timestamp),
+                Err(_) => Self::Invalid(value),
+            },
+            _ => Self::default(),
+        })
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let formatted = self.0.format(&Rfc3339).map_err(|_| fmt::Error)?;
+        f.write_str(&formatted)
+    }
+}
+
+impl FromStr for Timestamp {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, String> {
+        Self::parse(value)
+    }
+}
+
+impl Serialize for Timestamp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Timestamp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        Self::parse(&value).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{DateTime, Utc};
+    use proptest::prelude::*;
+
+    /// RFC 3339 timestamps with any offset and 0-9 fractional digits.
+    fn rfc3339() -> impl Strategy<Value = String> {
+        let date = (1971..=9998i32, 1..=12u32, 1..=28u32);
+        let time = (0..24u32, 0..60u32, 0..60u32);
+        let fraction = prop::option::of("[0-9]{1,9}");
+        let offset = prop::option::of((any::<bool>(), 0..24u32, 0..60u32));
+        (date, time, fraction, offset).prop_map(|((y, mo, d), (h, mi, s), fraction, offset)| {
+            let fraction = fraction.map(|f| format!(".{f}")).unwrap_or_default();
+            let offset = match offset {
+                Some((east, oh, om)) => format!("{}{oh:02}:{om:02}", if east { '+' } else { '-' }),
+                None => "Z".into(),
+            };
+            format!("{y:04}-{mo:02}-{d:02}T{h:02}:{mi:02}:{s:02}{fraction}{offset}")
+        })
+    }
+
+    fn chrono(value: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(value)
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    fn nanos(timestamp: &Timestamp) -> i128 {
+        timestamp.0.unix_timestamp_nanos()
+    }
+
+    /// Beyond 2262 chrono's own nanosecond count overflows.
+    fn chrono_nanos(value: &str) -> i128 {
+        let utc = chrono(value);
+        utc.timestamp() as i128 * 1_000_000_000 + utc.timestamp_subsec_nanos() as i128
+    }
+
+    proptest! {
+        #[test]
+        fn parses_the_same_instant_as_chrono(value in rfc3339()) {
+            let timestamp = Timestamp::parse(&value).unwrap();
+            prop_assert_eq!(nanos(&timestamp), chrono_nanos(&value));
+        }
+
+        #[test]
+        fn orders_like_chrono(a in rfc3339(), b in rfc3339()) {
+            let (ta, tb) = (Timestamp::parse(&a).unwrap(), Timestamp::parse(&b).unwrap());
+            prop_assert_eq!(ta.cmp(&tb), chrono(&a).cmp(&chrono(&b)));
+        }
+
+        #[test]
+        fn displays_in_utc_like_chrono(value in rfc3339()) {
+            let utc = chrono(&value);
+            // Fractional seconds are written with trailing zeros dropped
+            let digits = format!("{:09}", utc.timestamp_subsec_nanos());
+            let digits = digits.trim_end_matches('0');
+            let fraction = if digits.is_empty() { String::new() } else { format!(".{digits}") };
+            let expected = format!("{}{fraction}Z", utc.format("%Y-%m-%dT%H:%M:%S"));
+            prop_assert_eq!(Timestamp::parse(&value).unwrap().to_string(), expected);
+        }
+
+        #[test]
+        fn serde_round_trip_keeps_the_instant(value in rfc3339()) {
+            let timestamp = Timestamp::parse(&value).unwrap();
+            let json = serde_json::to_string(&timestamp).unwrap();
+            let read: Timestamp = serde_json::from_str(&json).unwrap();
+            prop_assert_eq!(read, timestamp);
+            prop_assert_eq!(nanos(&read), chrono_nanos(&value));
+        }
+    }
+
+    #[test]
+    fn unreadable_strings_are_kept_and_sort_as_the_epoch() {
+        let read = |json: &str| serde_json::from_str::<StoredTimestamp>(json).unwrap();
+        let valid = read("\"2024-03-01T13:00:00+01:00\"");
+        assert_eq!(valid.timestamp().to_string(), "2024-03-01T12:00:00Z");
+        assert_eq!(
+            serde_json::to_string(&valid).unwrap(),
+            "\"2024-03-01T12:00:00Z\""
+        );
+
+        let invalid = read("\"yesterday\"");
+        assert_eq!(invalid, StoredTimestamp::Invalid("yesterday".into()));
+        assert_eq!(invalid.timestamp(), Timestamp::default());
+        assert_eq!(serde_json::to_string(&invalid).unwrap(), "\"yesterday\"");
+
+        assert_eq!(read("null"), StoredTimestamp::default());
+        assert_eq!(read("42").timestamp(), Timestamp::default());
+    }
+}
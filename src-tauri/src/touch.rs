@@ -0,0 +1,675 @@
+use evdev::{Device, InputEvent};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tauri::ipc::Channel;
+use tauri::{AppHandle, Emitter, State};
+
+use crate::input::InputService;
+use crate::mapping::{CoordinateMapper, MappedPoint};
+use crate::palm::{PalmFilter, PalmState, PenProximity};
+use crate::stylus::{event_timestamp_us, read_device_axes, DeviceAxes};
+
+const EV_SYN: u16 = 0;
+const EV_ABS: u16 = 3;
+const SYN_REPORT: u16 = 0;
+
+const ABS_MT_SLOT: u16 = 47;
+const ABS_MT_TOUCH_MAJOR: u16 = 48;
+const ABS_MT_WIDTH_MAJOR: u16 = 50;
+const ABS_MT_POSITION_X: u16 = 53;
+const ABS_MT_POSITION_Y: u16 = 54;
+const ABS_MT_TRACKING_ID: u16 = 57;
+const ABS_MT_PRESSURE: u16 = 58;
+
+/// Slots kept when the device doesn't say; the kernel caps most panels at 10.
+const DEFAULT_SLOTS: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TouchEventKind {
+    Down,
+    Move,
+    Up,
+    /// Contact turned out to be a palm; discard it rather than treat it as lifted.
+    Cancel,
+}
+
+/// One contact's state change. Each `contact_id` (the kernel tracking ID)
+/// forms its own down, move..., up stream.
+#[derive(Debug, Clone, Serialize)]
+pub struct TouchEvent {
+    pub contact_id: i32,
+    pub slot: usize,
+    pub x: i32,
+    pub y: i32,
+    pub touch_major: i32,
+    pub width_major: i32,
+    pub pressure: i32,
+    pub event_type: TouchEventKind,
+    pub timestamp_us: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mapped: Option<MappedPoint>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Slot {
+    tracking_id: Option<i32>,
+    x: i32,
+    y: i32,
+    touch_major: i32,
+    width_major: i32,
+    pressure: i32,
+    /// Tracking ID that ended this frame, reported with the last position.
+    ended: Option<i32>,
+    began: bool,
+    dirty: bool,
+}
+
+impl Slot {
+    fn event(&self, slot: usize, contact_id: i32, kind: TouchEventKind, ts: u64) -> TouchEvent {
+        TouchEvent {
+            contact_id,
+            slot,
+            x: self.x,
+            y: self.y,
+            touch_major: self.touch_major,
+            width_major: self.width_major,
+            pressure: self.pressure,
+            event_type: kind,
+            timestamp_us: ts,
+            mapped: None,
+        }
+    }
+}
+
+/// Type-B multitouch decoder: follows `ABS_MT_SLOT` and keeps per-slot
+/// contact state, emitting events for every slot touched in a frame.
+pub struct MultitouchParser {
+    slots: Vec<Slot>,
+    current: usize,
+}
+
+impl MultitouchParser {
+    pub fn new(slot_count: usize) -> Self {
+        Self {
+            slots: vec![Slot::default(); slot_count.max(1)],
+            current: 0,
+        }
+    }
+
+    pub fn for_device(device: &Device) -> Self {
+        let slots = device
+            .get_abs_state()
+            .ok()
+            .map(|state| state[ABS_MT_SLOT as usize].maximum as usize + 1)
+            .filter(|&n| n > 1)
+            .unwrap_or(DEFAULT_SLOTS);
+        Self::new(slots)
+    }
+
+    pub fn feed_raw(&mut self, ev_type: u16, code: u16, value: i32, ts: u64) -> Vec<TouchEvent> {
+        match (ev_type, code) {
+            (EV_ABS, ABS_MT_SLOT) => {
+                let slot = value.max(0) as usize;
+                if slot >= self.slots.len() {
+                    self.slots.resize(slot + 1, Slot::default());
+                }
+                self.current = slot;
+            }
+            (EV_ABS, _) => {
+                let slot = &mut self.slots[self.current];
+                match code {
+                    ABS_MT_TRACKING_ID if value < 0 => {
+                        if let Some(id) = slot.tracking_id.take() {
+                            if slot.began {
+                                // Lifted within the frame it started in
+                                slot.began = false;
+                            } else {
+                                slot.ended = Some(id);
+                            }
+                        }
+                    }
+                    ABS_MT_TRACKING_ID => match slot.tracking_id.replace(value) {
+                        Some(old) if old == value => {}
+                        Some(old) => {
+                            // A new ID on an active slot ends the previous contact
+                            if !slot.began {
+                                slot.ended = Some(old);
+                            }
+                            slot.began = true;
+                        }
+                        None => slot.began = true,
+                    },
+                    ABS_MT_POSITION_X => slot.x = value,
+                    ABS_MT_POSITION_Y => slot.y = value,
+                    ABS_MT_TOUCH_MAJOR => slot.touch_major = value,
+                    ABS_MT_WIDTH_MAJOR => slot.width_major = value,
+                    ABS_MT_PRESSURE => slot.pressure = value,
+                    _ => return Vec::new(),
+                }
+                slot.dirty = true;
+            }
+            (EV_SYN, SYN_REPORT) => return self.flush_frame(ts),
+            _ => {}
+        }
+        Vec::new()
+    }
+
+    fn flush_frame(&mut self, ts: u64) -> Vec<TouchEvent> {
+        let mut events = Vec::new();
+        for (index, slot) in self.slots.iter_mut().enumerate() {
+            if let Some(id) = slot.ended.take() {
+                events.push(slot.event(index, id, TouchEventKind::Up, ts));
+            }
+            if let Some(id) = slot.tracking_id {
+                if slot.began {
+                    events.push(slot.event(index, id, TouchEventKind::Down, ts));
+                } else if slot.dirty {
+                    events.push(slot.event(index, id, TouchEventKind::Move, ts));
+                }
+            }
+            slot.began = false;
+            slot.dirty = false;
+        }
+        events
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TouchGesture {
+    TwoFingerSwipeLeft,
+    TwoFingerSwipeRight,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TouchGestureEvent {
+    pub device_path: String,
+    pub gesture: TouchGesture,
+}
+
+/// Minimum horizontal travel, as a fraction of the panel width.
+const SWIPE_MIN_DISTANCE: f64 = 0.12;
+/// Swipes slower than this are treated as scrolling or resting fingers.
+const SWIPE_MAX_DURATION_US: u64 = 800_000;
+
+#[derive(Debug, Clone, Copy)]
+struct Contact {
+    start: (f64, f64),
+    last: (f64, f64),
+}
+
+/// Recognizes two-finger horizontal swipes from per-contact events.
+///
+/// A swipe starts when the second finger lands and is decided when the
+/// first of the two lifts. Any third finger cancels it.
+pub struct GestureDetector {
+    axes: DeviceAxes,
+    contacts: HashMap<i32, Contact>,
+    started_at: Option<u64>,
+    cancelled: bool,
+}
+
+impl GestureDetector {
+    pub fn new(axes: DeviceAxes) -> Self {
+        Self {
+            axes,
+            contacts: HashMap::new(),
+            started_at: None,
+            cancelled: false,
+        }
+    }
+
+    fn normalize(&self, ev: &TouchEvent) -> (f64, f64) {
+        (
+            self.axes.x.map_or(0.0, |a| a.normalize(ev.x)),
+            self.axes.y.map_or(0.0, |a| a.normalize(ev.y)),
+        )
+    }
+
+    pub fn feed(&mut self, ev: &TouchEvent) -> Option<TouchGesture> {
+        let pos = self.normalize(ev);
+        match ev.event_type {
+            TouchEventKind::Down => {
+                self.contacts.insert(
+                    ev.contact_id,
+                    Contact {
+                        start: pos,
+                        last: pos,
+                    },
+                );
+                match self.contacts.len() {
+                    2 => {
+                        // Measure from where both fingers were down
+                        for contact in self.contacts.values_mut() {
+                            contact.start = contact.last;
+                        }
+                        self.started_at = Some(ev.timestamp_us);
+                    }
+                    n if n > 2 => self.cancelled = true,
+                    _ => {}
+                }
+                None
+            }
+            TouchEventKind::Move => {
+                if let Some(contact) = self.contacts.get_mut(&ev.contact_id) {
+                    contact.last = pos;
+                }
+                None
+            }
+            TouchEventKind::Up => {
+                if let Some(contact) = self.contacts.get_mut(&ev.contact_id) {
+                    contact.last = pos;
+                }
+                let gesture = self.decide(ev.timestamp_us);
+                self.contacts.remove(&ev.contact_id);
+                if self.contacts.is_empty() {
+                    self.cancelled = false;
+                }
+                gesture
+            }
+            TouchEventKind::Cancel => {
+                self.contacts.remove(&ev.contact_id);
+                self.cancelled = !self.contacts.is_empty();
+                self.started_at = None;
+                None
+            }
+        }
+    }
+
+    fn decide(&mut self, now_us: u64) -> Option<TouchGesture> {
+        let started_at = self.started_at.take()?;
+        if self.cancelled || self.contacts.len() != 2 {
+            return None;
+        }
+        if now_us.saturating_sub(started_at) > SWIPE_MAX_DURATION_US {
+            return None;
+        }
+
+        let deltas: Vec<(f64, f64)> = self
+            .contacts
+            .values()
+            .map(|c| (c.last.0 - c.start.0, c.last.1 - c.start.1))
+            .collect();
+        let (dx0, dy0) = deltas[0];
+        let (dx1, dy1) = deltas[1];
+
+        // Both fingers must travel the same way, mostly horizontally
+        let same_direction = dx0.signum() == dx1.signum();
+        let dx = (dx0 + dx1) / 2.0;
+        let dy = (dy0 + dy1) / 2.0;
+        if !same_direction || dx.abs() < SWIPE_MIN_DISTANCE || dx.abs() < 2.0 * dy.abs() {
+            return None;
+        }
+        Some(if dx < 0.0 {
+            TouchGesture::TwoFingerSwipeLeft
+        } else {
+            TouchGesture::TwoFingerSwipeRight
+        })
+    }
+}
+
+/// Events and gestures completed by one raw event.
+#[derive(Debug, Default)]
+pub struct TouchFrame {
+    pub events: Vec<TouchEvent>,
+    pub gestures: Vec<TouchGesture>,
+}
+
+/// Every stage a touch stream runs raw events through: slot decoding, palm
+/// rejection against the shared pen proximity, gestures and mapping. Trace
+/// replay uses it too, without an app handle and so without window geometry.
+pub struct TouchPipeline {
+    parser: MultitouchParser,
+    filter: PalmFilter,
+    gestures: GestureDetector,
+    mapper: CoordinateMapper,
+    pen: Arc<Mutex<PenProximity>>,
+    app: Option<AppHandle>,
+}
+
+impl TouchPipeline {
+    pub fn new(
+        parser: MultitouchParser,
+        filter: PalmFilter,
+        mapper: CoordinateMapper,
+        axes: DeviceAxes,
+        pen: Arc<Mutex<PenProximity>>,
+        app: Option<AppHandle>,
+    ) -> Self {
+        Self {
+            parser,
+            filter,
+            gestures: GestureDetector::new(axes),
+            mapper,
+            pen,
+            app,
+        }
+    }
+
+    pub fn feed(&mut self, ev: &InputEvent) -> TouchFrame {
+        self.feed_raw(
+            ev.event_type().0,
+            ev.code(),
+            ev.value(),
+            event_timestamp_us(ev),
+        )
+    }
+
+    pub fn feed_raw(&mut self, ev_type: u16, code: u16, value: i32, ts: u64) -> TouchFrame {
+        let mut frame = TouchFrame::default();
+        let events = self.parser.feed_raw(ev_type, code, value, ts);
+        if events.is_empty() {
+            return frame;
+        }
+        let pen = self.pen.lock().map(|p| *p).unwrap_or_default();
+        for event in events {
+            let Some(mut event) = self.filter.filter(event, pen) else {
+                continue;
+            };
+            if event.event_type == TouchEventKind::Down {
+                if let Some(app) = &self.app {
+                    self.mapper.refresh_geometry(app);
+                }
+            }
+            frame.gestures.extend(self.gestures.feed(&event));
+            let pressure = match event.event_type {
+                TouchEventKind::Down | TouchEventKind::Move => 1.0,
+                TouchEventKind::Up | TouchEventKind::Cancel => 0.0,
+            };
+            event.mapped = Some(self.mapper.map_raw(event.x, event.y, pressure));
+            frame.events.push(event);
+        }
+        frame
+    }
+}
+
+/// Streams per-contact `TouchEvent`s to `on_event`. Recognized gestures
+/// are emitted as `touch-gesture` events.
+#[tauri::command]
+pub fn start_touch_stream(
+    app: AppHandle,
+    service: State<'_, InputService>,
+    palm: State<'_, PalmState>,
+    device_path: String,
+    on_event: Channel<TouchEvent>,
+) -> Result<(), String> {
+    let device = Device::open(&device_path).map_err(|e| format!("Failed to open device: {e}"))?;
+    let mut pipeline = TouchPipeline::new(
+        MultitouchParser::for_device(&device),
+        PalmFilter::new(palm.config(&app)?, device.name().unwrap_or("Unknown")),
+        CoordinateMapper::for_device(&app, &device_path)?,
+        read_device_axes(&device),
+        palm.pen(),
+        Some(app.clone()),
+    );
+    drop(device);
+
+    let handle = app.clone();
+    let key = format!("touch:{device_path}");
+    let path = device_path.clone();
+    service.start(app, key, device_path, false, move |ev| {
+        let frame = pipeline.feed(ev);
+        for gesture in frame.gestures {
+            let _ = handle.emit(
+                "touch-gesture",
+                TouchGestureEvent {
+                    device_path: path.clone(),
+                    gesture,
+                },
+            );
+        }
+        frame
+            .events
+            .into_iter()
+            .all(|event| on_event.send(event).is_ok())
+    })
+}
+
+#[tauri::command]
+pub fn stop_touch_stream(
+    service: State<'_, InputService>,
+    device_path: String,
+) -> Result<bool, String> {
+    service.stop(&format!("touch:{device_path}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stylus::AxisRange;
+    use TouchEventKind::{Down, Move, Up};
+
+    /// Feeds one evdev frame of `(code, value)` ABS events and its SYN_REPORT.
+    fn frame(parser: &mut MultitouchParser, events: &[(u16, i32)], ts: u64) -> Vec<TouchEvent> {
+        for &(code, value) in events {
+            assert!(parser.feed_raw(EV_ABS, code, value, ts).is_empty());
+        }
+        parser.feed_raw(EV_SYN, SYN_REPORT, 0, ts)
+    }
+
+    fn summary(events: &[TouchEvent]) -> Vec<(TouchEventKind, i32, usize, i32, i32)> {
+        events
+            .iter()
+            .map(|e| (e.event_type, e.contact_id, e.slot, e.x, e.y))
+            .collect()
+    }
+
+    #[test]
+    fn tracks_two_contacts_across_slot_switches() {
+        let mut parser = MultitouchParser::new(4);
+        let events = frame(
+            &mut parser,
+            &[
+                (ABS_MT_SLOT, 0),
+                (ABS_MT_TRACKING_ID, 10),
+                (ABS_MT_POSITION_X, 100),
+                (ABS_MT_POSITION_Y, 200),
+                (ABS_MT_SLOT, 1),
+                (ABS_MT_TRACKING_ID, 11),
+                (ABS_MT_POSITION_X, 300),
+                (ABS_MT_POSITION_Y, 400),
+            ],
+            0,
+        );
+        assert_eq!(
+            summary(&events),
+            [(Down, 10, 0, 100, 200), (Down, 11, 1, 300, 400)]
+        );
+
+        // The kernel omits ABS_MT_SLOT when the selected slot hasn't
+        // changed, so this X still belongs to slot 1
+        let events = frame(
+            &mut parser,
+            &[
+                (ABS_MT_POSITION_X, 310),
+                (ABS_MT_SLOT, 0),
+                (ABS_MT_POSITION_Y, 210),
+            ],
+            1_000,
+        );
+        assert_eq!(
+            summary(&events),
+            [(Move, 10, 0, 100, 210), (Move, 11, 1, 310, 400)]
+        );
+
+        // Only the moved slot reports
+        let events = frame(&mut parser, &[(ABS_MT_POSITION_X, 120)], 2_000);
+        assert_eq!(summary(&events), [(Move, 10, 0, 120, 210)]);
+    }
+
+    #[test]
+    fn tracking_id_minus_one_ends_only_its_slot() {
+        let mut parser = MultitouchParser::new(2);
+        frame(
+            &mut parser,
+            &[
+                (ABS_MT_SLOT, 0),
+                (ABS_MT_TRACKING_ID, 20),
+                (ABS_MT_POSITION_X, 50),
+                (ABS_MT_POSITION_Y, 60),
+                (ABS_MT_SLOT, 1),
+                (ABS_MT_TRACKING_ID, 21),
+                (ABS_MT_POSITION_X, 70),
+                (ABS_MT_POSITION_Y, 80),
+            ],
+            0,
+        );
+
+        // Slot 0 lifts while slot 1 keeps moving in the same frame
+        let events = frame(
+            &mut parser,
+            &[
+                (ABS_MT_SLOT, 0),
+                (ABS_MT_TRACKING_ID, -1),
+                (ABS_MT_SLOT, 1),
+                (ABS_MT_POSITION_X, 75),
+            ],
+            1_000,
+        );
+        assert_eq!(
+            summary(&events),
+            [(Up, 20, 0, 50, 60), (Move, 21, 1, 75, 80)]
+        );
+        assert_eq!(events[0].timestamp_us, 1_000);
+
+        let events = frame(&mut parser, &[(ABS_MT_TRACKING_ID, -1)], 2_000);
+        assert_eq!(summary(&events), [(Up, 21, 1, 75, 80)]);
+
+        // A second -1 on an empty slot reports nothing
+        assert!(frame(&mut parser, &[(ABS_MT_TRACKING_ID, -1)], 3_000).is_empty());
+    }
+
+    #[test]
+    fn new_tracking_id_on_a_busy_slot_ends_the_old_contact() {
+        let mut parser = MultitouchParser::new(1);
+        frame(
+            &mut parser,
+            &[(ABS_MT_TRACKING_ID, 30), (ABS_MT_POSITION_X, 5)],
+            0,
+        );
+        let events = frame(
+            &mut parser,
+            &[(ABS_MT_TRACKING_ID, 31), (ABS_MT_POSITION_X, 9)],
+            1_000,
+        );
+        assert_eq!(summary(&events), [(Up, 30, 0, 9, 0), (Down, 31, 0, 9, 0)]);
+    }
+
+    #[test]
+    fn contact_lifted_within_its_first_frame_is_dropped() {
+        let mut parser = MultitouchParser::new(2);
+        let events = frame(
+            &mut parser,
+            &[
+                (ABS_MT_TRACKING_ID, 40),
+                (ABS_MT_POSITION_X, 5),
+                (ABS_MT_TRACKING_ID, -1),
+            ],
+            0,
+        );
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn slot_beyond_the_reported_count_grows_the_table() {
+        let mut parser = MultitouchParser::new(1);
+        let events = frame(
+            &mut parser,
+            &[
+                (ABS_MT_SLOT, 3),
+                (ABS_MT_TRACKING_ID, 50),
+                (ABS_MT_POSITION_X, 1),
+            ],
+            0,
+        );
+        assert_eq!(summary(&events), [(Down, 50, 3, 1, 0)]);
+    }
+
+    fn detector() -> GestureDetector {
+        let range = Some(AxisRange {
+            min: 0,
+            max: 1000,
+            resolution: 10,
+        });
+        GestureDetector::new(DeviceAxes {
+            x: range,
+            y: range,
+            ..DeviceAxes::default()
+        })
+    }
+
+    fn touch(contact_id: i32, kind: TouchEventKind, x: i32, y: i32, ts: u64) -> TouchEvent {
+        TouchEvent {
+            contact_id,
+            slot: contact_id as usize,
+            x,
+            y,
+            touch_major: 0,
+            width_major: 0,
+            pressure: 0,
+            event_type: kind,
+            timestamp_us: ts,
+            mapped: None,
+        }
+    }
+
+    /// Two fingers land at x = 500, travel `dx` and `dy` panel units each,
+    /// then the first lifts after `duration_us`.
+    fn swipe(dx: [i32; 2], dy: i32, duration_us: u64) -> Option<TouchGesture> {
+        let mut gestures = detector();
+        assert_eq!(gestures.feed(&touch(1, Down, 500, 400, 0)), None);
+        assert_eq!(gestures.feed(&touch(2, Down, 500, 600, 10_000)), None);
+        let start = 10_000;
+        for (id, y) in [(1, 400), (2, 600)] {
+            let ev = touch(id, Move, 500 + dx[id as usize - 1], y + dy, start + 1);
+            assert_eq!(gestures.feed(&ev), None);
+        }
+        gestures.feed(&touch(1, Up, 500 + dx[0], 400 + dy, start + duration_us))
+    }
+
+    #[test]
+    fn two_finger_swipe_thresholds() {
+        use TouchGesture::{TwoFingerSwipeLeft, TwoFingerSwipeRight};
+
+        assert_eq!(swipe([-200, -200], 0, 300_000), Some(TwoFingerSwipeLeft));
+        assert_eq!(swipe([150, 150], 0, 300_000), Some(TwoFingerSwipeRight));
+        // 12% of the width is the shortest swipe
+        assert_eq!(swipe([120, 120], 0, 300_000), Some(TwoFingerSwipeRight));
+        assert_eq!(swipe([110, 110], 0, 300_000), None);
+        // Averaged over both fingers
+        assert_eq!(swipe([80, 180], 0, 300_000), Some(TwoFingerSwipeRight));
+        // Fingers moving apart are a pinch
+        assert_eq!(swipe([-200, 200], 0, 300_000), None);
+        // Horizontal travel must be at least twice the vertical
+        assert_eq!(swipe([200, 200], 100, 300_000), Some(TwoFingerSwipeRight));
+        assert_eq!(swipe([200, 200], 101, 300_000), None);
+        // Timed from the second finger landing
+        assert_eq!(swipe([200, 200], 0, 800_000), Some(TwoFingerSwipeRight));
+        assert_eq!(swipe([200, 200], 0, 800_001), None);
+    }
+
+    #[test]
+    fn third_finger_cancels_until_all_lift() {
+        let mut gestures = detector();
+        gestures.feed(&touch(1, Down, 500, 400, 0));
+        gestures.feed(&touch(2, Down, 500, 600, 0));
+        gestures.feed(&touch(3, Down, 500, 800, 0));
+        gestures.feed(&touch(1, Move, 100, 400, 1));
+        gestures.feed(&touch(2, Move, 100, 600, 1));
+        assert_eq!(gestures.feed(&touch(1, Up, 100, 400, 2)), None);
+        assert_eq!(gestures.feed(&touch(3, Up, 500, 800, 3)), None);
+        assert_eq!(gestures.feed(&touch(2, Up, 100, 600, 4)), None);
+
+        // A fresh pair afterwards swipes normally
+        gestures.feed(&touch(4, Down, 500, 400, 10));
+        gestures.feed(&touch(5, Down, 500, 600, 10));
+        gestures.feed(&touch(4, Move, 300, 400, 11));
+        gestures.feed(&touch(5, Move, 300, 600, 11));
+        assert_eq!(
+            gestures.feed(&touch(4, Up, 300, 400, 12)),
+            Some(TouchGesture::TwoFingerSwipeLeft)
+        );
+    }
+}
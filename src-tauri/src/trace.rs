@@ -0,0 +1,483 @@
+use evdev::Device;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tauri::{AppHandle, Manager, State};
+
+use crate::input::{InputService, StylusPipeline};
+use crate::mapping::{CoordinateMapper, DeviceCalibration};
+use crate::palm::{PalmConfig, PalmFilter, PalmState, PenProximity};
+use crate::pedal::{GestureRecognizer, PedalAction, PedalConfig, PedalState, PressKind};
+use crate::stylus::{
+    event_timestamp_us, DeviceCapabilities, DeviceKind, InputDeviceInfo, StylusEvent,
+};
+use crate::timestamp::Timestamp;
+use crate::touch::{MultitouchParser, TouchEvent, TouchGesture, TouchPipeline};
+
+const TRACE_FORMAT_VERSION: &str = "0.1";
+
+const EV_KEY: u16 = 1;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct TraceEvent {
+    /// Kernel event time in microseconds since the Unix epoch.
+    pub timestamp_us: u64,
+    pub event_type: u16,
+    pub code: u16,
+    pub value: i32,
+}
+
+/// A raw event capture plus everything needed to parse it without the
+/// device: its capabilities and axis ranges at recording time.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InputTrace {
+    pub format_version: String,
+    pub recorded_at: Timestamp,
+    pub device: InputDeviceInfo,
+    pub capabilities: DeviceCapabilities,
+    pub events: Vec<TraceEvent>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ReplayedPedalAction {
+    pub timestamp_us: u64,
+    pub key: u16,
+    pub press: PressKind,
+    pub action: PedalAction,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ReplayedGesture {
+    pub timestamp_us: u64,
+    pub gesture: TouchGesture,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TouchReplay {
+    pub events: Vec<TouchEvent>,
+    pub gestures: Vec<ReplayedGesture>,
+}
+
+/// Output of a trace run through the pipeline matching its device kind.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "pipeline", content = "events", rename_all = "snake_case")]
+pub enum ReplayResult {
+    Stylus(Vec<StylusEvent>),
+    Touch(TouchReplay),
+    Pedal(Vec<ReplayedPedalAction>),
+    /// Device kinds without a parser get their events back unchanged.
+    Raw(Vec<TraceEvent>),
+}
+
+/// Maps with the recorded axis ranges and no calibration or window, so
+/// replays only depend on the trace.
+fn mapper(trace: &InputTrace) -> CoordinateMapper {
+    CoordinateMapper::new(trace.device.axes.clone(), DeviceCalibration::default())
+}
+
+fn stylus_pipeline(trace: &InputTrace, pen: Arc<Mutex<PenProximity>>) -> StylusPipeline {
+    StylusPipeline::new(mapper(trace), pen, None)
+}
+
+pub fn replay_stylus(trace: &InputTrace) -> Vec<StylusEvent> {
+    let mut pipeline = stylus_pipeline(trace, Arc::default());
+    trace
+        .events
+        .iter()
+        .flat_map(|e| pipeline.feed_raw(e.event_type, e.code, e.value, e.timestamp_us))
+        .collect()
+}
+
+/// Runs a touch trace through the live touch pipeline. With `pen`, a trace
+/// recorded from the pen at the same time, palm rejection sees the pen
+/// proximity as it was during the recording.
+pub fn replay_touch(
+    trace: &InputTrace,
+    pen: Option<&InputTrace>,
+    palm: &PalmConfig,
+) -> TouchReplay {
+    let proximity = Arc::new(Mutex::new(PenProximity::default()));
+    let mut pen_pipeline = pen.map(|pen| stylus_pipeline(pen, proximity.clone()));
+    let mut pen_events = pen.map_or(&[][..], |pen| &pen.events).iter().peekable();
+    let mut pipeline = TouchPipeline::new(
+        // The parser grows its slot table as slots show up in the trace
+        MultitouchParser::new(1),
+        PalmFilter::new(palm.clone(), &trace.device.name),
+        mapper(trace),
+        trace.device.axes.clone(),
+        proximity,
+        None,
+    );
+
+    let mut replay = TouchReplay {
+        events: Vec::new(),
+        gestures: Vec::new(),
+    };
+    for e in &trace.events {
+        while let Some(p) = pen_events.next_if(|p| p.timestamp_us <= e.timestamp_us) {
+            if let Some(pen_pipeline) = &mut pen_pipeline {
+                pen_pipeline.feed_raw(p.event_type, p.code, p.value, p.timestamp_us);
+            }
+        }
+        let frame = pipeline.feed_raw(e.event_type, e.code, e.value, e.timestamp_us);
+        replay.events.extend(frame.events);
+        replay
+            .gestures
+            .extend(frame.gestures.into_iter().map(|gesture| ReplayedGesture {
+                timestamp_us: e.timestamp_us,
+                gesture,
+            }));
+    }
+    replay
+}
+
+/// Runs key events through the pedal recognizer on a simulated clock, so
+/// long-press and double-tap timing matches what happened when recorded.
+pub fn replay_pedal(trace: &InputTrace, config: &PedalConfig) -> Vec<ReplayedPedalAction> {
+    let Some(first) = trace.events.first() else {
+        return Vec::new();
+    };
+    let origin = Instant::now();
+    let base_us = first.timestamp_us;
+    let at = |us: u64| origin + Duration::from_micros(us.saturating_sub(base_us));
+    let to_us = |t: Instant| base_us + (t - origin).as_micros() as u64;
+
+    let mut recognizer = GestureRecognizer::new();
+    let mut fired: Vec<(Instant, u16, PressKind)> = Vec::new();
+    let run_timers = |recognizer: &mut GestureRecognizer, until: Option<Instant>| {
+        let mut out = Vec::new();
+        while let Some(deadline) = recognizer.next_deadline(config) {
+            if until.is_some_and(|until| deadline > until) {
+                break;
+            }
+            out.extend(
+                recognizer
+                    .tick(deadline, config)
+                    .into_iter()
+                    .map(|(k, p)| (deadline, k, p)),
+            );
+        }
+        out
+    };
+
+    for event in trace.events.iter().filter(|e| e.event_type == EV_KEY) {
+        let now = at(event.timestamp_us);
+        fired.extend(run_timers(&mut recognizer, Some(now)));
+        match event.value {
+            1 => recognizer.press(event.code, now),
+            0 => {
+                if let Some(press) = recognizer.release(event.code, now, config) {
+                    fired.push((now, event.code, press));
+                }
+            }
+            _ => {}
+        }
+    }
+    // Let pending taps and holds resolve after the last event
+    fired.extend(run_timers(&mut recognizer, None));
+
+    fired
+        .into_iter()
+        .filter_map(|(t, key, press)| {
+            Some(ReplayedPedalAction {
+                timestamp_us: to_us(t),
+                key,
+                press,
+                action: config.action(key, press)?,
+            })
+        })
+        .collect()
+}
+
+/// Runs `trace` through the live pipeline for its device kind. `pen` is
+/// only used for touch traces, see `replay_touch`.
+pub fn replay(
+    trace: &InputTrace,
+    pen: Option<&InputTrace>,
+    pedal_config: &PedalConfig,
+    palm_config: &PalmConfig,
+) -> ReplayResult {
+    match trace.device.kind {
+        DeviceKind::Pen | DeviceKind::Eraser => ReplayResult::Stylus(replay_stylus(trace)),
+        DeviceKind::Touchscreen | DeviceKind::Touchpad => {
+            ReplayResult::Touch(replay_touch(trace, pen, palm_config))
+        }
+        DeviceKind::Pedal => ReplayResult::Pedal(replay_pedal(trace, pedal_config)),
+        DeviceKind::Keyboard | DeviceKind::Other => ReplayResult::Raw(trace.events.clone()),
+    }
+}
+
+pub fn read_trace(path: &str) -> Result<InputTrace, String> {
+    let data = fs::read_to_string(path).map_err(|e| format!("Failed to read trace: {e}"))?;
+    serde_json::from_str(&data).map_err(|e| format!("Failed to parse trace: {e}"))
+}
+
+struct Recording {
+    device: InputDeviceInfo,
+    capabilities: DeviceCapabilities,
+    events: Arc<Mutex<Vec<TraceEvent>>>,
+}
+
+/// Recordings in progress, keyed by device path.
+#[derive(Default)]
+pub struct RecordingState {
+    active: Mutex<HashMap<String, Recording>>,
+}
+
+fn traces_dir(app: &AppHandle) -> Result<PathBuf, String> {
+    let data_dir = app
+        .path()
+        .app_data_dir()
+        .map_err(|e| format!("Failed to get app data dir: {e}"))?;
+    Ok(data_dir.join("traces"))
+}
+
+/// Starts capturing raw events from `device_path`. Recording runs on its
+/// own reader, so it can be used alongside a live stylus or pedal stream.
+#[tauri::command]
+pub fn start_input_recording(
+    app: AppHandle,
+    service: State<'_, InputService>,
+    recordings: State<'_, RecordingState>,
+    device_path: String,
+) -> Result<(), String> {
+    let device = Device::open(&device_path).map_err(|e| format!("Failed to open device: {e}"))?;
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let recording = Recording {
+        device: InputDeviceInfo::of(&device, &device_path),
+        capabilities: DeviceCapabilities::of(&device),
+        events: events.clone(),
+    };
+    drop(device);
+
+    let key = format!("record:{device_path}");
+    service.start(app, key, device_path.clone(), false, move |ev| {
+        if let Ok(mut events) = events.lock() {
+            events.push(TraceEvent {
+                timestamp_us: event_timestamp_us(ev),
+                event_type: ev.event_type().0,
+                code: ev.code(),
+                value: ev.value(),
+            });
+        }
+        true
+    })?;
+
+    recordings
+        .active
+        .lock()
+        .map_err(|e| e.to_string())?
+        .insert(device_path, recording);
+    Ok(())
+}
+
+/// Stops a recording and writes it as a trace file. Without `output_path`
+/// it goes to the app's `traces/` directory. Returns the written path.
+#[tauri::command]
+pub fn stop_input_recording(
+    app: AppHandle,
+    service: State<'_, InputService>,
+    recordings: State<'_, RecordingState>,
+    device_path: String,
+    output_path: Option<String>,
+) -> Result<String, String> {
+    service.stop(&format!("record:{device_path}"))?;
+    let recording = recordings
+        .active
+        .lock()
+        .map_err(|e| e.to_string())?
+        .remove(&device_path)
+        .ok_or_else(|| format!("Not recording: {device_path}"))?;
+
+    let events = recording.events.lock().map_err(|e| e.to_string())?.clone();
+    let recorded_at = Timestamp::now();
+    let trace = InputTrace {
+        format_version: TRACE_FORMAT_VERSION.into(),
+        recorded_at,
+        device: recording.device,
+        capabilities: recording.capabilities,
+        events,
+    };
+
+    let path = match output_path {
+        Some(path) => PathBuf::from(path),
+        None => {
+            let dir = traces_dir(&app)?;
+            fs::create_dir_all(&dir).map_err(|e| format!("Failed to create traces dir: {e}"))?;
+            dir.join(format!(
+                "{}.json",
+                recorded_at.to_string().replace(':', "-")
+            ))
+        }
+    };
+    // Compact, traces run to thousands of events
+    let data = serde_json::to_string(&trace).map_err(|e| format!("Failed to serialize: {e}"))?;
+    fs::write(&path, data).map_err(|e| format!("Failed to write trace: {e}"))?;
+
+    path.to_str()
+        .map(String::from)
+        .ok_or_else(|| "Invalid path".into())
+}
+
+/// Runs a trace through the same stages a live stream of its device kind
+/// would. Pedal traces are resolved against the current bindings and touch
+/// traces against the current palm rejection settings; `pen_trace_path` is
+/// a pen recording made alongside a touch one.
+#[tauri::command]
+pub fn replay_input_trace(
+    app: AppHandle,
+    pedal: State<'_, PedalState>,
+    palm: State<'_, PalmState>,
+    trace_path: String,
+    pen_trace_path: Option<String>,
+) -> Result<ReplayResult, String> {
+    let trace = read_trace(&trace_path)?;
+    let pen = pen_trace_path.as_deref().map(read_trace).transpose()?;
+    let pedal_config = pedal
+        .config(&app)?
+        .read()
+        .map_err(|e| e.to_string())?
+        .clone();
+    let palm_config = palm.config(&app)?;
+    Ok(replay(&trace, pen.as_ref(), &pedal_config, &palm_config))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pedal::PedalBinding;
+    use crate::stylus::StylusEventKind;
+    use crate::touch::TouchEventKind;
+    use serde_json::Value;
+    use std::path::Path;
+
+    const KEY_LEFT: u16 = 105;
+    const KEY_RIGHT: u16 = 106;
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures")
+            .join(name)
+    }
+
+    fn load(name: &str) -> InputTrace {
+        read_trace(&fixture(name).to_string_lossy()).unwrap()
+    }
+
+    /// Compares `output` with the stored `<trace>.snap.json`. Run with
+    /// `UPDATE_SNAPSHOTS=1` to write it after an intended change.
+    fn assert_snapshot(trace: &str, output: impl Serialize) {
+        let path = fixture(&trace.replace(".json", ".snap.json"));
+        // Parsed back from text like the snapshot, since serde_json's float
+        // parsing doesn't always round-trip
+        let actual: Value = serde_json::from_str(&serde_json::to_string(&output).unwrap()).unwrap();
+        if std::env::var_os("UPDATE_SNAPSHOTS").is_some() {
+            let data = serde_json::to_string_pretty(&actual).unwrap();
+            fs::write(&path, data + "\n").unwrap();
+            return;
+        }
+        let expected: Value = serde_json::from_str(&fs::read_to_string(&path).unwrap())
+            .unwrap_or_else(|e| panic!("Bad snapshot {}: {e}", path.display()));
+        assert_eq!(actual, expected, "replay of {trace} changed");
+    }
+
+    fn pedal_config() -> PedalConfig {
+        let mut config = PedalConfig::default();
+        config.bindings.extend([
+            PedalBinding {
+                key: KEY_RIGHT,
+                press: PressKind::LongPress,
+                action: PedalAction::LastPage,
+            },
+            PedalBinding {
+                key: KEY_LEFT,
+                press: PressKind::DoubleTap,
+                action: PedalAction::FirstPage,
+            },
+        ]);
+        config
+    }
+
+    #[test]
+    fn wacom_pen_stroke() {
+        let trace = load("wacom-pen-stroke.json");
+        let events = replay_stylus(&trace);
+        let mut kinds: Vec<_> = events.iter().map(|e| e.event_type).collect();
+        kinds.dedup();
+        assert_eq!(
+            kinds,
+            [
+                StylusEventKind::ProximityIn,
+                StylusEventKind::Hover,
+                StylusEventKind::Down,
+                StylusEventKind::Move,
+                StylusEventKind::Up,
+                StylusEventKind::Hover,
+                StylusEventKind::ProximityOut,
+            ]
+        );
+        // Mapped with the recorded axis ranges
+        let down = events
+            .iter()
+            .find(|e| e.event_type == StylusEventKind::Down)
+            .unwrap();
+        let mapped = down.mapped.as_ref().unwrap();
+        assert!((mapped.norm_x - down.x as f64 / 27648.0).abs() < 1e-9);
+        assert!(mapped.pressure > 0.0);
+        assert_snapshot("wacom-pen-stroke.json", &events);
+    }
+
+    #[test]
+    fn touch_two_finger_swipe() {
+        let trace = load("touch-two-finger-swipe.json");
+        let replay = replay_touch(&trace, None, &PalmConfig::default());
+        let count = |kind| {
+            replay
+                .events
+                .iter()
+                .filter(|e| e.event_type == kind)
+                .count()
+        };
+        assert_eq!(
+            (count(TouchEventKind::Down), count(TouchEventKind::Up)),
+            (2, 2)
+        );
+        let gestures: Vec<_> = replay.gestures.iter().map(|g| g.gesture).collect();
+        assert_eq!(gestures, [TouchGesture::TwoFingerSwipeLeft]);
+        assert_snapshot("touch-two-finger-swipe.json", &replay);
+    }
+
+    #[test]
+    fn pedal_gestures() {
+        let trace = load("pedal-gestures.json");
+        let actions = replay_pedal(&trace, &pedal_config());
+        let presses: Vec<_> = actions.iter().map(|a| (a.key, a.press)).collect();
+        assert_eq!(
+            presses,
+            [
+                (KEY_RIGHT, PressKind::Tap),
+                (KEY_RIGHT, PressKind::LongPress),
+                (KEY_LEFT, PressKind::DoubleTap),
+                (KEY_LEFT, PressKind::Tap),
+            ]
+        );
+        assert_snapshot("pedal-gestures.json", &actions);
+    }
+
+    #[test]
+    fn replay_picks_pipeline_by_device_kind() {
+        let config = pedal_config();
+        for (name, pipeline) in [
+            ("wacom-pen-stroke.json", "stylus"),
+            ("touch-two-finger-swipe.json", "touch"),
+            ("pedal-gestures.json", "pedal"),
+        ] {
+            let result = replay(&load(name), None, &config, &PalmConfig::default());
+            let result = serde_json::to_value(result).unwrap();
+            assert_eq!(result["pipeline"], pipeline, "{name}");
+        }
+    }
+}
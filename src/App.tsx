@@ -162,6 +162,20 @@ function App() {
     [refreshLibrary, openScore]
   )
 
+  const importAndOpenImages = useCallback(
+    async (paths: string[]) => {
+      initCancelledRef.current = true
+      try {
+        const entry = await invoke<LibraryEntry>("import_images", { paths })
+        await refreshLibrary()
+        await openScore(entry.id)
+      } catch (err) {
+        console.error("Failed to import images:", err)
+      }
+    },
+    [refreshLibrary, openScore]
+  )
+
   // Startup: ensure library, check CLI arg, auto-open last score or show library
   // biome-ignore lint/correctness/useExhaustiveDependencies: startup-only effect
   useEffect(() => {
@@ -186,6 +200,10 @@ function App() {
           await importAndOpenPdf(cliArg)
           return
         }
+        if (/\.(jpe?g|png|tiff?)$/.test(lower)) {
+          await importAndOpenImages([cliArg])
+          return
+        }
         if (lower.endsWith(".olscore")) {
           const filename = cliArg.split("/").pop() ?? ""
           const scoreId = filename.replace(".olscore", "")
@@ -567,10 +585,9 @@ function App() {
                 title: scoreTitle,
                 onTitleChange: async (title: string) => {
                   setScoreTitle(title)
-                  const updated = { ...currentScore.manifest, title }
                   await invoke("update_manifest", {
                     scoreId: currentScore.scoreId,
-                    manifest: updated,
+                    update: { title },
                   })
                 },
               }
@@ -10,6 +10,7 @@ type LibraryBrowserProps = {
 function formatDate(iso: string): string {
   try {
     const d = new Date(iso)
+    if (Number.isNaN(d.getTime())) return iso
     return d.toLocaleDateString(undefined, {
       year: "numeric",
       month: "short",
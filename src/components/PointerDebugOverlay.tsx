@@ -4,6 +4,18 @@ import { useEffect, useState } from "react"
 interface InputDeviceInfo {
   name: string
   path: string
+  kind:
+    | "pen"
+    | "eraser"
+    | "touchscreen"
+    | "touchpad"
+    | "pedal"
+    | "keyboard"
+    | "other"
+  has_eraser: boolean
+  buttons: string[]
+  vendor_id: number
+  product_id: number
   is_pen: boolean
   is_touch: boolean
 }
@@ -21,7 +33,25 @@ interface StylusEvent {
   tilt_x: number
   tilt_y: number
   touch_major: number
-  event_type: string
+  distance: number
+  tool: "pen" | "eraser" | "finger"
+  buttons: { primary: boolean; secondary: boolean; tertiary: boolean }
+  event_type:
+    | "proximity_in"
+    | "hover"
+    | "down"
+    | "move"
+    | "up"
+    | "button"
+    | "proximity_out"
+  timestamp_us: number
+  mapped?: {
+    norm_x: number
+    norm_y: number
+    window_x: number
+    window_y: number
+    pressure: number
+  }
 }
 
 export function PointerDebugOverlay() {
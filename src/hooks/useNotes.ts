@@ -1,90 +1,129 @@
 import { invoke } from "@tauri-apps/api/core"
 import { useCallback, useEffect, useRef, useState } from "react"
-import type { NotesData, RepeatButton } from "../types/library"
+import type { NotesData, NotesSnapshot, RepeatButton } from "../types/library"
+
+const EMPTY_NOTES: NotesData = { format_version: "0.1", repeat_buttons: [] }
 
 export function useNotes(scoreId: string | undefined) {
-  const [notes, setNotes] = useState<NotesData>({
-    format_version: "0.1",
-    repeat_buttons: [],
-  })
+  const [notes, setNotesState] = useState<NotesData>(EMPTY_NOTES)
+  // Latest notes for the updaters, which must not run side effects inside
+  // a state updater
+  const notesRef = useRef(notes)
+  const revisionRef = useRef(0)
   const scoreIdRef = useRef(scoreId)
   scoreIdRef.current = scoreId
 
+  const setNotes = useCallback((next: NotesData) => {
+    notesRef.current = next
+    setNotesState(next)
+  }, [])
+
+  const reload = useCallback(() => {
+    const id = scoreIdRef.current
+    if (!id) return
+    invoke<NotesSnapshot>("get_notes", { scoreId: id })
+      .then((snapshot) => {
+        if (scoreIdRef.current !== id) return
+        revisionRef.current = snapshot.revision
+        setNotes(snapshot.notes)
+      })
+      .catch(console.error)
+  }, [setNotes])
+
   useEffect(() => {
     if (!scoreId) {
-      setNotes({ format_version: "0.1", repeat_buttons: [] })
+      setNotes(EMPTY_NOTES)
       return
     }
-    invoke<NotesData>("read_notes", { scoreId })
-      .then(setNotes)
-      .catch(console.error)
-  }, [scoreId])
+    reload()
+    return () => {
+      invoke("close_notes", { scoreId }).catch(console.error)
+    }
+  }, [scoreId, reload, setNotes])
 
-  const persist = useCallback((updated: NotesData) => {
-    const id = scoreIdRef.current
-    if (!id) return
-    invoke("save_notes", { scoreId: id, notes: updated }).catch(console.error)
-  }, [])
+  // Sends one patch against the revision local state is based on. Each
+  // patch bumps the revision by one, so patches sent before the previous
+  // one returned expect the next. If the backend rejects one, e.g. because
+  // another window changed the notes, local state is replaced with the
+  // backend's.
+  const patch = useCallback(
+    (command: string, args: Record<string, unknown>) => {
+      const id = scoreIdRef.current
+      if (!id) return
+      const expectedRevision = revisionRef.current
+      revisionRef.current = expectedRevision + 1
+      invoke<number>(command, { scoreId: id, expectedRevision, ...args })
+        .then((revision) => {
+          revisionRef.current = Math.max(revisionRef.current, revision)
+        })
+        .catch((e) => {
+          console.error(e)
+          reload()
+        })
+    },
+    [reload]
+  )
 
   const addRepeatButton = useCallback(
     (btn: RepeatButton) => {
-      setNotes((prev) => {
-        const updated = {
-          ...prev,
-          repeat_buttons: [...prev.repeat_buttons, btn],
-        }
-        persist(updated)
-        return updated
+      const prev = notesRef.current
+      setNotes({ ...prev, repeat_buttons: [...prev.repeat_buttons, btn] })
+      patch("add_annotation", {
+        annotation: { type: "repeat_button", layer: "default", ...btn },
       })
     },
-    [persist]
+    [patch, setNotes]
   )
 
   const updateRepeatButton = useCallback(
     (id: string, updates: Partial<RepeatButton>) => {
-      setNotes((prev) => {
-        const updated = {
-          ...prev,
-          repeat_buttons: prev.repeat_buttons.map((b) =>
-            b.id === id ? { ...b, ...updates } : b
-          ),
-        }
-        persist(updated)
-        return updated
+      const prev = notesRef.current
+      const current = prev.repeat_buttons.find((b) => b.id === id)
+      if (!current) return
+      const updated = { ...current, ...updates }
+      setNotes({
+        ...prev,
+        repeat_buttons: prev.repeat_buttons.map((b) =>
+          b.id === id ? updated : b
+        ),
+      })
+      patch("update_annotation", {
+        annotation: { type: "repeat_button", layer: "default", ...updated },
       })
     },
-    [persist]
+    [patch, setNotes]
   )
 
   const deleteRepeatButton = useCallback(
     (id: string) => {
-      setNotes((prev) => {
-        const updated = {
-          ...prev,
-          repeat_buttons: prev.repeat_buttons.filter((b) => b.id !== id),
-        }
-        persist(updated)
-        return updated
+      const prev = notesRef.current
+      setNotes({
+        ...prev,
+        repeat_buttons: prev.repeat_buttons.filter((b) => b.id !== id),
       })
+      patch("delete_annotation", { id })
     },
-    [persist]
+    [patch, setNotes]
   )
 
   const shiftAllRepeatButtons = useCallback(
     (delta: number) => {
-      setNotes((prev) => {
-        const updated = {
-          ...prev,
-          repeat_buttons: prev.repeat_buttons.map((b) => ({
-            ...b,
-            target_page: Math.max(0, b.target_page + delta),
-          })),
-        }
-        persist(updated)
-        return updated
+      const prev = notesRef.current
+      if (prev.repeat_buttons.length === 0) return
+      const repeat_buttons = prev.repeat_buttons.map((b) => ({
+        ...b,
+        target_page: Math.max(0, b.target_page + delta),
+      }))
+      setNotes({ ...prev, repeat_buttons })
+      patch("update_annotations", {
+        annotations: repeat_buttons.map((b) => ({
+          type: "repeat_button",
+          layer: "default",
+          ...b,
+        })),
       })
     },
-    [persist]
+    [patch, setNotes]
   )
 
   return {
@@ -4,7 +4,7 @@ import type { PDFDocumentProxy } from "pdfjs-dist"
 import * as pdfjsLib from "pdfjs-dist"
 import pdfjsWorker from "pdfjs-dist/build/pdf.worker.min.mjs?url"
 import { useCallback, useEffect, useState } from "react"
-import type { ScoreRef } from "../types/library"
+import type { ManifestUpdate, ScoreRef } from "../types/library"
 import {
   getFullscreenRenderSize,
   hasCachedPage,
@@ -118,12 +118,13 @@ export const usePdfDocument = (
           try {
             const { info } = await pdf.getMetadata()
             if (cancelled) return
-            const updated = { ...manifest, pdf_metadata: info }
-            const pdfTitle = (info as Record<string, unknown>).Title
+            const metadata = info as Record<string, unknown>
+            const update: ManifestUpdate = { pdf_metadata: metadata }
+            const pdfTitle = metadata.Title
             if (typeof pdfTitle === "string" && pdfTitle.trim()) {
-              updated.title = pdfTitle.trim()
+              update.title = pdfTitle.trim()
             }
-            await invoke("update_manifest", { scoreId, manifest: updated })
+            await invoke("update_manifest", { scoreId, update })
           } catch {
             // Metadata extraction is best-effort
           }
@@ -145,18 +146,17 @@ export const usePdfDocument = (
   const setStartsOnLeft = useCallback(
     async (value: boolean) => {
       setStartsOnLeftState(value)
-      if (!scoreId || !manifest) return
-      const updatedManifest = {
-        ...manifest,
-        display: { ...manifest.display, starts_on_left: value },
-      }
+      if (!scoreId) return
       try {
-        await invoke("update_manifest", { scoreId, manifest: updatedManifest })
+        await invoke("update_manifest", {
+          scoreId,
+          update: { starts_on_left: value },
+        })
       } catch (err) {
         console.error("Failed to persist startsOnLeft:", err)
       }
     },
-    [scoreId, manifest]
+    [scoreId]
   )
 
   const getPageImageUrl = useCallback(
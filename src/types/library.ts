@@ -5,8 +5,140 @@ export type PdfEntry = {
   sha256: string
 }
 
+export type DisplayMode =
+  | "auto"
+  | "single"
+  | "dual"
+  | "half_page"
+  | "vertical_scroll"
+
 export type DisplaySettings = {
   starts_on_left: boolean
+  default_spread?: DisplayMode
+  crop?: CropRect
+  even_crop?: CropRect
+}
+
+export type CropRect = {
+  x: number
+  y: number
+  width: number
+  height: number
+}
+
+export type PageRef = {
+  pdf: string
+  page: number
+  rotation?: number
+  crop?: CropRect
+}
+
+export type PageCropSuggestion = {
+  pdf: string
+  page: number
+  crop: CropRect | null
+}
+
+export type AutoCropResult = {
+  pages: PageCropSuggestion[]
+  odd: CropRect | null
+  even: CropRect | null
+}
+
+export type SourceFormat = "music_xml" | "mxl" | "mei"
+
+export type SourceMetadata = {
+  title?: string
+  composer?: string
+  parts: string[]
+  key?: string
+  time?: string
+  measures: number
+}
+
+export type SourceEntry = {
+  filename: string
+  format: SourceFormat
+  sha256: string
+  metadata: SourceMetadata
+}
+
+export type AudioKind = "reference" | "practice"
+
+export type AudioSyncPoint = {
+  time: number
+  pdf: string
+  page: number
+  region?: CropRect
+  measure?: number
+}
+
+export type TimelinePosition = {
+  time: number
+  pdf: string
+  page: number
+  region: CropRect | null
+  y: number
+  point: number
+  next_turn: number | null
+}
+
+export type PageTurnEvent = {
+  score_id: string
+  audio_id: string
+  position: TimelinePosition
+}
+
+export type AudioEntry = {
+  id: string
+  name: string
+  kind: AudioKind
+  format: string
+  duration?: number
+  sha256: string
+  added_at: string
+  sync_points?: AudioSyncPoint[]
+} & ({ storage: "embedded"; filename: string } | { storage: "external"; path: string })
+
+export type TimeSignature = {
+  beats: number
+  unit: number
+}
+
+export type Tempo = {
+  bpm: number
+  time_signature: TimeSignature
+}
+
+export type TempoAnchor =
+  | { type: "page"; pdf: string; page: number }
+  | { type: "bookmark"; name: string }
+
+export type TempoChange = {
+  at: TempoAnchor
+  bpm?: number
+  time_signature?: TimeSignature
+  label?: string
+}
+
+export type TempoMap = Tempo & {
+  changes?: TempoChange[]
+}
+
+export type MetronomeStatus = {
+  score_id: string
+  tempo: Tempo
+  following: boolean
+  scale: number
+  volume: number
+}
+
+export type MetronomeAdjust = {
+  bpm?: number
+  time_signature?: TimeSignature
+  scale?: number
+  volume?: number
+  follow?: boolean
 }
 
 export type ScoreManifest = {
@@ -19,6 +151,32 @@ export type ScoreManifest = {
   created_at?: string
   modified_at?: string
   pdf_metadata?: Record<string, unknown>
+  page_order?: PageRef[]
+  split_points?: Record<string, Record<string, number[]>>
+  sources?: SourceEntry[]
+  audio?: AudioEntry[]
+  tempo?: TempoMap
+}
+
+export type ManifestUpdate = {
+  title?: string
+  composer?: string
+  tags?: string[]
+  starts_on_left?: boolean
+  default_spread?: DisplayMode
+  pdf_metadata?: Record<string, unknown>
+}
+
+export type ScreenRegion = {
+  index: number
+  pdf: string
+  page: number
+  top: number
+  bottom: number
+  x: number
+  y: number
+  width: number
+  height: number
 }
 
 export type LibraryEntry = {
@@ -27,6 +185,45 @@ export type LibraryEntry = {
   sha256: string
   created_at: string
   last_opened_at: string
+  library_id?: string
+}
+
+export type PageVisit = {
+  pdf: string
+  page: number
+  seconds: number
+}
+
+export type PracticeSession = {
+  id: string
+  score_id: string
+  title: string
+  started_at: string
+  ended_at: string
+  seconds: number
+  pages: PageVisit[]
+  rating?: number
+  notes?: string
+}
+
+export type PracticeTotal = {
+  score_id: string
+  title: string
+  sessions: number
+  seconds: number
+  last_practiced_at: string
+  average_rating: number | null
+}
+
+export type LibraryLocation = {
+  id: string
+  name: string
+  path: string
+}
+
+export type LibraryInfo = LibraryLocation & {
+  is_default: boolean
+  available: boolean
 }
 
 export type RepeatButton = {
@@ -37,14 +234,111 @@ export type RepeatButton = {
   offset_x: number
   offset_y: number
   size: number
+  layer?: string
+}
+
+export type Layer = {
+  id: string
+  name: string
+  visible: boolean
+  opacity: number
+  locked: boolean
+  color: string
+  width: number
+}
+
+export type Stroke = {
+  id: string
+  layer: string
+  points: [number, number, number][]
+  color: string
+  width: number
+  tool: "pen" | "highlighter"
+}
+
+export type TextNote = {
+  id: string
+  layer: string
+  x: number
+  y: number
+  content: string
+  color: string
+}
+
+export type PageNotes = {
+  strokes?: Stroke[]
+  text_notes?: TextNote[]
+  bookmarks?: string[]
+}
+
+export type NavAnchor = {
+  pdf: string
+  page: number
+  y: number
+}
+
+export type NavMarkKind =
+  | { type: "repeat_start" }
+  | { type: "repeat_end"; times?: number }
+  | { type: "volta"; endings: number[]; end: NavAnchor }
+  | { type: "segno" }
+  | { type: "coda" }
+  | { type: "fine" }
+  | { type: "da_capo" }
+  | { type: "dal_segno" }
+  | { type: "to_coda" }
+
+export type NavMark = NavMarkKind & {
+  id: string
+  layer: string
+  anchor: NavAnchor
+  passes?: number[]
+}
+
+export type PerformancePage = {
+  pdf: string
+  page: number
+  score_page: number
 }
 
 export type NotesData = {
   format_version: string
+  layers?: Layer[]
   repeat_buttons: RepeatButton[]
+  navigation?: NavMark[]
+  pages?: Record<string, Record<string, PageNotes>>
+}
+
+export type NotesSnapshot = {
+  revision: number
+  notes: NotesData
 }
 
 export type ScoreRef = {
   scoreId: string
   manifest: ScoreManifest
 }
+
+export type Staff = {
+  lines: number[]
+  left: number
+  right: number
+  confidence: number
+}
+
+export type System = {
+  bounds: CropRect
+  staves: Staff[]
+  confidence: number
+}
+
+export type PageSystems = {
+  page: number
+  systems: System[]
+}
+
+export type SystemAnalysis = {
+  version: number
+  sha256: string
+  pages: PageSystems[]
+}